@@ -60,6 +60,9 @@ export const identitySearchMessageToServerTypes = Object.freeze({
   IDENTITY_SEARCH_AUTH_MESSAGE: 'IdentitySearchAuthMessage',
   IDENTITY_SEARCH_QUERY: 'IdentitySearchQuery',
   IDENTITY_SEARCH_PREFIX: 'IdentitySearchPrefix',
+  IDENTITY_SEARCH_FARCASTER: 'IdentitySearchFarcaster',
+  IDENTITY_SEARCH_WALLET: 'IdentitySearchWallet',
+  IDENTITY_SEARCH_FUZZY: 'IdentitySearchFuzzy',
   HEARTBEAT: 'Heartbeat',
 });
 
@@ -12,13 +12,11 @@
  *
  */
 
-import type { TInterface } from 'tcomb';
+import type { TInterface, TUnion } from 'tcomb';
 import t from 'tcomb';
 
 import { tShape, tString } from '../../utils/validation-utils.js';
 
-export type IdentitySearchMethod = IdentitySearchPrefix;
-
 export type IdentitySearchPrefix = {
   +type: 'IdentitySearchPrefix',
   +prefix: string,
@@ -30,13 +28,71 @@ export const identityPrefixValidator: TInterface<IdentitySearchPrefix> =
     prefix: t.String,
   });
 
-export const identitySearchMethodValidator: TInterface<IdentitySearchPrefix> =
-  identityPrefixValidator;
+export type IdentitySearchFarcaster = {
+  +type: 'IdentitySearchFarcaster',
+  +farcasterID: string,
+};
+
+export const identityFarcasterValidator: TInterface<IdentitySearchFarcaster> =
+  tShape<IdentitySearchFarcaster>({
+    type: tString('IdentitySearchFarcaster'),
+    farcasterID: t.String,
+  });
+
+export type IdentitySearchFarcasterUsername = {
+  +type: 'IdentitySearchFarcasterUsername',
+  +username: string,
+};
+
+export const identityFarcasterUsernameValidator: TInterface<IdentitySearchFarcasterUsername> =
+  tShape<IdentitySearchFarcasterUsername>({
+    type: tString('IdentitySearchFarcasterUsername'),
+    username: t.String,
+  });
+
+export type IdentitySearchWallet = {
+  +type: 'IdentitySearchWallet',
+  +wallet: string,
+};
+
+export const identityWalletValidator: TInterface<IdentitySearchWallet> =
+  tShape<IdentitySearchWallet>({
+    type: tString('IdentitySearchWallet'),
+    wallet: t.String,
+  });
+
+export type IdentitySearchFuzzy = {
+  +type: 'IdentitySearchFuzzy',
+  +query: string,
+};
+
+export const identityFuzzyValidator: TInterface<IdentitySearchFuzzy> =
+  tShape<IdentitySearchFuzzy>({
+    type: tString('IdentitySearchFuzzy'),
+    query: t.String,
+  });
+
+export type IdentitySearchMethod =
+  | IdentitySearchPrefix
+  | IdentitySearchFarcaster
+  | IdentitySearchFarcasterUsername
+  | IdentitySearchWallet
+  | IdentitySearchFuzzy;
+
+export const identitySearchMethodValidator: TUnion<IdentitySearchMethod> =
+  t.union([
+    identityPrefixValidator,
+    identityFarcasterValidator,
+    identityFarcasterUsernameValidator,
+    identityWalletValidator,
+    identityFuzzyValidator,
+  ]);
 
 export type IdentitySearchQuery = {
   +type: 'IdentitySearchQuery',
   +id: string,
   +searchMethod: IdentitySearchMethod,
+  +cursor?: string,
 };
 
 export const identitySearchQueryValidator: TInterface<IdentitySearchQuery> =
@@ -44,4 +100,5 @@ export const identitySearchQueryValidator: TInterface<IdentitySearchQuery> =
     type: tString('IdentitySearchQuery'),
     id: t.String,
     searchMethod: identitySearchMethodValidator,
+    cursor: t.maybe(t.String),
   });
@@ -42,12 +42,14 @@ export const identitySearchUserValidator: TInterface<IdentitySearchUser> =
 export type IdentitySearchResult = {
   +id: string,
   +hits: $ReadOnlyArray<IdentitySearchUser>,
+  +nextCursor?: string,
 };
 
 export const identitySearchResultValidator: TInterface<IdentitySearchResult> =
   tShape<IdentitySearchResult>({
     id: t.String,
     hits: t.list(identitySearchUserValidator),
+    nextCursor: t.maybe(t.String),
   });
 
 type IdentitySearchResponseSuccess = {
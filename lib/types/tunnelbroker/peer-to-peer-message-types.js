@@ -35,6 +35,7 @@ export const peerToPeerMessageTypes = Object.freeze({
   DEVICE_LIST_UPDATED: 'DeviceListUpdated',
   MESSAGE_PROCESSED: 'MessageProcessed',
   IDENTITY_DEVICE_LIST_UPDATED: 'IdentityDeviceListUpdated',
+  IDENTITY_USERNAME_UPDATED: 'IdentityUsernameUpdated',
   BAD_DEVICE_TOKEN: 'BadDeviceToken',
   NEW_FARCASTER_MESSAGE: 'NewFarcasterMessage',
   FARCASTER_INBOX_STATUS: 'FarcasterInboxStatus',
@@ -120,6 +121,16 @@ export const identityDeviceListUpdatedValidator: TInterface<IdentityDeviceListUp
     type: tString(peerToPeerMessageTypes.IDENTITY_DEVICE_LIST_UPDATED),
   });
 
+export type IdentityUsernameUpdated = {
+  +type: 'IdentityUsernameUpdated',
+  +username: string,
+};
+export const identityUsernameUpdatedValidator: TInterface<IdentityUsernameUpdated> =
+  tShape<IdentityUsernameUpdated>({
+    type: tString(peerToPeerMessageTypes.IDENTITY_USERNAME_UPDATED),
+    username: t.String,
+  });
+
 export type BadDeviceToken = {
   +type: 'BadDeviceToken',
   +invalidatedToken: string,
@@ -138,6 +149,7 @@ export type PeerToPeerMessage =
   | DeviceListUpdated
   | MessageProcessed
   | IdentityDeviceListUpdated
+  | IdentityUsernameUpdated
   | BadDeviceToken
   | NewFarcasterMessage
   | FarcasterInboxStatus;
@@ -150,6 +162,7 @@ export const peerToPeerMessageValidator: TUnion<PeerToPeerMessage> = t.union([
   deviceListUpdatedValidator,
   messageProcessedValidator,
   identityDeviceListUpdatedValidator,
+  identityUsernameUpdatedValidator,
   badDeviceTokenValidator,
   newFarcasterMessageValidator,
   farcasterInboxStatusValidator,
@@ -28,6 +28,7 @@ import type { TextInputKeyPressEvent } from '../types/react-native.js';
 import type { ViewStyle } from '../types/styles.js';
 import {
   appOutOfDateAlertDetails,
+  tooManyLoginAttemptsAlertDetails,
   unknownErrorAlertDetails,
   userNotFoundAlertDetails,
 } from '../utils/alert-messages.js';
@@ -266,6 +267,16 @@ class LogInPanel extends React.PureComponent<Props, State> {
           [{ text: 'OK', onPress: this.onUnsuccessfulLoginAlertAckowledged }],
           { cancelable: false },
         );
+      } else if (
+        messageForException === 'too_many_login_attempts' ||
+        messageForException === 'login_temporarily_locked'
+      ) {
+        Alert.alert(
+          tooManyLoginAttemptsAlertDetails.title,
+          tooManyLoginAttemptsAlertDetails.message,
+          [{ text: 'OK', onPress: this.onOtherErrorAlertAcknowledged }],
+          { cancelable: false },
+        );
       } else if (
         messageForException === 'unsupported_version' ||
         messageForException === 'client_version_unsupported' ||
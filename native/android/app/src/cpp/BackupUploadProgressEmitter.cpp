@@ -0,0 +1,40 @@
+#include "jniHelpers.h"
+#include <NativeModules/BackupUploadProgressEmitter.h>
+#include <fbjni/fbjni.h>
+
+using namespace facebook::jni;
+
+class BackupUploadProgressEmitterJavaClass
+    : public JavaClass<BackupUploadProgressEmitterJavaClass> {
+public:
+  static auto constexpr kJavaDescriptor =
+      "Lapp/comm/android/commservices/BackupUploadProgressEmitter;";
+
+  static void sendUploadProgressToJS(
+      rust::String backupID,
+      std::uint64_t uploadedBytes,
+      std::uint64_t totalBytes) {
+
+    static const auto cls = javaClassStatic();
+    static auto method =
+        cls->getStaticMethod<void(std::string, jdouble, jdouble)>(
+            "sendUploadProgressToJS");
+    method(
+        cls,
+        std::string(backupID),
+        static_cast<jdouble>(uploadedBytes),
+        static_cast<jdouble>(totalBytes));
+  }
+};
+
+namespace comm {
+void BackupUploadProgressEmitter::sendUploadProgressToJS(
+    rust::String backupID,
+    std::uint64_t uploadedBytes,
+    std::uint64_t totalBytes) {
+  NativeAndroidAccessProvider::runTask([&]() {
+    BackupUploadProgressEmitterJavaClass::sendUploadProgressToJS(
+        backupID, uploadedBytes, totalBytes);
+  });
+}
+} // namespace comm
@@ -0,0 +1,77 @@
+package app.comm.android.commservices;
+
+import com.facebook.react.bridge.Arguments;
+import com.facebook.react.bridge.ReactApplicationContext;
+import com.facebook.react.bridge.ReactContextBaseJavaModule;
+import com.facebook.react.bridge.ReactMethod;
+import com.facebook.react.bridge.WritableMap;
+import com.facebook.react.modules.core.DeviceEventManagerModule;
+import java.util.HashMap;
+import java.util.Map;
+
+public class BackupUploadProgressEmitter extends ReactContextBaseJavaModule {
+
+  private static int listenersCount = 0;
+  private static BackupUploadProgressEmitter sharedInstance = null;
+
+  public static final String BACKUP_UPLOAD_PROGRESS = "backupUploadProgress";
+
+  BackupUploadProgressEmitter(ReactApplicationContext reactContext) {
+    super(reactContext);
+  }
+
+  @Override
+  public String getName() {
+    return "BackupUploadProgressEmitter";
+  }
+
+  @ReactMethod
+  public void addListener(String eventName) {
+    synchronized (BackupUploadProgressEmitter.class) {
+      listenersCount++;
+      if (sharedInstance == null) {
+        sharedInstance = this;
+      }
+    }
+  }
+
+  @ReactMethod
+  public void removeListeners(Integer count) {
+    synchronized (BackupUploadProgressEmitter.class) {
+      listenersCount -= count;
+      boolean isLastListener = listenersCount == 0;
+      if (isLastListener) {
+        sharedInstance = null;
+      }
+    }
+  }
+
+  public static void sendUploadProgressToJS(
+      String backupID,
+      double uploadedBytes,
+      double totalBytes) {
+    synchronized (BackupUploadProgressEmitter.class) {
+      if (sharedInstance == null) {
+        return;
+      }
+
+      // Event body must match BackupUploadProgress
+      // type from 'native/event-emitters/backup-upload-progress-emitter.js'
+      WritableMap eventBody = Arguments.createMap();
+      eventBody.putString("backupID", backupID);
+      eventBody.putDouble("uploadedBytes", uploadedBytes);
+      eventBody.putDouble("totalBytes", totalBytes);
+
+      sharedInstance.getReactApplicationContext()
+          .getJSModule(DeviceEventManagerModule.RCTDeviceEventEmitter.class)
+          .emit(BACKUP_UPLOAD_PROGRESS, eventBody);
+    }
+  }
+
+  @Override
+  public Map<String, Object> getConstants() {
+    final Map<String, Object> constants = new HashMap<>();
+    constants.put("BACKUP_UPLOAD_PROGRESS", BACKUP_UPLOAD_PROGRESS);
+    return constants;
+  }
+}
@@ -21,6 +21,7 @@ public class CommServicesPackage implements ReactPackage {
     List<NativeModule> modules = new ArrayList<>();
 
     modules.add(new CommServicesAuthMetadataEmitter(reactContext));
+    modules.add(new BackupUploadProgressEmitter(reactContext));
 
     return modules;
   }
@@ -312,6 +312,7 @@ void DatabaseManager::captureBackupLogForLastOperation() {
     return;
   }
 
+  ::recordBackupLog(rust::string(backupID), rust::string(logID));
   DatabaseManager::getQueryExecutor().setMetadata(
       "logID", std::to_string(std::stoi(logID) + 1));
 }
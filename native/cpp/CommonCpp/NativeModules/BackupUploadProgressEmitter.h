@@ -0,0 +1,15 @@
+#pragma once
+
+#include "cxx.h"
+
+namespace comm {
+
+class BackupUploadProgressEmitter {
+public:
+  static void sendUploadProgressToJS(
+      rust::String backupID,
+      std::uint64_t uploadedBytes,
+      std::uint64_t totalBytes);
+};
+
+} // namespace comm
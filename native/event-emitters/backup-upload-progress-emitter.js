@@ -0,0 +1,31 @@
+// @flow
+
+import { NativeModules, NativeEventEmitter } from 'react-native';
+
+export type BackupUploadProgress = {
+  +backupID: string,
+  +uploadedBytes: number,
+  +totalBytes: number,
+};
+
+type BackupUploadProgressEmitterConstants = {
+  +BACKUP_UPLOAD_PROGRESS: 'backupUploadProgress',
+};
+
+type BackupUploadProgressEmitterModuleType = {
+  +addListener: (eventName: string) => void,
+  +removeListeners: (count: number) => void,
+  +getConstants: () => BackupUploadProgressEmitterConstants,
+  ...BackupUploadProgressEmitterConstants,
+};
+
+const BackupUploadProgressEmitterModule: BackupUploadProgressEmitterModuleType =
+  NativeModules.BackupUploadProgressEmitter;
+
+function getBackupUploadProgressEmitter(): NativeEventEmitter<{
+  +backupUploadProgress: [BackupUploadProgress],
+}> {
+  return new NativeEventEmitter(BackupUploadProgressEmitterModule);
+}
+
+export { getBackupUploadProgressEmitter };
@@ -0,0 +1,96 @@
+#import "BackupUploadProgressEmitter.h"
+
+#import <Foundation/Foundation.h>
+#import <React/RCTBridgeModule.h>
+#import <React/RCTEventEmitter.h>
+
+NSString *const backupUploadProgress = @"backupUploadProgress";
+
+@interface BackupUploadProgressEmitterIOSWrapper
+    : RCTEventEmitter <RCTBridgeModule>
+@property(nonatomic) BOOL hasListeners;
+
++ (void)sendUploadProgressToJS:(NSString *)backupID
+                 uploadedBytes:(double)uploadedBytes
+                    totalBytes:(double)totalBytes;
+@end
+
+@implementation BackupUploadProgressEmitterIOSWrapper
+
+RCT_EXPORT_MODULE(BackupUploadProgressEmitter);
+
+static BackupUploadProgressEmitterIOSWrapper *sharedInstance = nil;
+
+- (instancetype)init {
+  self = [super init];
+  if (!self) {
+    return self;
+  }
+  _hasListeners = NO;
+  return self;
+}
+
+- (void)startObserving {
+  @synchronized([self class]) {
+    _hasListeners = YES;
+    sharedInstance = self;
+  }
+}
+
+- (void)stopObserving {
+  @synchronized([self class]) {
+    _hasListeners = NO;
+    sharedInstance = nil;
+  }
+}
+
+- (NSArray<NSString *> *)supportedEvents {
+  return @[ backupUploadProgress ];
+}
+
+- (NSDictionary *)constantsToExport {
+  return @{@"BACKUP_UPLOAD_PROGRESS" : backupUploadProgress};
+}
+
++ (BOOL)requiresMainQueueSetup {
+  return YES;
+}
+
++ (void)sendUploadProgressToJS:(NSString *)backupID
+                 uploadedBytes:(double)uploadedBytes
+                    totalBytes:(double)totalBytes {
+  @synchronized([self class]) {
+    if (!sharedInstance) {
+      return;
+    }
+
+    // Event body must match BackupUploadProgress
+    // type from 'native/event-emitters/backup-upload-progress-emitter.js'
+    NSDictionary *eventBody = @{
+      @"backupID" : backupID,
+      @"uploadedBytes" : @(uploadedBytes),
+      @"totalBytes" : @(totalBytes)
+    };
+
+    [sharedInstance sendEventWithName:backupUploadProgress body:eventBody];
+  }
+}
+
+@end
+
+namespace comm {
+
+void BackupUploadProgressEmitter::sendUploadProgressToJS(
+    rust::String backupID,
+    std::uint64_t uploadedBytes,
+    std::uint64_t totalBytes) {
+  NSString *backupIDObjC =
+      [NSString stringWithCString:std::string(backupID).c_str()
+                         encoding:NSUTF8StringEncoding];
+
+  [BackupUploadProgressEmitterIOSWrapper
+      sendUploadProgressToJS:backupIDObjC
+               uploadedBytes:static_cast<double>(uploadedBytes)
+                  totalBytes:static_cast<double>(totalBytes)];
+}
+} // namespace comm
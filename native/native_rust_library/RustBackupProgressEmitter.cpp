@@ -0,0 +1,12 @@
+#include "RustBackupProgressEmitter.h"
+#include "../cpp/CommonCpp/NativeModules/BackupUploadProgressEmitter.h"
+
+namespace comm {
+void sendBackupUploadProgressToJS(
+    rust::String backupID,
+    std::uint64_t uploadedBytes,
+    std::uint64_t totalBytes) {
+  BackupUploadProgressEmitter::sendUploadProgressToJS(
+      backupID, uploadedBytes, totalBytes);
+}
+} // namespace comm
@@ -0,0 +1,12 @@
+#pragma once
+
+#include "cxx.h"
+
+namespace comm {
+
+void sendBackupUploadProgressToJS(
+    rust::String backupID,
+    std::uint64_t uploadedBytes,
+    std::uint64_t totalBytes);
+
+} // namespace comm
@@ -1,6 +1,7 @@
 mod compaction_upload_promises;
 mod file_info;
 mod upload_handler;
+mod upload_journal;
 
 use crate::argon2_tools::{compute_backup_key, compute_backup_key_str};
 use crate::constants::{aes, secure_store};
@@ -152,6 +153,12 @@ pub mod ffi {
         compaction_promise_id,
       );
 
+      if let Err(err) =
+        upload_handler::record_backup_compaction(backup_id.clone())
+      {
+        handle_backup_creation_error(backup_id, err.to_string());
+        return;
+      }
       trigger_backup_file_upload();
       // The promise will be resolved when the backup is uploaded
     });
@@ -188,6 +195,12 @@ pub mod ffi {
         return;
       }
 
+      if let Err(err) =
+        upload_handler::record_backup_compaction(backup_id.clone())
+      {
+        handle_backup_creation_error(backup_id, err.to_string());
+        return;
+      }
       trigger_backup_file_upload();
       // The promise will be resolved when the backup is uploaded
     });
@@ -506,7 +519,10 @@ async fn download_and_apply_logs(
   let backup_client = BackupClient::new(BACKUP_SOCKET_ADDR)?;
   let user_identity = get_user_identity_from_secure_store()?;
 
-  let stream = backup_client.download_logs(&user_identity, backup_id).await;
+  // logs of secondary devices are merged with primary logs
+  let stream = backup_client
+    .download_device_logs(&user_identity, backup_id)
+    .await;
   let mut stream = Box::pin(stream);
 
   while let Some(mut log) = stream.try_next().await? {
@@ -1,10 +1,15 @@
 use super::file_info::BackupFileInfo;
 use super::get_user_identity_from_secure_store;
+use super::upload_journal::{retry_delay, PendingUpload, UploadJournal};
 use crate::backup::compaction_upload_promises;
-use crate::constants::BACKUP_SERVICE_CONNECTION_RETRY_DELAY;
+use crate::constants::{
+  BACKUP_COMPACTION_UPLOAD_MAX_ATTEMPTS, BACKUP_LOG_UPLOAD_MAX_ATTEMPTS,
+  BACKUP_SERVICE_CONNECTION_RETRY_DELAY,
+};
 use crate::ffi::{
   get_backup_directory_path, get_backup_file_path, get_backup_log_file_path,
   get_backup_user_keys_file_path, get_siwe_backup_message_path,
+  send_backup_upload_progress_to_js,
 };
 use crate::BACKUP_SOCKET_ADDR;
 use crate::RUNTIME;
@@ -12,7 +17,9 @@ use backup_client::UserIdentity;
 use backup_client::{
   BackupClient, Error as BackupError, LogUploadConfirmation, Stream, StreamExt,
 };
-use backup_client::{BackupData, Sink, UploadLogRequest};
+use backup_client::{
+  BackupData, Sink, UploadLogRequest, UploadOptions, UploadProgress,
+};
 use lazy_static::lazy_static;
 use std::collections::HashSet;
 use std::convert::Infallible;
@@ -22,6 +29,7 @@ use std::io::BufRead;
 use std::io::ErrorKind;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock, Mutex};
 use tokio::sync::{Mutex as AsyncMutex, Notify};
 use tokio::task::JoinHandle;
@@ -29,6 +37,9 @@ use tokio_util::sync::CancellationToken;
 
 lazy_static! {
   static ref TRIGGER_BACKUP_FILE_UPLOAD: Arc<Notify> = Arc::new(Notify::new());
+  /// Files written since the handler last updated the journal
+  static ref RECORDED_UPLOADS: Mutex<Vec<PendingUpload>> =
+    Mutex::new(Vec::new());
   static ref BACKUP_FOLDER_PATH: PathBuf = PathBuf::from(
     get_backup_directory_path().expect("Getting backup directory path failed")
   );
@@ -52,6 +63,46 @@ pub mod ffi {
   pub fn trigger_backup_file_upload() {
     TRIGGER_BACKUP_FILE_UPLOAD.notify_one();
   }
+
+  /// Adds a log written to the backup directory to the upload journal.
+  /// It's uploaded after the next `trigger_backup_file_upload` call.
+  pub fn record_backup_log(
+    backup_id: String,
+    log_id: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let log_id = log_id.parse()?;
+    record_upload(PendingUpload::Log { backup_id, log_id })?;
+    Ok(())
+  }
+}
+
+/// Adds a compaction written to the backup directory to the upload journal.
+/// It's uploaded after the next `trigger_backup_file_upload` call.
+pub fn record_backup_compaction(
+  backup_id: String,
+) -> Result<(), BackupHandlerError> {
+  record_upload(PendingUpload::Compaction { backup_id })
+}
+
+fn record_upload(upload: PendingUpload) -> Result<(), BackupHandlerError> {
+  RECORDED_UPLOADS.lock()?.push(upload);
+  Ok(())
+}
+
+/// Moves uploads recorded by the file writers to the journal
+async fn add_recorded_uploads(
+  journal: &mut UploadJournal,
+) -> Result<(), BackupHandlerError> {
+  let recorded_uploads = std::mem::take(&mut *RECORDED_UPLOADS.lock()?);
+  if recorded_uploads.is_empty() {
+    return Ok(());
+  }
+
+  for upload in recorded_uploads {
+    journal.enqueue(upload);
+  }
+  journal.persist().await?;
+  Ok(())
 }
 
 type TaskResult<'err, T> = Result<T, Box<dyn Error + 'err>>;
@@ -168,6 +219,15 @@ pub fn start_handler_routine(
 
   Ok(async move {
     println!("Backup handler task id={task_id} started.");
+    let journal = match load_journal().await {
+      Ok(journal) => AsyncMutex::new(journal),
+      Err(err) => {
+        println!("Backup handler failed to load upload journal: '{err:?}'");
+        return;
+      }
+    };
+
+    let mut connection_attempts = 0;
     'task_loop: loop {
       let logs_upload_stream = tokio::select!(
         result = backup_client.upload_logs(&user_identity) => result,
@@ -179,21 +239,28 @@ pub fn start_handler_routine(
           println!(
             "Backup handler error when estabilishing connection: '{err:?}'"
           );
-          tokio::time::sleep(BACKUP_SERVICE_CONNECTION_RETRY_DELAY).await;
+          connection_attempts += 1;
+          tokio::select!(
+            _ = tokio::time::sleep(retry_delay(connection_attempts)) => (),
+            _ = cancel_token.cancelled() => { break 'task_loop; }
+          );
           continue;
         }
       };
+      connection_attempts = 0;
 
       let mut tx = Box::pin(tx);
       let mut rx = Box::pin(rx);
 
+      // Logs that weren't confirmed before the connection was lost
+      // are sent again over the new one
       let logs_waiting_for_confirmation =
-        AsyncMutex::new(HashSet::<PathBuf>::new());
+        AsyncMutex::new(HashSet::<PendingUpload>::new());
 
       loop {
         let err = tokio::select! {
-          Err(err) = watch_and_upload_files(&backup_client, &user_identity, &mut tx, &logs_waiting_for_confirmation) => err,
-          Err(err) = delete_confirmed_logs(&mut rx, &logs_waiting_for_confirmation) => err,
+          Err(err) = watch_and_upload_files(&backup_client, &user_identity, &mut tx, &journal, &logs_waiting_for_confirmation, &cancel_token) => err,
+          Err(err) = delete_confirmed_logs(&mut rx, &journal, &logs_waiting_for_confirmation) => err,
           _ = cancel_token.cancelled() => { break 'task_loop; }
         };
 
@@ -218,66 +285,161 @@ pub fn start_handler_routine(
   })
 }
 
+/// Loads the upload journal, finishes cleanup of logs confirmed before
+/// the handler was stopped and adds files recorded since then. The backup
+/// directory is scanned only when the journal has just been created,
+/// to migrate files written before it existed or after it was corrupted.
+async fn load_journal() -> Result<UploadJournal, BackupHandlerError> {
+  let mut journal = UploadJournal::load(&BACKUP_FOLDER_PATH).await?;
+
+  for upload in journal.confirmed_uploads() {
+    if let PendingUpload::Log { backup_id, log_id } = &upload {
+      log::cleanup_files(backup_id.clone(), *log_id).await;
+    }
+    journal.remove(&upload);
+  }
+
+  if journal.was_created() {
+    discover_files(&mut journal).await?;
+  }
+  journal.persist().await?;
+  add_recorded_uploads(&mut journal).await?;
+  Ok(journal)
+}
+
+/// Adds backup files from the backup directory to the journal
+async fn discover_files(
+  journal: &mut UploadJournal,
+) -> Result<(), BackupHandlerError> {
+  let mut file_stream = match tokio::fs::read_dir(&*BACKUP_FOLDER_PATH).await {
+    Ok(file_stream) => file_stream,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+    Err(err) => return Err(err.into()),
+  };
+
+  while let Some(file) = file_stream.next_entry().await? {
+    if let Ok(BackupFileInfo {
+      backup_id,
+      log_id,
+      additional_data,
+    }) = file.path().try_into()
+    {
+      // Skip additional data files (attachments). They will be
+      // handled when we iterate over the corresponding files with the
+      // main content
+      if additional_data.is_some() {
+        continue;
+      }
+
+      let upload = match log_id {
+        Some(log_id) => PendingUpload::Log { backup_id, log_id },
+        None => PendingUpload::Compaction { backup_id },
+      };
+      journal.enqueue(upload);
+    }
+  }
+
+  Ok(())
+}
+
 async fn watch_and_upload_files(
   backup_client: &BackupClient,
   user_identity: &UserIdentity,
   tx: &mut Pin<Box<impl Sink<UploadLogRequest, Error = BackupError>>>,
-  logs_waiting_for_confirmation: &AsyncMutex<HashSet<PathBuf>>,
+  journal: &AsyncMutex<UploadJournal>,
+  logs_waiting_for_confirmation: &AsyncMutex<HashSet<PendingUpload>>,
+  cancel_token: &CancellationToken,
 ) -> Result<Infallible, BackupHandlerError> {
   loop {
-    let mut file_stream = match tokio::fs::read_dir(&*BACKUP_FOLDER_PATH).await
-    {
-      Ok(file_stream) => file_stream,
-      Err(err) if err.kind() == ErrorKind::NotFound => {
-        TRIGGER_BACKUP_FILE_UPLOAD.notified().await;
+    let due_uploads = {
+      // the journal is always locked first to avoid deadlocks
+      let journal = journal.lock().await;
+      let logs_waiting_for_confirmation =
+        logs_waiting_for_confirmation.lock().await;
+      journal.due_uploads(&logs_waiting_for_confirmation)
+    };
+
+    // backups whose compaction is still pending after this pass,
+    // their logs have to wait for it
+    let mut blocked_backups = HashSet::new();
+    for upload in due_uploads {
+      if blocked_backups.contains(upload.backup_id()) {
         continue;
       }
-      Err(err) => return Err(err.into()),
-    };
 
-    let mut compaction_ids = HashSet::new();
-    let mut logs = Vec::new();
+      let result = match &upload {
+        PendingUpload::Compaction { backup_id } => compaction::upload_files(
+          backup_client,
+          user_identity,
+          backup_id.clone(),
+          cancel_token,
+        )
+        .await
+        .map(Some),
+        PendingUpload::Log { backup_id, log_id } => {
+          log::upload_files(tx, backup_id.clone(), *log_id)
+            .await
+            .map(|_| None)
+        }
+      };
 
-    while let Some(file) = file_stream.next_entry().await? {
-      let path = file.path();
-      if let Ok(BackupFileInfo {
-        backup_id,
-        log_id,
-        additional_data,
-      }) = path.clone().try_into()
-      {
-        // Skip additional data files (attachments). They will be
-        // handled when we iterate over the corresponding files with the
-        // main content
-        if additional_data.is_some() {
+      let mut journal = journal.lock().await;
+      match result {
+        Ok(Some(outcome)) => {
+          compaction::handle_outcome(&mut journal, &upload, outcome).await;
+          if journal.contains(&upload) {
+            blocked_backups.insert(upload.backup_id().to_string());
+          }
+        }
+        Ok(None) => {
+          logs_waiting_for_confirmation.lock().await.insert(upload);
           continue;
         }
-
-        match log_id {
-          Some(id) => logs.push((path, backup_id, id)),
-          None => {
-            compaction_ids.insert(backup_id);
+        // Log files were removed before they were uploaded
+        Err(BackupHandlerError::IoError(err))
+          if err.kind() == ErrorKind::NotFound
+            && matches!(upload, PendingUpload::Log { .. }) =>
+        {
+          journal.remove(&upload);
+        }
+        Err(err) => {
+          let attempts = journal.record_failure(&upload);
+          if let PendingUpload::Log { backup_id, log_id } = &upload {
+            if attempts >= BACKUP_LOG_UPLOAD_MAX_ATTEMPTS {
+              println!(
+                "Backup handler upload of backup_id={backup_id}, \
+                 log_id={log_id} failed {attempts} times, giving up"
+              );
+              log::cleanup_files(backup_id.clone(), *log_id).await;
+              journal.remove(&upload);
+            }
           }
+          journal.persist().await?;
+          return Err(err);
         }
       }
+      journal.persist().await?;
     }
 
-    for backup_id in compaction_ids {
-      compaction::upload_files(backup_client, user_identity, backup_id).await?;
-    }
+    let next_due_in = {
+      let journal = journal.lock().await;
+      let logs_waiting_for_confirmation =
+        logs_waiting_for_confirmation.lock().await;
+      journal.next_due_in(&logs_waiting_for_confirmation)
+    };
+    let wait_for_due_upload = async {
+      match next_due_in {
+        Some(delay) => tokio::time::sleep(delay).await,
+        None => std::future::pending().await,
+      }
+    };
 
-    for (path, backup_id, log_id) in logs {
-      if logs_waiting_for_confirmation.lock().await.contains(&path) {
-        continue;
+    tokio::select! {
+      _ = TRIGGER_BACKUP_FILE_UPLOAD.notified() => {
+        add_recorded_uploads(&mut *journal.lock().await).await?;
       }
-      log::upload_files(tx, backup_id, log_id).await?;
-      logs_waiting_for_confirmation
-        .lock()
-        .await
-        .insert(path.clone());
+      _ = wait_for_due_upload => (),
     }
-
-    TRIGGER_BACKUP_FILE_UPLOAD.notified().await;
   }
 }
 
@@ -285,19 +447,28 @@ async fn delete_confirmed_logs(
   rx: &mut Pin<
     Box<impl Stream<Item = Result<LogUploadConfirmation, BackupError>>>,
   >,
-  logs_waiting_for_confirmation: &AsyncMutex<HashSet<PathBuf>>,
+  journal: &AsyncMutex<UploadJournal>,
+  logs_waiting_for_confirmation: &AsyncMutex<HashSet<PendingUpload>>,
 ) -> Result<Infallible, BackupHandlerError> {
   while let Some(LogUploadConfirmation { backup_id, log_id }) =
     rx.next().await.transpose()?
   {
-    let path =
-      get_backup_log_file_path(&backup_id, &log_id.to_string(), false)?;
-    logs_waiting_for_confirmation
-      .lock()
-      .await
-      .remove(&PathBuf::from(path));
-
-    tokio::spawn(log::cleanup_files(backup_id, log_id));
+    let upload = PendingUpload::Log {
+      backup_id: backup_id.clone(),
+      log_id,
+    };
+
+    // Marking the log as confirmed first ensures it isn't uploaded again
+    // if the app is killed before its files are removed
+    let mut journal = journal.lock().await;
+    journal.mark_confirmed(&upload);
+    journal.persist().await?;
+
+    log::cleanup_files(backup_id, log_id).await;
+    journal.remove(&upload);
+    journal.persist().await?;
+
+    logs_waiting_for_confirmation.lock().await.remove(&upload);
   }
 
   Err(BackupHandlerError::WSClosed)
@@ -306,11 +477,19 @@ async fn delete_confirmed_logs(
 pub mod compaction {
   use super::*;
 
+  pub enum UploadOutcome {
+    Uploaded,
+    /// Files aren't ready yet or the upload was cancelled
+    Skipped,
+    Failed(BackupError),
+  }
+
   pub async fn upload_files(
     backup_client: &BackupClient,
     user_identity: &UserIdentity,
     backup_id: String,
-  ) -> Result<(), BackupHandlerError> {
+    cancel_token: &CancellationToken,
+  ) -> Result<UploadOutcome, BackupHandlerError> {
     let user_data_path = get_backup_file_path(&backup_id, false, false)?;
     let user_data = match tokio::fs::read(&user_data_path).await {
       Ok(data) => Some(data),
@@ -329,7 +508,7 @@ pub mod compaction {
         "Backup handler upload skipping backup_id={} because user_data is ready but user_keys are pending",
         backup_id,
       );
-      return Ok(());
+      return Ok(UploadOutcome::Skipped);
     }
 
     let attachments_path = get_backup_file_path(&backup_id, true, false)?;
@@ -371,15 +550,95 @@ pub mod compaction {
       version_info,
     };
 
+    let options = UploadOptions {
+      on_progress: Some(Box::new(progress_reporter(backup_id.clone()))),
+      cancellation_token: Some(cancel_token.child_token()),
+    };
     let result = backup_client
-      .upload_backup(user_identity, backup_data)
-      .await
-      .map_err(|e| e.to_string());
+      .upload_backup_with_options(user_identity, backup_data, options)
+      .await;
+
+    match result {
+      Ok(()) => Ok(UploadOutcome::Uploaded),
+      // Files are kept so that the upload is retried
+      // when the handler is started again
+      Err(BackupError::Cancelled) => {
+        println!("Backup handler upload of backup_id={backup_id} cancelled");
+        Ok(UploadOutcome::Skipped)
+      }
+      Err(err) => Ok(UploadOutcome::Failed(err)),
+    }
+  }
+
+  /// Updates the journal and resolves the upload promise once
+  /// the compaction was uploaded or won't be retried anymore
+  pub async fn handle_outcome(
+    journal: &mut UploadJournal,
+    upload: &PendingUpload,
+    outcome: UploadOutcome,
+  ) {
+    let backup_id = upload.backup_id().to_string();
+    let result = match outcome {
+      UploadOutcome::Uploaded => Ok(()),
+      UploadOutcome::Skipped => {
+        journal.postpone(upload);
+        return;
+      }
+      UploadOutcome::Failed(err) => {
+        let attempts = journal.record_failure(upload);
+        if err.is_retryable()
+          && attempts < BACKUP_COMPACTION_UPLOAD_MAX_ATTEMPTS
+        {
+          println!(
+            "Backup handler upload of backup_id={backup_id} failed \
+             (attempt {attempts}), retrying: '{err:?}'"
+          );
+          return;
+        }
+        Err(err.to_string())
+      }
+    };
 
     cleanup_files(backup_id.clone()).await;
     compaction_upload_promises::resolve(&backup_id, result);
+    journal.remove(upload);
+  }
 
-    Ok(())
+  /// Sends upload progress to JS on every percent,
+  /// the same way web clients get it from `uploadBackup`.
+  /// Progress is also logged every 10 percent.
+  fn progress_reporter(
+    backup_id: String,
+  ) -> impl Fn(UploadProgress) + Send + Sync {
+    let last_reported_percent = AtomicU64::new(0);
+    move |UploadProgress {
+            uploaded_bytes,
+            total_bytes,
+          }| {
+      let percent = (uploaded_bytes * 100)
+        .checked_div(total_bytes)
+        .unwrap_or(100);
+      let previous_percent =
+        last_reported_percent.fetch_max(percent, Ordering::Relaxed);
+      // the first report is sent at 0 percent
+      if percent <= previous_percent && uploaded_bytes > 0 {
+        return;
+      }
+
+      if let Err(err) = send_backup_upload_progress_to_js(
+        backup_id.clone(),
+        uploaded_bytes,
+        total_bytes,
+      ) {
+        println!("Failed to send backup upload progress to JS: '{err:?}'");
+      }
+      if percent / 10 > previous_percent / 10 {
+        println!(
+          "Backup handler upload of backup_id={backup_id}: \
+           {uploaded_bytes}/{total_bytes} bytes"
+        );
+      }
+    }
   }
 
   async fn remove_file_if_exists(path: &String) -> Result<(), Box<dyn Error>> {
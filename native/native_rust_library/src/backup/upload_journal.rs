@@ -0,0 +1,363 @@
+//! On-disk journal of backup files waiting for upload.
+//!
+//! The journal lets the backup handler resume after the app is killed
+//! without re-sending logs that were already confirmed. Entries are kept
+//! in a stable order: backups in the order they were first seen, and within
+//! a backup the compaction goes before its logs, which are ordered by ID.
+//! Uploads of a backup are sent in this order, so an upload that is
+//! backed off blocks all later uploads of the same backup.
+
+use crate::constants::{
+  BACKUP_SERVICE_CONNECTION_RETRY_DELAY, BACKUP_SERVICE_MAX_RETRY_DELAY,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const JOURNAL_FILE_NAME: &str = "upload-journal.json";
+const JOURNAL_TMP_FILE_NAME: &str = "upload-journal.json.tmp";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PendingUpload {
+  #[serde(rename_all = "camelCase")]
+  Compaction { backup_id: String },
+  #[serde(rename_all = "camelCase")]
+  Log { backup_id: String, log_id: usize },
+}
+
+impl PendingUpload {
+  pub fn backup_id(&self) -> &str {
+    match self {
+      PendingUpload::Compaction { backup_id } => backup_id,
+      PendingUpload::Log { backup_id, .. } => backup_id,
+    }
+  }
+
+  /// `None` sorts before `Some`, so compaction goes before the logs
+  fn log_id(&self) -> Option<usize> {
+    match self {
+      PendingUpload::Compaction { .. } => None,
+      PendingUpload::Log { log_id, .. } => Some(*log_id),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+  pub upload: PendingUpload,
+  /// Number of failed upload attempts
+  pub attempts: u32,
+  /// Unix timestamp in milliseconds
+  pub next_attempt_at: u64,
+  /// Log was confirmed by the backup service, but its files
+  /// haven't been removed yet
+  pub confirmed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadJournal {
+  /// Backup IDs in the order they were first seen
+  backup_order: Vec<String>,
+  entries: Vec<JournalEntry>,
+  #[serde(skip)]
+  directory: PathBuf,
+  /// Journal didn't exist or was corrupted, so files written
+  /// before it was created aren't in it
+  #[serde(skip)]
+  created: bool,
+}
+
+impl UploadJournal {
+  /// Loads the journal from the backup directory. A missing or corrupted
+  /// journal is replaced with an empty one.
+  pub async fn load(directory: &Path) -> std::io::Result<Self> {
+    let mut journal = match tokio::fs::read(directory.join(JOURNAL_FILE_NAME))
+      .await
+    {
+      Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+        println!("Backup upload journal is corrupted, resetting: {err:?}");
+        UploadJournal::created()
+      }),
+      Err(err) if err.kind() == ErrorKind::NotFound => UploadJournal::created(),
+      Err(err) => return Err(err),
+    };
+
+    journal.directory = directory.to_path_buf();
+    Ok(journal)
+  }
+
+  fn created() -> Self {
+    UploadJournal {
+      created: true,
+      ..Default::default()
+    }
+  }
+
+  /// True if the journal was created on load. Backup files already
+  /// in the directory have to be added to it by scanning the directory.
+  pub fn was_created(&self) -> bool {
+    self.created
+  }
+
+  /// Atomically writes the journal to disk
+  pub async fn persist(&self) -> std::io::Result<()> {
+    let data = serde_json::to_vec(self)?;
+    tokio::fs::create_dir_all(&self.directory).await?;
+
+    let tmp_path = self.directory.join(JOURNAL_TMP_FILE_NAME);
+    tokio::fs::write(&tmp_path, data).await?;
+    tokio::fs::rename(&tmp_path, self.directory.join(JOURNAL_FILE_NAME)).await
+  }
+
+  pub fn contains(&self, upload: &PendingUpload) -> bool {
+    self.entries.iter().any(|entry| &entry.upload == upload)
+  }
+
+  /// Adds an upload to the journal. Returns `false` if it was already there.
+  pub fn enqueue(&mut self, upload: PendingUpload) -> bool {
+    if self.contains(&upload) {
+      return false;
+    }
+
+    if !self.backup_order.iter().any(|id| id == upload.backup_id()) {
+      self.backup_order.push(upload.backup_id().to_string());
+    }
+    self.entries.push(JournalEntry {
+      upload,
+      attempts: 0,
+      next_attempt_at: 0,
+      confirmed: false,
+    });
+
+    let backup_order = &self.backup_order;
+    self.entries.sort_by_key(|entry| {
+      let backup_position = backup_order
+        .iter()
+        .position(|id| id == entry.upload.backup_id());
+      (backup_position, entry.upload.log_id())
+    });
+    true
+  }
+
+  /// Uploads that aren't confirmed, in flight, backed off or blocked
+  /// by a backed off upload of the same backup, in upload order
+  pub fn due_uploads(
+    &self,
+    in_flight: &HashSet<PendingUpload>,
+  ) -> Vec<PendingUpload> {
+    let now = now_millis();
+    self
+      .unblocked_entries(in_flight, now)
+      .filter(|entry| entry.next_attempt_at <= now)
+      .map(|entry| entry.upload.clone())
+      .collect()
+  }
+
+  /// Time until the next upload becomes due
+  pub fn next_due_in(
+    &self,
+    in_flight: &HashSet<PendingUpload>,
+  ) -> Option<Duration> {
+    let now = now_millis();
+    self
+      .unblocked_entries(in_flight, now)
+      .map(|entry| entry.next_attempt_at.saturating_sub(now))
+      .min()
+      .map(Duration::from_millis)
+  }
+
+  /// Entries that aren't confirmed or in flight, up to and including
+  /// the first backed off entry of each backup. Entries in flight don't
+  /// block later ones, as they're sent over the same ordered connection.
+  fn unblocked_entries<'a>(
+    &'a self,
+    in_flight: &'a HashSet<PendingUpload>,
+    now: u64,
+  ) -> impl Iterator<Item = &'a JournalEntry> {
+    let mut blocked_backups = HashSet::new();
+    self
+      .entries
+      .iter()
+      .filter(|entry| !entry.confirmed && !in_flight.contains(&entry.upload))
+      .filter(move |entry| {
+        let backup_id = entry.upload.backup_id();
+        if blocked_backups.contains(backup_id) {
+          return false;
+        }
+        if entry.next_attempt_at > now {
+          blocked_backups.insert(backup_id);
+        }
+        true
+      })
+  }
+
+  /// Increments attempt count and schedules the next attempt
+  /// with exponential backoff. Returns the updated attempt count.
+  pub fn record_failure(&mut self, upload: &PendingUpload) -> u32 {
+    let Some(entry) = self.entry_mut(upload) else {
+      return 0;
+    };
+
+    entry.attempts += 1;
+    let delay = retry_delay(entry.attempts);
+    entry.next_attempt_at = now_millis() + delay.as_millis() as u64;
+    entry.attempts
+  }
+
+  /// Delays the next attempt without counting it as a failure
+  pub fn postpone(&mut self, upload: &PendingUpload) {
+    if let Some(entry) = self.entry_mut(upload) {
+      entry.next_attempt_at =
+        now_millis() + BACKUP_SERVICE_CONNECTION_RETRY_DELAY.as_millis() as u64;
+    }
+  }
+
+  pub fn mark_confirmed(&mut self, upload: &PendingUpload) {
+    if let Some(entry) = self.entry_mut(upload) {
+      entry.confirmed = true;
+    }
+  }
+
+  /// Confirmed logs whose files might still need to be removed
+  pub fn confirmed_uploads(&self) -> Vec<PendingUpload> {
+    self
+      .entries
+      .iter()
+      .filter(|entry| entry.confirmed)
+      .map(|entry| entry.upload.clone())
+      .collect()
+  }
+
+  pub fn remove(&mut self, upload: &PendingUpload) {
+    self.entries.retain(|entry| &entry.upload != upload);
+
+    let entries = &self.entries;
+    self.backup_order.retain(|backup_id| {
+      entries
+        .iter()
+        .any(|entry| entry.upload.backup_id() == backup_id)
+    });
+  }
+
+  fn entry_mut(&mut self, upload: &PendingUpload) -> Option<&mut JournalEntry> {
+    self
+      .entries
+      .iter_mut()
+      .find(|entry| &entry.upload == upload)
+  }
+}
+
+/// Delay before the next attempt after given number of failed attempts
+pub fn retry_delay(attempts: u32) -> Duration {
+  let multiplier = 2u32.saturating_pow(attempts.saturating_sub(1));
+  BACKUP_SERVICE_CONNECTION_RETRY_DELAY
+    .saturating_mul(multiplier)
+    .min(BACKUP_SERVICE_MAX_RETRY_DELAY)
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn log(backup_id: &str, log_id: usize) -> PendingUpload {
+    PendingUpload::Log {
+      backup_id: backup_id.to_string(),
+      log_id,
+    }
+  }
+
+  fn compaction(backup_id: &str) -> PendingUpload {
+    PendingUpload::Compaction {
+      backup_id: backup_id.to_string(),
+    }
+  }
+
+  #[test]
+  fn test_upload_order() {
+    let mut journal = UploadJournal::default();
+    assert!(journal.enqueue(log("a", 2)));
+    assert!(journal.enqueue(log("b", 1)));
+    assert!(journal.enqueue(log("a", 1)));
+    assert!(journal.enqueue(compaction("b")));
+    assert!(journal.enqueue(compaction("a")));
+    assert!(!journal.enqueue(log("a", 1)));
+
+    assert_eq!(
+      journal.due_uploads(&HashSet::new()),
+      vec![
+        compaction("a"),
+        log("a", 1),
+        log("a", 2),
+        compaction("b"),
+        log("b", 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_failures_and_confirmations() {
+    let mut journal = UploadJournal::default();
+    journal.enqueue(log("a", 1));
+    journal.enqueue(log("a", 2));
+    journal.enqueue(log("b", 1));
+
+    assert_eq!(journal.record_failure(&log("a", 1)), 1);
+    assert_eq!(journal.record_failure(&log("a", 1)), 2);
+    let no_uploads_in_flight = HashSet::new();
+    assert_eq!(
+      journal.due_uploads(&no_uploads_in_flight),
+      vec![log("b", 1)],
+      "Backed off log should block later logs of the same backup"
+    );
+    journal.remove(&log("b", 1));
+    let next_due_in = journal.next_due_in(&no_uploads_in_flight).unwrap();
+    assert!(
+      next_due_in > Duration::ZERO,
+      "Blocked log shouldn't be due before the backed off one"
+    );
+
+    // the backoff has passed
+    journal.entry_mut(&log("a", 1)).unwrap().next_attempt_at = 0;
+    assert_eq!(
+      journal.due_uploads(&no_uploads_in_flight),
+      vec![log("a", 1), log("a", 2)]
+    );
+
+    let in_flight = HashSet::from([log("a", 1)]);
+    assert_eq!(journal.due_uploads(&in_flight), vec![log("a", 2)]);
+    journal.mark_confirmed(&log("a", 1));
+    journal.remove(&log("a", 1));
+
+    let in_flight = HashSet::from([log("a", 2)]);
+    assert!(journal.due_uploads(&in_flight).is_empty());
+
+    journal.mark_confirmed(&log("a", 2));
+    assert!(journal.due_uploads(&no_uploads_in_flight).is_empty());
+    assert_eq!(journal.confirmed_uploads(), vec![log("a", 2)]);
+
+    journal.remove(&log("a", 2));
+    assert!(journal.next_due_in(&no_uploads_in_flight).is_none());
+    assert!(journal.backup_order.is_empty());
+  }
+
+  #[test]
+  fn test_retry_delay() {
+    let base_delay = BACKUP_SERVICE_CONNECTION_RETRY_DELAY;
+    assert_eq!(retry_delay(1), base_delay);
+    assert_eq!(retry_delay(2), base_delay * 2);
+    assert_eq!(retry_delay(3), base_delay * 4);
+    assert_eq!(retry_delay(100), BACKUP_SERVICE_MAX_RETRY_DELAY);
+  }
+}
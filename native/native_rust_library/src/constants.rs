@@ -20,5 +20,13 @@ pub mod secure_store {
     "comm.restoredBackupDataKey";
 }
 
+/// Base delay of exponential backoff when connecting to the backup service
+/// and retrying backup uploads
 pub const BACKUP_SERVICE_CONNECTION_RETRY_DELAY: Duration =
   Duration::from_secs(5);
+pub const BACKUP_SERVICE_MAX_RETRY_DELAY: Duration =
+  Duration::from_secs(10 * 60);
+/// Compaction upload is abandoned after this many failed attempts
+pub const BACKUP_COMPACTION_UPLOAD_MAX_ATTEMPTS: u32 = 5;
+/// Log upload is abandoned after this many failed attempts
+pub const BACKUP_LOG_UPLOAD_MAX_ATTEMPTS: u32 = 10;
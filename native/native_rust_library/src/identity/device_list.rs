@@ -111,6 +111,8 @@ async fn get_device_list_for_user_helper(
     .get_device_list_for_user(GetDeviceListRequest {
       user_id,
       since_timestamp,
+      known_log_size: None,
+      include_log_proof: false,
     })
     .await?
     .into_inner();
@@ -440,6 +440,19 @@ mod ffi {
     ) -> Result<()>;
   }
 
+  // Backup Upload Progress Emission
+  #[namespace = "comm"]
+  unsafe extern "C++" {
+    include!("RustBackupProgressEmitter.h");
+
+    #[cxx_name = "sendBackupUploadProgressToJS"]
+    fn send_backup_upload_progress_to_js(
+      backup_id: String,
+      uploaded_bytes: u64,
+      total_bytes: u64,
+    ) -> Result<()>;
+  }
+
   // Backup
   extern "Rust" {
     #[cxx_name = "startBackupHandler"]
@@ -450,6 +463,9 @@ mod ffi {
     #[cxx_name = "triggerBackupFileUpload"]
     fn trigger_backup_file_upload();
 
+    #[cxx_name = "recordBackupLog"]
+    fn record_backup_log(backup_id: String, log_id: String) -> Result<()>;
+
     #[cxx_name = "createBackup"]
     fn create_backup(
       backup_id: String,
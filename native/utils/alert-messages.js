@@ -36,6 +36,13 @@ const userNotFoundAlertDetails: AlertDetails = {
   message: "Either that user doesn't exist, or the password is incorrect",
 };
 
+const tooManyLoginAttemptsAlertDetails: AlertDetails = {
+  title: 'Too many attempts',
+  message:
+    'There were too many unsuccessful login attempts. ' +
+    'Please wait a while before trying again.',
+};
+
 const unknownErrorAlertDetails: AlertDetails = {
   title: 'Unknown error',
   message: 'Uhh... try again?',
@@ -103,6 +110,7 @@ export {
   usernameReservedAlertDetails,
   usernameTakenAlertDetails,
   userNotFoundAlertDetails,
+  tooManyLoginAttemptsAlertDetails,
   unknownErrorAlertDetails,
   networkErrorAlertDetails,
   backupInfoFetchErrorAlertDetails,
@@ -1,5 +1,6 @@
 use aws_config::BehaviorVersion;
 use clap::Parser;
+use comm_lib::backup::BackupQuota;
 use once_cell::sync::Lazy;
 use tracing::info;
 
@@ -34,6 +35,36 @@ pub struct AppConfig {
   #[arg(env = "LOG_SIZE_THRESHOLD_FOR_LOGGING")]
   #[arg(long, default_value_t = 5_242_880)]
   pub log_size_threshold_for_logging: usize,
+  /// Max total size of all backups and logs of a user in bytes.
+  /// Old backups are counted until they're replaced, so the limit should
+  /// leave room for two backups. Unlimited if not set
+  #[arg(env = "MAX_BACKUP_SIZE")]
+  #[arg(long)]
+  pub max_backup_size: Option<u64>,
+  /// Max number of logs of a user, including device logs.
+  /// Unlimited if not set
+  #[arg(env = "MAX_LOG_COUNT")]
+  #[arg(long)]
+  pub max_log_count: Option<u64>,
+  /// Max size of a single log in bytes. Unlimited if not set
+  #[arg(env = "MAX_LOG_SIZE")]
+  #[arg(long)]
+  pub max_log_size: Option<u64>,
+  /// Number of seconds during which deleted user data can be restored
+  /// before it's purged. Data is removed immediately if set to 0
+  #[arg(env = "USER_DATA_GRACE_PERIOD_SECS")]
+  #[arg(long, default_value_t = 0)]
+  pub user_data_grace_period_secs: u64,
+}
+
+impl AppConfig {
+  pub fn backup_quota(&self) -> BackupQuota {
+    BackupQuota {
+      max_backup_size: self.max_backup_size,
+      max_log_count: self.max_log_count,
+      max_log_size: self.max_log_size,
+    }
+  }
 }
 
 /// Stores configuration parsed from command-line arguments
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 // Assorted constants
 
 pub const MPSC_CHANNEL_BUFFER_CAPACITY: usize = 1;
@@ -5,6 +7,11 @@ pub const ID_SEPARATOR: &str = ":";
 pub const ATTACHMENT_HOLDER_SEPARATOR: &str = ";";
 pub const LOG_DEFAULT_PAGE_SIZE: i32 = 20;
 pub const LOG_BACKUP_ID_SEPARATOR: &str = "#";
+pub const DEVICE_LOG_ID_SEPARATOR: &str = "#";
+/// Limits for imported backup archives, used when quotas aren't configured
+pub const MAX_ARCHIVE_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+pub const MAX_ARCHIVE_LOG_SIZE: u64 = 64 * 1024 * 1024;
+pub const USER_DATA_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 // Configuration defaults
 pub const DEFAULT_HTTP_PORT: u16 = 50052;
@@ -28,6 +35,55 @@ pub mod log_table {
     pub const CONTENT_DB: &str = "content";
     pub const CONTENT_BLOB_INFO: &str = "blobInfo";
     pub const ATTACHMENTS: &str = "attachments";
+    /// Unix timestamp in milliseconds
+    pub const UPLOADED_AT: &str = "uploadedAt";
+    pub const SIZE: &str = "size";
+  }
+}
+
+/// Logs of device log streams. Items share attributes
+/// with [`log_table`], but are sorted by [`device_log_table::attr::LOG_KEY`].
+pub mod device_log_table {
+  pub const TABLE_NAME: &str = "backup-service-device-log";
+  pub const UPLOADED_AT_INDEX: &str = "backupID-uploadedAt-index";
+
+  pub mod attr {
+    /// `deviceID#logID`, with log ID zero-padded to keep numeric order
+    pub const LOG_KEY: &str = "logKey";
+    pub const DEVICE_ID: &str = "deviceID";
+  }
+}
+
+pub mod attachment_ref_table {
+  pub const TABLE_NAME: &str = "backup-service-attachment-ref";
+
+  pub mod attr {
+    pub const USER_ID: &str = "userID";
+    pub const BLOB_HASH: &str = "blobHash";
+    pub const HOLDER: &str = "holder";
+    pub const REF_COUNT: &str = "refCount";
+    pub const SIZE: &str = "size";
+  }
+}
+
+pub mod user_tombstone_table {
+  pub const TABLE_NAME: &str = "backup-service-user-tombstone";
+
+  pub mod attr {
+    pub const USER_ID: &str = "userID";
+    pub const DELETED_AT: &str = "deletedAt";
+    pub const PURGE_AT: &str = "purgeAt";
+    pub const PURGE_STARTED_AT: &str = "purgeStartedAt";
+  }
+}
+
+pub mod user_usage_table {
+  pub const TABLE_NAME: &str = "backup-service-user-usage";
+
+  pub mod attr {
+    pub const USER_ID: &str = "userID";
+    pub const TOTAL_SIZE: &str = "totalSize";
+    pub const LOG_COUNT: &str = "logCount";
   }
 }
 
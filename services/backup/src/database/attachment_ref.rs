@@ -0,0 +1,80 @@
+use crate::constants::attachment_ref_table::attr;
+use aws_sdk_dynamodb::types::AttributeValue;
+use comm_lib::database::{
+  parse_int_attribute, AttributeExtractor, AttributeMap, DBItemError,
+};
+use std::collections::HashMap;
+
+/// A per-user reference to an attachment blob. All backups and logs
+/// of a user that reference the same blob hash share a single blob holder.
+/// The holder is revoked when [`AttachmentRefItem::ref_count`] drops to zero.
+#[derive(Clone, Debug)]
+pub struct AttachmentRefItem {
+  pub user_id: String,
+  pub blob_hash: String,
+  pub holder: String,
+  pub ref_count: i64,
+  /// Blob size. It's counted towards the user usage once, when the first
+  /// reference is added, and released with the last reference.
+  pub size: u64,
+}
+
+/// Outcome of removing references to an attachment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefRemoval {
+  /// Other references are left, so the holder has to be kept
+  StillReferenced,
+  /// The last reference was removed
+  LastReference,
+  /// The holder isn't tracked by the reference index
+  Untracked,
+}
+
+impl RefRemoval {
+  pub fn should_revoke_holder(&self) -> bool {
+    match self {
+      RefRemoval::StillReferenced => false,
+      RefRemoval::LastReference | RefRemoval::Untracked => true,
+    }
+  }
+}
+
+impl AttachmentRefItem {
+  pub fn item_key(
+    user_id: impl Into<String>,
+    blob_hash: impl Into<String>,
+  ) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+      (attr::USER_ID.to_string(), AttributeValue::S(user_id.into())),
+      (
+        attr::BLOB_HASH.to_string(),
+        AttributeValue::S(blob_hash.into()),
+      ),
+    ])
+  }
+}
+
+impl TryFrom<AttributeMap> for AttachmentRefItem {
+  type Error = DBItemError;
+
+  fn try_from(mut value: AttributeMap) -> Result<Self, Self::Error> {
+    let user_id = value.take_attr(attr::USER_ID)?;
+    let blob_hash = value.take_attr(attr::BLOB_HASH)?;
+    let holder = value.take_attr(attr::HOLDER)?;
+    let ref_count =
+      parse_int_attribute(attr::REF_COUNT, value.remove(attr::REF_COUNT))?;
+    let size = value
+      .remove(attr::SIZE)
+      .map(|size| parse_int_attribute(attr::SIZE, Some(size)))
+      .transpose()?
+      .unwrap_or_default();
+
+    Ok(AttachmentRefItem {
+      user_id,
+      blob_hash,
+      holder,
+      ref_count,
+      size,
+    })
+  }
+}
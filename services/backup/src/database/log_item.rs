@@ -1,7 +1,14 @@
-use crate::constants::{error_types, log_table::attr, LOG_BACKUP_ID_SEPARATOR};
+use crate::constants::{
+  device_log_table::{self, attr as device_attr},
+  error_types,
+  log_table::{self, attr},
+  DEVICE_LOG_ID_SEPARATOR, LOG_BACKUP_ID_SEPARATOR,
+};
 use crate::CONFIG;
 use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
 use comm_lib::{
+  backup::DeviceLogPosition,
   blob::{
     client::{BlobServiceClient, BlobServiceError},
     types::BlobInfo,
@@ -9,8 +16,8 @@ use comm_lib::{
   constants::DDB_ITEM_SIZE_LIMIT,
   database::{
     blob::BlobOrDBContent, calculate_size_in_db, parse_int_attribute,
-    AttributeExtractor, AttributeTryInto, DBItemAttributeError, DBItemError,
-    Value,
+    parse_timestamp_attribute, AttributeExtractor, AttributeTryInto,
+    DBItemAttributeError, DBItemError, Value,
   },
 };
 use std::collections::HashMap;
@@ -20,12 +27,27 @@ use tracing::{debug, error};
 pub struct LogItem {
   pub user_id: String,
   pub backup_id: String,
+  /// Device log stream of this log. `None` for the primary log stream.
+  /// Log IDs are sequential within a single stream.
+  pub device_id: Option<String>,
   pub log_id: usize,
+  pub uploaded_at: DateTime<Utc>,
   pub content: BlobOrDBContent,
   pub attachments: Vec<BlobInfo>,
+  /// Size of content in bytes, counted towards the user quota. Zero if
+  /// the log wasn't counted. Attachments are counted by their references.
+  pub size: u64,
 }
 
 impl LogItem {
+  /// Primary stream logs and device logs are stored in separate tables
+  pub fn table_name(&self) -> &'static str {
+    match self.device_id {
+      Some(_) => device_log_table::TABLE_NAME,
+      None => log_table::TABLE_NAME,
+    }
+  }
+
   pub async fn ensure_size_constraints(
     &mut self,
     blob_client: &BlobServiceClient,
@@ -70,63 +92,111 @@ impl LogItem {
     )
   }
 
-  pub fn revoke_holders(&self, blob_client: &BlobServiceClient) {
+  /// Revokes holder of the log content, if it's stored in blob.
+  /// Attachments are reference-counted, see
+  /// [`crate::database::DatabaseClient::remove_attachment_refs`].
+  pub fn revoke_content_holder(&self, blob_client: &BlobServiceClient) {
     if let BlobOrDBContent::Blob(content_info) = &self.content {
       blob_client
         .schedule_revoke_holder(&content_info.blob_hash, &content_info.holder);
     }
+  }
 
-    for attachment_info in &self.attachments {
-      blob_client.schedule_revoke_holder(
-        &attachment_info.blob_hash,
-        &attachment_info.holder,
-      );
-    }
+  /// Sort key of device logs
+  pub fn device_log_key(device_id: &str, log_id: usize) -> String {
+    format!("{device_id}{DEVICE_LOG_ID_SEPARATOR}{log_id:020}")
+  }
+
+  /// Position in the device log streams. `None` for primary stream logs.
+  pub fn device_log_position(&self) -> Option<DeviceLogPosition> {
+    let device_id = self.device_id.clone()?;
+    Some(DeviceLogPosition {
+      uploaded_at: self.uploaded_at.timestamp_millis(),
+      device_id,
+      log_id: self.log_id,
+    })
   }
 
   pub fn item_key(
     user_id: impl Into<String>,
     backup_id: impl Into<String>,
+    device_id: Option<&str>,
     log_id: usize,
   ) -> HashMap<String, AttributeValue> {
+    let sort_key = match device_id {
+      Some(device_id) => (
+        device_attr::LOG_KEY.to_string(),
+        AttributeValue::S(Self::device_log_key(device_id, log_id)),
+      ),
+      None => (
+        attr::LOG_ID.to_string(),
+        AttributeValue::N(log_id.to_string()),
+      ),
+    };
+
     HashMap::from([
       (
         attr::BACKUP_ID.to_string(),
         AttributeValue::S(Self::partition_key(user_id, backup_id)),
       ),
-      (
-        attr::LOG_ID.to_string(),
-        AttributeValue::N(log_id.to_string()),
-      ),
+      sort_key,
     ])
   }
 
-  /// Assigns a new backup ID for this log item. This also refreshes holders
-  /// for all [`BlobInfo`]s of this log.
+  pub fn key(&self) -> HashMap<String, AttributeValue> {
+    Self::item_key(
+      &self.user_id,
+      &self.backup_id,
+      self.device_id.as_deref(),
+      self.log_id,
+    )
+  }
+
+  /// Assigns a new backup ID for this log item. This also refreshes holder
+  /// for the content [`BlobInfo`] of this log. Attachment holders are shared
+  /// between backups so they're left untouched.
   pub fn reassign_backup_id_and_holders(&mut self, new_backup_id: String) {
     self.backup_id = new_backup_id;
 
     if let BlobOrDBContent::Blob(ref mut blob_info) = self.content {
       blob_info.holder = uuid::Uuid::new_v4().to_string();
     }
-    for attachment in &mut self.attachments {
-      attachment.holder = uuid::Uuid::new_v4().to_string();
-    }
   }
 
+  /// Returns [`BlobInfo`]s owned exclusively by this log,
+  /// i.e. without attachments.
   pub fn blob_infos(&self) -> Vec<BlobInfo> {
-    let mut blobs = self.attachments.clone();
-    if let BlobOrDBContent::Blob(content_blob) = &self.content {
-      blobs.push(content_blob.clone());
+    match &self.content {
+      BlobOrDBContent::Blob(content_blob) => vec![content_blob.clone()],
+      BlobOrDBContent::Database(_) => Vec::new(),
     }
-    blobs
   }
 }
 
 impl From<LogItem> for HashMap<String, AttributeValue> {
   fn from(value: LogItem) -> Self {
-    let mut attrs =
-      LogItem::item_key(value.user_id, value.backup_id, value.log_id);
+    let mut attrs = value.key();
+
+    attrs.insert(
+      attr::UPLOADED_AT.to_string(),
+      AttributeValue::N(value.uploaded_at.timestamp_millis().to_string()),
+    );
+    attrs.insert(
+      attr::SIZE.to_string(),
+      AttributeValue::N(value.size.to_string()),
+    );
+    if let Some(device_id) = value.device_id {
+      // the log ID is also kept as a number, the sort key is only
+      // used for ordering
+      attrs.insert(
+        attr::LOG_ID.to_string(),
+        AttributeValue::N(value.log_id.to_string()),
+      );
+      attrs.insert(
+        device_attr::DEVICE_ID.to_string(),
+        AttributeValue::S(device_id),
+      );
+    }
 
     let (content_attr_name, content_attr) = value
       .content
@@ -169,6 +239,21 @@ impl TryFrom<HashMap<String, AttributeValue>> for LogItem {
         }
       };
     let log_id = parse_int_attribute(attr::LOG_ID, value.remove(attr::LOG_ID))?;
+    let device_id = value.take_attr(device_attr::DEVICE_ID)?;
+    // logs uploaded before this attribute existed are ordered first
+    let uploaded_at = value
+      .remove(attr::UPLOADED_AT)
+      .map(|timestamp| {
+        parse_timestamp_attribute(attr::UPLOADED_AT, Some(timestamp))
+      })
+      .transpose()?
+      .unwrap_or_default();
+    // logs stored before sizes were tracked aren't counted
+    let size = value
+      .remove(attr::SIZE)
+      .map(|size| parse_int_attribute(attr::SIZE, Some(size)))
+      .transpose()?
+      .unwrap_or_default();
     let content = BlobOrDBContent::parse_from_attrs(
       &mut value,
       attr::CONTENT_BLOB_INFO,
@@ -185,9 +270,12 @@ impl TryFrom<HashMap<String, AttributeValue>> for LogItem {
     Ok(LogItem {
       user_id,
       backup_id,
+      device_id,
       log_id,
+      uploaded_at,
       content,
       attachments,
+      size,
     })
   }
 }
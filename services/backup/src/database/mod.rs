@@ -1,21 +1,38 @@
+pub mod attachment_ref;
 pub mod backup_item;
 pub mod log_item;
+pub mod user_tombstone;
+pub mod user_usage;
 
 use self::{
+  attachment_ref::{AttachmentRefItem, RefRemoval},
   backup_item::{BackupItem, OrderedBackupItem},
   log_item::LogItem,
+  user_tombstone::UserTombstoneItem,
+  user_usage::{UsageReservation, UserUsageItem},
 };
 use crate::{
-  constants::{backup_table, error_types, log_table, LOG_DEFAULT_PAGE_SIZE},
+  constants::{
+    attachment_ref_table, backup_table, device_log_table, error_types,
+    log_table, user_tombstone_table, user_usage_table, LOG_DEFAULT_PAGE_SIZE,
+    USER_DATA_SWEEP_INTERVAL,
+  },
+  error::BackupError,
   CONFIG,
 };
 use aws_sdk_dynamodb::{
-  operation::get_item::GetItemOutput,
+  operation::{
+    delete_item::DeleteItemError, get_item::GetItemOutput,
+    put_item::PutItemError, update_item::UpdateItemError,
+  },
   types::{
-    AttributeValue, DeleteRequest, PutRequest, ReturnValue, WriteRequest,
+    AttributeValue, DeleteRequest, PutRequest, ReturnValue,
+    ReturnValuesOnConditionCheckFailure, WriteRequest,
   },
 };
+use chrono::{DateTime, Utc};
 use comm_lib::{
+  backup::{DeviceLogCursor, DeviceLogPosition},
   blob::{client::BlobServiceClient, types::BlobInfo},
   database::{
     self, batch_operations::ExponentialBackoffConfig, parse_int_attribute,
@@ -23,7 +40,8 @@ use comm_lib::{
   },
   tools::Defer,
 };
-use tracing::{error, trace, warn};
+use std::collections::HashMap;
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Clone)]
 pub struct DatabaseClient {
@@ -40,17 +58,21 @@ impl DatabaseClient {
 
 /// Backup functions
 impl DatabaseClient {
+  /// Stores the backup item. Usage of a replaced item with the same ID
+  /// is released, usage of the new item has to be reserved by the caller.
   pub async fn put_backup_item(
     &self,
     backup_item: BackupItem,
   ) -> Result<(), Error> {
+    let user_id = backup_item.user_id.clone();
     let item = backup_item.into();
 
-    self
+    let result = self
       .client
       .put_item()
       .table_name(backup_table::TABLE_NAME)
       .set_item(Some(item))
+      .return_values(ReturnValue::AllOld)
       .send()
       .await
       .map_err(|e| {
@@ -61,9 +83,49 @@ impl DatabaseClient {
         Error::AwsSdk(e.into())
       })?;
 
+    if let Some(replaced_item_attrs) = result.attributes {
+      match BackupItem::try_from(replaced_item_attrs) {
+        Ok(replaced_item) => {
+          self.release_usage(&user_id, replaced_item.size, 0).await?;
+        }
+        Err(_) => warn!("Couldn't parse replaced backup item"),
+      }
+    }
+
     Ok(())
   }
 
+  /// Stores the backup item unless a backup with the same ID exists.
+  /// Returns `false` if it does.
+  pub async fn put_new_backup_item(
+    &self,
+    backup_item: BackupItem,
+  ) -> Result<bool, Error> {
+    let result = self
+      .client
+      .put_item()
+      .table_name(backup_table::TABLE_NAME)
+      .set_item(Some(backup_item.into()))
+      .condition_expression("attribute_not_exists(#userID)")
+      .expression_attribute_names("#userID", backup_table::attr::USER_ID)
+      .send()
+      .await;
+
+    match result {
+      Ok(_) => Ok(true),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        PutItemError::ConditionalCheckFailedException(_) => Ok(false),
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to put backup item"
+          );
+          Err(Error::AwsSdk(other.into()))
+        }
+      },
+    }
+  }
+
   pub async fn find_backup_item(
     &self,
     user_id: &str,
@@ -140,6 +202,10 @@ impl DatabaseClient {
     if let Some(backup_item) = &result {
       backup_item.revoke_user_keys_holders(blob_client);
       backup_item.revoke_user_data_holders(blob_client);
+      self
+        .remove_attachment_refs(user_id, &backup_item.attachments, blob_client)
+        .await?;
+      self.release_usage(user_id, backup_item.size, 0).await?;
     }
 
     self
@@ -149,6 +215,30 @@ impl DatabaseClient {
     Ok(result)
   }
 
+  /// Removes only the backup item, without revoking its blobs
+  /// or releasing its usage
+  pub async fn remove_backup_item_only(
+    &self,
+    user_id: &str,
+    backup_id: &str,
+  ) -> Result<(), Error> {
+    self
+      .client
+      .delete_item()
+      .table_name(backup_table::TABLE_NAME)
+      .set_key(Some(BackupItem::item_key(user_id, backup_id)))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::DDB_ERROR,
+          "DynamoDB client failed to remove backup item"
+        );
+        Error::AwsSdk(e.into())
+      })?;
+    Ok(())
+  }
+
   /// For the purposes of the initial backup version this function
   /// removes all backups except for the latest one
   pub async fn remove_old_backups(
@@ -194,17 +284,20 @@ impl DatabaseClient {
 
 /// Backup log functions
 impl DatabaseClient {
+  /// Stores the log item. Usage of a replaced log with the same ID
+  /// is released, usage of the new log has to be reserved by the caller.
   pub async fn put_log_item(
     &self,
     log_item: LogItem,
     blob_client: &BlobServiceClient,
   ) -> Result<(), Error> {
+    let table_name = log_item.table_name();
     let item = log_item.into();
 
     let result = self
       .client
       .put_item()
-      .table_name(log_table::TABLE_NAME)
+      .table_name(table_name)
       .set_item(Some(item))
       .return_values(ReturnValue::AllOld)
       .send()
@@ -226,7 +319,19 @@ impl DatabaseClient {
       return Ok(());
     };
 
-    replaced_log.revoke_holders(blob_client);
+    replaced_log.revoke_content_holder(blob_client);
+    self
+      .remove_attachment_refs(
+        &replaced_log.user_id,
+        &replaced_log.attachments,
+        blob_client,
+      )
+      .await?;
+    if replaced_log.size > 0 {
+      self
+        .release_usage(&replaced_log.user_id, replaced_log.size, 1)
+        .await?;
+    }
 
     Ok(())
   }
@@ -314,7 +419,112 @@ impl DatabaseClient {
     Ok((items, last_id))
   }
 
-  /// Fetches all log items for given backup.
+  /// Fetches a page of device log items in upload order, starting after
+  /// [`from`]. Page size is [`LOG_DEFAULT_PAGE_SIZE`]. Returns log items
+  /// and position of the last one if there might be more items.
+  async fn fetch_device_log_items(
+    &self,
+    user_id: &str,
+    backup_id: &str,
+    from: Option<&DeviceLogPosition>,
+  ) -> Result<(Vec<LogItem>, Option<DeviceLogPosition>), Error> {
+    use log_table::attr;
+
+    let id = LogItem::partition_key(user_id, backup_id);
+    let mut query = self
+      .client
+      .query()
+      .table_name(device_log_table::TABLE_NAME)
+      .index_name(device_log_table::UPLOADED_AT_INDEX)
+      .key_condition_expression("#backupID = :valueToMatch")
+      .expression_attribute_names("#backupID", attr::BACKUP_ID)
+      .expression_attribute_values(
+        ":valueToMatch",
+        AttributeValue::S(id.clone()),
+      )
+      .limit(LOG_DEFAULT_PAGE_SIZE);
+
+    if let Some(position) = from {
+      let log_key =
+        LogItem::device_log_key(&position.device_id, position.log_id);
+      query = query
+        .exclusive_start_key(attr::BACKUP_ID, AttributeValue::S(id))
+        .exclusive_start_key(
+          device_log_table::attr::LOG_KEY,
+          AttributeValue::S(log_key),
+        )
+        .exclusive_start_key(
+          attr::UPLOADED_AT,
+          AttributeValue::N(position.uploaded_at.to_string()),
+        );
+    }
+
+    let response = query.send().await.map_err(|e| {
+      error!(
+        errorType = error_types::DDB_ERROR,
+        "DynamoDB client failed to fetch device logs"
+      );
+      Error::AwsSdk(e.into())
+    })?;
+
+    let items = response
+      .items
+      .unwrap_or_default()
+      .into_iter()
+      .map(LogItem::try_from)
+      .collect::<Result<Vec<_>, _>>()?;
+
+    // the position is built from the last item, because DDB returns
+    // the last evaluated key only if there might be more items
+    let last_position = match (&response.last_evaluated_key, items.last()) {
+      (Some(_), Some(last_item)) => last_item.device_log_position(),
+      _ => None,
+    };
+
+    Ok((items, last_position))
+  }
+
+  /// Fetches a page of logs of the primary stream and device streams,
+  /// starting after [`from`]. Device logs are merged with primary logs
+  /// in upload order, see [`merge_log_pages`]. Returns log items and
+  /// a cursor for a subsequent call, or `None` if all logs were fetched.
+  pub async fn fetch_merged_log_items(
+    &self,
+    user_id: &str,
+    backup_id: &str,
+    from: Option<&DeviceLogCursor>,
+  ) -> Result<(Vec<LogItem>, Option<DeviceLogCursor>), Error> {
+    let mut cursor = from.cloned().unwrap_or_default();
+    let (
+      (primary_items, primary_last_id),
+      (device_items, device_last_position),
+    ) = tokio::try_join!(
+      self.fetch_log_items(user_id, backup_id, cursor.primary_log_id),
+      self.fetch_device_log_items(
+        user_id,
+        backup_id,
+        cursor.device_log.as_ref()
+      ),
+    )?;
+
+    let (items, has_more) = merge_log_pages(
+      (primary_items, primary_last_id.is_some()),
+      (device_items, device_last_position.is_some()),
+      LOG_DEFAULT_PAGE_SIZE as usize,
+    );
+
+    for item in &items {
+      match item.device_log_position() {
+        Some(position) => cursor.device_log = Some(position),
+        None => cursor.primary_log_id = Some(item.log_id),
+      }
+    }
+
+    Ok((items, has_more.then_some(cursor)))
+  }
+
+  /// Fetches all log items for given backup, both from the primary
+  /// log stream and device log streams.
   pub async fn fetch_all_log_items_for_backup(
     &self,
     user_id: &str,
@@ -331,6 +541,12 @@ impl DatabaseClient {
       last_id.is_some()
     } {}
 
+    raw_items.extend(
+      self
+        .fetch_all_raw_device_log_items(user_id, backup_id)
+        .await?,
+    );
+
     let items = raw_items
       .into_iter()
       .map(LogItem::try_from)
@@ -338,31 +554,46 @@ impl DatabaseClient {
     Ok(items)
   }
 
-  pub async fn get_blob_infos_and_size_for_logs(
+  async fn fetch_all_raw_device_log_items(
     &self,
     user_id: &str,
     backup_id: &str,
-  ) -> Result<(Vec<BlobInfo>, u64), Error> {
-    let mut blob_infos = Vec::new();
-    let mut logs_ddb_size = 0u64;
-
-    let log_items = self
-      .fetch_all_log_items_for_backup(user_id, backup_id)
-      .await?;
+  ) -> Result<Vec<AttributeMap>, Error> {
+    let query = self
+      .client
+      .query()
+      .table_name(device_log_table::TABLE_NAME)
+      .key_condition_expression("#backupID = :valueToMatch")
+      .expression_attribute_names("#backupID", log_table::attr::BACKUP_ID)
+      .expression_attribute_values(
+        ":valueToMatch",
+        AttributeValue::S(LogItem::partition_key(user_id, backup_id)),
+      );
 
-    use comm_lib::database::blob::BlobOrDBContent as LogContent;
-    for log in log_items {
-      match log.content {
-        LogContent::Blob(blob_info) => blob_infos.push(blob_info),
-        LogContent::Database(db_content) => {
-          logs_ddb_size += db_content.len() as u64;
-        }
-      }
+    let mut raw_items = Vec::new();
+    let mut cursor = None;
+    loop {
+      let response = query
+        .clone()
+        .set_exclusive_start_key(cursor)
+        .send()
+        .await
+        .map_err(|e| {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to fetch device logs"
+          );
+          Error::AwsSdk(e.into())
+        })?;
 
-      blob_infos.extend(log.attachments);
+      raw_items.extend(response.items.unwrap_or_default());
+      cursor = match response.last_evaluated_key {
+        key @ Some(_) => key,
+        None => break,
+      };
     }
 
-    Ok((blob_infos, logs_ddb_size))
+    Ok(raw_items)
   }
 
   pub async fn remove_log_items_for_backup(
@@ -376,34 +607,75 @@ impl DatabaseClient {
       .await?;
 
     for log_item in &items {
-      log_item.revoke_holders(blob_client);
+      log_item.revoke_content_holder(blob_client);
     }
+    let attachments: Vec<BlobInfo> = items
+      .iter()
+      .flat_map(|log_item| log_item.attachments.clone())
+      .collect();
+    self
+      .remove_attachment_refs(user_id, &attachments, blob_client)
+      .await?;
+
+    // only logs stored with their size were counted
+    let counted_logs: Vec<u64> = items
+      .iter()
+      .map(|log_item| log_item.size)
+      .filter(|size| *size > 0)
+      .collect();
+    self
+      .release_usage(
+        user_id,
+        counted_logs.iter().sum(),
+        counted_logs.len() as u64,
+      )
+      .await?;
 
     let write_requests = items
       .into_iter()
-      .map(|key| {
-        DeleteRequest::builder()
-          .set_key(Some(LogItem::item_key(user_id, key.backup_id, key.log_id)))
+      .map(|log_item| {
+        let request = DeleteRequest::builder()
+          .set_key(Some(log_item.key()))
           .build()
-          .expect("key not set in DeleteRequest builder")
+          .expect("key not set in DeleteRequest builder");
+        let request = WriteRequest::builder().delete_request(request).build();
+        (log_item.table_name(), request)
       })
-      .map(|request| WriteRequest::builder().delete_request(request).build())
       .collect::<Vec<_>>();
 
-    database::batch_operations::batch_write(
-      &self.client,
-      log_table::TABLE_NAME,
-      write_requests,
-      ExponentialBackoffConfig::default(),
-    )
-    .await?;
+    self.batch_write_log_items(write_requests).await
+  }
+
+  /// Sends write requests to the log tables they belong to
+  async fn batch_write_log_items(
+    &self,
+    write_requests: Vec<(&'static str, WriteRequest)>,
+  ) -> Result<(), Error> {
+    let (device_log_requests, log_requests): (Vec<_>, Vec<_>) = write_requests
+      .into_iter()
+      .partition(|(table_name, _)| *table_name == device_log_table::TABLE_NAME);
+
+    for (table_name, requests) in [
+      (log_table::TABLE_NAME, log_requests),
+      (device_log_table::TABLE_NAME, device_log_requests),
+    ] {
+      database::batch_operations::batch_write(
+        &self.client,
+        table_name,
+        requests.into_iter().map(|(_, request)| request).collect(),
+        ExponentialBackoffConfig::default(),
+      )
+      .await?;
+    }
 
     Ok(())
   }
 
   /// Copies all log items from [`old_backup_id`] to [`new_backup_id`].
-  /// Assigns new holders to all logs' [`BlobInfo`]s, and returns
-  /// a [`Defer'] revoke object that removes these holders unless canceled.
+  /// Assigns new holders to logs' content [`BlobInfo`]s and adds
+  /// references to their attachments. Copied logs are counted towards
+  /// the user usage. Returns a [`Defer'] revoke object that removes these
+  /// holders and references and releases the usage unless canceled.
   #[must_use = "Holders will be discarded unless returned revoke is canceled"]
   pub async fn copy_log_items_to_new_backup<'revoke, 'blob: 'revoke>(
     &self,
@@ -430,32 +702,83 @@ impl DatabaseClient {
       .await?;
     let num_holders_assigned = assigned_holder_infos.len();
 
-    let revoke = Defer::new(|| {
+    let holders_revoke = Defer::new(|| {
       for BlobInfo { blob_hash, holder } in assigned_holder_infos {
         blob_client.schedule_revoke_holder(blob_hash, holder);
       }
     });
 
-    // 3. Store new logs in DDB
+    // 3. Add references to attachments shared with the old backup
+    let attachment_hashes: Vec<String> = items
+      .iter()
+      .flat_map(|log_item| &log_item.attachments)
+      .map(|attachment| attachment.blob_hash.clone())
+      .collect();
+    // References are shared with the old backup, so attachments
+    // have already been counted towards the user usage
+    let (attachment_infos, attachments_revoke) = self
+      .add_attachment_refs(
+        user_id,
+        attachment_hashes,
+        &HashMap::new(),
+        blob_client,
+      )
+      .await?;
+    let mut attachment_infos = attachment_infos.into_iter();
+    for log_item in &mut items {
+      for attachment in &mut log_item.attachments {
+        if let Some(blob_info) = attachment_infos.next() {
+          *attachment = blob_info;
+        }
+      }
+    }
+
+    // 4. Count copied logs towards user usage. Logs aren't
+    // rejected because of quota, as they're already stored.
+    let counted_logs: Vec<u64> = items
+      .iter()
+      .map(|log_item| log_item.size)
+      .filter(|size| *size > 0)
+      .collect();
+    let copied_size: u64 = counted_logs.iter().sum();
+    let copied_log_count = counted_logs.len() as u64;
+    self
+      .add_usage(user_id, copied_size, copied_log_count)
+      .await?;
+    let usage_release = {
+      let db_client = self.clone();
+      let user_id = user_id.to_string();
+      Defer::new(move || {
+        db_client.schedule_release_usage(
+          &user_id,
+          copied_size,
+          copied_log_count,
+        )
+      })
+    };
+
+    let revoke = Defer::new(move || {
+      drop(holders_revoke);
+      drop(attachments_revoke);
+      drop(usage_release);
+    });
+
+    // 5. Store new logs in DDB
     let write_requests = items
       .into_iter()
       .map(|log_item| {
+        let table_name = log_item.table_name();
         let put_request = PutRequest::builder()
           .set_item(Some(log_item.into()))
           .build()
           .expect("item not set in PutRequest builder");
-        WriteRequest::builder().put_request(put_request).build()
+        let request = WriteRequest::builder().put_request(put_request).build();
+        (table_name, request)
       })
       .collect::<Vec<_>>();
     let num_writes = write_requests.len();
 
-    database::batch_operations::batch_write(
-      &self.client,
-      log_table::TABLE_NAME,
-      write_requests,
-      ExponentialBackoffConfig::default(),
-    )
-    .await?;
+    self.batch_write_log_items(write_requests).await?;
 
     tracing::debug!(
       "Logs copied. {} logs written to DDB, {} holders assigned.",
@@ -466,72 +789,748 @@ impl DatabaseClient {
   }
 }
 
-// general functions
+/// Attachment reference functions
 impl DatabaseClient {
-  pub async fn delete_user_data(
+  /// Adds a reference to each of the given attachment blob hashes.
+  /// The first reference to a hash establishes a blob holder that is shared
+  /// by all backups and logs of the user, and counts the blob size from
+  /// `blob_sizes` towards the user usage. Returns [`BlobInfo`]s (in the same
+  /// order as `blob_hashes`) and a [`Defer`] revoke object that removes
+  /// the added references unless canceled.
+  #[must_use = "References will be removed unless returned revoke is canceled"]
+  pub async fn add_attachment_refs<'revoke, 'blob: 'revoke>(
+    &self,
+    user_id: &str,
+    blob_hashes: Vec<String>,
+    blob_sizes: &HashMap<String, u64>,
+    blob_client: &'blob BlobServiceClient,
+  ) -> Result<(Vec<BlobInfo>, Defer<'revoke>), BackupError> {
+    let mut ref_counts: HashMap<&str, i64> = HashMap::new();
+    for blob_hash in &blob_hashes {
+      *ref_counts.entry(blob_hash.as_str()).or_default() += 1;
+    }
+
+    let mut holders: HashMap<&str, String> = HashMap::new();
+    let mut added_refs: Vec<BlobInfo> = Vec::new();
+    for (blob_hash, count) in ref_counts {
+      let size = blob_sizes.get(blob_hash).copied().unwrap_or_default();
+      let result = self
+        .add_attachment_ref(user_id, blob_hash, count, size, blob_client)
+        .await;
+      let holder = match result {
+        Ok(holder) => holder,
+        Err(err) => {
+          self.schedule_remove_attachment_refs(
+            user_id,
+            added_refs,
+            blob_client,
+          );
+          return Err(err);
+        }
+      };
+
+      added_refs.extend((0..count).map(|_| BlobInfo {
+        blob_hash: blob_hash.to_string(),
+        holder: holder.clone(),
+      }));
+      holders.insert(blob_hash, holder);
+    }
+
+    let blob_infos = blob_hashes
+      .iter()
+      .map(|blob_hash| BlobInfo {
+        blob_hash: blob_hash.clone(),
+        holder: holders[blob_hash.as_str()].clone(),
+      })
+      .collect();
+
+    let db_client = self.clone();
+    let user_id = user_id.to_string();
+    let revoke = Defer::new(move || {
+      db_client.schedule_remove_attachment_refs(
+        &user_id,
+        added_refs,
+        blob_client,
+      );
+    });
+
+    Ok((blob_infos, revoke))
+  }
+
+  /// Removes a reference for each of the given attachment [`BlobInfo`]s.
+  /// Blob holders are revoked and their size is released from the user
+  /// usage only when the last reference disappears.
+  /// Holders that aren't tracked by the reference index (e.g. created before
+  /// the index existed) are revoked immediately.
+  pub async fn remove_attachment_refs(
     &self,
     user_id: &str,
+    attachments: &[BlobInfo],
     blob_client: &BlobServiceClient,
   ) -> Result<(), Error> {
-    // query the index to avoid unnecessarily querying backup data
-    let items = self.query_ordered_backups_index(user_id, None).await?;
+    let mut ref_counts: HashMap<(&str, &str), i64> = HashMap::new();
+    for BlobInfo { blob_hash, holder } in attachments {
+      *ref_counts.entry((blob_hash, holder)).or_default() += 1;
+    }
 
-    for item in items {
-      trace!("Removing backup item: {item:?}");
+    for ((blob_hash, holder), count) in ref_counts {
       self
-        .remove_backup_item(user_id, &item.backup_id, blob_client)
+        .remove_attachment_ref(user_id, blob_hash, holder, count, blob_client)
         .await?;
     }
 
     Ok(())
   }
 
-  async fn query_ordered_backups_index(
+  /// Removes attachment references in a separate task. Useful to clean up
+  /// after upload failure without blocking the current task.
+  pub fn schedule_remove_attachment_refs(
     &self,
     user_id: &str,
-    limit: Option<i32>,
-  ) -> Result<Vec<OrderedBackupItem>, Error> {
-    let query = self
+    attachments: Vec<BlobInfo>,
+    blob_client: &BlobServiceClient,
+  ) {
+    if attachments.is_empty() {
+      return;
+    }
+
+    let db_client = self.clone();
+    let blob_client = blob_client.clone();
+    let user_id = user_id.to_string();
+    tokio::spawn(async move {
+      if let Err(err) = db_client
+        .remove_attachment_refs(&user_id, &attachments, &blob_client)
+        .await
+      {
+        warn!("Failed to remove attachment references: {0:?} - {0}", err);
+      }
+    });
+  }
+
+  /// Increments reference count for given blob hash by `count`.
+  /// Returns the holder shared by all references.
+  async fn add_attachment_ref(
+    &self,
+    user_id: &str,
+    blob_hash: &str,
+    count: i64,
+    size: u64,
+    blob_client: &BlobServiceClient,
+  ) -> Result<String, BackupError> {
+    let new_holder = uuid::Uuid::new_v4().to_string();
+    let holder = self
+      .increment_attachment_ref(user_id, blob_hash, count, &new_holder, size)
+      .await?;
+
+    if holder != new_holder {
+      return Ok(holder);
+    }
+
+    // This is the first reference, so the blob is counted towards
+    // the user usage and the holder has to be established.
+    // Removing the references releases the usage.
+    let remove_refs = || {
+      let refs = (0..count)
+        .map(|_| BlobInfo {
+          blob_hash: blob_hash.to_string(),
+          holder: new_holder.clone(),
+        })
+        .collect();
+      self.schedule_remove_attachment_refs(user_id, refs, blob_client);
+    };
+    if let Err(err) = self.add_usage(user_id, size, 0).await {
+      remove_refs();
+      return Err(err.into());
+    }
+
+    debug!(blob_hash, "Assigning holder for a new attachment reference");
+    let assign_result = blob_client.assign_holder(blob_hash, &new_holder).await;
+    match assign_result {
+      Ok(true) => (),
+      Ok(false) => {
+        warn!("Blob attachment with hash {blob_hash:?} doesn't exist")
+      }
+      Err(err) => {
+        remove_refs();
+        return Err(err.into());
+      }
+    }
+
+    Ok(new_holder)
+  }
+
+  /// Atomically increments reference count for given blob hash by `count`.
+  /// `new_holder` and `size` are stored if this is the first reference.
+  /// Returns the holder shared by all references, which is `new_holder`
+  /// only for the first reference.
+  async fn increment_attachment_ref(
+    &self,
+    user_id: &str,
+    blob_hash: &str,
+    count: i64,
+    new_holder: &str,
+    size: u64,
+  ) -> Result<String, Error> {
+    use attachment_ref_table::attr;
+
+    let response = self
       .client
-      .query()
-      .table_name(backup_table::TABLE_NAME)
-      .index_name(backup_table::CREATED_INDEX)
-      .key_condition_expression("#userID = :valueToMatch")
-      .expression_attribute_names("#userID", backup_table::attr::USER_ID)
+      .update_item()
+      .table_name(attachment_ref_table::TABLE_NAME)
+      .set_key(Some(AttachmentRefItem::item_key(user_id, blob_hash)))
+      .update_expression(
+        "SET #holder = if_not_exists(#holder, :holder), \
+         #size = if_not_exists(#size, :size) \
+         ADD #refCount :count",
+      )
+      .expression_attribute_names("#holder", attr::HOLDER)
+      .expression_attribute_names("#size", attr::SIZE)
+      .expression_attribute_names("#refCount", attr::REF_COUNT)
       .expression_attribute_values(
-        ":valueToMatch",
-        AttributeValue::S(user_id.to_string()),
+        ":holder",
+        AttributeValue::S(new_holder.to_string()),
       )
-      .scan_index_forward(false);
-
-    let mut raw_items = Vec::new();
-    let mut cursor = None;
-    loop {
-      let new_limit = limit
-        .map(|it| it - raw_items.len() as i32)
-        .filter(|it| *it > 0);
-      let request = query
-        .clone()
-        .set_exclusive_start_key(cursor)
-        .set_limit(new_limit);
-      let response = request.send().await.map_err(|e| {
+      .expression_attribute_values(":size", AttributeValue::N(size.to_string()))
+      .expression_attribute_values(
+        ":count",
+        AttributeValue::N(count.to_string()),
+      )
+      .return_values(ReturnValue::AllNew)
+      .send()
+      .await
+      .map_err(|e| {
         error!(
           errorType = error_types::DDB_ERROR,
-          "DynamoDB client failed to fetch backups"
+          "DynamoDB client failed to add attachment reference"
         );
         Error::AwsSdk(e.into())
       })?;
 
-      raw_items.extend(response.items.unwrap_or_default());
-      let limit_reached = limit.is_some_and(|it| raw_items.len() as i32 >= it);
+    let item =
+      AttachmentRefItem::try_from(response.attributes.unwrap_or_default())?;
+    Ok(item.holder)
+  }
 
-      cursor = match response.last_evaluated_key {
-        key @ Some(_) if !limit_reached => key,
-        _ => break,
-      };
+  /// Decrements reference count for given attachment by `count`.
+  /// Revokes the holder when no references are left.
+  async fn remove_attachment_ref(
+    &self,
+    user_id: &str,
+    blob_hash: &str,
+    holder: &str,
+    count: i64,
+    blob_client: &BlobServiceClient,
+  ) -> Result<(), Error> {
+    let removal = self
+      .decrement_attachment_ref(user_id, blob_hash, holder, count)
+      .await?;
+
+    if removal.should_revoke_holder() {
+      debug!(blob_hash, ?removal, "Revoking attachment holder");
+      blob_client.schedule_revoke_holder(blob_hash, holder);
     }
+    Ok(())
+  }
 
-    let items = raw_items
+  /// Atomically decrements reference count for given attachment by `count`
+  /// and removes the reference item when the count drops to zero,
+  /// releasing its size from the user usage.
+  async fn decrement_attachment_ref(
+    &self,
+    user_id: &str,
+    blob_hash: &str,
+    holder: &str,
+    count: i64,
+  ) -> Result<RefRemoval, Error> {
+    use attachment_ref_table::attr;
+
+    let result = self
+      .client
+      .update_item()
+      .table_name(attachment_ref_table::TABLE_NAME)
+      .set_key(Some(AttachmentRefItem::item_key(user_id, blob_hash)))
+      .update_expression("ADD #refCount :count")
+      .condition_expression("#holder = :holder")
+      .expression_attribute_names("#holder", attr::HOLDER)
+      .expression_attribute_names("#refCount", attr::REF_COUNT)
+      .expression_attribute_values(
+        ":holder",
+        AttributeValue::S(holder.to_string()),
+      )
+      .expression_attribute_values(
+        ":count",
+        AttributeValue::N((-count).to_string()),
+      )
+      .return_values(ReturnValue::AllNew)
+      .send()
+      .await;
+
+    let response = match result {
+      Ok(response) => response,
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        UpdateItemError::ConditionalCheckFailedException(_) => {
+          return Ok(RefRemoval::Untracked);
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to remove attachment reference"
+          );
+          return Err(Error::AwsSdk(other.into()));
+        }
+      },
+    };
+
+    let item =
+      AttachmentRefItem::try_from(response.attributes.unwrap_or_default())?;
+    if item.ref_count > 0 {
+      return Ok(RefRemoval::StillReferenced);
+    }
+
+    let result = self
+      .client
+      .delete_item()
+      .table_name(attachment_ref_table::TABLE_NAME)
+      .set_key(Some(AttachmentRefItem::item_key(user_id, blob_hash)))
+      .return_values(ReturnValue::AllOld)
+      .condition_expression("#holder = :holder AND #refCount <= :zero")
+      .expression_attribute_names("#holder", attr::HOLDER)
+      .expression_attribute_names("#refCount", attr::REF_COUNT)
+      .expression_attribute_values(
+        ":holder",
+        AttributeValue::S(holder.to_string()),
+      )
+      .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+      .send()
+      .await;
+
+    let response = match result {
+      Ok(response) => response,
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        // attachment has been referenced again in the meantime
+        DeleteItemError::ConditionalCheckFailedException(_) => {
+          return Ok(RefRemoval::StillReferenced);
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to remove attachment reference item"
+          );
+          return Err(Error::AwsSdk(other.into()));
+        }
+      },
+    };
+
+    let item =
+      AttachmentRefItem::try_from(response.attributes.unwrap_or_default())?;
+    self.release_usage(user_id, item.size, 0).await?;
+    Ok(RefRemoval::LastReference)
+  }
+}
+
+/// User usage functions
+impl DatabaseClient {
+  /// Atomically adds `size` bytes and `log_count` logs to the user usage,
+  /// unless the usage would exceed `max_size` or `max_log_count`.
+  pub async fn reserve_usage(
+    &self,
+    user_id: &str,
+    size: u64,
+    log_count: u64,
+    max_size: Option<u64>,
+    max_log_count: Option<u64>,
+  ) -> Result<UsageReservation, Error> {
+    use user_usage_table::attr;
+
+    let size_left = max_size.map(|max_size| max_size.checked_sub(size));
+    let log_count_left =
+      max_log_count.map(|max_log_count| max_log_count.checked_sub(log_count));
+    if matches!(size_left, Some(None)) || matches!(log_count_left, Some(None)) {
+      let usage = self.get_user_usage(user_id).await?;
+      return Ok(UsageReservation::LimitExceeded(usage));
+    }
+
+    let mut request = self
+      .client
+      .update_item()
+      .table_name(user_usage_table::TABLE_NAME)
+      .set_key(Some(UserUsageItem::item_key(user_id)))
+      .update_expression("ADD #totalSize :size, #logCount :logCount")
+      .expression_attribute_names("#totalSize", attr::TOTAL_SIZE)
+      .expression_attribute_names("#logCount", attr::LOG_COUNT)
+      .expression_attribute_values(":size", AttributeValue::N(size.to_string()))
+      .expression_attribute_values(
+        ":logCount",
+        AttributeValue::N(log_count.to_string()),
+      )
+      .return_values_on_condition_check_failure(
+        ReturnValuesOnConditionCheckFailure::AllOld,
+      );
+
+    let mut conditions = Vec::new();
+    if let Some(Some(size_left)) = size_left {
+      conditions
+        .push("(attribute_not_exists(#totalSize) OR #totalSize <= :sizeLeft)");
+      request = request.expression_attribute_values(
+        ":sizeLeft",
+        AttributeValue::N(size_left.to_string()),
+      );
+    }
+    if let Some(Some(log_count_left)) = log_count_left {
+      conditions.push(
+        "(attribute_not_exists(#logCount) OR #logCount <= :logCountLeft)",
+      );
+      request = request.expression_attribute_values(
+        ":logCountLeft",
+        AttributeValue::N(log_count_left.to_string()),
+      );
+    }
+    if !conditions.is_empty() {
+      request = request.condition_expression(conditions.join(" AND "));
+    }
+
+    match request.send().await {
+      Ok(_) => (),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        UpdateItemError::ConditionalCheckFailedException(err) => {
+          let usage = match err.item {
+            Some(item) => UserUsageItem::try_from(item)?,
+            None => self.get_user_usage(user_id).await?,
+          };
+          return Ok(UsageReservation::LimitExceeded(usage));
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to reserve user usage"
+          );
+          return Err(Error::AwsSdk(other.into()));
+        }
+      },
+    }
+
+    let db_client = self.clone();
+    let user_id = user_id.to_string();
+    let release = Defer::new(move || {
+      db_client.schedule_release_usage(&user_id, size, log_count)
+    });
+    Ok(UsageReservation::Reserved(release))
+  }
+
+  /// Subtracts `size` bytes and `log_count` logs from the user usage,
+  /// e.g. when items are removed or replaced. Usage never drops below zero.
+  pub async fn release_usage(
+    &self,
+    user_id: &str,
+    size: u64,
+    log_count: u64,
+  ) -> Result<(), Error> {
+    use user_usage_table::attr;
+
+    if size == 0 && log_count == 0 {
+      return Ok(());
+    }
+
+    let result = self
+      .client
+      .update_item()
+      .table_name(user_usage_table::TABLE_NAME)
+      .set_key(Some(UserUsageItem::item_key(user_id)))
+      .update_expression("ADD #totalSize :negSize, #logCount :negLogCount")
+      .condition_expression("#totalSize >= :size AND #logCount >= :logCount")
+      .expression_attribute_names("#totalSize", attr::TOTAL_SIZE)
+      .expression_attribute_names("#logCount", attr::LOG_COUNT)
+      .expression_attribute_values(":size", AttributeValue::N(size.to_string()))
+      .expression_attribute_values(
+        ":negSize",
+        AttributeValue::N((-(size as i64)).to_string()),
+      )
+      .expression_attribute_values(
+        ":logCount",
+        AttributeValue::N(log_count.to_string()),
+      )
+      .expression_attribute_values(
+        ":negLogCount",
+        AttributeValue::N((-(log_count as i64)).to_string()),
+      )
+      .return_values_on_condition_check_failure(
+        ReturnValuesOnConditionCheckFailure::AllOld,
+      )
+      .send()
+      .await;
+
+    let current_usage = match result {
+      Ok(_) => return Ok(()),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        UpdateItemError::ConditionalCheckFailedException(err) => {
+          UserUsageItem::try_from(
+            err.item.unwrap_or_else(|| UserUsageItem::item_key(user_id)),
+          )?
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to release user usage"
+          );
+          return Err(Error::AwsSdk(other.into()));
+        }
+      },
+    };
+
+    // Released more than was counted, e.g. because of a failed upload
+    // that was counted only partially. Clamp the usage at zero.
+    warn!(
+      ?current_usage,
+      size, log_count, "Released usage exceeds current user usage"
+    );
+    let total_size = current_usage.total_size.saturating_sub(size);
+    let new_log_count = current_usage.log_count.saturating_sub(log_count);
+    let result = self
+      .client
+      .update_item()
+      .table_name(user_usage_table::TABLE_NAME)
+      .set_key(Some(UserUsageItem::item_key(user_id)))
+      .update_expression("SET #totalSize = :newSize, #logCount = :newLogCount")
+      .condition_expression(
+        "(attribute_not_exists(#totalSize) OR #totalSize = :oldSize) \
+         AND (attribute_not_exists(#logCount) OR #logCount = :oldLogCount)",
+      )
+      .expression_attribute_names("#totalSize", attr::TOTAL_SIZE)
+      .expression_attribute_names("#logCount", attr::LOG_COUNT)
+      .expression_attribute_values(
+        ":newSize",
+        AttributeValue::N(total_size.to_string()),
+      )
+      .expression_attribute_values(
+        ":newLogCount",
+        AttributeValue::N(new_log_count.to_string()),
+      )
+      .expression_attribute_values(
+        ":oldSize",
+        AttributeValue::N(current_usage.total_size.to_string()),
+      )
+      .expression_attribute_values(
+        ":oldLogCount",
+        AttributeValue::N(current_usage.log_count.to_string()),
+      )
+      .send()
+      .await;
+
+    match result {
+      Ok(_) => Ok(()),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        // usage has changed in the meantime, so it's not clamped this time
+        UpdateItemError::ConditionalCheckFailedException(_) => {
+          warn!("User usage changed concurrently, skipping clamp");
+          Ok(())
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to clamp user usage"
+          );
+          Err(Error::AwsSdk(other.into()))
+        }
+      },
+    }
+  }
+
+  /// Releases usage in a separate task. Useful to clean up
+  /// after upload failure without blocking the current task.
+  pub fn schedule_release_usage(
+    &self,
+    user_id: &str,
+    size: u64,
+    log_count: u64,
+  ) {
+    if size == 0 && log_count == 0 {
+      return;
+    }
+
+    let db_client = self.clone();
+    let user_id = user_id.to_string();
+    tokio::spawn(async move {
+      if let Err(err) = db_client.release_usage(&user_id, size, log_count).await
+      {
+        warn!("Failed to release user usage: {0:?} - {0}", err);
+      }
+    });
+  }
+
+  /// Adds usage without checking limits, e.g. when existing data
+  /// is copied to a new backup
+  pub async fn add_usage(
+    &self,
+    user_id: &str,
+    size: u64,
+    log_count: u64,
+  ) -> Result<(), Error> {
+    if size == 0 && log_count == 0 {
+      return Ok(());
+    }
+
+    match self
+      .reserve_usage(user_id, size, log_count, None, None)
+      .await?
+    {
+      UsageReservation::Reserved(release) => release.cancel(),
+      UsageReservation::LimitExceeded(_) => {
+        unreachable!("usage without limits can't exceed them")
+      }
+    }
+    Ok(())
+  }
+
+  pub async fn get_user_usage(
+    &self,
+    user_id: &str,
+  ) -> Result<UserUsageItem, Error> {
+    let output = self
+      .client
+      .get_item()
+      .table_name(user_usage_table::TABLE_NAME)
+      .set_key(Some(UserUsageItem::item_key(user_id)))
+      .consistent_read(true)
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::DDB_ERROR,
+          "DynamoDB client failed to get user usage"
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    let item = output
+      .item
+      .unwrap_or_else(|| UserUsageItem::item_key(user_id));
+    let usage = UserUsageItem::try_from(item)?;
+    Ok(usage)
+  }
+}
+
+// general functions
+impl DatabaseClient {
+  /// Deletes all backups of the user. With a `grace_period`, the data
+  /// is only tombstoned and can be restored until it's purged
+  /// by [`crate::sweeper`]. Otherwise it's removed immediately.
+  pub async fn delete_user_data(
+    &self,
+    user_id: &str,
+    grace_period: Option<chrono::Duration>,
+    blob_client: &BlobServiceClient,
+  ) -> Result<(), Error> {
+    let Some(grace_period) = grace_period else {
+      return self.purge_user_data(user_id, Utc::now(), blob_client).await;
+    };
+
+    let tombstone = UserTombstoneItem::new(user_id, grace_period);
+    info!(purge_at = %tombstone.purge_at, "Tombstoning user data");
+    self.put_user_tombstone(tombstone).await
+  }
+
+  /// Permanently removes backups and logs of the user created
+  /// before `deleted_before`
+  pub async fn purge_user_data(
+    &self,
+    user_id: &str,
+    deleted_before: DateTime<Utc>,
+    blob_client: &BlobServiceClient,
+  ) -> Result<(), Error> {
+    // query the index to avoid unnecessarily querying backup data
+    let items = self.query_ordered_backups_index(user_id, None).await?;
+
+    for item in items {
+      if item.created >= deleted_before {
+        warn!(
+          backup_id = item.backup_id,
+          "Skipping backup created after user data was deleted"
+        );
+        continue;
+      }
+
+      trace!("Removing backup item: {item:?}");
+      self
+        .remove_backup_item(user_id, &item.backup_id, blob_client)
+        .await?;
+    }
+
+    Ok(())
+  }
+
+  /// Fails with [`BackupError::NoBackup`] if the user data was deleted,
+  /// so that tombstoned backups are hidden from clients.
+  pub async fn ensure_user_not_deleted(
+    &self,
+    user_id: &str,
+  ) -> Result<(), BackupError> {
+    if self.find_user_tombstone(user_id).await?.is_some() {
+      debug!("User data is tombstoned");
+      return Err(BackupError::NoBackup);
+    }
+    Ok(())
+  }
+
+  /// Fails with [`BackupError::UserDataDeleted`] if the user data was
+  /// deleted. New data would be hidden and not purged together with
+  /// the tombstoned data, so uploads are rejected until it's restored.
+  pub async fn ensure_user_can_upload(
+    &self,
+    user_id: &str,
+  ) -> Result<(), BackupError> {
+    if self.find_user_tombstone(user_id).await?.is_some() {
+      debug!("Rejecting upload of tombstoned user");
+      return Err(BackupError::UserDataDeleted);
+    }
+    Ok(())
+  }
+
+  async fn query_ordered_backups_index(
+    &self,
+    user_id: &str,
+    limit: Option<i32>,
+  ) -> Result<Vec<OrderedBackupItem>, Error> {
+    let query = self
+      .client
+      .query()
+      .table_name(backup_table::TABLE_NAME)
+      .index_name(backup_table::CREATED_INDEX)
+      .key_condition_expression("#userID = :valueToMatch")
+      .expression_attribute_names("#userID", backup_table::attr::USER_ID)
+      .expression_attribute_values(
+        ":valueToMatch",
+        AttributeValue::S(user_id.to_string()),
+      )
+      .scan_index_forward(false);
+
+    let mut raw_items = Vec::new();
+    let mut cursor = None;
+    loop {
+      let new_limit = limit
+        .map(|it| it - raw_items.len() as i32)
+        .filter(|it| *it > 0);
+      let request = query
+        .clone()
+        .set_exclusive_start_key(cursor)
+        .set_limit(new_limit);
+      let response = request.send().await.map_err(|e| {
+        error!(
+          errorType = error_types::DDB_ERROR,
+          "DynamoDB client failed to fetch backups"
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+      raw_items.extend(response.items.unwrap_or_default());
+      let limit_reached = limit.is_some_and(|it| raw_items.len() as i32 >= it);
+
+      cursor = match response.last_evaluated_key {
+        key @ Some(_) if !limit_reached => key,
+        _ => break,
+      };
+    }
+
+    let items = raw_items
       .into_iter()
       .map(OrderedBackupItem::try_from)
       .collect::<Result<Vec<_>, _>>()?;
@@ -539,3 +1538,741 @@ impl DatabaseClient {
     Ok(items)
   }
 }
+
+/// User tombstone functions
+impl DatabaseClient {
+  /// Stores the tombstone unless the user is already tombstoned,
+  /// in which case the original purge time is kept
+  async fn put_user_tombstone(
+    &self,
+    tombstone: UserTombstoneItem,
+  ) -> Result<(), Error> {
+    let result = self
+      .client
+      .put_item()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .set_item(Some(tombstone.into()))
+      .condition_expression("attribute_not_exists(#userID)")
+      .expression_attribute_names(
+        "#userID",
+        user_tombstone_table::attr::USER_ID,
+      )
+      .send()
+      .await;
+
+    match result {
+      Ok(_) => Ok(()),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        PutItemError::ConditionalCheckFailedException(_) => {
+          debug!("User data is already tombstoned");
+          Ok(())
+        }
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to put user tombstone"
+          );
+          Err(Error::AwsSdk(other.into()))
+        }
+      },
+    }
+  }
+
+  pub async fn find_user_tombstone(
+    &self,
+    user_id: &str,
+  ) -> Result<Option<UserTombstoneItem>, Error> {
+    let output = self
+      .client
+      .get_item()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .set_key(Some(UserTombstoneItem::item_key(user_id)))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::DDB_ERROR,
+          "DynamoDB client failed to find user tombstone"
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    let Some(item) = output.item else {
+      return Ok(None);
+    };
+    Ok(Some(item.try_into()?))
+  }
+
+  /// Removes the tombstone, making user backups visible again.
+  /// Returns the removed tombstone or `None` if the user data
+  /// isn't tombstoned or is already being purged.
+  pub async fn restore_user_data(
+    &self,
+    user_id: &str,
+  ) -> Result<Option<UserTombstoneItem>, Error> {
+    use user_tombstone_table::attr;
+
+    let result = self
+      .client
+      .delete_item()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .set_key(Some(UserTombstoneItem::item_key(user_id)))
+      .condition_expression(
+        "attribute_exists(#userID) AND attribute_not_exists(#purgeStartedAt)",
+      )
+      .expression_attribute_names("#userID", attr::USER_ID)
+      .expression_attribute_names("#purgeStartedAt", attr::PURGE_STARTED_AT)
+      .return_values(ReturnValue::AllOld)
+      .send()
+      .await;
+
+    match result {
+      Ok(response) => response
+        .attributes
+        .map(UserTombstoneItem::try_from)
+        .transpose()
+        .map_err(Error::from),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        DeleteItemError::ConditionalCheckFailedException(_) => Ok(None),
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to remove user tombstone"
+          );
+          Err(Error::AwsSdk(other.into()))
+        }
+      },
+    }
+  }
+
+  /// Returns tombstones whose grace period has ended
+  pub async fn find_expired_user_tombstones(
+    &self,
+  ) -> Result<Vec<UserTombstoneItem>, Error> {
+    let now = Utc::now().timestamp_millis();
+    let scan = self
+      .client
+      .scan()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .filter_expression("#purgeAt <= :now")
+      .expression_attribute_names(
+        "#purgeAt",
+        user_tombstone_table::attr::PURGE_AT,
+      )
+      .expression_attribute_values(":now", AttributeValue::N(now.to_string()));
+
+    let mut raw_items = Vec::new();
+    let mut cursor = None;
+    loop {
+      let response = scan
+        .clone()
+        .set_exclusive_start_key(cursor)
+        .send()
+        .await
+        .map_err(|e| {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to scan user tombstones"
+          );
+          Error::AwsSdk(e.into())
+        })?;
+
+      raw_items.extend(response.items.unwrap_or_default());
+      cursor = match response.last_evaluated_key {
+        key @ Some(_) => key,
+        None => break,
+      };
+    }
+
+    let items = raw_items
+      .into_iter()
+      .map(UserTombstoneItem::try_from)
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(items)
+  }
+
+  /// Marks the tombstone as being purged so that it can't be restored
+  /// anymore and isn't purged concurrently by another instance. A purge
+  /// that didn't finish within [`USER_DATA_SWEEP_INTERVAL`] can be taken
+  /// over. Returns `false` if the tombstone can't be claimed.
+  pub async fn start_user_data_purge(
+    &self,
+    user_id: &str,
+  ) -> Result<bool, Error> {
+    use user_tombstone_table::attr;
+
+    let now = Utc::now().timestamp_millis();
+    let stale_before = now - USER_DATA_SWEEP_INTERVAL.as_millis() as i64;
+    let result = self
+      .client
+      .update_item()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .set_key(Some(UserTombstoneItem::item_key(user_id)))
+      .update_expression("SET #purgeStartedAt = :now")
+      .condition_expression(
+        "#purgeAt <= :now AND (attribute_not_exists(#purgeStartedAt) \
+         OR #purgeStartedAt < :staleBefore)",
+      )
+      .expression_attribute_names("#purgeAt", attr::PURGE_AT)
+      .expression_attribute_names("#purgeStartedAt", attr::PURGE_STARTED_AT)
+      .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+      .expression_attribute_values(
+        ":staleBefore",
+        AttributeValue::N(stale_before.to_string()),
+      )
+      .send()
+      .await;
+
+    match result {
+      Ok(_) => Ok(true),
+      Err(sdk_error) => match sdk_error.into_service_error() {
+        UpdateItemError::ConditionalCheckFailedException(_) => Ok(false),
+        other => {
+          error!(
+            errorType = error_types::DDB_ERROR,
+            "DynamoDB client failed to update user tombstone"
+          );
+          Err(Error::AwsSdk(other.into()))
+        }
+      },
+    }
+  }
+
+  pub async fn remove_user_tombstone(
+    &self,
+    user_id: &str,
+  ) -> Result<(), Error> {
+    self
+      .client
+      .delete_item()
+      .table_name(user_tombstone_table::TABLE_NAME)
+      .set_key(Some(UserTombstoneItem::item_key(user_id)))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::DDB_ERROR,
+          "DynamoDB client failed to remove user tombstone"
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    Ok(())
+  }
+}
+
+/// Merges pages of primary and device logs by upload time, keeping
+/// the order of logs within each page, so that primary logs stay
+/// ordered by their IDs. Pages are given with a flag whether there
+/// might be more items. Merging stops when such a page runs out,
+/// because its next item could go before the remaining ones.
+/// Returns at most `limit` items and whether there might be more.
+fn merge_log_pages(
+  (primary_items, primary_has_more): (Vec<LogItem>, bool),
+  (device_items, device_has_more): (Vec<LogItem>, bool),
+  limit: usize,
+) -> (Vec<LogItem>, bool) {
+  let mut primary_items = primary_items.into_iter().peekable();
+  let mut device_items = device_items.into_iter().peekable();
+
+  let mut merged = Vec::new();
+  while merged.len() < limit {
+    let next_item = match (primary_items.peek(), device_items.peek()) {
+      (Some(primary), Some(device)) => {
+        if primary.uploaded_at <= device.uploaded_at {
+          primary_items.next()
+        } else {
+          device_items.next()
+        }
+      }
+      (Some(_), None) if !device_has_more => primary_items.next(),
+      (None, Some(_)) if !primary_has_more => device_items.next(),
+      (None, None) => return (merged, primary_has_more || device_has_more),
+      // a page that has more items ran out
+      _ => return (merged, true),
+    };
+    merged.extend(next_item);
+  }
+
+  let has_more = primary_has_more
+    || device_has_more
+    || primary_items.peek().is_some()
+    || device_items.peek().is_some();
+  (merged, has_more)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use comm_lib::database::blob::BlobOrDBContent;
+  use comm_lib::database::localstack::{localstack_config, random_user_id};
+
+  async fn localstack_db_client() -> DatabaseClient {
+    DatabaseClient::new(&localstack_config().await)
+  }
+
+  fn test_backup_item(user_id: &str, backup_id: &str) -> BackupItem {
+    BackupItem::new(
+      user_id.to_string(),
+      backup_id.to_string(),
+      BlobInfo {
+        blob_hash: format!("{backup_id}-user-keys"),
+        holder: "holder".to_string(),
+      },
+      None,
+      Vec::new(),
+      None,
+      Default::default(),
+    )
+  }
+
+  /// Holder revokes are scheduled in the background and only logged
+  /// when they fail, so the blob service doesn't have to be running
+  fn test_blob_client() -> BlobServiceClient {
+    BlobServiceClient::new("http://localhost:50053".parse().unwrap())
+  }
+
+  async fn find_attachment_ref(
+    db_client: &DatabaseClient,
+    user_id: &str,
+    blob_hash: &str,
+  ) -> Option<AttachmentRefItem> {
+    let output = db_client
+      .client
+      .get_item()
+      .table_name(attachment_ref_table::TABLE_NAME)
+      .set_key(Some(AttachmentRefItem::item_key(user_id, blob_hash)))
+      .consistent_read(true)
+      .send()
+      .await
+      .expect("Failed to get attachment reference");
+
+    output
+      .item
+      .map(|item| item.try_into().expect("Invalid attachment reference"))
+  }
+
+  fn test_log_item(device_id: Option<&str>, log_id: usize, t: i64) -> LogItem {
+    LogItem {
+      user_id: "user".to_string(),
+      backup_id: "backup".to_string(),
+      device_id: device_id.map(str::to_string),
+      log_id,
+      uploaded_at: DateTime::from_timestamp_millis(t).unwrap(),
+      content: BlobOrDBContent::new(Vec::new()),
+      attachments: Vec::new(),
+      size: 0,
+    }
+  }
+
+  fn log_keys(items: &[LogItem]) -> Vec<(Option<&str>, usize)> {
+    items
+      .iter()
+      .map(|item| (item.device_id.as_deref(), item.log_id))
+      .collect()
+  }
+
+  #[test]
+  fn test_merge_log_pages() {
+    let primary = vec![
+      test_log_item(None, 1, 10),
+      // replaced log keeps its position in the primary stream
+      test_log_item(None, 2, 50),
+      test_log_item(None, 3, 30),
+    ];
+    let device = vec![
+      test_log_item(Some("a"), 1, 20),
+      test_log_item(Some("b"), 1, 30),
+      test_log_item(Some("a"), 2, 40),
+    ];
+
+    let (merged, has_more) =
+      merge_log_pages((primary.clone(), false), (device.clone(), false), 10);
+    assert!(!has_more);
+    assert_eq!(
+      log_keys(&merged),
+      vec![
+        (None, 1),
+        (Some("a"), 1),
+        (Some("b"), 1),
+        (Some("a"), 2),
+        (None, 2),
+        (None, 3),
+      ]
+    );
+
+    let (merged, has_more) =
+      merge_log_pages((primary.clone(), false), (device.clone(), false), 2);
+    assert!(has_more);
+    assert_eq!(log_keys(&merged), vec![(None, 1), (Some("a"), 1)]);
+  }
+
+  #[test]
+  fn test_merge_log_pages_stops_at_end_of_partial_page() {
+    let primary = vec![test_log_item(None, 1, 10), test_log_item(None, 2, 40)];
+    let device = vec![test_log_item(Some("a"), 1, 20)];
+
+    // the next device log might have been uploaded before primary log 2
+    let (merged, has_more) =
+      merge_log_pages((primary.clone(), false), (device.clone(), true), 10);
+    assert!(has_more);
+    assert_eq!(log_keys(&merged), vec![(None, 1), (Some("a"), 1)]);
+
+    let (merged, has_more) =
+      merge_log_pages((primary, true), (device, false), 10);
+    assert!(has_more);
+    assert_eq!(
+      log_keys(&merged),
+      vec![(None, 1), (Some("a"), 1), (None, 2)]
+    );
+
+    let (merged, has_more) =
+      merge_log_pages((Vec::new(), false), (Vec::new(), false), 10);
+    assert!(!has_more);
+    assert!(merged.is_empty());
+  }
+
+  #[test]
+  fn test_ref_removal_revokes_holder() {
+    assert!(!RefRemoval::StillReferenced.should_revoke_holder());
+    assert!(RefRemoval::LastReference.should_revoke_holder());
+    assert!(RefRemoval::Untracked.should_revoke_holder());
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_attachment_ref_holder_is_shared() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let holder = db_client
+      .increment_attachment_ref(&user_id, "hash", 1, "holder1", 100)
+      .await
+      .unwrap();
+    assert_eq!(holder, "holder1");
+
+    let holder = db_client
+      .increment_attachment_ref(&user_id, "hash", 2, "holder2", 200)
+      .await
+      .unwrap();
+    assert_eq!(
+      holder, "holder1",
+      "Holder of first reference should be kept"
+    );
+
+    let item = find_attachment_ref(&db_client, &user_id, "hash")
+      .await
+      .expect("Reference should exist");
+    assert_eq!(item.ref_count, 3);
+    assert_eq!(item.size, 100, "Size of first reference should be kept");
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_attachment_ref_concurrent_increments() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let tasks: Vec<_> = (0..10)
+      .map(|i| {
+        let db_client = db_client.clone();
+        let user_id = user_id.clone();
+        tokio::spawn(async move {
+          let new_holder = format!("holder{i}");
+          let holder = db_client
+            .increment_attachment_ref(&user_id, "hash", 1, &new_holder, 0)
+            .await
+            .unwrap();
+          (holder, new_holder)
+        })
+      })
+      .collect();
+
+    let mut holders = Vec::new();
+    for task in tasks {
+      holders.push(task.await.unwrap());
+    }
+
+    let shared_holder = &holders[0].0;
+    assert!(holders.iter().all(|(holder, _)| holder == shared_holder));
+    let first_refs = holders
+      .iter()
+      .filter(|(holder, new_holder)| holder == new_holder)
+      .count();
+    assert_eq!(first_refs, 1, "Exactly one holder should be established");
+
+    let item = find_attachment_ref(&db_client, &user_id, "hash")
+      .await
+      .expect("Reference should exist");
+    assert_eq!(item.ref_count, 10);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_attachment_ref_decrement_to_zero() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    db_client
+      .increment_attachment_ref(&user_id, "hash", 2, "holder", 100)
+      .await
+      .unwrap();
+    // counted by `add_attachment_ref` for the first reference
+    db_client.add_usage(&user_id, 100, 0).await.unwrap();
+
+    let removal = db_client
+      .decrement_attachment_ref(&user_id, "hash", "holder", 1)
+      .await
+      .unwrap();
+    assert_eq!(removal, RefRemoval::StillReferenced);
+    let item = find_attachment_ref(&db_client, &user_id, "hash")
+      .await
+      .expect("Reference should exist");
+    assert_eq!(item.size, 100);
+    let usage = db_client.get_user_usage(&user_id).await.unwrap();
+    assert_eq!(usage.total_size, 100, "Size should be released only once");
+
+    let removal = db_client
+      .decrement_attachment_ref(&user_id, "hash", "holder", 1)
+      .await
+      .unwrap();
+    assert_eq!(removal, RefRemoval::LastReference);
+    assert!(removal.should_revoke_holder());
+    assert!(find_attachment_ref(&db_client, &user_id, "hash")
+      .await
+      .is_none());
+    let usage = db_client.get_user_usage(&user_id).await.unwrap();
+    assert_eq!(usage.total_size, 0);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_untracked_attachment_ref() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let removal = db_client
+      .decrement_attachment_ref(&user_id, "hash", "holder", 1)
+      .await
+      .unwrap();
+    assert_eq!(removal, RefRemoval::Untracked);
+
+    // holder created before the reference index existed
+    db_client
+      .increment_attachment_ref(&user_id, "hash", 1, "holder", 0)
+      .await
+      .unwrap();
+    let removal = db_client
+      .decrement_attachment_ref(&user_id, "hash", "legacy-holder", 1)
+      .await
+      .unwrap();
+    assert_eq!(removal, RefRemoval::Untracked);
+    let item = find_attachment_ref(&db_client, &user_id, "hash")
+      .await
+      .expect("Reference should be kept");
+    assert_eq!(item.ref_count, 1);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_concurrent_usage_reservations_respect_limit() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let tasks: Vec<_> = (0..10)
+      .map(|_| {
+        let db_client = db_client.clone();
+        let user_id = user_id.clone();
+        tokio::spawn(async move {
+          match db_client
+            .reserve_usage(&user_id, 100, 1, Some(450), None)
+            .await
+            .unwrap()
+          {
+            UsageReservation::Reserved(release) => {
+              release.cancel();
+              true
+            }
+            UsageReservation::LimitExceeded(_) => false,
+          }
+        })
+      })
+      .collect();
+
+    let mut num_reserved = 0;
+    for task in tasks {
+      if task.await.unwrap() {
+        num_reserved += 1;
+      }
+    }
+    assert_eq!(
+      num_reserved, 4,
+      "Only reservations within limit should pass"
+    );
+
+    let usage = db_client.get_user_usage(&user_id).await.unwrap();
+    assert_eq!(usage.total_size, 400);
+    assert_eq!(usage.log_count, 4);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_log_count_limit_and_release() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    db_client.add_usage(&user_id, 10, 2).await.unwrap();
+    let reservation = db_client
+      .reserve_usage(&user_id, 10, 1, None, Some(2))
+      .await
+      .unwrap();
+    let UsageReservation::LimitExceeded(usage) = reservation else {
+      panic!("Log count limit should be exceeded");
+    };
+    assert_eq!(usage.log_count, 2);
+
+    db_client.release_usage(&user_id, 10, 1).await.unwrap();
+    let usage = db_client.get_user_usage(&user_id).await.unwrap();
+    assert_eq!(usage.total_size, 0);
+    assert_eq!(usage.log_count, 1);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_usage_release_clamps_at_zero() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    db_client.add_usage(&user_id, 100, 1).await.unwrap();
+    db_client.release_usage(&user_id, 150, 2).await.unwrap();
+
+    let usage = db_client.get_user_usage(&user_id).await.unwrap();
+    assert_eq!(usage.total_size, 0);
+    assert_eq!(usage.log_count, 0);
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_tombstoned_user_data_can_be_restored() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let grace_period = chrono::Duration::days(1);
+    db_client
+      .delete_user_data(&user_id, Some(grace_period), &test_blob_client())
+      .await
+      .unwrap();
+    let tombstone = db_client.find_user_tombstone(&user_id).await.unwrap();
+    assert!(tombstone.is_some());
+    assert!(matches!(
+      db_client.ensure_user_can_upload(&user_id).await,
+      Err(BackupError::UserDataDeleted)
+    ));
+
+    let restored = db_client.restore_user_data(&user_id).await.unwrap();
+    assert_eq!(restored.map(|item| item.user_id), Some(user_id.clone()));
+    assert!(db_client
+      .find_user_tombstone(&user_id)
+      .await
+      .unwrap()
+      .is_none());
+    assert!(db_client.ensure_user_can_upload(&user_id).await.is_ok());
+    assert!(
+      db_client
+        .restore_user_data(&user_id)
+        .await
+        .unwrap()
+        .is_none(),
+      "User data shouldn't be restored twice"
+    );
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_purge_keeps_backups_created_after_deletion() {
+    let db_client = localstack_db_client().await;
+    let blob_client = test_blob_client();
+    let user_id = random_user_id();
+
+    db_client
+      .put_backup_item(test_backup_item(&user_id, "old-backup"))
+      .await
+      .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    db_client
+      .delete_user_data(&user_id, Some(chrono::Duration::zero()), &blob_client)
+      .await
+      .unwrap();
+    let tombstone = db_client
+      .find_user_tombstone(&user_id)
+      .await
+      .unwrap()
+      .expect("User data should be tombstoned");
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    db_client
+      .put_backup_item(test_backup_item(&user_id, "new-backup"))
+      .await
+      .unwrap();
+
+    assert!(db_client.start_user_data_purge(&user_id).await.unwrap());
+    assert!(
+      db_client
+        .restore_user_data(&user_id)
+        .await
+        .unwrap()
+        .is_none(),
+      "User data shouldn't be restored once purge started"
+    );
+    db_client
+      .purge_user_data(&user_id, tombstone.deleted_at, &blob_client)
+      .await
+      .unwrap();
+    db_client.remove_user_tombstone(&user_id).await.unwrap();
+
+    let old_backup = db_client
+      .find_backup_item(&user_id, "old-backup")
+      .await
+      .unwrap();
+    assert!(old_backup.is_none());
+    let new_backup = db_client
+      .find_backup_item(&user_id, "new-backup")
+      .await
+      .unwrap();
+    assert!(new_backup.is_some());
+
+    db_client
+      .remove_backup_item(&user_id, "new-backup", &blob_client)
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_put_new_backup_item_keeps_existing_backup() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let created = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+    let mut imported_backup = test_backup_item(&user_id, "backup");
+    imported_backup.created = created;
+    assert!(db_client
+      .put_new_backup_item(imported_backup)
+      .await
+      .unwrap());
+
+    let mut duplicate_backup = test_backup_item(&user_id, "backup");
+    duplicate_backup.user_keys.holder = "other-holder".to_string();
+    assert!(!db_client
+      .put_new_backup_item(duplicate_backup)
+      .await
+      .unwrap());
+
+    let backup = db_client
+      .find_backup_item(&user_id, "backup")
+      .await
+      .unwrap()
+      .expect("Backup should exist");
+    assert_eq!(backup.created, created);
+    assert_eq!(backup.user_keys.holder, "holder");
+  }
+}
@@ -0,0 +1,76 @@
+use crate::constants::user_tombstone_table::attr;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use comm_lib::database::{
+  parse_timestamp_attribute, AttributeExtractor, AttributeMap, DBItemError,
+};
+use std::collections::HashMap;
+
+/// Marks a user whose backup data has been deleted, but can still be
+/// restored. Backups of such user are hidden from clients and are purged
+/// once [`UserTombstoneItem::purge_at`] passes.
+#[derive(Clone, Debug)]
+pub struct UserTombstoneItem {
+  pub user_id: String,
+  pub deleted_at: DateTime<Utc>,
+  pub purge_at: DateTime<Utc>,
+}
+
+impl UserTombstoneItem {
+  pub fn new(
+    user_id: impl Into<String>,
+    grace_period: chrono::Duration,
+  ) -> Self {
+    let deleted_at = Utc::now();
+    UserTombstoneItem {
+      user_id: user_id.into(),
+      deleted_at,
+      purge_at: deleted_at + grace_period,
+    }
+  }
+
+  pub fn item_key(
+    user_id: impl Into<String>,
+  ) -> HashMap<String, AttributeValue> {
+    HashMap::from([(
+      attr::USER_ID.to_string(),
+      AttributeValue::S(user_id.into()),
+    )])
+  }
+}
+
+impl From<UserTombstoneItem> for AttributeMap {
+  fn from(value: UserTombstoneItem) -> Self {
+    HashMap::from([
+      (attr::USER_ID.to_string(), AttributeValue::S(value.user_id)),
+      (
+        attr::DELETED_AT.to_string(),
+        AttributeValue::N(value.deleted_at.timestamp_millis().to_string()),
+      ),
+      (
+        attr::PURGE_AT.to_string(),
+        AttributeValue::N(value.purge_at.timestamp_millis().to_string()),
+      ),
+    ])
+  }
+}
+
+impl TryFrom<AttributeMap> for UserTombstoneItem {
+  type Error = DBItemError;
+
+  fn try_from(mut value: AttributeMap) -> Result<Self, Self::Error> {
+    let user_id = value.take_attr(attr::USER_ID)?;
+    let deleted_at = parse_timestamp_attribute(
+      attr::DELETED_AT,
+      value.remove(attr::DELETED_AT),
+    )?;
+    let purge_at =
+      parse_timestamp_attribute(attr::PURGE_AT, value.remove(attr::PURGE_AT))?;
+
+    Ok(UserTombstoneItem {
+      user_id,
+      deleted_at,
+      purge_at,
+    })
+  }
+}
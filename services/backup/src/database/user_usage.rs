@@ -0,0 +1,66 @@
+use crate::constants::user_usage_table::attr;
+use aws_sdk_dynamodb::types::AttributeValue;
+use comm_lib::{
+  database::{
+    parse_int_attribute, AttributeExtractor, AttributeMap, DBItemError,
+  },
+  tools::Defer,
+};
+use std::collections::HashMap;
+
+/// Running totals of the backup data stored by a user, used to enforce
+/// quotas. Counters are updated atomically on each upload and removal
+/// with sizes stored on backup and log items, so usage doesn't have
+/// to be recalculated from all items.
+#[derive(Clone, Debug, Default)]
+pub struct UserUsageItem {
+  pub user_id: String,
+  /// Size of all backups and logs of the user in bytes
+  pub total_size: u64,
+  /// Number of all logs of the user
+  pub log_count: u64,
+}
+
+impl UserUsageItem {
+  pub fn item_key(
+    user_id: impl Into<String>,
+  ) -> HashMap<String, AttributeValue> {
+    HashMap::from([(
+      attr::USER_ID.to_string(),
+      AttributeValue::S(user_id.into()),
+    )])
+  }
+}
+
+/// Outcome of reserving usage for new data
+pub enum UsageReservation {
+  /// Usage has been added. The [`Defer`] releases it unless canceled,
+  /// which should be done once items of the reserved size are stored.
+  Reserved(Defer<'static>),
+  /// Reserving would exceed a limit. Contains current usage.
+  LimitExceeded(UserUsageItem),
+}
+
+impl TryFrom<AttributeMap> for UserUsageItem {
+  type Error = DBItemError;
+
+  fn try_from(mut value: AttributeMap) -> Result<Self, Self::Error> {
+    let user_id = value.take_attr(attr::USER_ID)?;
+    // counters don't exist until something is added
+    let mut take_counter = |attr_name: &str| {
+      value
+        .remove(attr_name)
+        .map(|counter| parse_int_attribute(attr_name, Some(counter)))
+        .transpose()
+        .map(Option::unwrap_or_default)
+    };
+    let total_size = take_counter(attr::TOTAL_SIZE)?;
+    let log_count = take_counter(attr::LOG_COUNT)?;
+
+    Ok(UserUsageItem {
+      user_id,
+      total_size,
+      log_count,
+    })
+  }
+}
@@ -1,32 +1,41 @@
 use actix_web::{
   error::{
-    ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorNotFound,
-    ErrorServiceUnavailable,
+    ErrorBadRequest, ErrorConflict, ErrorGone, ErrorInternalServerError,
+    ErrorNotFound, ErrorPayloadTooLarge, ErrorServiceUnavailable,
   },
   HttpResponse, ResponseError,
 };
 pub use aws_sdk_dynamodb::Error as DynamoDBError;
 use comm_lib::database::Error as DBError;
-use comm_lib::{auth::AuthServiceError, blob::client::BlobServiceError};
+use comm_lib::{
+  auth::AuthServiceError, backup::archive::ArchiveError,
+  blob::client::BlobServiceError,
+};
 use grpc_clients::error::Error as IdentityClientError;
 use reqwest::StatusCode;
 use tracing::{error, trace, warn};
 
 use crate::constants::error_types;
+use crate::quota::QuotaError;
 
 #[derive(
   Debug, derive_more::Display, derive_more::From, derive_more::Error,
 )]
 pub enum BackupError {
   NoBackup,
+  BackupAlreadyExists,
   NoUserID,
   BlobError(BlobServiceError),
   AuthError(AuthServiceError),
   DB(comm_lib::database::Error),
   IdentityClientError(IdentityClientError),
+  Archive(ArchiveError),
+  QuotaExceeded(QuotaError),
   #[error(ignore)]
   BadRequest(&'static str),
   NoUserData,
+  /// User data was deleted and waits to be restored or purged
+  UserDataDeleted,
 }
 
 impl From<&BackupError> for actix_web::Error {
@@ -34,6 +43,9 @@ impl From<&BackupError> for actix_web::Error {
     trace!("Handling backup service error: {value}");
     match value {
       BackupError::NoBackup => ErrorNotFound("not found"),
+      BackupError::BackupAlreadyExists => {
+        ErrorConflict("backup_already_exists")
+      }
       BackupError::BlobError(
         err @ (BlobServiceError::ClientError(_)
         | BlobServiceError::UnexpectedHttpStatus(_)
@@ -87,9 +99,18 @@ impl From<&BackupError> for actix_web::Error {
         warn!("Transient identity error occurred: {err}");
         ErrorServiceUnavailable("please retry")
       }
+      BackupError::Archive(err) => {
+        warn!("Malformed backup archive: {err}");
+        ErrorBadRequest("invalid_archive")
+      }
+      BackupError::QuotaExceeded(err) => {
+        warn!("Backup quota exceeded: {err:?}");
+        ErrorPayloadTooLarge(*err)
+      }
       BackupError::NoUserID => ErrorBadRequest("no_user_id"),
       BackupError::BadRequest(reason) => ErrorBadRequest(*reason),
       BackupError::NoUserData => ErrorNotFound("not found"),
+      BackupError::UserDataDeleted => ErrorGone("user_data_deleted"),
     }
   }
 }
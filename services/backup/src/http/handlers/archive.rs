@@ -0,0 +1,636 @@
+use actix_web::{
+  web::{self, Bytes},
+  HttpResponse,
+};
+use async_stream::try_stream;
+use chrono::{DateTime, Utc};
+use comm_lib::{
+  auth::UserIdentity,
+  backup::archive::{
+    self, ArchiveEvent, BackupArchiveDecoder, BackupArchiveManifest,
+    BackupArchivePart, PartDigest, ARCHIVE_FORMAT_VERSION,
+  },
+  blob::{
+    client::{BlobServiceClient, BlobServiceError},
+    types::{http::BlobSizesRequest, BlobInfo},
+  },
+  database::blob::BlobOrDBContent,
+  http::auth_service::Authenticated,
+  tools::{is_valid_identifier, Defer},
+};
+use std::convert::Infallible;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::{
+  constants::error_types,
+  database::{backup_item::BackupItem, log_item::LogItem, DatabaseClient},
+  error::BackupError,
+  quota::{self, ArchiveSizes, QuotaError},
+};
+
+/// Source of data of a single archive part
+enum PartSource {
+  Blob(String),
+  Inline(Vec<u8>),
+}
+
+#[instrument(skip_all, fields(backup_id = %path))]
+pub async fn export_backup(
+  user: UserIdentity,
+  path: web::Path<String>,
+  blob_client: Authenticated<BlobServiceClient>,
+  db_client: web::Data<DatabaseClient>,
+  auth_service: comm_lib::auth::AuthService,
+) -> actix_web::Result<HttpResponse> {
+  info!("Export backup request");
+  let backup_id = path.into_inner();
+
+  db_client.ensure_user_not_deleted(&user.user_id).await?;
+  let backup_item = db_client
+    .find_backup_item(&user.user_id, &backup_id)
+    .await
+    .map_err(BackupError::from)?
+    .ok_or(BackupError::NoBackup)?;
+  let mut log_items = db_client
+    .fetch_all_log_items_for_backup(&user.user_id, &backup_id)
+    .await
+    .map_err(BackupError::from)?;
+  // primary stream goes first, then device streams in upload order
+  log_items.sort_by(|a, b| {
+    let key = |log: &LogItem| match &log.device_id {
+      None => (false, DateTime::UNIX_EPOCH, String::new(), log.log_id),
+      Some(device_id) => (true, log.uploaded_at, device_id.clone(), log.log_id),
+    };
+    key(a).cmp(&key(b))
+  });
+
+  // gather all blob hashes to fetch their sizes
+  let mut attachment_hashes: Vec<String> = Vec::new();
+  let all_attachments = backup_item
+    .attachments
+    .iter()
+    .chain(log_items.iter().flat_map(|log| &log.attachments));
+  for attachment in all_attachments {
+    if !attachment_hashes.contains(&attachment.blob_hash) {
+      attachment_hashes.push(attachment.blob_hash.clone());
+    }
+  }
+
+  let mut blob_hashes = vec![backup_item.user_keys.blob_hash.clone()];
+  blob_hashes
+    .extend(backup_item.user_data.iter().map(|it| it.blob_hash.clone()));
+  blob_hashes.extend(attachment_hashes.iter().cloned());
+  for log in &log_items {
+    if let BlobOrDBContent::Blob(blob_info) = &log.content {
+      blob_hashes.push(blob_info.blob_hash.clone());
+    }
+  }
+
+  // we have to re-auth blob client with s2s token because the sizes endpoint is service-only
+  let credential = auth_service
+    .get_services_token()
+    .await
+    .map_err(BackupError::from)?;
+  let blob_sizes = blob_client
+    .with_authentication(credential.into())
+    .fetch_blob_sizes(BlobSizesRequest { blob_hashes })
+    .await
+    .map_err(BackupError::from)?
+    .blob_sizes;
+  let get_blob_size = |blob_hash: &str| {
+    blob_sizes.get(blob_hash).copied().ok_or_else(|| {
+      error!(
+        errorType = error_types::BLOB_ERROR,
+        "Blob {blob_hash:?} for backup export not found"
+      );
+      BackupError::BlobError(BlobServiceError::NotFound)
+    })
+  };
+
+  let mut parts = Vec::new();
+  let mut sources = Vec::new();
+
+  let user_keys_hash = backup_item.user_keys.blob_hash;
+  parts.push(BackupArchivePart::UserKeys {
+    size: get_blob_size(&user_keys_hash)?,
+    blob_hash: user_keys_hash.clone(),
+  });
+  sources.push(PartSource::Blob(user_keys_hash));
+
+  if let Some(BlobInfo { blob_hash, .. }) = backup_item.user_data {
+    parts.push(BackupArchivePart::UserData {
+      size: get_blob_size(&blob_hash)?,
+      blob_hash: blob_hash.clone(),
+    });
+    sources.push(PartSource::Blob(blob_hash));
+  }
+
+  for blob_hash in attachment_hashes {
+    let Some(size) = blob_sizes.get(&blob_hash).copied() else {
+      warn!("Blob attachment with hash {blob_hash:?} doesn't exist");
+      continue;
+    };
+    parts.push(BackupArchivePart::Attachment {
+      blob_hash: blob_hash.clone(),
+      size,
+    });
+    sources.push(PartSource::Blob(blob_hash));
+  }
+
+  for log in log_items {
+    let (size, source) = match log.content {
+      BlobOrDBContent::Blob(BlobInfo { blob_hash, .. }) => {
+        (get_blob_size(&blob_hash)?, PartSource::Blob(blob_hash))
+      }
+      BlobOrDBContent::Database(data) => {
+        (data.len() as u64, PartSource::Inline(data))
+      }
+    };
+    parts.push(BackupArchivePart::Log {
+      log_id: log.log_id,
+      size,
+      attachments: log
+        .attachments
+        .into_iter()
+        .map(|attachment| attachment.blob_hash)
+        .collect(),
+      uploaded_at: log.device_id.as_ref().map(|_| log.uploaded_at.to_rfc3339()),
+      device_id: log.device_id,
+    });
+    sources.push(source);
+  }
+
+  let manifest = BackupArchiveManifest {
+    format_version: ARCHIVE_FORMAT_VERSION,
+    backup_id,
+    creation_timestamp: backup_item.created.to_rfc3339(),
+    siwe_backup_msg: backup_item.siwe_backup_msg,
+    version_info: backup_item.version_info,
+    attachments: backup_item
+      .attachments
+      .into_iter()
+      .map(|attachment| attachment.blob_hash)
+      .collect(),
+    parts,
+  };
+  let header = archive::encode_header(&manifest).map_err(BackupError::from)?;
+
+  info!(num_parts = manifest.parts.len(), "Streaming backup archive");
+  let stream = archive_stream(header, sources, blob_client.into_inner());
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type("application/octet-stream")
+      .streaming(stream),
+  )
+}
+
+fn archive_stream(
+  header: Vec<u8>,
+  sources: Vec<PartSource>,
+  blob_client: BlobServiceClient,
+) -> impl Stream<Item = Result<Bytes, BlobServiceError>> {
+  try_stream! {
+    yield Bytes::from(header);
+
+    for source in sources {
+      let mut digest = PartDigest::new();
+      match source {
+        PartSource::Inline(data) => {
+          digest.update(&data);
+          yield Bytes::from(data);
+        }
+        PartSource::Blob(blob_hash) => {
+          let blob_stream = blob_client.get(&blob_hash).await?;
+          tokio::pin!(blob_stream);
+          while let Some(chunk) = blob_stream.try_next().await? {
+            digest.update(&chunk);
+            yield chunk;
+          }
+        }
+      }
+      yield Bytes::copy_from_slice(&digest.finalize());
+    }
+  }
+}
+
+#[instrument(skip_all, fields(backup_id))]
+pub async fn import_backup(
+  user: UserIdentity,
+  blob_client: Authenticated<BlobServiceClient>,
+  db_client: web::Data<DatabaseClient>,
+  mut payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+  info!("Import backup request");
+  db_client.ensure_user_can_upload(&user.user_id).await?;
+
+  let mut decoder = BackupArchiveDecoder::new();
+  let mut importer: Option<ArchiveImporter> = None;
+
+  while let Some(chunk) = payload.try_next().await? {
+    let events = decoder.feed(&chunk).map_err(BackupError::from)?;
+    for event in events {
+      match (event, importer.as_mut()) {
+        (ArchiveEvent::Manifest(manifest), None) => {
+          tracing::Span::current().record("backup_id", &manifest.backup_id);
+          if !is_valid_manifest(&manifest) {
+            return Err(BackupError::BadRequest("invalid_archive").into());
+          }
+
+          // checked again when the backup item is stored
+          if db_client
+            .find_backup_item(&user.user_id, &manifest.backup_id)
+            .await
+            .map_err(BackupError::from)?
+            .is_some()
+          {
+            return Err(BackupError::BackupAlreadyExists.into());
+          }
+
+          let (sizes, usage_release) =
+            quota::reserve_archive_usage(&user.user_id, &manifest, &db_client)
+              .await?;
+
+          importer = Some(ArchiveImporter::new(
+            user.user_id.clone(),
+            manifest,
+            sizes,
+            usage_release,
+            &blob_client,
+          ));
+        }
+        (ArchiveEvent::PartData { index, data }, Some(importer)) => {
+          importer.handle_part_data(index, data).await?;
+        }
+        (ArchiveEvent::PartEnd { index }, Some(importer)) => {
+          importer.handle_part_end(index).await?;
+        }
+        _ => {
+          warn!("Malformed archive: unexpected event order");
+          return Err(BackupError::BadRequest("invalid_archive").into());
+        }
+      }
+    }
+  }
+  decoder.finish().map_err(BackupError::from)?;
+
+  let Some(importer) = importer else {
+    return Err(BackupError::BadRequest("invalid_archive").into());
+  };
+  // Old backups aren't removed here: the imported backup keeps its
+  // original creation time, so it may be older than the current ones
+  importer.finish(&db_client).await?;
+
+  Ok(HttpResponse::Ok().finish())
+}
+
+fn is_valid_manifest(manifest: &BackupArchiveManifest) -> bool {
+  if !is_valid_identifier(&manifest.backup_id) {
+    warn!("Malformed archive: invalid backup ID");
+    return false;
+  }
+  if parse_creation_timestamp(manifest).is_none() {
+    warn!("Malformed archive: invalid creation timestamp");
+    return false;
+  }
+
+  let num_user_keys = manifest
+    .parts
+    .iter()
+    .filter(|part| matches!(part, BackupArchivePart::UserKeys { .. }))
+    .count();
+  let num_user_data = manifest
+    .parts
+    .iter()
+    .filter(|part| matches!(part, BackupArchivePart::UserData { .. }))
+    .count();
+
+  if num_user_keys != 1 || num_user_data > 1 {
+    warn!("Malformed archive: expected single User Keys and User Data");
+    return false;
+  }
+  true
+}
+
+fn parse_creation_timestamp(
+  manifest: &BackupArchiveManifest,
+) -> Option<DateTime<Utc>> {
+  DateTime::parse_from_rfc3339(&manifest.creation_timestamp)
+    .ok()
+    .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// Upload of a single blob part, streamed to Blob service
+/// as the archive is being received. If dropped before finished,
+/// the upload is aborted and its holder is revoked.
+struct BlobPartUpload {
+  blob_info: BlobInfo,
+  blob_client: BlobServiceClient,
+  sender: Option<mpsc::Sender<Result<Bytes, Infallible>>>,
+  task: JoinHandle<Result<bool, BlobServiceError>>,
+  finished: bool,
+}
+
+impl BlobPartUpload {
+  fn start(blob_hash: String, blob_client: &BlobServiceClient) -> Self {
+    let blob_info = BlobInfo {
+      blob_hash,
+      holder: uuid::Uuid::new_v4().to_string(),
+    };
+
+    let (sender, receiver) = mpsc::channel(1);
+    let upload_client = blob_client.clone();
+    let BlobInfo { blob_hash, holder } = blob_info.clone();
+    let task = tokio::spawn(async move {
+      let data_stream = ReceiverStream::new(receiver);
+      upload_client
+        .simple_put(&blob_hash, &holder, data_stream)
+        .await
+    });
+
+    Self {
+      blob_info,
+      blob_client: blob_client.clone(),
+      sender: Some(sender),
+      task,
+      finished: false,
+    }
+  }
+
+  async fn send(&self, data: Vec<u8>) {
+    let Some(sender) = &self.sender else {
+      return;
+    };
+    if let Err(err) = sender.send(Ok(Bytes::from(data))).await {
+      // Upload task has finished early. Its result is checked in `finish()`
+      warn!("Error when sending data through a channel: '{err}'");
+    }
+  }
+
+  async fn finish(mut self) -> Result<BlobInfo, BackupError> {
+    // closing the channel ends the upload data stream
+    self.sender.take();
+
+    let result = (&mut self.task).await;
+    self.finished = true;
+    match result {
+      Ok(result) => {
+        result?;
+        Ok(self.blob_info.clone())
+      }
+      Err(err) => {
+        error!(
+          errorType = error_types::BLOB_ERROR,
+          "Blob upload task failed: {err:?}"
+        );
+        Err(BackupError::BlobError(BlobServiceError::UnexpectedError))
+      }
+    }
+  }
+}
+
+impl Drop for BlobPartUpload {
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+    // Don't let the upload complete with truncated data
+    self.task.abort();
+    self.blob_client.schedule_revoke_holder(
+      self.blob_info.blob_hash.clone(),
+      self.blob_info.holder.clone(),
+    );
+  }
+}
+
+/// Recreates backup and its logs from archive events.
+/// Blobs are uploaded as soon as their data arrives. DDB items are
+/// written when the whole archive is processed.
+struct ArchiveImporter<'blob> {
+  user_id: String,
+  manifest: BackupArchiveManifest,
+  /// Item sizes, already counted towards the user usage
+  sizes: ArchiveSizes,
+  blob_client: &'blob BlobServiceClient,
+  current_upload: Option<BlobPartUpload>,
+  current_log_content: Vec<u8>,
+  user_keys: Option<BlobInfo>,
+  user_data: Option<BlobInfo>,
+  /// Log items with blob hashes of their attachments
+  logs: Vec<(LogItem, Vec<String>)>,
+  /// Attachments are uploaded with temporary holders, which are replaced
+  /// with references shared between backups at the end of import.
+  /// These are never canceled.
+  temporary_holder_revokes: Vec<Defer<'blob>>,
+  revokes: Vec<Defer<'blob>>,
+}
+
+impl<'blob> ArchiveImporter<'blob> {
+  fn new(
+    user_id: String,
+    manifest: BackupArchiveManifest,
+    sizes: ArchiveSizes,
+    usage_release: Defer<'static>,
+    blob_client: &'blob BlobServiceClient,
+  ) -> Self {
+    Self {
+      user_id,
+      manifest,
+      sizes,
+      blob_client,
+      current_upload: None,
+      current_log_content: Vec::new(),
+      user_keys: None,
+      user_data: None,
+      logs: Vec::new(),
+      temporary_holder_revokes: Vec::new(),
+      revokes: vec![usage_release],
+    }
+  }
+
+  async fn handle_part_data(
+    &mut self,
+    index: usize,
+    data: Vec<u8>,
+  ) -> Result<(), BackupError> {
+    let part = &self.manifest.parts[index];
+    let blob_hash = match part {
+      BackupArchivePart::Log { .. } => {
+        let log_size = self.current_log_content.len() + data.len();
+        let max_log_size = quota::max_archive_log_size();
+        if log_size as u64 > max_log_size {
+          return Err(QuotaError::LogSize { max_log_size }.into());
+        }
+        self.current_log_content.extend(data);
+        return Ok(());
+      }
+      BackupArchivePart::UserKeys { blob_hash, .. }
+      | BackupArchivePart::UserData { blob_hash, .. }
+      | BackupArchivePart::Attachment { blob_hash, .. } => blob_hash,
+    };
+
+    let upload = self.current_upload.get_or_insert_with(|| {
+      trace!(blob_hash, "Starting archive part upload");
+      BlobPartUpload::start(blob_hash.clone(), self.blob_client)
+    });
+    upload.send(data).await;
+    Ok(())
+  }
+
+  async fn handle_part_end(&mut self, index: usize) -> Result<(), BackupError> {
+    let blob_info = match self.current_upload.take() {
+      Some(upload) => Some(upload.finish().await?),
+      None => None,
+    };
+    let blob_revoke = blob_info.clone().map(|revoke_info| {
+      let blob_client = self.blob_client;
+      Defer::new(move || {
+        blob_client
+          .schedule_revoke_holder(revoke_info.blob_hash, revoke_info.holder)
+      })
+    });
+
+    match &self.manifest.parts[index] {
+      BackupArchivePart::UserKeys { .. } => {
+        self.user_keys = blob_info;
+        self.revokes.extend(blob_revoke);
+      }
+      BackupArchivePart::UserData { .. } => {
+        self.user_data = blob_info;
+        self.revokes.extend(blob_revoke);
+      }
+      BackupArchivePart::Attachment { .. } => {
+        self.temporary_holder_revokes.extend(blob_revoke);
+      }
+      BackupArchivePart::Log {
+        log_id,
+        attachments,
+        device_id,
+        uploaded_at,
+        ..
+      } => {
+        let uploaded_at = uploaded_at
+          .as_deref()
+          .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+          .map(|timestamp| timestamp.with_timezone(&Utc))
+          .unwrap_or_else(Utc::now);
+        let mut log_item = LogItem {
+          user_id: self.user_id.clone(),
+          backup_id: self.manifest.backup_id.clone(),
+          log_id: *log_id,
+          device_id: device_id.clone(),
+          content: BlobOrDBContent::new(std::mem::take(
+            &mut self.current_log_content,
+          )),
+          attachments: Vec::new(),
+          uploaded_at,
+          size: self
+            .sizes
+            .log_sizes
+            .get(&index)
+            .copied()
+            .unwrap_or_default(),
+        };
+        log_item.ensure_size_constraints(self.blob_client).await?;
+
+        if let BlobOrDBContent::Blob(content_info) = &log_item.content {
+          let blob_client = self.blob_client;
+          let revoke_info = content_info.clone();
+          self.revokes.push(Defer::new(move || {
+            blob_client
+              .schedule_revoke_holder(revoke_info.blob_hash, revoke_info.holder)
+          }));
+        }
+        self.logs.push((log_item, attachments.clone()));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Adds attachment references and stores backup and log items in DDB.
+  async fn finish(self, db_client: &DatabaseClient) -> Result<(), BackupError> {
+    let Self {
+      user_id,
+      manifest,
+      sizes,
+      blob_client,
+      user_keys,
+      user_data,
+      mut logs,
+      mut revokes,
+      // temporary holders have to be kept until references are added
+      temporary_holder_revokes: _temporary_holder_revokes,
+      ..
+    } = self;
+
+    let Some(user_keys) = user_keys else {
+      return Err(BackupError::BadRequest("invalid_archive"));
+    };
+    let created = parse_creation_timestamp(&manifest)
+      .ok_or(BackupError::BadRequest("invalid_archive"))?;
+
+    let (attachments, attachments_revoke) = db_client
+      .add_attachment_refs(
+        &user_id,
+        manifest.attachments,
+        &sizes.attachment_sizes,
+        blob_client,
+      )
+      .await?;
+    revokes.push(attachments_revoke);
+
+    for (log_item, attachment_hashes) in &mut logs {
+      let (log_attachments, log_attachments_revoke) = db_client
+        .add_attachment_refs(
+          &user_id,
+          std::mem::take(attachment_hashes),
+          &sizes.attachment_sizes,
+          blob_client,
+        )
+        .await?;
+      log_item.attachments = log_attachments;
+      revokes.push(log_attachments_revoke);
+    }
+
+    let mut item = BackupItem::new(
+      user_id.clone(),
+      manifest.backup_id.clone(),
+      user_keys,
+      user_data,
+      attachments,
+      manifest.siwe_backup_msg,
+      manifest.version_info,
+    );
+    item.created = created;
+    item.size = sizes.backup_size;
+    // Stored before logs, so that they're never added
+    // to a backup with the same ID created in the meantime
+    if !db_client.put_new_backup_item(item).await? {
+      warn!("Backup with the same ID was created during import");
+      return Err(BackupError::BackupAlreadyExists);
+    }
+
+    let num_logs = logs.len();
+    for (log_item, _) in logs {
+      if let Err(err) = db_client.put_log_item(log_item, blob_client).await {
+        // blobs and usage are released by revokes
+        db_client
+          .remove_backup_item_only(&user_id, &manifest.backup_id)
+          .await?;
+        return Err(err.into());
+      }
+    }
+
+    for revoke in revokes {
+      revoke.cancel();
+    }
+    // attachments are now counted by their references
+    db_client.schedule_release_usage(&user_id, sizes.attachments_size(), 0);
+
+    info!(num_logs, "Backup archive imported");
+    Ok(())
+  }
+}
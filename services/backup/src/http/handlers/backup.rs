@@ -4,12 +4,9 @@ use actix_web::{
   HttpResponse, Responder,
 };
 use comm_lib::{
-  auth::{AuthorizationCredential, UserIdentity},
+  auth::{AuthService, AuthorizationCredential, UserIdentity},
   backup::{BackupVersionInfo, LatestBackupInfoResponse},
-  blob::{
-    client::BlobServiceClient,
-    types::{http::BlobSizesRequest, BlobInfo},
-  },
+  blob::{client::BlobServiceClient, types::BlobInfo},
   http::{
     auth_service::Authenticated,
     multipart::{
@@ -25,8 +22,10 @@ use tracing::{info, instrument, trace, warn};
 
 use crate::identity::{find_keyserver_device_for_user, find_user_id};
 use crate::{
+  config::CONFIG,
   database::{backup_item::BackupItem, DatabaseClient},
   error::BackupError,
+  quota,
 };
 
 #[instrument(skip_all, fields(backup_id))]
@@ -34,12 +33,14 @@ pub async fn upload(
   user: UserIdentity,
   blob_client: Authenticated<BlobServiceClient>,
   db_client: web::Data<DatabaseClient>,
+  auth_service: comm_lib::auth::AuthService,
   mut multipart: actix_multipart::Multipart,
 ) -> actix_web::Result<HttpResponse> {
   let backup_id = get_named_text_field("backup_id", &mut multipart).await?;
   tracing::Span::current().record("backup_id", &backup_id);
 
   info!("Backup data upload started");
+  db_client.ensure_user_can_upload(&user.user_id).await?;
 
   let (user_keys_blob_info, user_keys_revoke) = multipart
     .forward_field_to_blob(&blob_client, "user_keys_hash", "user_keys")
@@ -49,8 +50,14 @@ pub async fn upload(
     .forward_field_to_blob(&blob_client, "user_data_hash", "user_data")
     .await?;
 
-  let (attachments, attachments_revokes) =
-    multipart.process_attachmens_field(&blob_client).await?;
+  let (attachments, attachments_revoke) = multipart
+    .process_attachmens_field(
+      &db_client,
+      &blob_client,
+      &auth_service,
+      &user.user_id,
+    )
+    .await?;
 
   let aux_data = multipart.get_aux_data().await?;
   let siwe_backup_msg = aux_data.get_siwe_backup_msg()?;
@@ -58,7 +65,7 @@ pub async fn upload(
     .get_backup_version_info()?
     .ok_or(BackupError::BadRequest("missing_version_info"))?;
 
-  let item = BackupItem::new(
+  let mut item = BackupItem::new(
     user.user_id.clone(),
     backup_id,
     user_keys_blob_info,
@@ -68,6 +75,15 @@ pub async fn upload(
     version_info,
   );
 
+  let usage_release = quota::reserve_backup_usage(
+    &mut item,
+    true,
+    &auth_service,
+    &db_client,
+    &blob_client,
+  )
+  .await?;
+
   db_client
     .put_backup_item(item)
     .await
@@ -75,9 +91,8 @@ pub async fn upload(
 
   user_keys_revoke.cancel();
   user_data_revoke.cancel();
-  for attachment_revoke in attachments_revokes {
-    attachment_revoke.cancel();
-  }
+  attachments_revoke.cancel();
+  usage_release.cancel();
 
   db_client
     .remove_old_backups(&user.user_id, &blob_client)
@@ -92,9 +107,11 @@ pub async fn upload_user_keys(
   user: UserIdentity,
   blob_client: Authenticated<BlobServiceClient>,
   db_client: web::Data<DatabaseClient>,
+  auth_service: comm_lib::auth::AuthService,
   multipart: actix_multipart::Multipart,
 ) -> actix_web::Result<HttpResponse> {
-  let (item, revokes) = upload_userkeys_and_create_backup_item(
+  db_client.ensure_user_can_upload(&user.user_id).await?;
+  let (mut item, revokes) = upload_userkeys_and_create_backup_item(
     &db_client,
     &blob_client,
     multipart,
@@ -102,6 +119,17 @@ pub async fn upload_user_keys(
   )
   .await?;
 
+  // User Keys are required to log in, so they're not rejected
+  // because of quota, but still counted
+  let usage_release = quota::reserve_backup_usage(
+    &mut item,
+    false,
+    &auth_service,
+    &db_client,
+    &blob_client,
+  )
+  .await?;
+
   db_client
     .put_backup_item(item)
     .await
@@ -110,6 +138,7 @@ pub async fn upload_user_keys(
   for revoke in revokes {
     revoke.cancel();
   }
+  usage_release.cancel();
 
   db_client
     .remove_old_backups(&user.user_id, &blob_client)
@@ -157,19 +186,27 @@ pub async fn upload_user_data(
   user: UserIdentity,
   blob_client: Authenticated<BlobServiceClient>,
   db_client: web::Data<DatabaseClient>,
+  auth_service: comm_lib::auth::AuthService,
   mut multipart: actix_multipart::Multipart,
 ) -> actix_web::Result<HttpResponse> {
   let backup_id = get_named_text_field("backup_id", &mut multipart).await?;
   tracing::Span::current().record("backup_id", &backup_id);
 
   info!("Backup User Data upload started");
+  db_client.ensure_user_can_upload(&user.user_id).await?;
 
   let (user_data_blob_info, user_data_revoke) = multipart
     .forward_field_to_blob(&blob_client, "user_data_hash", "user_data")
     .await?;
 
-  let (attachments, attachments_revokes) =
-    multipart.process_attachmens_field(&blob_client).await?;
+  let (attachments, attachments_revoke) = multipart
+    .process_attachmens_field(
+      &db_client,
+      &blob_client,
+      &auth_service,
+      &user.user_id,
+    )
+    .await?;
 
   let aux_data = multipart.get_aux_data().await?;
   let version_info = aux_data
@@ -182,7 +219,7 @@ pub async fn upload_user_data(
     .map_err(BackupError::from)?
     .ok_or(BackupError::NoBackup)?;
 
-  let item = BackupItem::new(
+  let mut item = BackupItem::new(
     user.user_id.clone(),
     backup_id,
     existing_backup_item.user_keys.clone(),
@@ -192,17 +229,34 @@ pub async fn upload_user_data(
     version_info,
   );
 
+  let usage_release = quota::reserve_backup_usage(
+    &mut item,
+    true,
+    &auth_service,
+    &db_client,
+    &blob_client,
+  )
+  .await?;
+
+  // usage of the existing item is released when it's replaced
   db_client
     .put_backup_item(item)
     .await
     .map_err(BackupError::from)?;
 
   user_data_revoke.cancel();
-  for attachment_revoke in attachments_revokes {
-    attachment_revoke.cancel();
-  }
+  attachments_revoke.cancel();
+  usage_release.cancel();
 
   existing_backup_item.revoke_user_data_holders(&blob_client);
+  db_client
+    .remove_attachment_refs(
+      &user.user_id,
+      &existing_backup_item.attachments,
+      &blob_client,
+    )
+    .await
+    .map_err(BackupError::from)?;
 
   db_client
     .remove_old_backups(&user.user_id, &blob_client)
@@ -255,12 +309,12 @@ pub async fn get_latest_backup_info(
   path: web::Path<String>,
   db_client: web::Data<DatabaseClient>,
   auth_service: comm_lib::auth::AuthService,
-  blob_client: Authenticated<BlobServiceClient>,
 ) -> actix_web::Result<impl Responder> {
   info!("Latest backup info request.");
 
   let user_identifier = path.into_inner();
   let user_id = find_user_id(&user_identifier).await?;
+  db_client.ensure_user_not_deleted(&user_id).await?;
 
   let Some(backup_item) = db_client
     .find_last_backup_item(&user_id)
@@ -273,23 +327,28 @@ pub async fn get_latest_backup_info(
   let keyserver_device_id =
     find_keyserver_device_for_user(&user_id, &auth_service).await?;
 
-  let total_backup_size = get_total_backup_size(
-    &user_id,
-    &backup_item.backup_id,
-    &auth_service,
-    &db_client,
-    &blob_client,
-  )
-  .await?;
+  let backup_item = db_client
+    .find_backup_item(&user_id, &backup_item.backup_id)
+    .await
+    .map_err(BackupError::from)?
+    .ok_or(BackupError::NoBackup)?;
+
+  // quotas are per-user, so usage of all user backups is returned
+  let usage = db_client
+    .get_user_usage(&user_id)
+    .await
+    .map_err(BackupError::from)?;
 
   let response = LatestBackupInfoResponse {
     backup_id: backup_item.backup_id,
     user_id,
     siwe_backup_msg: backup_item.siwe_backup_msg,
     keyserver_device_id,
-    total_backup_size,
+    total_backup_size: usage.total_size,
     creation_timestamp: backup_item.created.to_rfc3339(),
     version_info: backup_item.version_info,
+    log_count: usage.log_count,
+    quota: CONFIG.backup_quota(),
   };
 
   Ok(web::Json(response))
@@ -305,6 +364,7 @@ pub async fn download_latest_backup_keys(
 
   let user_identifier = path.into_inner();
   let user_id = find_user_id(&user_identifier).await?;
+  db_client.ensure_user_not_deleted(&user_id).await?;
 
   let Some(backup_item) = db_client
     .find_last_backup_item(&user_id)
@@ -363,8 +423,8 @@ async fn upload_userkeys_and_create_backup_item<'revoke, 'blob: 'revoke>(
   let (user_data, attachments, version_info) = match old_backup_item {
     // old clients didn't upload version info so use defaults
     None => (None, Vec::new(), input_version_info.unwrap_or_default()),
-    // If attachments and user_data exists, we need to create holder.
-    // Otherwise, cleanup can remove this data.
+    // If user_data exists, we need to create holder and add references
+    // to attachments. Otherwise, cleanup can remove this data.
     Some(item) => {
       tracing::debug!("Found existing backup item, copying data.");
       let attachments_hashes: Vec<String> = item
@@ -372,14 +432,18 @@ async fn upload_userkeys_and_create_backup_item<'revoke, 'blob: 'revoke>(
         .iter()
         .map(|attachment| attachment.blob_hash.clone())
         .collect();
-      let (attachments, attachments_revokes) =
-        blob_utils::create_holders_for_blob_hashes(
+      // References are shared with the old backup, so attachments
+      // have already been counted towards the user usage
+      let (attachments, attachments_revoke) = db_client
+        .add_attachment_refs(
+          user_id,
           attachments_hashes,
+          &HashMap::new(),
           blob_client,
         )
         .await?;
 
-      revokes.extend(attachments_revokes);
+      revokes.push(attachments_revoke);
 
       let user_data = if let Some(data) = item.user_data {
         let (blob_info, defer) =
@@ -423,52 +487,6 @@ async fn upload_userkeys_and_create_backup_item<'revoke, 'blob: 'revoke>(
   Ok((item, revokes))
 }
 
-#[tracing::instrument(skip_all, fields(backup_id))]
-async fn get_total_backup_size(
-  user_id: &str,
-  backup_id: &str,
-  auth_service: &comm_lib::auth::AuthService,
-  db_client: &DatabaseClient,
-  blob_client: &BlobServiceClient,
-) -> Result<u64, BackupError> {
-  let backup_item = db_client
-    .find_backup_item(user_id, backup_id)
-    .await?
-    .ok_or(BackupError::NoBackup)?;
-
-  // gather blob infos for backup item
-  let mut blob_infos = backup_item.attachments;
-  blob_infos.push(backup_item.user_keys);
-  blob_infos.extend(backup_item.user_data);
-
-  // gather blob infos and DDB size for logs
-  let (log_blob_infos, ddb_logs_size) = db_client
-    .get_blob_infos_and_size_for_logs(user_id, &backup_item.backup_id)
-    .await?;
-  blob_infos.extend(log_blob_infos);
-
-  // we have to re-auth blob client with s2s token because the sizes endpoint is service-only
-  let credential = auth_service.get_services_token().await?;
-  let blob_client = blob_client.with_authentication(credential.into());
-
-  // query Blob Service for blobs size
-  let blob_hashes = blob_infos.into_iter().map(|it| it.blob_hash).collect();
-  let total_blobs_size = blob_client
-    .fetch_blob_sizes(BlobSizesRequest { blob_hashes })
-    .await?
-    .total_size();
-
-  let total_backup_size = total_blobs_size + ddb_logs_size;
-  tracing::debug!(
-    total_blobs_size,
-    ddb_logs_size,
-    total_backup_size,
-    "Calculated backup size."
-  );
-
-  Ok(total_backup_size)
-}
-
 trait BackupRequestInput {
   /// Consumes two next fields: blob hash and blob data
   /// and uploads content to Blob Service, returning a BlobInfo
@@ -483,14 +501,17 @@ trait BackupRequestInput {
   ) -> actix_web::Result<(BlobInfo, Defer<'revoke>)>;
 
   /// Consumes a text field named `attachments`.
-  /// For each attachment hash, establishes a new blob holder.
-  /// Returns BlobInfos and a revoke handle for removing the holder
-  /// in case of further failure. All revokes have to be later
-  /// canceled.
+  /// For each attachment hash, adds a reference to a blob holder
+  /// shared by all user's backups. Returns BlobInfos and a revoke handle
+  /// for removing the references in case of further failure.
+  /// The revoke has to be later canceled.
   async fn process_attachmens_field<'revoke, 'blob: 'revoke>(
     &mut self,
+    db_client: &DatabaseClient,
     blob_client: &'blob BlobServiceClient,
-  ) -> Result<(Vec<BlobInfo>, Vec<Defer<'revoke>>), BackupError>;
+    auth_service: &AuthService,
+    user_id: &str,
+  ) -> Result<(Vec<BlobInfo>, Defer<'revoke>), BackupError>;
 
   /// Consumes all remaining fields and stores them in [`BackupAuxFields`]
   async fn get_aux_data(&mut self) -> actix_web::Result<BackupAuxFields>;
@@ -571,8 +592,11 @@ impl BackupRequestInput for actix_multipart::Multipart {
   #[instrument(skip_all)]
   async fn process_attachmens_field<'revoke, 'blob: 'revoke>(
     &mut self,
+    db_client: &DatabaseClient,
     blob_client: &'blob BlobServiceClient,
-  ) -> Result<(Vec<BlobInfo>, Vec<Defer<'revoke>>), BackupError> {
+    auth_service: &AuthService,
+    user_id: &str,
+  ) -> Result<(Vec<BlobInfo>, Defer<'revoke>), BackupError> {
     let attachments_hashes: Vec<String> = match get_text_field(self).await {
       Ok(Some((name, attachments))) => {
         if name != "attachments" {
@@ -589,7 +613,19 @@ impl BackupRequestInput for actix_multipart::Multipart {
       Err(_) => return Err(BackupError::BadRequest("multipart_error")),
     };
 
-    blob_utils::create_holders_for_blob_hashes(attachments_hashes, blob_client)
+    let blob_sizes = quota::fetch_blob_sizes(
+      attachments_hashes.clone(),
+      auth_service,
+      blob_client,
+    )
+    .await?;
+    db_client
+      .add_attachment_refs(
+        user_id,
+        attachments_hashes,
+        &blob_sizes,
+        blob_client,
+      )
       .await
   }
 
@@ -633,6 +669,7 @@ mod blob_utils {
     blob_client: BlobServiceClient,
     db_client: web::Data<DatabaseClient>,
   ) -> actix_web::Result<HttpResponse> {
+    db_client.ensure_user_not_deleted(user_id).await?;
     let backup_item = db_client
       .find_backup_item(user_id, backup_id)
       .await
@@ -653,22 +690,6 @@ mod blob_utils {
     )
   }
 
-  pub async fn create_holders_for_blob_hashes<'revoke, 'blob: 'revoke>(
-    hashes: Vec<String>,
-    blob_client: &'blob BlobServiceClient,
-  ) -> Result<(Vec<BlobInfo>, Vec<Defer<'revoke>>), BackupError> {
-    let mut blob_infos = Vec::new();
-    let mut revokes = Vec::new();
-    for hash in hashes {
-      let (blob_info, revoke) =
-        create_holder_for_hash(&hash, blob_client).await?;
-      blob_infos.push(blob_info);
-      revokes.push(revoke);
-    }
-
-    Ok((blob_infos, revokes))
-  }
-
   #[instrument(skip_all)]
   pub async fn create_holder_for_hash<'revoke, 'blob: 'revoke>(
     hash: &str,
@@ -1,6 +1,8 @@
 use crate::config::CONFIG;
 use crate::constants::error_types;
 use crate::database::{log_item::LogItem, DatabaseClient};
+use crate::error::BackupError;
+use crate::quota;
 use actix::fut::ready;
 use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, StreamHandler};
 use actix_http::ws::{CloseCode, Item};
@@ -9,10 +11,13 @@ use actix_web::{
   Error, HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws::{self, WebsocketContext};
+use chrono::Utc;
 use comm_lib::auth::{AuthService, AuthServiceError, UserIdentity};
 use comm_lib::{
   backup::{
-    DownloadLogsRequest, LogWSRequest, LogWSResponse, UploadLogRequest,
+    log_ws_capabilities, DownloadDeviceLogsRequest, DownloadLogsRequest,
+    LogWSHandshake, LogWSRequest, LogWSRequestError, LogWSResponse,
+    UploadLogRequest, LOG_WS_MIN_PROTOCOL_VERSION, LOG_WS_PROTOCOL_VERSION,
   },
   blob::{
     client::{BlobServiceClient, BlobServiceError},
@@ -40,6 +45,7 @@ pub async fn handle_ws(
       auth_service,
       last_msg_time: Instant::now(),
       buffer: BytesMut::new(),
+      handshake: None,
     },
     &req,
     stream,
@@ -56,6 +62,7 @@ enum LogWSError {
   Blob(BlobServiceError),
   DB(database::Error),
   Auth(AuthServiceError),
+  Backup(BackupError),
 }
 
 struct LogWSActor {
@@ -65,6 +72,8 @@ struct LogWSActor {
   auth_service: AuthService,
   last_msg_time: Instant,
   buffer: BytesMut,
+  /// Negotiated protocol. `None` for clients that didn't send a handshake
+  handshake: Option<LogWSHandshake>,
 }
 
 impl LogWSActor {
@@ -77,6 +86,10 @@ impl LogWSActor {
     bytes: Bytes,
   ) {
     match bincode::deserialize(&bytes) {
+      Ok(LogWSRequest::Handshake(handshake)) => {
+        let response = self.handle_handshake(handshake);
+        Self::spawn_response_future(ctx, ready(Ok(vec![response])));
+      }
       Ok(request) => {
         if let LogWSRequest::Authenticate(user) = request {
           Self::spawn_response_future(
@@ -103,8 +116,10 @@ impl LogWSActor {
           ctx,
           Self::handle_msg(
             user.user_id.clone(),
+            user.device_id.clone(),
             self.blob_client.clone().with_user_identity(user.clone()),
             self.db_client.clone(),
+            self.auth_service.clone(),
             request,
           ),
         );
@@ -112,12 +127,60 @@ impl LogWSActor {
       Err(err) => {
         error!(errorType = error_types::WS_ERROR, "Error: {err:?}");
 
-        Self::spawn_response_future(
-          ctx,
-          ready(Ok(vec![LogWSResponse::ServerError])),
-        );
+        let response = self.invalid_request_response(&bytes);
+        Self::spawn_response_future(ctx, ready(Ok(vec![response])));
+      }
+    };
+  }
+
+  fn handle_handshake(&mut self, client: LogWSHandshake) -> LogWSResponse {
+    if client.protocol_version < LOG_WS_MIN_PROTOCOL_VERSION {
+      warn!(
+        "Unsupported log socket protocol version: {}",
+        client.protocol_version
+      );
+      return LogWSResponse::RequestError(
+        LogWSRequestError::UnsupportedProtocolVersion {
+          min_supported_version: LOG_WS_MIN_PROTOCOL_VERSION,
+        },
+      );
+    }
+
+    let capabilities = log_ws_capabilities::ALL
+      .iter()
+      .filter(|capability| client.has_capability(capability))
+      .map(|capability| capability.to_string())
+      .collect();
+    let handshake = LogWSHandshake {
+      protocol_version: client.protocol_version.min(LOG_WS_PROTOCOL_VERSION),
+      capabilities,
+    };
+    info!("Log socket handshake: {handshake:?}");
+
+    self.handshake = Some(handshake.clone());
+    LogWSResponse::HandshakeAccepted(handshake)
+  }
+
+  /// Clients that didn't negotiate typed errors
+  /// only understand [`LogWSResponse::ServerError`]
+  fn typed_errors(&self) -> bool {
+    self.handshake.as_ref().is_some_and(|handshake| {
+      handshake.has_capability(log_ws_capabilities::TYPED_ERRORS)
+    })
+  }
+
+  fn invalid_request_response(&self, bytes: &[u8]) -> LogWSResponse {
+    if !self.typed_errors() {
+      return LogWSResponse::ServerError;
+    }
+
+    let error = match LogWSRequest::variant_index(bytes) {
+      Some(variant_index) if variant_index >= LogWSRequest::VARIANT_COUNT => {
+        LogWSRequestError::UnknownRequest { variant_index }
       }
+      _ => LogWSRequestError::MalformedRequest,
     };
+    LogWSResponse::RequestError(error)
   }
 
   fn spawn_response_future(
@@ -126,10 +189,24 @@ impl LogWSActor {
   ) {
     let fut = actix::fut::wrap_future(future).map(
       |responses,
-       _: &mut LogWSActor,
+       actor: &mut LogWSActor,
        ctx: &mut WebsocketContext<LogWSActor>| {
         let responses = match responses {
           Ok(responses) => responses,
+          Err(LogWSError::Backup(BackupError::QuotaExceeded(err)))
+            if actor.typed_errors() =>
+          {
+            warn!("Backup quota exceeded: {err:?}");
+            vec![LogWSResponse::RequestError(err.into())]
+          }
+          Err(LogWSError::Backup(BackupError::UserDataDeleted))
+            if actor.typed_errors() =>
+          {
+            warn!("Rejecting request of tombstoned user");
+            vec![LogWSResponse::RequestError(
+              LogWSRequestError::UserDataDeleted,
+            )]
+          }
           Err(err) => {
             error!(errorType = error_types::WS_ERROR, "Error: {err:?}");
             vec![LogWSResponse::ServerError]
@@ -173,43 +250,79 @@ impl LogWSActor {
 
   async fn handle_msg(
     user_id: String,
+    device_id: String,
     blob_client: BlobServiceClient,
     db_client: DatabaseClient,
+    auth_service: AuthService,
     request: LogWSRequest,
   ) -> Result<Vec<LogWSResponse>, LogWSError> {
     match request {
-      LogWSRequest::UploadLog(UploadLogRequest {
+      LogWSRequest::UploadLog(request) => {
+        let response = Self::upload_log(
+          user_id,
+          None,
+          request,
+          &blob_client,
+          &db_client,
+          &auth_service,
+        )
+        .await?;
+        Ok(vec![response])
+      }
+      LogWSRequest::UploadDeviceLog(request) => {
+        // device is taken from the authenticated identity so that
+        // a device can't write into another device's stream
+        let response = Self::upload_log(
+          user_id,
+          Some(device_id),
+          request,
+          &blob_client,
+          &db_client,
+          &auth_service,
+        )
+        .await?;
+        Ok(vec![response])
+      }
+      LogWSRequest::DownloadDeviceLogs(DownloadDeviceLogsRequest {
         backup_id,
-        log_id,
-        content,
-        attachments,
+        from,
       }) => {
-        let mut attachment_blob_infos = Vec::new();
+        if db_client.find_user_tombstone(&user_id).await?.is_some() {
+          return Ok(vec![LogWSResponse::DeviceLogDownloadFinished {
+            last_cursor: None,
+          }]);
+        }
 
-        for attachment in attachments.unwrap_or_default() {
-          let blob_info =
-            Self::create_attachment(&blob_client, attachment).await?;
+        let (log_items, last_cursor) = db_client
+          .fetch_merged_log_items(&user_id, &backup_id, from.as_ref())
+          .await?;
 
-          attachment_blob_infos.push(blob_info);
+        let mut messages = vec![];
+        for log_item in log_items {
+          let content = log_item.content.fetch_bytes(&blob_client).await?;
+          messages.push(LogWSResponse::DeviceLogDownload {
+            device_id: log_item.device_id,
+            log_id: log_item.log_id,
+            content,
+            attachments: attachment_hashes(log_item.attachments),
+          })
         }
 
-        let mut log_item = LogItem {
-          user_id,
-          backup_id: backup_id.clone(),
-          log_id,
-          content: BlobOrDBContent::new(content),
-          attachments: attachment_blob_infos,
-        };
+        messages.push(LogWSResponse::DeviceLogDownloadFinished { last_cursor });
 
-        log_item.ensure_size_constraints(&blob_client).await?;
-        db_client.put_log_item(log_item, &blob_client).await?;
-
-        Ok(vec![LogWSResponse::LogUploaded { backup_id, log_id }])
+        Ok(messages)
       }
       LogWSRequest::DownloadLogs(DownloadLogsRequest {
         backup_id,
         from_id,
       }) => {
+        // logs of tombstoned backups are hidden
+        if db_client.find_user_tombstone(&user_id).await?.is_some() {
+          return Ok(vec![LogWSResponse::LogDownloadFinished {
+            last_log_id: None,
+          }]);
+        }
+
         let (log_items, last_id) = db_client
           .fetch_log_items(&user_id, &backup_id, from_id)
           .await?;
@@ -224,17 +337,10 @@ impl LogWSActor {
         } in log_items
         {
           let content = content.fetch_bytes(&blob_client).await?;
-          let attachments: Vec<String> =
-            attachments.into_iter().map(|att| att.blob_hash).collect();
-          let attachments = if attachments.is_empty() {
-            None
-          } else {
-            Some(attachments)
-          };
           messages.push(LogWSResponse::LogDownload {
             log_id,
             content,
-            attachments,
+            attachments: attachment_hashes(attachments),
           })
         }
 
@@ -248,30 +354,66 @@ impl LogWSActor {
         warn!("LogWSRequest::Authenticate should have been handled earlier.");
         Ok(Vec::new())
       }
+      LogWSRequest::Handshake(_) => {
+        warn!("LogWSRequest::Handshake should have been handled earlier.");
+        Ok(Vec::new())
+      }
     }
   }
 
-  async fn create_attachment(
+  /// Stores a log in the primary stream, or in the device stream
+  /// if `device_id` is given
+  async fn upload_log(
+    user_id: String,
+    device_id: Option<String>,
+    UploadLogRequest {
+      backup_id,
+      log_id,
+      content,
+      attachments,
+    }: UploadLogRequest,
     blob_client: &BlobServiceClient,
-    attachment: String,
-  ) -> Result<BlobInfo, BlobServiceError> {
-    let blob_info = BlobInfo {
-      blob_hash: attachment,
-      holder: uuid::Uuid::new_v4().to_string(),
+    db_client: &DatabaseClient,
+    auth_service: &AuthService,
+  ) -> Result<LogWSResponse, LogWSError> {
+    db_client.ensure_user_can_upload(&user_id).await?;
+    let content_size = content.len() as u64;
+    let attachments = attachments.unwrap_or_default();
+    let blob_sizes =
+      quota::fetch_blob_sizes(attachments.clone(), auth_service, blob_client)
+        .await?;
+    let (attachment_blob_infos, attachments_revoke) = db_client
+      .add_attachment_refs(&user_id, attachments, &blob_sizes, blob_client)
+      .await?;
+
+    let mut log_item = LogItem {
+      user_id,
+      backup_id: backup_id.clone(),
+      log_id,
+      device_id,
+      content: BlobOrDBContent::new(content),
+      attachments: attachment_blob_infos,
+      uploaded_at: Utc::now(),
+      size: 0,
     };
 
-    if !blob_client
-      .assign_holder(&blob_info.blob_hash, &blob_info.holder)
-      .await?
-    {
-      warn!(
-        "Blob attachment with hash {:?} doesn't exist",
-        blob_info.blob_hash
-      );
-    }
+    let usage_release =
+      quota::reserve_log_usage(&mut log_item, content_size, db_client).await?;
+
+    log_item.ensure_size_constraints(blob_client).await?;
+    db_client.put_log_item(log_item, blob_client).await?;
+    attachments_revoke.cancel();
+    usage_release.cancel();
+
+    Ok(LogWSResponse::LogUploaded { backup_id, log_id })
+  }
+}
 
-    Ok(blob_info)
+fn attachment_hashes(attachments: Vec<BlobInfo>) -> Option<Vec<String>> {
+  if attachments.is_empty() {
+    return None;
   }
+  Some(attachments.into_iter().map(|att| att.blob_hash).collect())
 }
 
 impl Actor for LogWSActor {
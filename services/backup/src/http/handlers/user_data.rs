@@ -1,5 +1,5 @@
 use actix_web::{
-  error::ErrorForbidden,
+  error::{ErrorForbidden, ErrorNotFound},
   web::{self},
   HttpResponse,
 };
@@ -7,14 +7,24 @@ use comm_lib::{
   auth::AuthorizationCredential, blob::client::BlobServiceClient,
   http::auth_service::Authenticated,
 };
+use serde::Deserialize;
 use tracing::{info, instrument};
 
-use crate::{database::DatabaseClient, error::BackupError};
+use crate::{config::CONFIG, database::DatabaseClient, error::BackupError};
 
-#[instrument(skip_all, fields(backup_id = %path))]
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserDataQuery {
+  /// Whether the data can be restored during the configured grace period,
+  /// e.g. when the account is deleted. Otherwise it's removed immediately.
+  #[serde(default)]
+  restorable: bool,
+}
+
+#[instrument(skip_all, fields(user_id = %path))]
 pub async fn delete_user_data(
   requesting_identity: AuthorizationCredential,
   path: web::Path<String>,
+  query: web::Query<DeleteUserDataQuery>,
   db_client: web::Data<DatabaseClient>,
   blob_client: Authenticated<BlobServiceClient>,
 ) -> actix_web::Result<HttpResponse> {
@@ -27,12 +37,46 @@ pub async fn delete_user_data(
     }
   };
 
-  info!("Delete user data request");
+  info!(restorable = query.restorable, "Delete user data request");
   let user_id = path.into_inner();
+  let grace_period =
+    (query.restorable && CONFIG.user_data_grace_period_secs > 0).then(|| {
+      chrono::Duration::seconds(CONFIG.user_data_grace_period_secs as i64)
+    });
   db_client
-    .delete_user_data(&user_id, &blob_client)
+    .delete_user_data(&user_id, grace_period, &blob_client)
     .await
     .map_err(BackupError::from)?;
 
   Ok(HttpResponse::NoContent().finish())
 }
+
+/// Restores user data deleted during the configured grace period
+#[instrument(skip_all, fields(user_id = %path))]
+pub async fn restore_user_data(
+  requesting_identity: AuthorizationCredential,
+  path: web::Path<String>,
+  db_client: web::Data<DatabaseClient>,
+) -> actix_web::Result<HttpResponse> {
+  match requesting_identity {
+    AuthorizationCredential::ServicesToken(_) => (),
+    _ => {
+      return Err(ErrorForbidden(
+        "This endpoint can only be called by other services",
+      ));
+    }
+  };
+
+  info!("Restore user data request");
+  let user_id = path.into_inner();
+  let Some(tombstone) = db_client
+    .restore_user_data(&user_id)
+    .await
+    .map_err(BackupError::from)?
+  else {
+    return Err(ErrorNotFound("no_deleted_user_data"));
+  };
+
+  info!(deleted_at = %tombstone.deleted_at, "User data restored");
+  Ok(HttpResponse::NoContent().finish())
+}
@@ -9,6 +9,7 @@ use tracing::info;
 use crate::{database::DatabaseClient, http::handlers::log::handle_ws, CONFIG};
 
 mod handlers {
+  pub(super) mod archive;
   pub(super) mod backup;
   pub(super) mod log;
   pub(super) mod user_data;
@@ -74,6 +75,12 @@ pub async fn run_http_server(
             web::resource("/user_data")
               .route(web::post().to(handlers::backup::upload_user_data)),
           )
+          // Recreates a backup and its logs from an archive
+          // produced by the export endpoint.
+          .service(
+            web::resource("/import")
+              .route(web::post().to(handlers::archive::import_backup)),
+          )
           .service(
             web::resource("{backup_id}/user_keys")
               .route(web::get().to(handlers::backup::download_user_keys)),
@@ -81,6 +88,11 @@ pub async fn run_http_server(
           .service(
             web::resource("{backup_id}/user_data")
               .route(web::get().to(handlers::backup::download_user_data)),
+          )
+          // Streams a self-contained archive with all backup data and logs
+          .service(
+            web::resource("{backup_id}/export")
+              .route(web::get().to(handlers::archive::export_backup)),
           ),
       )
       .service(
@@ -93,6 +105,11 @@ pub async fn run_http_server(
           .service(
             web::resource("{user_id}")
               .route(web::delete().to(handlers::user_data::delete_user_data)),
+          )
+          // Restores user data deleted within the grace period
+          .service(
+            web::resource("{user_id}/restore")
+              .route(web::post().to(handlers::user_data::restore_user_data)),
           ),
       )
       // Called by Identity Service during restore protocol to upload and store
@@ -11,6 +11,8 @@ pub mod database;
 pub mod error;
 pub mod http;
 pub mod identity;
+pub mod quota;
+pub mod sweeper;
 
 // re-export this to be available as crate::CONFIG
 pub use config::CONFIG;
@@ -50,6 +52,11 @@ async fn main() -> Result<()> {
   let blob_client = BlobServiceClient::new(CONFIG.blob_service_url.clone());
   let auth_service = AuthService::new(&aws_config, &CONFIG.identity_endpoint);
 
+  tokio::spawn(sweeper::run_user_data_sweeper(
+    db_client.clone(),
+    blob_client.clone(),
+    auth_service.clone(),
+  ));
   http::run_http_server(db_client, blob_client, auth_service).await?;
 
   Ok(())
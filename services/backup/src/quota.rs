@@ -0,0 +1,332 @@
+use comm_lib::{
+  auth::AuthService,
+  backup::{
+    archive::{BackupArchiveManifest, BackupArchivePart},
+    LogWSRequestError,
+  },
+  blob::{
+    client::BlobServiceClient,
+    types::{http::BlobSizesRequest, BlobInfo},
+  },
+  tools::Defer,
+};
+use std::collections::HashMap;
+use tracing::instrument;
+
+use crate::{
+  config::CONFIG,
+  constants::{MAX_ARCHIVE_LOG_SIZE, MAX_ARCHIVE_SIZE},
+  database::{
+    backup_item::BackupItem, log_item::LogItem, user_usage::UsageReservation,
+    DatabaseClient,
+  },
+  error::BackupError,
+};
+
+#[derive(Debug, Clone, Copy, derive_more::Display, derive_more::Error)]
+pub enum QuotaError {
+  #[display(fmt = "backup_size_quota_exceeded")]
+  BackupSize { max_backup_size: u64 },
+  #[display(fmt = "log_count_quota_exceeded")]
+  LogCount { max_log_count: u64 },
+  #[display(fmt = "log_size_quota_exceeded")]
+  LogSize { max_log_size: u64 },
+}
+
+impl From<QuotaError> for LogWSRequestError {
+  fn from(value: QuotaError) -> Self {
+    match value {
+      QuotaError::BackupSize { max_backup_size } => {
+        LogWSRequestError::BackupSizeQuotaExceeded { max_backup_size }
+      }
+      QuotaError::LogCount { max_log_count } => {
+        LogWSRequestError::LogCountQuotaExceeded { max_log_count }
+      }
+      QuotaError::LogSize { max_log_size } => {
+        LogWSRequestError::LogSizeQuotaExceeded { max_log_size }
+      }
+    }
+  }
+}
+
+/// Returns sizes of given blobs. Blobs that don't exist are omitted.
+#[instrument(skip_all)]
+pub async fn fetch_blob_sizes(
+  blob_hashes: Vec<String>,
+  auth_service: &AuthService,
+  blob_client: &BlobServiceClient,
+) -> Result<HashMap<String, u64>, BackupError> {
+  if blob_hashes.is_empty() {
+    return Ok(HashMap::new());
+  }
+
+  // we have to re-auth blob client with s2s token because the sizes endpoint is service-only
+  let credential = auth_service.get_services_token().await?;
+  let blob_client = blob_client.with_authentication(credential.into());
+
+  let blob_sizes = blob_client
+    .fetch_blob_sizes(BlobSizesRequest { blob_hashes })
+    .await?
+    .blob_sizes;
+  Ok(blob_sizes)
+}
+
+/// Returns total size of given blobs
+async fn fetch_blobs_size<'a>(
+  blob_infos: impl IntoIterator<Item = &'a BlobInfo>,
+  auth_service: &AuthService,
+  blob_client: &BlobServiceClient,
+) -> Result<u64, BackupError> {
+  let blob_hashes: Vec<String> = blob_infos
+    .into_iter()
+    .map(|blob_info| blob_info.blob_hash.clone())
+    .collect();
+  let blob_sizes =
+    fetch_blob_sizes(blob_hashes.clone(), auth_service, blob_client).await?;
+
+  let total_size = blob_hashes
+    .iter()
+    .filter_map(|blob_hash| blob_sizes.get(blob_hash))
+    .sum();
+  Ok(total_size)
+}
+
+/// Adds `size` bytes and `log_count` logs to the user usage.
+/// If `enforce_quota` is set, fails when usage would exceed
+/// the configured limits.
+async fn reserve_usage(
+  user_id: &str,
+  size: u64,
+  log_count: u64,
+  enforce_quota: bool,
+  db_client: &DatabaseClient,
+) -> Result<Defer<'static>, BackupError> {
+  let (max_backup_size, max_log_count) = match enforce_quota {
+    true => (CONFIG.max_backup_size, CONFIG.max_log_count),
+    false => (None, None),
+  };
+
+  let reservation = db_client
+    .reserve_usage(user_id, size, log_count, max_backup_size, max_log_count)
+    .await?;
+  let usage = match reservation {
+    UsageReservation::Reserved(release) => return Ok(release),
+    UsageReservation::LimitExceeded(usage) => usage,
+  };
+
+  tracing::debug!(?usage, size, log_count, "User usage quota exceeded.");
+  if let Some(max_log_count) = max_log_count {
+    if usage.log_count.saturating_add(log_count) > max_log_count {
+      return Err(QuotaError::LogCount { max_log_count }.into());
+    }
+  }
+  let max_backup_size = max_backup_size.unwrap_or_default();
+  Err(QuotaError::BackupSize { max_backup_size }.into())
+}
+
+/// Calculates size of the backup item and counts it towards the user
+/// usage. Fails if the size quota would be exceeded, unless
+/// `enforce_quota` is unset, e.g. for User Keys which are required
+/// to log in. Returns a [`Defer`] that releases the usage unless
+/// canceled, which should be done once the item is stored.
+///
+/// Attachments aren't included, they're counted once for all backups
+/// and logs referencing them when their references are added.
+/// See [`DatabaseClient::add_attachment_refs`].
+#[must_use = "Usage will be released unless returned revoke is canceled"]
+pub async fn reserve_backup_usage(
+  backup_item: &mut BackupItem,
+  enforce_quota: bool,
+  auth_service: &AuthService,
+  db_client: &DatabaseClient,
+  blob_client: &BlobServiceClient,
+) -> Result<Defer<'static>, BackupError> {
+  let blob_infos =
+    std::iter::once(&backup_item.user_keys).chain(&backup_item.user_data);
+  let size = fetch_blobs_size(blob_infos, auth_service, blob_client).await?;
+  backup_item.size = size;
+
+  reserve_usage(
+    &backup_item.user_id,
+    backup_item.size,
+    0,
+    enforce_quota,
+    db_client,
+  )
+  .await
+}
+
+/// Checks the log size quota and counts the log towards the user usage.
+/// Like for backups, attachments are counted by their references.
+/// Uploading a log with an existing ID replaces it, but the old one
+/// is released only after the new one is stored. Returns a [`Defer`]
+/// that releases the usage unless canceled.
+#[must_use = "Usage will be released unless returned revoke is canceled"]
+pub async fn reserve_log_usage(
+  log_item: &mut LogItem,
+  content_size: u64,
+  db_client: &DatabaseClient,
+) -> Result<Defer<'static>, BackupError> {
+  if let Some(max_log_size) = CONFIG.max_log_size {
+    if content_size > max_log_size {
+      return Err(QuotaError::LogSize { max_log_size }.into());
+    }
+  }
+
+  log_item.size = content_size;
+  reserve_usage(&log_item.user_id, log_item.size, 1, true, db_client).await
+}
+
+/// Sizes of items recreated from a backup archive,
+/// calculated from part sizes declared in the manifest
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchiveSizes {
+  /// Size of User Keys and User Data
+  pub backup_size: u64,
+  /// Log sizes by their part index
+  pub log_sizes: HashMap<usize, u64>,
+  /// Sizes of all attachments by their blob hashes. Each attachment
+  /// is counted once, no matter how many items reference it.
+  pub attachment_sizes: HashMap<String, u64>,
+}
+
+impl ArchiveSizes {
+  pub fn from_manifest(
+    manifest: &BackupArchiveManifest,
+  ) -> Result<Self, QuotaError> {
+    let mut sizes = ArchiveSizes::default();
+    for (index, part) in manifest.parts.iter().enumerate() {
+      match part {
+        BackupArchivePart::UserKeys { size, .. }
+        | BackupArchivePart::UserData { size, .. } => {
+          sizes.backup_size = sizes.backup_size.saturating_add(*size);
+        }
+        BackupArchivePart::Attachment { blob_hash, size } => {
+          sizes.attachment_sizes.insert(blob_hash.clone(), *size);
+        }
+        BackupArchivePart::Log { size, .. } => {
+          let max_log_size = max_archive_log_size();
+          if *size > max_log_size {
+            return Err(QuotaError::LogSize { max_log_size });
+          }
+          sizes.log_sizes.insert(index, *size);
+        }
+      }
+    }
+    Ok(sizes)
+  }
+
+  pub fn attachments_size(&self) -> u64 {
+    self
+      .attachment_sizes
+      .values()
+      .fold(0, |total, size| total.saturating_add(*size))
+  }
+
+  fn total_size(&self) -> u64 {
+    self
+      .log_sizes
+      .values()
+      .fold(self.backup_size, |total, size| total.saturating_add(*size))
+      .saturating_add(self.attachments_size())
+  }
+}
+
+/// Calculates sizes of archive items and counts them towards the user
+/// usage. Part sizes come from the manifest, the archive decoder ensures
+/// that parts don't contain more data than declared. Attachments are
+/// reserved too, so that the whole archive is within quota. Adding
+/// attachment references counts new attachments again, so then
+/// [`ArchiveSizes::attachments_size`] should be released. Returns
+/// a [`Defer`] that releases the usage unless canceled.
+#[must_use = "Usage will be released unless returned revoke is canceled"]
+pub async fn reserve_archive_usage(
+  user_id: &str,
+  manifest: &BackupArchiveManifest,
+  db_client: &DatabaseClient,
+) -> Result<(ArchiveSizes, Defer<'static>), BackupError> {
+  let sizes = ArchiveSizes::from_manifest(manifest)?;
+
+  // archive content is uploaded before the usage is stored,
+  // so it's limited even if there is no size quota
+  if CONFIG.max_backup_size.is_none() && sizes.total_size() > MAX_ARCHIVE_SIZE {
+    return Err(
+      QuotaError::BackupSize {
+        max_backup_size: MAX_ARCHIVE_SIZE,
+      }
+      .into(),
+    );
+  }
+
+  let release = reserve_usage(
+    user_id,
+    sizes.total_size(),
+    sizes.log_sizes.len() as u64,
+    true,
+    db_client,
+  )
+  .await?;
+  Ok((sizes, release))
+}
+
+/// Log content is buffered in memory during archive import,
+/// so it's limited even if there is no log size quota.
+pub fn max_archive_log_size() -> u64 {
+  CONFIG
+    .max_log_size
+    .map_or(MAX_ARCHIVE_LOG_SIZE, |size| size.min(MAX_ARCHIVE_LOG_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use comm_lib::backup::archive::ARCHIVE_FORMAT_VERSION;
+
+  fn log_part(attachments: &[&str]) -> BackupArchivePart {
+    BackupArchivePart::Log {
+      log_id: 1,
+      size: 10,
+      attachments: attachments.iter().map(ToString::to_string).collect(),
+      device_id: None,
+      uploaded_at: None,
+    }
+  }
+
+  #[test]
+  fn test_archive_attachments_are_counted_once() {
+    let manifest = BackupArchiveManifest {
+      format_version: ARCHIVE_FORMAT_VERSION,
+      backup_id: "backup".to_string(),
+      creation_timestamp: "2025-05-13T11:20:33Z".to_string(),
+      siwe_backup_msg: None,
+      version_info: Default::default(),
+      attachments: vec!["shared".to_string()],
+      parts: vec![
+        BackupArchivePart::UserKeys {
+          blob_hash: "keys".to_string(),
+          size: 1,
+        },
+        BackupArchivePart::UserData {
+          blob_hash: "data".to_string(),
+          size: 2,
+        },
+        BackupArchivePart::Attachment {
+          blob_hash: "shared".to_string(),
+          size: 100,
+        },
+        BackupArchivePart::Attachment {
+          blob_hash: "other".to_string(),
+          size: 50,
+        },
+        log_part(&["shared", "other"]),
+        log_part(&["shared"]),
+      ],
+    };
+
+    let sizes = ArchiveSizes::from_manifest(&manifest).unwrap();
+    assert_eq!(sizes.backup_size, 3);
+    assert_eq!(sizes.log_sizes, HashMap::from([(4, 10), (5, 10)]));
+    assert_eq!(sizes.attachments_size(), 150);
+    assert_eq!(sizes.total_size(), 173);
+  }
+}
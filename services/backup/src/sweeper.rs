@@ -0,0 +1,57 @@
+use comm_lib::{auth::AuthService, blob::client::BlobServiceClient};
+use tracing::{info, instrument, warn};
+
+use crate::{
+  constants::USER_DATA_SWEEP_INTERVAL, database::DatabaseClient,
+  error::BackupError,
+};
+
+/// Periodically purges tombstoned user data whose grace period has ended
+pub async fn run_user_data_sweeper(
+  db_client: DatabaseClient,
+  blob_client: BlobServiceClient,
+  auth_service: AuthService,
+) {
+  let mut interval = tokio::time::interval(USER_DATA_SWEEP_INTERVAL);
+  loop {
+    interval.tick().await;
+    if let Err(err) =
+      purge_expired_user_data(&db_client, &blob_client, &auth_service).await
+    {
+      warn!("Failed to purge deleted user data: {0:?} - {0}", err);
+    }
+  }
+}
+
+#[instrument(skip_all)]
+async fn purge_expired_user_data(
+  db_client: &DatabaseClient,
+  blob_client: &BlobServiceClient,
+  auth_service: &AuthService,
+) -> Result<(), BackupError> {
+  let tombstones = db_client.find_expired_user_tombstones().await?;
+  if tombstones.is_empty() {
+    return Ok(());
+  }
+
+  // revoking holders requires service authentication
+  let credential = auth_service.get_services_token().await?;
+  let blob_client = blob_client.with_authentication(credential.into());
+
+  for tombstone in tombstones {
+    let user_id = &tombstone.user_id;
+    // restored or being purged by another instance
+    if !db_client.start_user_data_purge(user_id).await? {
+      continue;
+    }
+
+    info!(user_id, deleted_at = %tombstone.deleted_at, "Purging user data");
+    // backups created after deletion can't be tombstoned ones
+    db_client
+      .purge_user_data(user_id, tombstone.deleted_at, &blob_client)
+      .await?;
+    db_client.remove_user_tombstone(user_id).await?;
+  }
+
+  Ok(())
+}
@@ -558,6 +558,8 @@ async fn get_device_list_history(
   let request = GetDeviceListRequest {
     user_id: user_id.to_string(),
     since_timestamp: None,
+    known_log_size: None,
+    include_log_proof: false,
   };
 
   let response = client
@@ -5,7 +5,6 @@ use comm_lib::auth::AuthService;
 use comm_lib::backup::database::BackupItem;
 use comm_lib::blob::client::BlobServiceClient;
 // External crate imports
-use comm_lib::shared::reserved_users::RESERVED_USERNAME_SET;
 use comm_lib::tools::Defer;
 use comm_opaque2::grpc::protocol_error_to_grpc_status;
 use grpc_clients::identity::PlatformMetadata;
@@ -19,14 +18,17 @@ use crate::comm_service::tunnelbroker;
 // Workspace crate imports
 use crate::config::CONFIG;
 use crate::constants::staff::AUTHORITATIVE_KEYSERVER_OWNER_USER_ID;
-use crate::constants::{error_types, tonic_status_messages};
+use crate::constants::{error_types, request_metadata, tonic_status_messages};
 use crate::database::{
-  DBDeviceTypeInt, DatabaseClient, DeviceType, KeyPayload, UserInfoAndPasswordFile,
+  AuditLogEntry, DBDeviceTypeInt, DatabaseClient, DeviceType, KeyPayload,
+  UserInfoAndPasswordFile,
 };
 use crate::ddb_utils::{Identifier, is_transaction_conflict};
 use crate::device_list::SignedDeviceList;
 use crate::error::{DeviceListError, Error as DBError, consume_error};
-use crate::grpc_services::authenticated::{DeletePasswordUserInfo, UpdatePasswordInfo, PrivilegedPasswordResetInfo};
+use crate::fname_registry;
+use crate::grpc_services::authenticated::{DeletePasswordUserInfo, UpdatePasswordInfo, PrivilegedPasswordResetInfo, TotpEnrollmentInfo, UsernameChangeInfo};
+use crate::grpc_services::protos::auth::AuditEventType;
 use crate::grpc_services::protos::unauth::{
   find_user_id_request, AddReservedUsernamesRequest, AuthResponse, Empty,
   ExistingDeviceLoginRequest, FindUserIdRequest, FindUserIdResponse,
@@ -34,15 +36,17 @@ use crate::grpc_services::protos::unauth::{
   OpaqueLoginStartResponse, RegistrationFinishRequest, RegistrationStartRequest,
   RegistrationStartResponse, RemoveReservedUsernameRequest,
   ReservedRegistrationStartRequest, RestoreUserRequest,
-  SecondaryDeviceKeysUploadRequest, VerifyUserAccessTokenRequest,
+  SecondFactorLoginRequest, SecondaryDeviceKeysUploadRequest,
+  VerifyUserAccessTokenRequest,
   VerifyUserAccessTokenResponse, WalletAuthRequest, GetFarcasterUsersRequest,
   GetFarcasterUsersResponse
 };
-use crate::grpc_services::shared::get_platform_metadata;
+use crate::grpc_services::shared::{get_client_address, get_platform_metadata};
 use crate::grpc_utils::{
   DeviceKeyUploadActions, RegistrationActions, SignedNonce
 };
 use crate::log::redact_sensitive_data;
+use crate::login_attempts::{register_login_attempt, AttemptSubject};
 use crate::nonce::generate_nonce_data;
 use crate::reserved_users::{
   validate_account_ownership_message_and_get_user_id,
@@ -52,6 +56,7 @@ use crate::reserved_users::{
 use crate::siwe::{
   parse_and_verify_siwe_message, SocialProof,
 };
+use crate::skeleton::is_reserved_username;
 use comm_lib::{
   crypto,
 };
@@ -68,6 +73,10 @@ pub enum WorkflowInProgress {
   Update(Box<UpdatePasswordInfo>),
   PasswordUserDeletion(Box<DeletePasswordUserInfo>),
   PrivilegedPasswordReset(Box<PrivilegedPasswordResetInfo>),
+  /// Password login waiting for the second factor
+  SecondFactorLogin(Box<UserLoginInfo>),
+  TotpEnrollment(Box<TotpEnrollmentInfo>),
+  UsernameChange(Box<UsernameChangeInfo>),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -133,7 +142,7 @@ impl IdentityClientService for ClientService {
     self.check_username_taken(&message.username).await?;
     let username_in_reserved_usernames_table = self
       .client
-      .get_user_id_from_reserved_usernames_table(&message.username)
+      .get_user_id_of_similar_reserved_username(&message.username)
       .await?
       .is_some();
 
@@ -143,7 +152,19 @@ impl IdentityClientService for ClientService {
       ));
     }
 
-    if RESERVED_USERNAME_SET.contains(&message.username.to_lowercase()) {
+    // usernames changed by other users are unavailable for a cooldown period
+    let username_recently_released = !self
+      .client
+      .get_released_username_owners(&message.username)
+      .await?
+      .is_empty();
+    if username_recently_released {
+      return Err(tonic::Status::already_exists(
+        tonic_status_messages::USERNAME_ALREADY_EXISTS,
+      ));
+    }
+
+    if is_reserved_username(&message.username) {
       return Err(tonic::Status::invalid_argument(
         tonic_status_messages::USERNAME_RESERVED,
       ));
@@ -267,6 +288,7 @@ impl IdentityClientService for ClientService {
       let login_time = chrono::Utc::now();
       let device_id = state.flattened_device_key_upload.device_id_key.clone();
       let username = state.username.clone();
+      let farcaster_id = state.farcaster_id.clone();
       let user_id = self
         .client
         .add_password_user_to_database(
@@ -276,6 +298,7 @@ impl IdentityClientService for ClientService {
           login_time,
         )
         .await?;
+      self.spawn_farcaster_username_update(&user_id, farcaster_id);
 
       // Create access token
       let token = AccessTokenData::with_created_time(
@@ -288,7 +311,11 @@ impl IdentityClientService for ClientService {
 
       let access_token = token.access_token.clone();
 
+      let audit_entry =
+        AuditLogEntry::new(&token.user_id, AuditEventType::Registration)
+          .with_device_id(&token.signing_public_key);
       self.client.put_access_token_data(token).await?;
+      self.client.record_audit_event(audit_entry).await;
 
       let response = AuthResponse {
         user_id,
@@ -309,6 +336,7 @@ impl IdentityClientService for ClientService {
     request: tonic::Request<OpaqueLoginStartRequest>,
   ) -> Result<tonic::Response<OpaqueLoginStartResponse>, tonic::Status> {
     let platform_metadata = get_platform_metadata(&request)?;
+    let client_address = get_client_address(&request);
     let message = request.into_inner();
 
     info!(
@@ -316,6 +344,11 @@ impl IdentityClientService for ClientService {
       redact_sensitive_data(&message.username)
     );
 
+    let mut attempt_subjects =
+      vec![AttemptSubject::Username(message.username.clone())];
+    attempt_subjects.extend(client_address.map(AttemptSubject::Client));
+    register_login_attempt(&self.client, &attempt_subjects).await?;
+
     let user_id_and_password_file = self
       .client
       .get_user_info_and_password_file_from_username(&message.username)
@@ -434,7 +467,7 @@ impl IdentityClientService for ClientService {
     let platform_metadata = get_platform_metadata(&request)?;
     let message = request.into_inner();
 
-    let Some(WorkflowInProgress::Login(state)) =
+    let Some(WorkflowInProgress::Login(mut state)) =
       self.client.get_workflow(message.session_id).await?
     else {
       return Err(tonic::Status::not_found(
@@ -442,47 +475,70 @@ impl IdentityClientService for ClientService {
       ));
     };
 
-    let mut server_login = state.opaque_server_login;
-    server_login
+    state
+      .opaque_server_login
       .finish(&message.opaque_login_upload)
       .map_err(protocol_error_to_grpc_status)?;
 
-    if let Some(device_to_remove) = state.device_to_remove {
-      self
+    if self
+      .client
+      .get_totp_settings(&state.user_id)
+      .await?
+      .is_some()
+    {
+      let session_id = self
         .client
-        .v1_remove_device(state.user_id.clone(), device_to_remove)
+        .insert_workflow(WorkflowInProgress::SecondFactorLogin(state))
         .await?;
+      return Err(second_factor_required(session_id));
     }
 
-    let login_time = chrono::Utc::now();
-    self
-      .client
-      .v1_add_user_device(
-        state.user_id.clone(),
-        state.flattened_device_key_upload.clone(),
-        platform_metadata,
-        login_time,
-      )
+    let response = self
+      .finish_password_login(*state, platform_metadata)
       .await?;
+    Ok(Response::new(response))
+  }
 
-    // Create access token
-    let token = AccessTokenData::with_created_time(
-      state.user_id.clone(),
-      state.flattened_device_key_upload.device_id_key,
-      login_time,
-      crate::token::AuthType::Password,
-      &mut OsRng,
-    );
+  #[tracing::instrument(skip_all)]
+  async fn log_in_password_user_second_factor(
+    &self,
+    request: tonic::Request<SecondFactorLoginRequest>,
+  ) -> Result<tonic::Response<AuthResponse>, tonic::Status> {
+    let platform_metadata = get_platform_metadata(&request)?;
+    let client_address = get_client_address(&request);
+    let message = request.into_inner();
 
-    let access_token = token.access_token.clone();
+    let Some(WorkflowInProgress::SecondFactorLogin(state)) =
+      self.client.get_workflow(message.session_id).await?
+    else {
+      return Err(tonic::Status::not_found(
+        tonic_status_messages::SESSION_NOT_FOUND,
+      ));
+    };
 
-    self.client.put_access_token_data(token).await?;
+    let mut attempt_subjects =
+      vec![AttemptSubject::Username(state.username.clone())];
+    attempt_subjects.extend(client_address.map(AttemptSubject::Client));
+    register_login_attempt(&self.client, &attempt_subjects).await?;
 
-    let response = AuthResponse {
-      user_id: state.user_id,
-      access_token,
-      username: state.username,
-    };
+    // Two-factor authentication might have been disabled in the meantime
+    if let Some(totp_settings) =
+      self.client.get_totp_settings(&state.user_id).await?
+    {
+      let is_valid = self
+        .client
+        .verify_second_factor(&state.user_id, &totp_settings, &message.code)
+        .await?;
+      if !is_valid {
+        return Err(tonic::Status::unauthenticated(
+          tonic_status_messages::INVALID_SECOND_FACTOR_CODE,
+        ));
+      }
+    }
+
+    let response = self
+      .finish_password_login(*state, platform_metadata)
+      .await?;
     Ok(Response::new(response))
   }
 
@@ -605,11 +661,12 @@ impl IdentityClientService for ClientService {
           Some(user_id.clone()),
           platform_metadata,
           login_time,
-          message.farcaster_id,
+          message.farcaster_id.clone(),
           message.farcaster_dcs_token,
           None,
         )
         .await?;
+      self.spawn_farcaster_username_update(&user_id, message.farcaster_id);
 
       user_id
     };
@@ -625,7 +682,10 @@ impl IdentityClientService for ClientService {
 
     let access_token = token.access_token.clone();
 
+    let audit_entry = AuditLogEntry::new(&token.user_id, AuditEventType::Login)
+      .with_device_id(&token.signing_public_key);
     self.client.put_access_token_data(token).await?;
+    self.client.record_audit_event(audit_entry).await;
 
     let response = AuthResponse {
       user_id,
@@ -691,11 +751,12 @@ impl IdentityClientService for ClientService {
         None,
         platform_metadata,
         login_time,
-        message.farcaster_id,
+        message.farcaster_id.clone(),
         message.farcaster_dcs_token,
         initial_device_list,
       )
       .await?;
+    self.spawn_farcaster_username_update(&user_id, message.farcaster_id);
 
     // Create access token
     let token = AccessTokenData::with_created_time(
@@ -708,7 +769,11 @@ impl IdentityClientService for ClientService {
 
     let access_token = token.access_token.clone();
 
+    let audit_entry =
+      AuditLogEntry::new(&token.user_id, AuditEventType::Registration)
+        .with_device_id(&token.signing_public_key);
     self.client.put_access_token_data(token).await?;
+    self.client.record_audit_event(audit_entry).await;
 
     let response = AuthResponse {
       user_id,
@@ -928,7 +993,10 @@ impl IdentityClientService for ClientService {
       &mut OsRng,
     );
     let access_token = token.access_token.clone();
+    let audit_entry = AuditLogEntry::new(&token.user_id, AuditEventType::Login)
+      .with_device_id(&token.signing_public_key);
     self.client.put_access_token_data(token).await?;
+    self.client.record_audit_event(audit_entry).await;
 
     self
       .client
@@ -995,7 +1063,10 @@ impl IdentityClientService for ClientService {
       &mut OsRng,
     );
     let access_token = token.access_token.clone();
+    let audit_entry = AuditLogEntry::new(&token.user_id, AuditEventType::Login)
+      .with_device_id(&token.signing_public_key);
     self.client.put_access_token_data(token).await?;
+    self.client.record_audit_event(audit_entry).await;
 
     let response = AuthResponse {
       user_id,
@@ -1157,6 +1228,57 @@ impl IdentityClientService for ClientService {
 }
 
 impl ClientService {
+  /// Registers the device and issues an access token
+  /// after the user has been authenticated
+  async fn finish_password_login(
+    &self,
+    state: UserLoginInfo,
+    platform_metadata: PlatformMetadata,
+  ) -> Result<AuthResponse, tonic::Status> {
+    let attempt_subject = AttemptSubject::Username(state.username.clone());
+    consume_error(self.client.reset_login_attempts(&attempt_subject).await);
+
+    if let Some(device_to_remove) = state.device_to_remove {
+      self
+        .client
+        .v1_remove_device(state.user_id.clone(), device_to_remove)
+        .await?;
+    }
+
+    let login_time = chrono::Utc::now();
+    self
+      .client
+      .v1_add_user_device(
+        state.user_id.clone(),
+        state.flattened_device_key_upload.clone(),
+        platform_metadata,
+        login_time,
+      )
+      .await?;
+
+    // Create access token
+    let token = AccessTokenData::with_created_time(
+      state.user_id.clone(),
+      state.flattened_device_key_upload.device_id_key,
+      login_time,
+      crate::token::AuthType::Password,
+      &mut OsRng,
+    );
+
+    let access_token = token.access_token.clone();
+
+    let audit_entry = AuditLogEntry::new(&token.user_id, AuditEventType::Login)
+      .with_device_id(&token.signing_public_key);
+    self.client.put_access_token_data(token).await?;
+    self.client.record_audit_event(audit_entry).await;
+
+    Ok(AuthResponse {
+      user_id: state.user_id,
+      access_token,
+      username: state.username,
+    })
+  }
+
   async fn check_username_taken(
     &self,
     username: &str,
@@ -1204,6 +1326,22 @@ impl ClientService {
     Ok(())
   }
 
+  /// Looks up the Farcaster username of a newly registered user in
+  /// the background, see [`fname_registry::update_farcaster_username`]
+  fn spawn_farcaster_username_update(
+    &self,
+    user_id: &str,
+    farcaster_id: Option<String>,
+  ) {
+    if let Some(farcaster_id) = farcaster_id {
+      tokio::spawn(fname_registry::update_farcaster_username(
+        self.client.clone(),
+        user_id.to_string(),
+        farcaster_id,
+      ));
+    }
+  }
+
   async fn check_device_id_taken(
     &self,
     key_upload: &FlattenedDeviceKeyUpload,
@@ -1394,7 +1532,13 @@ impl ClientService {
     );
 
     let access_token = token_data.access_token.clone();
+    let audit_entry = AuditLogEntry::new(
+      &token_data.user_id,
+      AuditEventType::PrimaryDeviceRotation,
+    )
+    .with_device_id(&token_data.signing_public_key);
     self.client.put_access_token_data(token_data).await?;
+    self.client.record_audit_event(audit_entry).await;
 
     Ok(access_token)
   }
@@ -1433,6 +1577,20 @@ pub fn handle_db_error(db_error: DBError) -> tonic::Status {
   }
 }
 
+/// Returned by password login when the second factor has to be verified
+/// with the `LogInPasswordUserSecondFactor` RPC
+fn second_factor_required(session_id: String) -> tonic::Status {
+  let mut metadata = tonic::metadata::MetadataMap::new();
+  if let Ok(session_id) = session_id.parse() {
+    metadata.insert(request_metadata::SECOND_FACTOR_SESSION_ID, session_id);
+  }
+  tonic::Status::with_metadata(
+    tonic::Code::FailedPrecondition,
+    tonic_status_messages::SECOND_FACTOR_REQUIRED,
+    metadata,
+  )
+}
+
 impl From<DBError> for tonic::Status {
   fn from(err: DBError) -> Self {
     handle_db_error(err)
@@ -10,21 +10,27 @@ use crate::{
   log::redact_sensitive_data,
 };
 
+/// Removes backups of the user. When `restorable` is set, e.g. on account
+/// deletion, the backup service keeps them for a grace period.
 #[tracing::instrument(skip_all)]
 pub async fn delete_backup_user_data(
   user_id: &str,
   auth_service: &AuthService,
+  restorable: bool,
 ) -> Result<(), crate::error::Error> {
   info!(
     user_id = redact_sensitive_data(user_id),
-    "Attempting to remove user backups."
+    restorable, "Attempting to remove user backups."
   );
 
   let path = format!("/user_data/{}", user_id);
-  let url = CONFIG
+  let mut url = CONFIG
     .backup_service_url
     .join(&path)
     .expect("failed to construct backup service URL");
+  if restorable {
+    url.query_pairs_mut().append_pair("restorable", "true");
+  }
   let services_token =
     auth_service.get_services_token().await.map_err(|err| {
       tracing::error!(
@@ -74,6 +74,33 @@ pub async fn send_device_list_update(device_ids: &[&str]) -> Result<(), Error> {
   Ok(())
 }
 
+pub async fn send_username_updated(
+  device_ids: &[&str],
+  username: &str,
+) -> Result<(), Error> {
+  let mut tunnelbroker_client = create_tunnelbroker_client().await?;
+
+  let update = messages::IdentityUsernameUpdated {
+    username: username.to_string(),
+  };
+  let payload = serde_json::to_string(&update).unwrap();
+
+  for &device_id in device_ids {
+    let request = MessageToDevice {
+      device_id: device_id.to_string(),
+      payload: payload.clone(),
+    };
+
+    let grpc_message = tonic::Request::new(request);
+
+    tunnelbroker_client
+      .send_message_to_device(grpc_message)
+      .await?;
+  }
+
+  Ok(())
+}
+
 pub async fn delete_devices_data(device_ids: &[String]) -> Result<(), Error> {
   let mut tunnelbroker_client = create_tunnelbroker_client().await?;
 
@@ -1,7 +1,9 @@
-use std::{env, fmt, fs, io, path};
+use std::{env, fmt, fs, io, path, sync::Arc};
 
 use base64::{engine::general_purpose, DecodeError, Engine as _};
 use clap::{Parser, Subcommand};
+use comm_lib::crypto::aes256::EncryptionKey;
+use ed25519_dalek::Keypair;
 use http::HeaderValue;
 use once_cell::sync::Lazy;
 use tower_http::cors::AllowOrigin;
@@ -9,13 +11,20 @@ use tracing::{error, info};
 use url::Url;
 
 use crate::constants::{
-  cors::ALLOW_ORIGIN_LIST, BACKUP_SERVICE_URL, BLOB_SERVICE_URL,
+  cors::ALLOW_ORIGIN_LIST, ACCESS_TOKEN_IDLE_TIMEOUT_SECS,
+  ACCESS_TOKEN_MAX_AGE_SECS, BACKUP_SERVICE_URL, BLOB_SERVICE_URL,
   DEFAULT_BACKUP_SERVICE_URL, DEFAULT_BLOB_SERVICE_URL,
-  DEFAULT_OPENSEARCH_ENDPOINT, DEFAULT_TUNNELBROKER_ENDPOINT,
-  KEYSERVER_PUBLIC_KEY, LOCALSTACK_ENDPOINT, OPAQUE_SERVER_SETUP,
-  OPENSEARCH_ENDPOINT, REDACT_SENSITIVE_DATA, SECRETS_DIRECTORY,
-  SECRETS_SETUP_FILE, TUNNELBROKER_GRPC_ENDPOINT,
+  DEFAULT_OPENSEARCH_ENDPOINT, DEFAULT_SIWE_ALLOWED_DOMAINS,
+  DEFAULT_TRUSTED_PROXY_COUNT, DEFAULT_TUNNELBROKER_ENDPOINT,
+  DEVICE_LIST_LOG_SIGNING_KEY, ETHEREUM_RPC_URL, KEYSERVER_PUBLIC_KEY,
+  LOCALSTACK_ENDPOINT, OPAQUE_SERVER_SETUP, OPENSEARCH_ENDPOINT,
+  REDACT_SENSITIVE_DATA, SECRETS_DEVICE_LIST_LOG_KEY_FILE, SECRETS_DIRECTORY,
+  SECRETS_SETUP_FILE, SECRETS_TOTP_ENCRYPTION_KEY_FILE, SIWE_ALLOWED_DOMAINS,
+  SIWE_UNIVERSAL_VALIDATOR_ADDRESS, TOTP_ENCRYPTION_KEY, TRUSTED_PROXY_COUNT,
+  TUNNELBROKER_GRPC_ENDPOINT,
 };
+use crate::device_list_log::keypair_from_secret;
+use crate::token::AccessTokenLifetimes;
 
 /// Raw CLI arguments, should be only used internally to create ServerConfig
 static CLI: Lazy<Cli> = Lazy::new(Cli::parse);
@@ -76,6 +85,46 @@ struct Cli {
   #[arg(env = BLOB_SERVICE_URL)]
   #[arg(long, default_value = DEFAULT_BLOB_SERVICE_URL)]
   blob_service_url: reqwest::Url,
+
+  /// Max access token age in seconds. Tokens have to be refreshed
+  /// before that. Tokens don't expire if not set.
+  #[arg(long, global = true)]
+  #[arg(env = ACCESS_TOKEN_MAX_AGE_SECS)]
+  access_token_max_age_secs: Option<u64>,
+
+  /// Access tokens unused for this number of seconds expire.
+  /// Disabled if not set.
+  #[arg(long, global = true)]
+  #[arg(env = ACCESS_TOKEN_IDLE_TIMEOUT_SECS)]
+  access_token_idle_timeout_secs: Option<u64>,
+
+  /// Domains accepted in SIWE messages. Messages for other domains
+  /// are rejected.
+  #[arg(long, global = true)]
+  #[arg(env = SIWE_ALLOWED_DOMAINS)]
+  #[arg(value_delimiter = ',')]
+  #[arg(default_value = DEFAULT_SIWE_ALLOWED_DOMAINS)]
+  siwe_allowed_domains: Vec<String>,
+
+  /// Ethereum JSON-RPC endpoint used to verify smart contract wallet
+  /// signatures. Only EOA signatures are accepted if not set.
+  #[arg(long, global = true)]
+  #[arg(env = ETHEREUM_RPC_URL)]
+  ethereum_rpc_url: Option<reqwest::Url>,
+
+  /// Address of an EIP-6492 universal signature validator contract.
+  /// Required to verify signatures of wallets that aren't deployed yet.
+  #[arg(long, global = true)]
+  #[arg(env = SIWE_UNIVERSAL_VALIDATOR_ADDRESS)]
+  siwe_universal_validator_address: Option<String>,
+
+  /// Number of trusted proxies in front of the service, each appending
+  /// the address it received the request from to `X-Forwarded-For`.
+  /// Entries before them can be set by the client. Zero ignores the header.
+  #[arg(long, global = true)]
+  #[arg(env = TRUSTED_PROXY_COUNT)]
+  #[arg(default_value = DEFAULT_TRUSTED_PROXY_COUNT)]
+  trusted_proxy_count: usize,
 }
 
 #[derive(Subcommand)]
@@ -83,13 +132,24 @@ pub enum Command {
   /// Runs the server
   Server,
   /// Generates and persists a keypair to use for PAKE registration and login
+  /// and a key to sign device list log tree heads
   Keygen {
     #[arg(short, long)]
     #[arg(default_value = SECRETS_DIRECTORY)]
     dir: String,
   },
-  /// Syncs DynamoDB users with identity-search search index
+  /// Rebuilds identity-search search index from DynamoDB users. Resumes
+  /// the previous sync if it was interrupted
   SyncIdentitySearch,
+  /// Stores username skeletons for users registered before they were
+  /// introduced
+  BackfillUsernameSkeletons,
+  /// Asks devices that are running out of one-time keys or have stale
+  /// prekeys to upload new ones
+  SweepKeyHealth,
+  /// Schedules key health checks for devices registered before
+  /// the sweeper was introduced
+  BackfillKeyRefreshSchedule,
 }
 
 #[derive(Clone)]
@@ -97,6 +157,12 @@ pub struct ServerConfig {
   pub localstack_endpoint: Option<String>,
   // Opaque 2.0 server secrets
   pub server_setup: comm_opaque2::ServerSetup<comm_opaque2::Cipher>,
+  /// Signs device list transparency log tree heads. The OPAQUE server
+  /// keypair is a Ristretto255 key usable only within OPAQUE, so this
+  /// is a separate Ed25519 key.
+  pub device_list_log_keypair: Arc<Keypair>,
+  /// Encrypts TOTP secrets stored in the database
+  pub totp_encryption_key: EncryptionKey,
   pub keyserver_public_key: Option<String>,
   pub tunnelbroker_endpoint: String,
   pub backup_service_url: reqwest::Url,
@@ -104,12 +170,20 @@ pub struct ServerConfig {
   pub opensearch_endpoint: String,
   pub allow_origin: Option<AllowOrigin>,
   pub redact_sensitive_data: bool,
+  pub access_token_lifetimes: AccessTokenLifetimes,
+  pub siwe_allowed_domains: Vec<String>,
+  pub ethereum_rpc_url: Option<reqwest::Url>,
+  pub siwe_universal_validator_address: Option<String>,
+  pub trusted_proxy_count: usize,
 }
 
 impl ServerConfig {
   fn from_cli(cli: &Cli) -> Result<Self, Error> {
-    if !matches!(cli.command, Command::Server | Command::SyncIdentitySearch) {
-      panic!("ServerConfig is only available for the `server` or `sync-identity-search` command");
+    if !matches!(
+      cli.command,
+      Command::Server | Command::SyncIdentitySearch | Command::SweepKeyHealth
+    ) {
+      panic!("ServerConfig is only available for the `server`, `sync-identity-search` or `sweep-key-health` command");
     }
 
     info!("Tunnelbroker endpoint: {}", &cli.tunnelbroker_endpoint);
@@ -123,6 +197,17 @@ impl ServerConfig {
     path_buf.push(SECRETS_SETUP_FILE);
     let server_setup = get_server_setup(path_buf.as_path())?;
 
+    let mut path_buf = path::PathBuf::new();
+    path_buf.push(SECRETS_DIRECTORY);
+    path_buf.push(SECRETS_DEVICE_LIST_LOG_KEY_FILE);
+    let device_list_log_keypair =
+      Arc::new(get_device_list_log_keypair(path_buf.as_path())?);
+
+    let mut path_buf = path::PathBuf::new();
+    path_buf.push(SECRETS_DIRECTORY);
+    path_buf.push(SECRETS_TOTP_ENCRYPTION_KEY_FILE);
+    let totp_encryption_key = get_totp_encryption_key(path_buf.as_path())?;
+
     let keyserver_public_key = env::var(KEYSERVER_PUBLIC_KEY).ok();
 
     let allow_origin = cli
@@ -131,6 +216,17 @@ impl ServerConfig {
       .map(|s| slice_to_allow_origin(s.as_slice()))
       .transpose()?;
 
+    let access_token_lifetimes = AccessTokenLifetimes {
+      max_age: cli.access_token_max_age_secs.map(seconds_to_duration),
+      idle_timeout: cli.access_token_idle_timeout_secs.map(seconds_to_duration),
+    };
+    info!("Access token lifetimes: {:?}", access_token_lifetimes);
+
+    info!("Allowed SIWE domains: {:?}", cli.siwe_allowed_domains);
+    if cli.ethereum_rpc_url.is_none() {
+      info!("Ethereum RPC URL not set. Only EOA signatures will be accepted.");
+    }
+
     Ok(Self {
       localstack_endpoint: cli.localstack_endpoint.clone(),
       tunnelbroker_endpoint: cli.tunnelbroker_endpoint.clone(),
@@ -138,9 +234,18 @@ impl ServerConfig {
       blob_service_url: cli.blob_service_url.clone(),
       opensearch_endpoint: cli.opensearch_endpoint.clone(),
       server_setup,
+      device_list_log_keypair,
+      totp_encryption_key,
       keyserver_public_key,
       allow_origin,
       redact_sensitive_data: cli.redact_sensitive_data,
+      access_token_lifetimes,
+      siwe_allowed_domains: cli.siwe_allowed_domains.clone(),
+      ethereum_rpc_url: cli.ethereum_rpc_url.clone(),
+      siwe_universal_validator_address: cli
+        .siwe_universal_validator_address
+        .clone(),
+      trusted_proxy_count: cli.trusted_proxy_count,
     })
   }
 }
@@ -155,13 +260,22 @@ impl fmt::Debug for ServerConfig {
       blob_service_url,
       opensearch_endpoint,
       redact_sensitive_data,
+      access_token_lifetimes,
+      siwe_allowed_domains,
+      siwe_universal_validator_address,
+      trusted_proxy_count,
       // Explicitly redacted values
       server_setup: _,
+      device_list_log_keypair: _,
+      totp_encryption_key: _,
       allow_origin: _,
+      ethereum_rpc_url: _,
     } = &self;
     f.debug_struct("ServerConfig")
       .field("localstack_endpoint", localstack_endpoint)
       .field("server_setup", &"** redacted **")
+      .field("device_list_log_keypair", &"** redacted **")
+      .field("totp_encryption_key", &"** redacted **")
       .field("keyserver_public_key", keyserver_public_key)
       .field("tunnelbroker_endpoint", tunnelbroker_endpoint)
       .field("backup_service_url", backup_service_url)
@@ -169,6 +283,14 @@ impl fmt::Debug for ServerConfig {
       .field("opensearch_endpoint", opensearch_endpoint)
       .field("allow_origin_list", &"** redacted **")
       .field("redact_sensitive_data", redact_sensitive_data)
+      .field("access_token_lifetimes", access_token_lifetimes)
+      .field("siwe_allowed_domains", siwe_allowed_domains)
+      .field("ethereum_rpc_url", &"** redacted **")
+      .field(
+        "siwe_universal_validator_address",
+        siwe_universal_validator_address,
+      )
+      .field("trusted_proxy_count", trusted_proxy_count)
       .finish()
   }
 }
@@ -178,6 +300,8 @@ pub enum Error {
   #[display(...)]
   Opaque(comm_opaque2::ProtocolError),
   #[display(...)]
+  Signature(ed25519_dalek::SignatureError),
+  #[display(...)]
   Io(io::Error),
   #[display(...)]
   Env(env::VarError),
@@ -226,6 +350,69 @@ fn get_server_setup(
     .map_err(Error::Opaque)
 }
 
+fn get_device_list_log_keypair(path: &path::Path) -> Result<Keypair, Error> {
+  let encoded_secret = if let Ok(env_secret) =
+    env::var(DEVICE_LIST_LOG_SIGNING_KEY)
+  {
+    info!(
+      "Using device list log signing key from env var: {}",
+      DEVICE_LIST_LOG_SIGNING_KEY
+    );
+    env_secret
+  } else if let Ok(file_secret) = fs::read_to_string(path) {
+    info!(
+      "Using device list log signing key from file: {}",
+      path.display()
+    );
+    file_secret
+  } else {
+    error!("Unable to locate device list log signing key. Please run `keygen` command and run Identity service again.");
+    return Err(Error::Io(io::Error::new(
+      io::ErrorKind::NotFound,
+      "Missing device list log signing key",
+    )));
+  };
+
+  let decoded_secret =
+    general_purpose::STANDARD_NO_PAD.decode(encoded_secret.trim())?;
+  let keypair = keypair_from_secret(&decoded_secret)?;
+  Ok(keypair)
+}
+
+fn get_totp_encryption_key(path: &path::Path) -> Result<EncryptionKey, Error> {
+  let encoded_key = if let Ok(env_key) = env::var(TOTP_ENCRYPTION_KEY) {
+    info!(
+      "Using TOTP encryption key from env var: {}",
+      TOTP_ENCRYPTION_KEY
+    );
+    env_key
+  } else if let Ok(file_key) = fs::read_to_string(path) {
+    info!("Using TOTP encryption key from file: {}", path.display());
+    file_key
+  } else {
+    error!("Unable to locate TOTP encryption key. Please run `keygen` command and run Identity service again.");
+    return Err(Error::Io(io::Error::new(
+      io::ErrorKind::NotFound,
+      "Missing TOTP encryption key",
+    )));
+  };
+
+  let decoded_key =
+    general_purpose::STANDARD_NO_PAD.decode(encoded_key.trim())?;
+  EncryptionKey::from_bytes(&decoded_key).ok_or_else(|| {
+    Error::Io(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "TOTP encryption key has to be 32 bytes long",
+    ))
+  })
+}
+
+fn seconds_to_duration(seconds: u64) -> chrono::Duration {
+  // larger values would overflow chrono::Duration
+  let max_seconds = (i64::MAX / 1000) as u64;
+  chrono::Duration::seconds(seconds.min(max_seconds) as i64)
+}
+
 fn slice_to_allow_origin(origins: &[String]) -> Result<AllowOrigin, Error> {
   let allow_origin_result: Result<Vec<HeaderValue>, Error> = origins
     .iter()
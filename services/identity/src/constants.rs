@@ -4,6 +4,8 @@ use tokio::time::Duration;
 
 pub const SECRETS_DIRECTORY: &str = "secrets";
 pub const SECRETS_SETUP_FILE: &str = "server_setup.txt";
+pub const SECRETS_DEVICE_LIST_LOG_KEY_FILE: &str = "device_list_log_key.txt";
+pub const SECRETS_TOTP_ENCRYPTION_KEY_FILE: &str = "totp_encryption_key.txt";
 
 // DynamoDB
 
@@ -20,11 +22,26 @@ pub const USERS_TABLE_DEVICELIST_TIMESTAMP_ATTRIBUTE_NAME: &str =
 pub const USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME: &str = "farcasterID";
 pub const USERS_TABLE_FARCASTER_DCS_TOKEN_ATTRIBUTE_NAME: &str =
   "farcasterDCsToken";
+/// Farcaster username (fname) of the linked FID, used only by identity search
+pub const USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME: &str =
+  "farcasterUsername";
 pub const USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME: &str = "usernameLower";
+/// See [`crate::skeleton::username_skeleton`]
+pub const USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME: &str =
+  "usernameSkeleton";
+/// Encrypted with the key from [`TOTP_ENCRYPTION_KEY`]
+pub const USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME: &str = "totpSecret";
+/// SHA-256 hashes of unused recovery codes
+pub const USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME: &str =
+  "totpRecoveryCodes";
+/// Last accepted TOTP time step, so that codes can't be reused
+pub const USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME: &str =
+  "totpLastUsedStep";
 pub const USERS_TABLE_USERNAME_INDEX: &str = "username-index";
 pub const USERS_TABLE_WALLET_ADDRESS_INDEX: &str = "walletAddress-index";
 pub const USERS_TABLE_FARCASTER_ID_INDEX: &str = "farcasterID-index";
 pub const USERS_TABLE_USERNAME_LOWER_INDEX: &str = "usernameLower-index";
+pub const USERS_TABLE_USERNAME_SKELETON_INDEX: &str = "usernameSkeleton-index";
 
 pub mod token_table {
   pub const NAME: &str = "identity-tokens";
@@ -34,6 +51,45 @@ pub mod token_table {
   pub const ATTR_AUTH_TYPE: &str = "authType";
   pub const ATTR_VALID: &str = "valid";
   pub const ATTR_TOKEN: &str = "token";
+  pub const ATTR_LAST_USED: &str = "lastUsed";
+  pub const ATTR_PREVIOUS_TOKEN: &str = "previousToken";
+  pub const ATTR_PREVIOUS_TOKEN_VALID_UNTIL: &str = "previousTokenValidUntil";
+}
+
+pub mod audit_log_table {
+  pub const NAME: &str = "identity-audit-log";
+  pub const PARTITION_KEY: &str = "userID";
+  /// `timestamp#uuid`, so that events are sorted by time
+  pub const SORT_KEY: &str = "eventID";
+  pub const ATTR_EVENT_TYPE: &str = "eventType";
+  pub const ATTR_TIMESTAMP: &str = "timestamp";
+  pub const ATTR_DEVICE_ID: &str = "deviceID";
+  pub const ATTR_STAFF_USER_ID: &str = "staffUserID";
+  pub const ATTR_DETAILS: &str = "details";
+}
+
+pub mod login_attempts_table {
+  pub const NAME: &str = "identity-login-attempts";
+  /// See [`crate::login_attempts::AttemptSubject::key`]
+  pub const PARTITION_KEY: &str = "attemptKey";
+  pub const ATTR_ATTEMPTS: &str = "attempts";
+  pub const ATTR_LAST_ATTEMPT: &str = "lastAttempt";
+  pub const ATTR_EXPIRATION_TIME_UNIX: &str = "expirationTimeUnix";
+}
+
+pub mod released_usernames_table {
+  pub const NAME: &str = "identity-released-usernames";
+  /// Lowercase username
+  pub const PARTITION_KEY: &str = "usernameLower";
+  pub const ATTR_USERNAME: &str = "username";
+  /// Username skeleton, so that look-alikes are released too.
+  /// Not set for usernames released before skeletons were introduced.
+  pub const ATTR_USERNAME_SKELETON: &str = "usernameSkeleton";
+  pub const USERNAME_SKELETON_INDEX: &str = "usernameSkeleton-index";
+  /// ID of the user that used the username before
+  pub const ATTR_USER_ID: &str = "userID";
+  pub const ATTR_RELEASED_AT: &str = "releasedAt";
+  pub const ATTR_EXPIRATION_TIME_UNIX: &str = "expirationTimeUnix";
 }
 
 pub const NONCE_TABLE: &str = "identity-nonces";
@@ -58,6 +114,10 @@ pub const RESERVED_USERNAMES_TABLE_USERNAME_LOWER_ATTRIBUTE: &str =
 pub const RESERVED_USERNAMES_TABLE_USERNAME_LOWER_INDEX: &str =
   "usernameLower-index";
 pub const RESERVED_USERNAMES_TABLE_USER_ID_INDEX: &str = "userID-index";
+pub const RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE: &str =
+  "usernameSkeleton";
+pub const RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_INDEX: &str =
+  "usernameSkeleton-index";
 
 // Users table social proof attribute
 pub const SOCIAL_PROOF_MESSAGE_ATTRIBUTE: &str = "siweMessage";
@@ -68,6 +128,7 @@ pub mod devices_table {
   pub const NAME: &str = "identity-devices";
   pub const TIMESTAMP_INDEX_NAME: &str = "deviceList-timestamp-index";
   pub const DEVICE_ID_INDEX_NAME: &str = "deviceID-index";
+  pub const KEY_REFRESH_DUE_INDEX_NAME: &str = "keyRefreshDue-index";
 
   /// partition key
   pub const ATTR_USER_ID: &str = "userID";
@@ -109,6 +170,20 @@ pub mod devices_table {
   pub const ATTR_CONTENT_OTK_COUNT: &str = "contentOTKCount";
   pub const ATTR_NOTIF_OTK_COUNT: &str = "notifOTKCount";
 
+  // key health constants
+  pub const ATTR_PREKEYS_UPDATED_AT: &str = "preKeysUpdatedAt";
+  pub const ATTR_LAST_KEY_REFRESH_REQUEST: &str = "lastKeyRefreshRequest";
+  /// Unix timestamp in seconds when the key health sweeper should check
+  /// the device. Sparse, so that the sweeper doesn't scan all devices.
+  pub const ATTR_KEY_REFRESH_DUE_AT: &str = "keyRefreshDueAt";
+  /// Spreads devices across partitions of the key refresh due index
+  pub const ATTR_KEY_HEALTH_SHARD: &str = "keyHealthShard";
+  /// Number of times a peer got no one-time key and had to use the prekey
+  pub const ATTR_CONTENT_OTK_EXHAUSTION_COUNT: &str =
+    "contentOTKExhaustionCount";
+  pub const ATTR_NOTIF_OTK_EXHAUSTION_COUNT: &str = "notifOTKExhaustionCount";
+  pub const ATTR_LAST_OTK_EXHAUSTION: &str = "lastOTKExhaustion";
+
   // deprecated attributes
   pub const OLD_ATTR_DEVICE_TYPE: &str = "deviceType";
   pub const OLD_ATTR_CODE_VERSION: &str = "codeVersion";
@@ -134,6 +209,11 @@ pub const SOCKET_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
 // Token
 
 pub const ACCESS_TOKEN_LENGTH: usize = 512;
+/// How long a token replaced during rotation is still accepted
+pub const ACCESS_TOKEN_ROTATION_OVERLAP: Duration = Duration::from_secs(60);
+/// Idle timeout should be much longer than this interval
+pub const ACCESS_TOKEN_LAST_USED_UPDATE_INTERVAL: Duration =
+  Duration::from_secs(5 * 60);
 
 // Temporary config
 
@@ -148,6 +228,50 @@ pub const NONCE_TTL_DURATION: Duration = Duration::from_secs(900); // seconds
 // Device list
 
 pub const DEVICE_LIST_TIMESTAMP_VALID_FOR: Duration = Duration::from_secs(300);
+pub const DEVICE_LIST_HISTORY_PAGE_SIZE: i32 = 20;
+pub const DEVICE_LIST_DIFFS_MAX_USERS: usize = 100;
+pub const DEVICE_LIST_DIFFS_CONCURRENCY: usize = 10;
+
+// Login attempts
+
+/// Attempt counters are reset after this much time without attempts
+pub const LOGIN_ATTEMPTS_TTL: Duration = Duration::from_secs(60 * 60);
+pub const LOGIN_ATTEMPTS_BASE_DELAY: Duration = Duration::from_secs(1);
+pub const LOGIN_ATTEMPTS_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+pub const LOGIN_LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+// Attempts allowed before delays kick in, and before lockout.
+// Limits for clients are higher because many users can share an IP address.
+pub const ACCOUNT_FREE_LOGIN_ATTEMPTS: u32 = 5;
+pub const ACCOUNT_LOGIN_LOCKOUT_THRESHOLD: u32 = 10;
+pub const CLIENT_FREE_LOGIN_ATTEMPTS: u32 = 20;
+pub const CLIENT_LOGIN_LOCKOUT_THRESHOLD: u32 = 50;
+/// Number of proxies appending to `X-Forwarded-For`, see
+/// [`crate::grpc_services::shared::get_client_address`]
+pub const TRUSTED_PROXY_COUNT: &str = "TRUSTED_PROXY_COUNT";
+pub const DEFAULT_TRUSTED_PROXY_COUNT: &str = "1";
+
+// Username change
+
+/// Old usernames can't be taken by other users for this long
+pub const USERNAME_CHANGE_COOLDOWN: Duration =
+  Duration::from_secs(30 * 24 * 60 * 60);
+
+// Two-factor authentication
+
+pub const TOTP_ISSUER: &str = "Comm";
+pub const TOTP_SECRET_LENGTH: usize = 20;
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_PERIOD_SECS: u64 = 30;
+/// Codes from this many adjacent periods are accepted
+pub const TOTP_ALLOWED_DRIFT_STEPS: u64 = 1;
+pub const TOTP_RECOVERY_CODE_COUNT: usize = 10;
+pub const TOTP_RECOVERY_CODE_LENGTH: usize = 10;
+/// Encrypts TOTP secrets stored in the users table
+pub const TOTP_ENCRYPTION_KEY: &str = "TOTP_ENCRYPTION_KEY";
+
+// Audit log
+
+pub const AUDIT_LOG_PAGE_SIZE: i32 = 50;
 
 // Workflows in progress
 
@@ -162,26 +286,63 @@ pub const LOCALSTACK_ENDPOINT: &str = "LOCALSTACK_ENDPOINT";
 
 pub const OPAQUE_SERVER_SETUP: &str = "OPAQUE_SERVER_SETUP";
 
+// Device list transparency log
+
+pub const DEVICE_LIST_LOG_SIGNING_KEY: &str = "DEVICE_LIST_LOG_SIGNING_KEY";
+
+// Access token lifetimes
+
+pub const ACCESS_TOKEN_MAX_AGE_SECS: &str = "ACCESS_TOKEN_MAX_AGE_SECS";
+pub const ACCESS_TOKEN_IDLE_TIMEOUT_SECS: &str =
+  "ACCESS_TOKEN_IDLE_TIMEOUT_SECS";
+
+// Sign-In With Ethereum
+
+pub const SIWE_ALLOWED_DOMAINS: &str = "SIWE_ALLOWED_DOMAINS";
+pub const DEFAULT_SIWE_ALLOWED_DOMAINS: &str = "comm.app,web.comm.app";
+pub const ETHEREUM_RPC_URL: &str = "ETHEREUM_RPC_URL";
+pub const SIWE_UNIVERSAL_VALIDATOR_ADDRESS: &str =
+  "SIWE_UNIVERSAL_VALIDATOR_ADDRESS";
+pub const ETHEREUM_RPC_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Farcaster
+
+pub const FNAME_REGISTRY_URL: &str = "https://fnames.farcaster.xyz";
+pub const FNAME_REGISTRY_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 // Identity Search
 
 pub const OPENSEARCH_ENDPOINT: &str = "OPENSEARCH_ENDPOINT";
 pub const DEFAULT_OPENSEARCH_ENDPOINT: &str =
   "identity-search-domain.us-east-2.opensearch.localhost.localstack.cloud:4566";
+/// Alias of the index currently in use, see [`crate::sync_identity_search`]
 pub const IDENTITY_SEARCH_INDEX: &str = "users";
 pub const IDENTITY_SEARCH_RESULT_SIZE: u32 = 20;
+/// Number of users loaded into a new index at once during reindexing
+pub const IDENTITY_SEARCH_SYNC_BATCH_SIZE: i32 = 500;
+/// Stores progress of a reindex, so that it can be resumed
+pub const IDENTITY_SEARCH_SYNC_STATE_INDEX: &str = "identity-search-sync";
+/// Alias of the index being loaded by a reindex. The search index lambda
+/// writes changes to it as well as to [`IDENTITY_SEARCH_INDEX`].
+pub const IDENTITY_SEARCH_SYNC_ALIAS: &str = "users-sync";
 
 // Log Error Types
 
 pub mod error_types {
   pub const GENERIC_DB_LOG: &str = "DB Error";
   pub const OTK_DB_LOG: &str = "One-time Key DB Error";
+  pub const KEY_HEALTH_LOG: &str = "Key Health Error";
   pub const DEVICE_LIST_DB_LOG: &str = "Device List DB Error";
   pub const TOKEN_DB_LOG: &str = "Token DB Error";
   pub const FARCASTER_DB_LOG: &str = "Farcaster DB Error";
+  pub const AUDIT_LOG_DB_LOG: &str = "Audit Log DB Error";
+  pub const LOGIN_ATTEMPTS_DB_LOG: &str = "Login Attempts DB Error";
 
   pub const SYNC_LOG: &str = "Sync Error";
   pub const SEARCH_LOG: &str = "Search Error";
   pub const SIWE_LOG: &str = "SIWE Error";
+  pub const ETHEREUM_RPC_LOG: &str = "Ethereum RPC Error";
+  pub const FNAME_REGISTRY_LOG: &str = "Fname Registry Error";
   pub const GRPC_SERVICES_LOG: &str = "gRPC Services Error";
   pub const TUNNELBROKER_LOG: &str = "Tunnelbroker Error";
   pub const HTTP_LOG: &str = "HTTP Error";
@@ -211,8 +372,18 @@ pub mod tonic_status_messages {
   pub const INVALID_PLATFORM_METADATA: &str = "invalid_platform_metadata";
   pub const MISSING_CREDENTIALS: &str = "missing_credentials";
   pub const BAD_CREDENTIALS: &str = "bad_credentials";
+  pub const ACCESS_TOKEN_ROTATED: &str = "access_token_rotated";
   pub const SESSION_NOT_FOUND: &str = "session_not_found";
+  pub const TOO_MANY_LOGIN_ATTEMPTS: &str = "too_many_login_attempts";
+  pub const LOGIN_TEMPORARILY_LOCKED: &str = "login_temporarily_locked";
+  pub const SECOND_FACTOR_REQUIRED: &str = "second_factor_required";
+  pub const INVALID_SECOND_FACTOR_CODE: &str = "invalid_second_factor_code";
+  pub const SECOND_FACTOR_ALREADY_ENABLED: &str =
+    "second_factor_already_enabled";
+  pub const SECOND_FACTOR_NOT_ENABLED: &str = "second_factor_not_enabled";
   pub const INVALID_TIMESTAMP: &str = "invalid_timestamp";
+  pub const INVALID_CURSOR: &str = "invalid_cursor";
+  pub const TOO_MANY_USERS: &str = "too_many_users";
   pub const INVALID_USERNAME: &str = "invalid_username";
   pub const USERNAME_NOT_RESERVED: &str = "username_not_reserved";
   pub const NEED_KEYSERVER_MESSAGE_TO_CLAIM_USERNAME: &str =
@@ -242,10 +413,12 @@ pub mod tonic_status_messages {
     "missing_platform_or_code_version_metadata";
   pub const MISSING_KEY: &str = "missing_key";
   pub const MESSAGE_NOT_AUTHENTICATED: &str = "message_not_authenticated";
+  pub const INVALID_SIWE_DOMAIN: &str = "invalid_siwe_domain";
   pub const RETRY_FROM_NATIVE: &str = "retry_from_native";
   pub const USER_IS_NOT_STAFF: &str = "user_is_not_staff";
   pub const USE_NEW_FLOW: &str = "use_new_flow";
   pub const USE_V1_FLOW: &str = "use_v1_flow";
+  pub const INVALID_LOG_SIZE: &str = "invalid_log_size";
 }
 
 // Tunnelbroker
@@ -266,6 +439,13 @@ pub const DEFAULT_BLOB_SERVICE_URL: &str = "http://localhost:50053";
 pub const ONE_TIME_KEY_MINIMUM_THRESHOLD: usize = 5;
 // Number of keys to be refreshed when below the threshold
 pub const ONE_TIME_KEY_REFRESH_NUMBER: u32 = 5;
+// Prekeys older than this are reported as stale
+pub const PREKEY_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Number of partitions of the key refresh due index
+pub const KEY_HEALTH_SHARD_COUNT: u32 = 8;
+// Minimum time between refresh requests sent to a device by the sweeper
+pub const KEY_REFRESH_REQUEST_COOLDOWN: Duration =
+  Duration::from_secs(24 * 60 * 60);
 
 // Minimum supported code versions
 
@@ -283,6 +463,12 @@ pub mod request_metadata {
   pub const DEVICE_ID: &str = "device_id";
   pub const ACCESS_TOKEN: &str = "access_token";
   pub const SERVICES_TOKEN: &str = "services_token";
+  /// Set by the load balancer
+  pub const FORWARDED_FOR: &str = "x-forwarded-for";
+  /// Returned with throttled login attempts
+  pub const RETRY_AFTER_SECS: &str = "retry-after-secs";
+  /// Returned with `second_factor_required` status
+  pub const SECOND_FACTOR_SESSION_ID: &str = "second-factor-session-id";
 }
 
 // CORS
@@ -291,8 +477,13 @@ pub mod cors {
   use std::time::Duration;
 
   pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
-  pub const DEFAULT_EXPOSED_HEADERS: [&str; 3] =
-    ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+  pub const DEFAULT_EXPOSED_HEADERS: [&str; 5] = [
+    "grpc-status",
+    "grpc-message",
+    "grpc-status-details-bin",
+    super::request_metadata::RETRY_AFTER_SECS,
+    super::request_metadata::SECOND_FACTOR_SESSION_ID,
+  ];
   pub const DEFAULT_ALLOW_HEADERS: [&str; 12] = [
     "x-grpc-web",
     "content-type",
@@ -320,6 +511,8 @@ pub use comm_lib::constants::env_var::{
 
 pub const VALID_USERNAME_REGEX_STRING: &str =
   r"^[a-zA-Z0-9][a-zA-Z0-9-_]{0,190}$";
+pub const VALID_FARCASTER_USERNAME_REGEX_STRING: &str =
+  r"^[a-z0-9][a-z0-9-]{0,15}$";
 
 // Retry
 
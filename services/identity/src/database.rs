@@ -40,31 +40,41 @@ use tracing::{debug, error, info, warn, Instrument};
 
 use crate::client_service::{FlattenedDeviceKeyUpload, UserRegistrationInfo};
 use crate::constants::{
-  error_types, NONCE_TABLE, NONCE_TABLE_CREATED_ATTRIBUTE,
-  NONCE_TABLE_EXPIRATION_TIME_ATTRIBUTE,
+  error_types, IDENTITY_SEARCH_SYNC_BATCH_SIZE, NONCE_TABLE,
+  NONCE_TABLE_CREATED_ATTRIBUTE, NONCE_TABLE_EXPIRATION_TIME_ATTRIBUTE,
   NONCE_TABLE_EXPIRATION_TIME_UNIX_ATTRIBUTE, NONCE_TABLE_PARTITION_KEY,
   RESERVED_USERNAMES_TABLE, RESERVED_USERNAMES_TABLE_PARTITION_KEY,
   RESERVED_USERNAMES_TABLE_USERNAME_LOWER_ATTRIBUTE,
   RESERVED_USERNAMES_TABLE_USERNAME_LOWER_INDEX,
+  RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE,
   RESERVED_USERNAMES_TABLE_USER_ID_ATTRIBUTE, USERS_TABLE,
   USERS_TABLE_DEVICES_MAP_DEVICE_TYPE_ATTRIBUTE_NAME,
   USERS_TABLE_FARCASTER_DCS_TOKEN_ATTRIBUTE_NAME,
-  USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME, USERS_TABLE_PARTITION_KEY,
+  USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME,
+  USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME, USERS_TABLE_PARTITION_KEY,
   USERS_TABLE_REGISTRATION_ATTRIBUTE, USERS_TABLE_SOCIAL_PROOF_ATTRIBUTE_NAME,
   USERS_TABLE_USERNAME_ATTRIBUTE, USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME,
-  USERS_TABLE_USERNAME_LOWER_INDEX, USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE,
-  USERS_TABLE_WALLET_ADDRESS_INDEX,
+  USERS_TABLE_USERNAME_LOWER_INDEX,
+  USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME,
+  USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE, USERS_TABLE_WALLET_ADDRESS_INDEX,
 };
 use crate::id::generate_uuid;
 use crate::nonce::NonceData;
+use crate::skeleton::username_skeleton;
 use crate::token::AuthType;
 pub use grpc_clients::identity::DeviceType;
 
+mod audit_log;
 mod device_list;
 mod farcaster;
+mod key_health;
+mod login_attempts;
 mod one_time_keys;
 mod token;
+mod totp;
+mod usernames;
 mod workflows;
+pub use audit_log::AuditLogEntry;
 pub use device_list::{
   DeviceListRow, DeviceListUpdate, DeviceRow, PlatformDetails, Prekey,
 };
@@ -130,6 +140,8 @@ impl From<OutboundKeys> for protos::auth::OutboundKeyInfo {
       }),
       content_prekey: Some(db_keys.content_prekey.into()),
       notif_prekey: Some(db_keys.notif_prekey.into()),
+      content_prekey_only: db_keys.content_one_time_key.is_none(),
+      notif_prekey_only: db_keys.notif_one_time_key.is_none(),
       one_time_content_prekey: db_keys.content_one_time_key,
       one_time_notif_prekey: db_keys.notif_one_time_key,
     }
@@ -142,6 +154,18 @@ pub struct UserInfoAndPasswordFile {
   pub password_file: Vec<u8>,
 }
 
+/// User details kept in the identity search index
+pub struct IndexedUser {
+  pub user_id: String,
+  /// Username, or the wallet address if the user signed up with a wallet
+  /// and has no username
+  pub username: String,
+  pub wallet_address: Option<String>,
+  pub farcaster_id: Option<String>,
+  /// Farcaster username (fname) of the linked FID
+  pub farcaster_username: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct DatabaseClient {
   client: Arc<DynamoDBClient>,
@@ -302,6 +326,10 @@ impl DatabaseClient {
         USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME.to_string(),
         AttributeValue::S(username.to_lowercase()),
       );
+      user.insert(
+        USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME.to_string(),
+        AttributeValue::S(username_skeleton(&username)),
+      );
     }
 
     if let Some(eth_identity) = wallet_identity.clone() {
@@ -681,34 +709,11 @@ impl DatabaseClient {
     Ok(result.is_some())
   }
 
+  /// Checks if the username or a look-alike of it is taken
   pub async fn username_taken(&self, username: String) -> Result<bool, Error> {
-    let username_lower = username.to_lowercase();
-
-    let request = self
-      .client
-      .query()
-      .table_name(USERS_TABLE)
-      .index_name(USERS_TABLE_USERNAME_LOWER_INDEX)
-      .key_condition_expression("#username_lower = :username_lower")
-      .expression_attribute_names(
-        "#username_lower",
-        USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME,
-      )
-      .expression_attribute_values(
-        ":username_lower",
-        AttributeValue::S(username_lower),
-      );
-
-    let response = request.send().await.map_err(|e| {
-      error!(
-        errorType = error_types::GENERIC_DB_LOG,
-        "Failed to query lowercase usernames by index: {:?}", e
-      );
-      Error::AwsSdk(e.into())
-    })?;
-
-    let username_available = response.items().is_empty();
-    Ok(!username_available)
+    let similar_users =
+      self.get_user_ids_with_similar_username(&username).await?;
+    Ok(!similar_users.is_empty())
   }
 
   pub async fn filter_out_taken_usernames(
@@ -717,15 +722,15 @@ impl DatabaseClient {
   ) -> Result<Vec<UserDetail>, Error> {
     let db_usernames = self.get_all_usernames().await?;
 
-    let db_usernames_set: HashSet<String> = db_usernames
-      .into_iter()
-      .map(|username| username.to_lowercase())
+    let db_skeletons_set: HashSet<String> = db_usernames
+      .iter()
+      .map(|username| username_skeleton(username))
       .collect();
 
     let available_user_details: Vec<UserDetail> = user_details
       .into_iter()
       .filter(|user_detail| {
-        !db_usernames_set.contains(&user_detail.username.to_lowercase())
+        !db_skeletons_set.contains(&username_skeleton(&user_detail.username))
       })
       .collect();
 
@@ -1083,29 +1088,50 @@ impl DatabaseClient {
     Ok(result)
   }
 
-  pub async fn get_all_user_details(&self) -> Result<Vec<UserDetail>, Error> {
+  /// Returns a page of users to index for identity search and the key
+  /// to pass to get the next page, if there is one
+  pub async fn get_user_details_page(
+    &self,
+    exclusive_start_key: Option<String>,
+  ) -> Result<(Vec<IndexedUser>, Option<String>), Error> {
     let scan_output = self
       .client
       .scan()
       .table_name(USERS_TABLE)
-      .projection_expression("#userID, #username, #walletAddress")
+      .projection_expression(
+        "#userID, #username, #walletAddress, #farcasterID, #farcasterUsername",
+      )
       .expression_attribute_names("#userID", USERS_TABLE_PARTITION_KEY)
       .expression_attribute_names("#username", USERS_TABLE_USERNAME_ATTRIBUTE)
       .expression_attribute_names(
         "#walletAddress",
         USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE,
       )
+      .expression_attribute_names(
+        "#farcasterID",
+        USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#farcasterUsername",
+        USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME,
+      )
+      .limit(IDENTITY_SEARCH_SYNC_BATCH_SIZE)
+      .set_exclusive_start_key(exclusive_start_key.map(|user_id| {
+        HashMap::from([(
+          USERS_TABLE_PARTITION_KEY.to_string(),
+          AttributeValue::S(user_id),
+        )])
+      }))
       .send()
       .await
       .map_err(|e| Error::AwsSdk(e.into()))?;
 
-    let mut result = Vec::new();
-
-    let Some(items) = scan_output.items else {
-      return Ok(result);
-    };
+    let next_key: Option<String> = scan_output
+      .last_evaluated_key
+      .and_then(|mut key| key.take_attr(USERS_TABLE_PARTITION_KEY).ok());
 
-    for mut item in items {
+    let mut result = Vec::new();
+    for mut item in scan_output.items.unwrap_or_default() {
       let Ok(user_id) = item.take_attr(USERS_TABLE_PARTITION_KEY) else {
         error!(
           errorType = error_types::GENERIC_DB_LOG,
@@ -1113,24 +1139,45 @@ impl DatabaseClient {
         );
         continue;
       };
-      if let Ok(username) = item.take_attr(USERS_TABLE_USERNAME_ATTRIBUTE) {
-        result.push(UserDetail { username, user_id });
-      } else if let Ok(wallet_address) =
-        item.take_attr(USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE)
-      {
-        result.push(UserDetail {
-          username: wallet_address,
-          user_id,
-        })
-      }
+      let wallet_address: Option<String> = item
+        .take_attr(USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE)
+        .ok()
+        .flatten();
+      let farcaster_id: Option<String> = item
+        .take_attr(USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME)
+        .ok()
+        .flatten();
+      let farcaster_username: Option<String> = item
+        .take_attr(USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME)
+        .ok()
+        .flatten();
+
+      let username = match item.take_attr(USERS_TABLE_USERNAME_ATTRIBUTE) {
+        Ok(username) => username,
+        Err(_) => match &wallet_address {
+          Some(wallet_address) => wallet_address.clone(),
+          None => continue,
+        },
+      };
+
+      result.push(IndexedUser {
+        user_id,
+        username,
+        wallet_address,
+        farcaster_id,
+        farcaster_username,
+      });
     }
 
-    Ok(result)
+    Ok((result, next_key))
   }
 
-  pub async fn get_all_reserved_user_details(
+  /// Returns a page of reserved usernames and the key to pass to get
+  /// the next page, if there is one
+  pub async fn get_reserved_user_details_page(
     &self,
-  ) -> Result<Vec<UserDetail>, Error> {
+    exclusive_start_key: Option<String>,
+  ) -> Result<(Vec<UserDetail>, Option<String>), Error> {
     let scan_output = self
       .client
       .scan()
@@ -1139,10 +1186,22 @@ impl DatabaseClient {
         "{RESERVED_USERNAMES_TABLE_PARTITION_KEY},\
       {RESERVED_USERNAMES_TABLE_USER_ID_ATTRIBUTE}"
       ))
+      .limit(IDENTITY_SEARCH_SYNC_BATCH_SIZE)
+      .set_exclusive_start_key(exclusive_start_key.map(|username| {
+        HashMap::from([(
+          RESERVED_USERNAMES_TABLE_PARTITION_KEY.to_string(),
+          AttributeValue::S(username),
+        )])
+      }))
       .send()
       .await
       .map_err(|e| Error::AwsSdk(e.into()))?;
 
+    let next_key: Option<String> =
+      scan_output.last_evaluated_key.and_then(|mut key| {
+        key.take_attr(RESERVED_USERNAMES_TABLE_PARTITION_KEY).ok()
+      });
+
     let mut result = Vec::new();
     if let Some(attributes) = scan_output.items {
       for mut attribute in attributes {
@@ -1154,7 +1213,7 @@ impl DatabaseClient {
         }
       }
     }
-    Ok(result)
+    Ok((result, next_key))
   }
 
   pub async fn add_nonce_to_nonces_table(
@@ -1264,6 +1323,10 @@ impl DatabaseClient {
               RESERVED_USERNAMES_TABLE_USERNAME_LOWER_ATTRIBUTE,
               AttributeValue::S(user_detail.username.to_lowercase()),
             )
+            .item(
+              RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE,
+              AttributeValue::S(username_skeleton(&user_detail.username)),
+            )
             .build()
             .expect("no items set in PutRequest builder");
 
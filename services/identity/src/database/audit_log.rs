@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use comm_lib::{
+  aws::ddb::types::AttributeValue,
+  database::{
+    AttributeExtractor, AttributeMap, DBItemAttributeError, DBItemError,
+    TryFromAttribute, Value,
+  },
+};
+use tracing::error;
+
+use super::DatabaseClient;
+use crate::{
+  constants::{audit_log_table::*, error_types, AUDIT_LOG_PAGE_SIZE},
+  error::Error,
+  grpc_services::protos::auth::{
+    AuditEventType, AuditLogEntry as ProtoAuditLogEntry,
+  },
+  id::generate_uuid,
+};
+
+/// A security-relevant event in user's account history.
+/// Entries are never modified after they're written.
+#[derive(Clone, Debug)]
+pub struct AuditLogEntry {
+  pub user_id: String,
+  pub event_type: AuditEventType,
+  pub timestamp: DateTime<Utc>,
+  /// Device that triggered the event
+  pub device_id: Option<String>,
+  /// Set when a staff member acted on behalf of the user
+  pub staff_user_id: Option<String>,
+  /// Event-specific details, e.g. a linked Farcaster ID
+  pub details: Option<String>,
+}
+
+impl AuditLogEntry {
+  pub fn new(user_id: impl Into<String>, event_type: AuditEventType) -> Self {
+    Self {
+      user_id: user_id.into(),
+      event_type,
+      timestamp: Utc::now(),
+      device_id: None,
+      staff_user_id: None,
+      details: None,
+    }
+  }
+
+  pub fn with_device_id(mut self, device_id: impl Into<String>) -> Self {
+    self.device_id = Some(device_id.into());
+    self
+  }
+
+  pub fn with_staff_user_id(
+    mut self,
+    staff_user_id: impl Into<String>,
+  ) -> Self {
+    self.staff_user_id = Some(staff_user_id.into());
+    self
+  }
+
+  pub fn with_details(mut self, details: impl Into<String>) -> Self {
+    self.details = Some(details.into());
+    self
+  }
+}
+
+impl From<AuditLogEntry> for ProtoAuditLogEntry {
+  fn from(value: AuditLogEntry) -> Self {
+    Self {
+      event_type: value.event_type as i32,
+      timestamp: value.timestamp.timestamp_millis(),
+      device_id: value.device_id,
+      staff_user_id: value.staff_user_id,
+      details: value.details,
+    }
+  }
+}
+
+impl From<AuditLogEntry> for AttributeMap {
+  fn from(value: AuditLogEntry) -> Self {
+    let event_id = format!(
+      "{}#{}",
+      value.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+      generate_uuid()
+    );
+    let mut item = HashMap::from([
+      (PARTITION_KEY.to_string(), AttributeValue::S(value.user_id)),
+      (SORT_KEY.to_string(), AttributeValue::S(event_id)),
+      (
+        ATTR_EVENT_TYPE.to_string(),
+        AttributeValue::S(value.event_type.as_str_name().to_string()),
+      ),
+      (
+        ATTR_TIMESTAMP.to_string(),
+        AttributeValue::S(value.timestamp.to_rfc3339()),
+      ),
+    ]);
+
+    let optional_attrs = [
+      (ATTR_DEVICE_ID, value.device_id),
+      (ATTR_STAFF_USER_ID, value.staff_user_id),
+      (ATTR_DETAILS, value.details),
+    ];
+    for (name, value) in optional_attrs {
+      if let Some(value) = value {
+        item.insert(name.to_string(), AttributeValue::S(value));
+      }
+    }
+    item
+  }
+}
+
+impl TryFrom<AttributeMap> for AuditLogEntry {
+  type Error = DBItemError;
+
+  fn try_from(mut value: AttributeMap) -> Result<Self, Self::Error> {
+    let user_id = value.take_attr(PARTITION_KEY)?;
+    let raw_event_type: String = value.take_attr(ATTR_EVENT_TYPE)?;
+    let event_type = AuditEventType::from_str_name(&raw_event_type)
+      .ok_or_else(|| {
+        DBItemError::new(
+          ATTR_EVENT_TYPE.to_string(),
+          Value::String(raw_event_type),
+          DBItemAttributeError::IncorrectType,
+        )
+      })?;
+    let timestamp = DateTime::<Utc>::try_from_attr(
+      ATTR_TIMESTAMP,
+      value.remove(ATTR_TIMESTAMP),
+    )?;
+
+    Ok(Self {
+      user_id,
+      event_type,
+      timestamp,
+      device_id: value.take_attr(ATTR_DEVICE_ID)?,
+      staff_user_id: value.take_attr(ATTR_STAFF_USER_ID)?,
+      details: value.take_attr(ATTR_DETAILS)?,
+    })
+  }
+}
+
+impl DatabaseClient {
+  /// Appends an entry to user's audit log. Failures are only logged,
+  /// so that they don't fail the audited action.
+  #[tracing::instrument(skip_all)]
+  pub async fn record_audit_event(&self, entry: AuditLogEntry) {
+    let event_type = entry.event_type;
+    let result = self
+      .client
+      .put_item()
+      .table_name(NAME)
+      .set_item(Some(entry.into()))
+      // entries are append-only
+      .condition_expression("attribute_not_exists(#event_id)")
+      .expression_attribute_names("#event_id", SORT_KEY)
+      .send()
+      .await;
+
+    if let Err(e) = result {
+      error!(
+        errorType = error_types::AUDIT_LOG_DB_LOG,
+        "Failed to record {} audit event: {:?}",
+        event_type.as_str_name(),
+        e
+      );
+    }
+  }
+
+  /// Returns a page of user's audit log entries, newest first, and a cursor
+  /// for the next page if there are more entries.
+  #[tracing::instrument(skip_all)]
+  pub async fn get_audit_log(
+    &self,
+    user_id: &str,
+    cursor: Option<String>,
+  ) -> Result<(Vec<AuditLogEntry>, Option<String>), Error> {
+    let mut query = self
+      .client
+      .query()
+      .table_name(NAME)
+      .key_condition_expression("#user_id = :user_id")
+      .expression_attribute_names("#user_id", PARTITION_KEY)
+      .expression_attribute_values(
+        ":user_id",
+        AttributeValue::S(user_id.to_string()),
+      )
+      .scan_index_forward(false)
+      .limit(AUDIT_LOG_PAGE_SIZE);
+
+    if let Some(cursor) = cursor {
+      query = query
+        .exclusive_start_key(
+          PARTITION_KEY,
+          AttributeValue::S(user_id.to_string()),
+        )
+        .exclusive_start_key(SORT_KEY, AttributeValue::S(cursor));
+    }
+
+    let response = query.send().await.map_err(|e| {
+      error!(
+        errorType = error_types::AUDIT_LOG_DB_LOG,
+        "Failed to query audit log: {:?}", e
+      );
+      Error::AwsSdk(e.into())
+    })?;
+
+    let next_cursor = response
+      .last_evaluated_key
+      .and_then(|mut key| key.remove(SORT_KEY))
+      .and_then(|event_id| event_id.as_s().ok().cloned());
+    let entries = response
+      .items
+      .unwrap_or_default()
+      .into_iter()
+      .map(AuditLogEntry::try_from)
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((entries, next_cursor))
+  }
+}
@@ -29,8 +29,8 @@ use crate::{
   comm_service::tunnelbroker,
   constants::{
     devices_table::{self, *},
-    error_types, USERS_TABLE, USERS_TABLE_DEVICELIST_TIMESTAMP_ATTRIBUTE_NAME,
-    USERS_TABLE_PARTITION_KEY,
+    error_types, DEVICE_LIST_HISTORY_PAGE_SIZE, PREKEY_MAX_AGE, USERS_TABLE,
+    USERS_TABLE_DEVICELIST_TIMESTAMP_ATTRIBUTE_NAME, USERS_TABLE_PARTITION_KEY,
   },
   ddb_utils::is_transaction_conflict,
   error::{DeviceListError, Error},
@@ -43,7 +43,10 @@ use crate::{
 };
 use crate::{error::consume_error, log::redact_sensitive_data};
 
-use super::DatabaseClient;
+use super::{
+  key_health::{key_health_shard, key_refresh_due_at},
+  DatabaseClient,
+};
 
 // We omit the content and notif one-time key count attributes from this struct
 // because they are internal helpers and are not provided by users
@@ -423,6 +426,10 @@ impl TryFrom<AttributeMap> for DeviceRow {
 
 impl From<DeviceRow> for AttributeMap {
   fn from(value: DeviceRow) -> Self {
+    let key_health_shard = key_health_shard(&value.device_id);
+    // checked by the key health sweeper once prekeys become stale
+    let key_refresh_due_at =
+      key_refresh_due_at(value.login_time + PREKEY_MAX_AGE);
     HashMap::from([
       (ATTR_USER_ID.to_string(), AttributeValue::S(value.user_id)),
       (
@@ -439,6 +446,13 @@ impl From<DeviceRow> for AttributeMap {
       ),
       (ATTR_CONTENT_PREKEY.to_string(), value.content_prekey.into()),
       (ATTR_NOTIF_PREKEY.to_string(), value.notif_prekey.into()),
+      // prekeys are uploaded when the device logs in
+      (
+        ATTR_PREKEYS_UPDATED_AT.to_string(),
+        AttributeValue::S(value.login_time.to_rfc3339()),
+      ),
+      (ATTR_KEY_REFRESH_DUE_AT.to_string(), key_refresh_due_at),
+      (ATTR_KEY_HEALTH_SHARD.to_string(), key_health_shard),
       // migration attributes
       (
         ATTR_LOGIN_TIME.to_string(),
@@ -783,6 +797,103 @@ impl DatabaseClient {
       .map_err(Error::from)
   }
 
+  /// Returns a page of user's device list history, oldest first, starting
+  /// after the `start_after` timestamp (if provided). If there are more
+  /// updates, also returns the timestamp of the last update on the page,
+  /// so that the next page can start after it.
+  #[tracing::instrument(skip_all)]
+  pub async fn get_device_list_history_page(
+    &self,
+    user_id: &str,
+    start_after: Option<DateTime<Utc>>,
+  ) -> Result<(Vec<DeviceListRow>, Option<DateTime<Utc>>), Error> {
+    let mut query =
+      query_rows_with_prefix(self, user_id, DEVICE_LIST_KEY_PREFIX)
+        .limit(DEVICE_LIST_HISTORY_PAGE_SIZE);
+    if let Some(start_after) = start_after {
+      query = query
+        .exclusive_start_key(
+          ATTR_USER_ID,
+          AttributeValue::S(user_id.to_string()),
+        )
+        .exclusive_start_key(
+          ATTR_ITEM_ID,
+          DeviceListKeyAttribute(start_after).into(),
+        );
+    }
+
+    let response = query.send().await.map_err(|e| {
+      error!(
+        errorType = error_types::DEVICE_LIST_DB_LOG,
+        "Failed to query device list history page: {:?}", e
+      );
+      Error::AwsSdk(e.into())
+    })?;
+
+    let next_start_after = response
+      .last_evaluated_key
+      .map(|mut key| {
+        let DeviceListKeyAttribute(timestamp) =
+          key.remove(ATTR_ITEM_ID).try_into()?;
+        Ok::<_, DBItemError>(timestamp)
+      })
+      .transpose()?;
+    let rows = response
+      .items
+      .unwrap_or_default()
+      .into_iter()
+      .map(DeviceListRow::try_from)
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((rows, next_start_after))
+  }
+
+  /// Gets the device list that was current at the given time, i.e. the latest
+  /// one not newer than `timestamp`
+  #[tracing::instrument(skip_all)]
+  pub async fn get_device_list_at(
+    &self,
+    user_id: impl Into<String>,
+    timestamp: DateTime<Utc>,
+  ) -> Result<Option<DeviceListRow>, Error> {
+    self
+      .client
+      .query()
+      .table_name(devices_table::NAME)
+      .index_name(devices_table::TIMESTAMP_INDEX_NAME)
+      .consistent_read(true)
+      .key_condition_expression(
+        "#user_id = :user_id AND #timestamp <= :timestamp",
+      )
+      // sort descending
+      .scan_index_forward(false)
+      .expression_attribute_names("#user_id", ATTR_USER_ID)
+      .expression_attribute_names("#timestamp", ATTR_TIMESTAMP)
+      .expression_attribute_values(
+        ":user_id",
+        AttributeValue::S(user_id.into()),
+      )
+      .expression_attribute_values(
+        ":timestamp",
+        AttributeValue::N(timestamp.timestamp_millis().to_string()),
+      )
+      .limit(1)
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::DEVICE_LIST_DB_LOG,
+          "Failed to query device list at timestamp: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?
+      .items
+      .and_then(|mut items| items.pop())
+      .map(DeviceListRow::try_from)
+      .transpose()
+      .map_err(Error::from)
+  }
+
   /// Returns all devices' keys for the given user. Response is in the same format
   /// as [DatabaseClient::get_keys_for_user] for compatibility reasons.
   #[tracing::instrument(skip_all)]
@@ -877,24 +988,45 @@ impl DatabaseClient {
       return Err(Error::InvalidFormat);
     }
 
+    let device_id: String = device_id.into();
+    let now = Utc::now();
     let db_operation = self
       .client
       .update_item()
       .table_name(devices_table::NAME)
       .key(ATTR_USER_ID, AttributeValue::S(user_id.into()))
-      .key(ATTR_ITEM_ID, DeviceIDAttribute(device_id.into()).into())
+      .key(ATTR_ITEM_ID, DeviceIDAttribute(device_id.clone()).into())
       .condition_expression(
         "attribute_exists(#user_id) AND attribute_exists(#item_id)",
       )
+      // an earlier check is kept, the sweeper reschedules healthy devices
       .update_expression(
-        "SET #content_prekey = :content_prekey, #notif_prekey = :notif_prekey",
+        "SET #content_prekey = :content_prekey, #notif_prekey = :notif_prekey, \
+        #prekeys_updated_at = :now, \
+        #due_at = if_not_exists(#due_at, :due_at), \
+        #shard = if_not_exists(#shard, :shard)",
       )
       .expression_attribute_names("#user_id", ATTR_USER_ID)
       .expression_attribute_names("#item_id", ATTR_ITEM_ID)
       .expression_attribute_names("#content_prekey", ATTR_CONTENT_PREKEY)
       .expression_attribute_names("#notif_prekey", ATTR_NOTIF_PREKEY)
+      .expression_attribute_names(
+        "#prekeys_updated_at",
+        ATTR_PREKEYS_UPDATED_AT,
+      )
+      .expression_attribute_names("#due_at", ATTR_KEY_REFRESH_DUE_AT)
+      .expression_attribute_names("#shard", ATTR_KEY_HEALTH_SHARD)
       .expression_attribute_values(":content_prekey", content_prekey.into())
-      .expression_attribute_values(":notif_prekey", notif_prekey.into());
+      .expression_attribute_values(":notif_prekey", notif_prekey.into())
+      .expression_attribute_values(
+        ":now",
+        AttributeValue::S(now.to_rfc3339()),
+      )
+      .expression_attribute_values(
+        ":due_at",
+        key_refresh_due_at(now + PREKEY_MAX_AGE),
+      )
+      .expression_attribute_values(":shard", key_health_shard(&device_id));
 
     let retry_config = ExponentialBackoffConfig::default();
     let mut exponential_backoff = retry_config.new_counter();
@@ -2502,6 +2634,132 @@ mod migration {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use chrono::Duration;
+  use comm_lib::database::localstack::{localstack_config, random_user_id};
+
+  async fn localstack_db_client() -> DatabaseClient {
+    DatabaseClient::new(&localstack_config().await)
+  }
+
+  async fn put_device_list(
+    db_client: &DatabaseClient,
+    user_id: &str,
+    timestamp: DateTime<Utc>,
+    device_ids: &[&str],
+  ) {
+    let row = DeviceListRow {
+      user_id: user_id.to_string(),
+      timestamp,
+      device_ids: device_ids.iter().map(ToString::to_string).collect(),
+      current_primary_signature: None,
+      last_primary_signature: None,
+    };
+    db_client
+      .client
+      .put_item()
+      .table_name(devices_table::NAME)
+      .set_item(Some(row.into()))
+      .send()
+      .await
+      .expect("Failed to put device list");
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_device_list_history_pages() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let num_updates = DEVICE_LIST_HISTORY_PAGE_SIZE as i64 + 5;
+    // sub-millisecond precision checks that the page boundary round-trips
+    let first_timestamp = DateTime::from_timestamp(1_700_000_000, 123_456_789)
+      .expect("Invalid timestamp");
+    let timestamps: Vec<_> = (0..num_updates)
+      .map(|i| first_timestamp + Duration::seconds(i))
+      .collect();
+    for timestamp in &timestamps {
+      put_device_list(&db_client, &user_id, *timestamp, &["device"]).await;
+    }
+
+    let (first_page, start_after) = db_client
+      .get_device_list_history_page(&user_id, None)
+      .await
+      .expect("Failed to get first page");
+    assert_eq!(first_page.len(), DEVICE_LIST_HISTORY_PAGE_SIZE as usize);
+    let start_after = start_after.expect("Missing next page");
+    assert_eq!(Some(&start_after), timestamps.get(first_page.len() - 1));
+
+    let (second_page, start_after) = db_client
+      .get_device_list_history_page(&user_id, Some(start_after))
+      .await
+      .expect("Failed to get second page");
+    assert!(start_after.is_none(), "Unexpected third page");
+
+    let paged_timestamps: Vec<_> = first_page
+      .iter()
+      .chain(&second_page)
+      .map(|row| row.timestamp)
+      .collect();
+    assert_eq!(paged_timestamps, timestamps);
+
+    let (since_page, _) = db_client
+      .get_device_list_history_page(&user_id, Some(timestamps[2]))
+      .await
+      .expect("Failed to get page since timestamp");
+    assert_eq!(
+      since_page.first().map(|row| row.timestamp),
+      Some(timestamps[3])
+    );
+  }
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_device_list_at_timestamp() {
+    let db_client = localstack_db_client().await;
+    let user_id = random_user_id();
+
+    let first_timestamp = DateTime::from_timestamp_millis(1_700_000_000_000)
+      .expect("Invalid timestamp");
+    let second_timestamp = first_timestamp + Duration::seconds(10);
+    put_device_list(&db_client, &user_id, first_timestamp, &["device1"]).await;
+    put_device_list(
+      &db_client,
+      &user_id,
+      second_timestamp,
+      &["device2", "device3"],
+    )
+    .await;
+
+    let device_ids_at = |timestamp| {
+      let db_client = &db_client;
+      let user_id = &user_id;
+      async move {
+        db_client
+          .get_device_list_at(user_id, timestamp)
+          .await
+          .expect("Failed to get device list")
+          .map(|row| row.device_ids)
+      }
+    };
+
+    let before_first = first_timestamp - Duration::milliseconds(1);
+    assert!(device_ids_at(before_first).await.is_none(), "No list yet");
+    assert_eq!(
+      device_ids_at(first_timestamp).await,
+      Some(vec!["device1".into()])
+    );
+
+    let from_list = device_ids_at(first_timestamp + Duration::seconds(5))
+      .await
+      .expect("Missing first list");
+    let to_list = device_ids_at(second_timestamp + Duration::seconds(5))
+      .await
+      .expect("Missing second list");
+    let (added, removed) =
+      crate::device_list::device_list_diff(&from_list, &to_list);
+    assert_eq!(added, vec!["device2".to_string(), "device3".to_string()]);
+    assert_eq!(removed, vec!["device1".to_string()]);
+  }
 
   #[test]
   fn test_prekey_ed25519_verification() {
@@ -5,6 +5,7 @@ use crate::constants::USERS_TABLE;
 use crate::constants::USERS_TABLE_FARCASTER_DCS_TOKEN_ATTRIBUTE_NAME;
 use crate::constants::USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME;
 use crate::constants::USERS_TABLE_FARCASTER_ID_INDEX;
+use crate::constants::USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME;
 use crate::constants::USERS_TABLE_PARTITION_KEY;
 use crate::constants::USERS_TABLE_USERNAME_ATTRIBUTE;
 use crate::constants::USERS_TABLE_WALLET_ADDRESS_ATTRIBUTE;
@@ -131,6 +132,58 @@ impl DatabaseClient {
     Ok(())
   }
 
+  /// Sets or removes the Farcaster username of the user, as long as
+  /// the FID it belongs to is still linked
+  #[tracing::instrument(skip_all)]
+  pub async fn update_farcaster_username(
+    &self,
+    user_id: &str,
+    farcaster_id: &str,
+    farcaster_username: Option<String>,
+  ) -> Result<(), Error> {
+    let request = self
+      .client
+      .update_item()
+      .table_name(USERS_TABLE)
+      .key(
+        USERS_TABLE_PARTITION_KEY,
+        AttributeValue::S(user_id.to_string()),
+      )
+      .condition_expression("#farcaster_id = :farcaster_id")
+      .expression_attribute_names(
+        "#farcaster_id",
+        USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#farcaster_username",
+        USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_values(
+        ":farcaster_id",
+        AttributeValue::S(farcaster_id.to_string()),
+      );
+    let request = match farcaster_username {
+      Some(farcaster_username) => request
+        .update_expression("SET #farcaster_username = :farcaster_username")
+        .expression_attribute_values(
+          ":farcaster_username",
+          AttributeValue::S(farcaster_username),
+        ),
+      None => request.update_expression("REMOVE #farcaster_username"),
+    };
+
+    match request.send().await.map_err(DynamoDBError::from) {
+      Ok(_) | Err(DynamoDBError::ConditionalCheckFailedException(_)) => Ok(()),
+      Err(e) => {
+        error!(
+          errorType = error_types::FARCASTER_DB_LOG,
+          "DDB client failed to update Farcaster username: {:?}", e
+        );
+        Err(Error::AwsSdk(e))
+      }
+    }
+  }
+
   pub async fn add_farcaster_dcs_token(
     &self,
     user_id: String,
@@ -244,9 +297,10 @@ impl DatabaseClient {
 
     // Remove farcaster data from users table
     let update_expression = format!(
-      "REMOVE {}, {}",
+      "REMOVE {}, {}, {}",
       USERS_TABLE_FARCASTER_ID_ATTRIBUTE_NAME,
-      USERS_TABLE_FARCASTER_DCS_TOKEN_ATTRIBUTE_NAME
+      USERS_TABLE_FARCASTER_DCS_TOKEN_ATTRIBUTE_NAME,
+      USERS_TABLE_FARCASTER_USERNAME_ATTRIBUTE_NAME
     );
 
     let update_users = Update::builder()
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use comm_lib::{
+  aws::{ddb::types::AttributeValue, DynamoDBError},
+  database::{
+    parse_int_attribute, AttributeExtractor, AttributeMap,
+    DBItemAttributeError, DBItemError,
+  },
+};
+use tracing::error;
+
+use super::{DatabaseClient, DeviceIDAttribute};
+use crate::{
+  constants::{
+    devices_table::*, error_types, KEY_HEALTH_SHARD_COUNT,
+    KEY_REFRESH_REQUEST_COOLDOWN, ONE_TIME_KEY_MINIMUM_THRESHOLD,
+    PREKEY_MAX_AGE,
+  },
+  error::Error,
+};
+
+/// Key inventory of a device, without the keys themselves
+#[derive(Clone, Debug)]
+pub struct DeviceKeyHealth {
+  pub user_id: String,
+  pub device_id: String,
+  pub content_otk_count: usize,
+  pub notif_otk_count: usize,
+  /// `None` for devices that haven't rotated prekeys since this was tracked
+  pub prekeys_updated_at: Option<DateTime<Utc>>,
+  /// Last time the device was asked to refresh keys by the sweeper
+  pub last_refresh_request: Option<DateTime<Utc>>,
+  /// Number of times peers got no one-time key and fell back to the prekey
+  pub content_otk_exhaustion_count: usize,
+  pub notif_otk_exhaustion_count: usize,
+  pub last_otk_exhaustion: Option<DateTime<Utc>>,
+}
+
+impl DeviceKeyHealth {
+  pub fn has_few_one_time_keys(&self) -> bool {
+    self.content_otk_count < ONE_TIME_KEY_MINIMUM_THRESHOLD
+      || self.notif_otk_count < ONE_TIME_KEY_MINIMUM_THRESHOLD
+  }
+
+  /// Prekey age is unknown for devices that haven't rotated prekeys since
+  /// it was tracked, so they aren't reported as stale
+  pub fn has_stale_prekeys(&self, now: DateTime<Utc>) -> bool {
+    self
+      .prekeys_updated_at
+      .is_some_and(|updated_at| updated_at + PREKEY_MAX_AGE < now)
+  }
+
+  pub fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+    self.has_few_one_time_keys() || self.has_stale_prekeys(now)
+  }
+
+  /// Whether the sweeper should send a refresh request to the device
+  pub fn should_request_refresh(&self, now: DateTime<Utc>) -> bool {
+    let recently_requested =
+      self.last_refresh_request.is_some_and(|requested_at| {
+        requested_at + KEY_REFRESH_REQUEST_COOLDOWN > now
+      });
+    self.needs_refresh(now) && !recently_requested
+  }
+
+  /// When the sweeper should check the device next. `None` if it shouldn't
+  /// be checked until keys are updated.
+  pub fn next_refresh_due(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if self.needs_refresh(now) {
+      let cooldown_end = self
+        .last_refresh_request
+        .map(|requested_at| requested_at + KEY_REFRESH_REQUEST_COOLDOWN);
+      return Some(cooldown_end.map_or(now, |end| end.max(now)));
+    }
+    self
+      .prekeys_updated_at
+      .map(|updated_at| updated_at + PREKEY_MAX_AGE)
+  }
+}
+
+/// Partition of the key refresh due index the device belongs to
+pub(super) fn key_health_shard(device_id: &str) -> AttributeValue {
+  let hash = device_id.bytes().fold(0u32, |hash, byte| {
+    hash.wrapping_mul(31).wrapping_add(byte as u32)
+  });
+  AttributeValue::N((hash % KEY_HEALTH_SHARD_COUNT).to_string())
+}
+
+pub(super) fn key_refresh_due_at(time: DateTime<Utc>) -> AttributeValue {
+  AttributeValue::N(time.timestamp().to_string())
+}
+
+impl TryFrom<AttributeMap> for DeviceKeyHealth {
+  type Error = DBItemError;
+
+  fn try_from(mut attrs: AttributeMap) -> Result<Self, Self::Error> {
+    let user_id = attrs.take_attr(ATTR_USER_ID)?;
+    let DeviceIDAttribute(device_id) = attrs.remove(ATTR_ITEM_ID).try_into()?;
+    let content_otk_count = take_count(&mut attrs, ATTR_CONTENT_OTK_COUNT)?;
+    let notif_otk_count = take_count(&mut attrs, ATTR_NOTIF_OTK_COUNT)?;
+    let prekeys_updated_at = attrs.take_attr(ATTR_PREKEYS_UPDATED_AT)?;
+    let last_refresh_request =
+      attrs.take_attr(ATTR_LAST_KEY_REFRESH_REQUEST)?;
+    let content_otk_exhaustion_count =
+      take_count(&mut attrs, ATTR_CONTENT_OTK_EXHAUSTION_COUNT)?;
+    let notif_otk_exhaustion_count =
+      take_count(&mut attrs, ATTR_NOTIF_OTK_EXHAUSTION_COUNT)?;
+    let last_otk_exhaustion = attrs.take_attr(ATTR_LAST_OTK_EXHAUSTION)?;
+
+    Ok(Self {
+      user_id,
+      device_id,
+      content_otk_count,
+      notif_otk_count,
+      prekeys_updated_at,
+      last_refresh_request,
+      content_otk_exhaustion_count,
+      notif_otk_exhaustion_count,
+      last_otk_exhaustion,
+    })
+  }
+}
+
+/// Count attributes are missing until first incremented
+fn take_count(
+  attrs: &mut AttributeMap,
+  attr_name: &str,
+) -> Result<usize, DBItemError> {
+  match parse_int_attribute(attr_name, attrs.remove(attr_name)) {
+    Ok(count) => Ok(count),
+    Err(DBItemError {
+      attribute_error: DBItemAttributeError::Missing,
+      ..
+    }) => Ok(0),
+    Err(err) => Err(err),
+  }
+}
+
+const KEY_HEALTH_PROJECTION: &str =
+  "#user_id, #item_id, #content_otk_count, #notif_otk_count, \
+  #prekeys_updated_at, #last_refresh_request, #content_exhaustion_count, \
+  #notif_exhaustion_count, #last_exhaustion";
+
+fn key_health_projection_names() -> HashMap<String, String> {
+  HashMap::from([
+    ("#user_id".to_string(), ATTR_USER_ID.to_string()),
+    ("#item_id".to_string(), ATTR_ITEM_ID.to_string()),
+    (
+      "#content_otk_count".to_string(),
+      ATTR_CONTENT_OTK_COUNT.to_string(),
+    ),
+    (
+      "#notif_otk_count".to_string(),
+      ATTR_NOTIF_OTK_COUNT.to_string(),
+    ),
+    (
+      "#prekeys_updated_at".to_string(),
+      ATTR_PREKEYS_UPDATED_AT.to_string(),
+    ),
+    (
+      "#last_refresh_request".to_string(),
+      ATTR_LAST_KEY_REFRESH_REQUEST.to_string(),
+    ),
+    (
+      "#content_exhaustion_count".to_string(),
+      ATTR_CONTENT_OTK_EXHAUSTION_COUNT.to_string(),
+    ),
+    (
+      "#notif_exhaustion_count".to_string(),
+      ATTR_NOTIF_OTK_EXHAUSTION_COUNT.to_string(),
+    ),
+    (
+      "#last_exhaustion".to_string(),
+      ATTR_LAST_OTK_EXHAUSTION.to_string(),
+    ),
+  ])
+}
+
+impl DatabaseClient {
+  #[tracing::instrument(skip_all)]
+  pub async fn get_device_key_health(
+    &self,
+    user_id: &str,
+    device_id: &str,
+  ) -> Result<Option<DeviceKeyHealth>, Error> {
+    let response = self
+      .client
+      .get_item()
+      .table_name(NAME)
+      .key(ATTR_USER_ID, AttributeValue::S(user_id.to_string()))
+      .key(ATTR_ITEM_ID, DeviceIDAttribute(device_id.into()).into())
+      .projection_expression(KEY_HEALTH_PROJECTION)
+      .set_expression_attribute_names(Some(key_health_projection_names()))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::OTK_DB_LOG,
+          "Failed to get device key health: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    let key_health =
+      response.item.map(DeviceKeyHealth::try_from).transpose()?;
+    Ok(key_health)
+  }
+
+  /// Queries key health of devices whose key refresh is due
+  #[tracing::instrument(skip_all)]
+  pub async fn get_devices_due_for_key_refresh(
+    &self,
+    now: DateTime<Utc>,
+  ) -> Result<Vec<DeviceKeyHealth>, Error> {
+    let mut result = Vec::new();
+
+    for shard in 0..KEY_HEALTH_SHARD_COUNT {
+      let mut exclusive_start_key = None;
+      loop {
+        let mut expression_attribute_names = key_health_projection_names();
+        expression_attribute_names
+          .insert("#shard".to_string(), ATTR_KEY_HEALTH_SHARD.to_string());
+        expression_attribute_names
+          .insert("#due_at".to_string(), ATTR_KEY_REFRESH_DUE_AT.to_string());
+
+        let query_output = self
+          .client
+          .query()
+          .table_name(NAME)
+          .index_name(KEY_REFRESH_DUE_INDEX_NAME)
+          .key_condition_expression("#shard = :shard AND #due_at <= :now")
+          .expression_attribute_values(
+            ":shard",
+            AttributeValue::N(shard.to_string()),
+          )
+          .expression_attribute_values(":now", key_refresh_due_at(now))
+          .projection_expression(KEY_HEALTH_PROJECTION)
+          .set_expression_attribute_names(Some(expression_attribute_names))
+          .set_exclusive_start_key(exclusive_start_key)
+          .send()
+          .await
+          .map_err(|e| {
+            error!(
+              errorType = error_types::OTK_DB_LOG,
+              "Failed to query devices due for key refresh: {:?}", e
+            );
+            Error::AwsSdk(e.into())
+          })?;
+
+        for item in query_output.items.unwrap_or_default() {
+          match DeviceKeyHealth::try_from(item) {
+            Ok(key_health) => result.push(key_health),
+            Err(err) => error!(
+              errorType = error_types::KEY_HEALTH_LOG,
+              "Failed to parse device key health: {:?}", err
+            ),
+          }
+        }
+
+        exclusive_start_key = query_output.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+          break;
+        }
+      }
+    }
+
+    Ok(result)
+  }
+
+  /// Adds devices registered before key health was tracked to the key
+  /// refresh due index. Safe to run while the service is running.
+  /// Returns the number of updated devices.
+  #[tracing::instrument(skip_all)]
+  pub async fn backfill_key_refresh_schedule(
+    &self,
+    now: DateTime<Utc>,
+  ) -> Result<usize, Error> {
+    let mut updated = 0;
+    let mut exclusive_start_key = None;
+
+    loop {
+      let mut expression_attribute_names = key_health_projection_names();
+      expression_attribute_names
+        .insert("#shard".to_string(), ATTR_KEY_HEALTH_SHARD.to_string());
+
+      let scan_output = self
+        .client
+        .scan()
+        .table_name(NAME)
+        .filter_expression(
+          "begins_with(#item_id, :device_prefix) \
+          AND attribute_not_exists(#shard)",
+        )
+        .expression_attribute_values(
+          ":device_prefix",
+          AttributeValue::S(DEVICE_ITEM_KEY_PREFIX.to_string()),
+        )
+        .projection_expression(KEY_HEALTH_PROJECTION)
+        .set_expression_attribute_names(Some(expression_attribute_names))
+        .set_exclusive_start_key(exclusive_start_key)
+        .send()
+        .await
+        .map_err(|e| {
+          error!(
+            errorType = error_types::OTK_DB_LOG,
+            "Failed to scan devices for key refresh backfill: {:?}", e
+          );
+          Error::AwsSdk(e.into())
+        })?;
+
+      for item in scan_output.items.unwrap_or_default() {
+        let key_health = match DeviceKeyHealth::try_from(item) {
+          Ok(key_health) => key_health,
+          Err(err) => {
+            error!(
+              errorType = error_types::KEY_HEALTH_LOG,
+              "Failed to parse device key health: {:?}", err
+            );
+            continue;
+          }
+        };
+
+        let request = self
+          .client
+          .update_item()
+          .table_name(NAME)
+          .key(ATTR_USER_ID, AttributeValue::S(key_health.user_id.clone()))
+          .key(
+            ATTR_ITEM_ID,
+            DeviceIDAttribute(key_health.device_id.clone()).into(),
+          )
+          // the device could have been removed or updated since the scan
+          .condition_expression(
+            "attribute_exists(#user_id) AND attribute_not_exists(#shard)",
+          )
+          .expression_attribute_names("#user_id", ATTR_USER_ID)
+          .expression_attribute_names("#shard", ATTR_KEY_HEALTH_SHARD)
+          .expression_attribute_values(
+            ":shard",
+            key_health_shard(&key_health.device_id),
+          );
+        let request = match key_health.next_refresh_due(now) {
+          Some(due_at) => request
+            .update_expression("SET #shard = :shard, #due_at = :due_at")
+            .expression_attribute_names("#due_at", ATTR_KEY_REFRESH_DUE_AT)
+            .expression_attribute_values(":due_at", key_refresh_due_at(due_at)),
+          None => request.update_expression("SET #shard = :shard"),
+        };
+
+        match request.send().await.map_err(DynamoDBError::from) {
+          Ok(_) => updated += 1,
+          Err(DynamoDBError::ConditionalCheckFailedException(_)) => (),
+          Err(e) => {
+            error!(
+              errorType = error_types::OTK_DB_LOG,
+              "Failed to backfill key refresh schedule: {:?}", e
+            );
+            return Err(Error::AwsSdk(e));
+          }
+        }
+      }
+
+      exclusive_start_key = scan_output.last_evaluated_key;
+      if exclusive_start_key.is_none() {
+        break;
+      }
+    }
+
+    Ok(updated)
+  }
+
+  /// Sets when the sweeper should check the device next.
+  /// `None` removes the device from the key refresh due index.
+  #[tracing::instrument(skip_all)]
+  pub async fn reschedule_key_refresh(
+    &self,
+    user_id: &str,
+    device_id: &str,
+    due_at: Option<DateTime<Utc>>,
+  ) -> Result<(), Error> {
+    let request = self
+      .client
+      .update_item()
+      .table_name(NAME)
+      .key(ATTR_USER_ID, AttributeValue::S(user_id.to_string()))
+      .key(ATTR_ITEM_ID, DeviceIDAttribute(device_id.into()).into())
+      // don't recreate rows of removed devices
+      .condition_expression("attribute_exists(#user_id)")
+      .expression_attribute_names("#user_id", ATTR_USER_ID)
+      .expression_attribute_names("#due_at", ATTR_KEY_REFRESH_DUE_AT);
+    let request = match due_at {
+      Some(due_at) => request
+        .update_expression("SET #due_at = :due_at")
+        .expression_attribute_values(":due_at", key_refresh_due_at(due_at)),
+      None => request.update_expression("REMOVE #due_at"),
+    };
+
+    match request.send().await.map_err(DynamoDBError::from) {
+      Ok(_) | Err(DynamoDBError::ConditionalCheckFailedException(_)) => Ok(()),
+      Err(e) => {
+        error!(
+          errorType = error_types::OTK_DB_LOG,
+          "Failed to reschedule key refresh: {:?}", e
+        );
+        Err(Error::AwsSdk(e))
+      }
+    }
+  }
+
+  /// Records that the device was asked to refresh keys and reschedules
+  /// the next check for the end of the cooldown. Returns `false` if another
+  /// request was recorded within the cooldown period.
+  #[tracing::instrument(skip_all)]
+  pub async fn try_mark_key_refresh_requested(
+    &self,
+    user_id: &str,
+    device_id: &str,
+    now: DateTime<Utc>,
+  ) -> Result<bool, Error> {
+    let cooldown_start = now - KEY_REFRESH_REQUEST_COOLDOWN;
+    let result = self
+      .client
+      .update_item()
+      .table_name(NAME)
+      .key(ATTR_USER_ID, AttributeValue::S(user_id.to_string()))
+      .key(ATTR_ITEM_ID, DeviceIDAttribute(device_id.into()).into())
+      .update_expression(
+        "SET #last_refresh_request = :now, #due_at = :cooldown_end",
+      )
+      .condition_expression(
+        "attribute_exists(#user_id) AND \
+        (attribute_not_exists(#last_refresh_request) \
+        OR #last_refresh_request < :cooldown_start)",
+      )
+      .expression_attribute_names("#user_id", ATTR_USER_ID)
+      .expression_attribute_names(
+        "#last_refresh_request",
+        ATTR_LAST_KEY_REFRESH_REQUEST,
+      )
+      .expression_attribute_names("#due_at", ATTR_KEY_REFRESH_DUE_AT)
+      .expression_attribute_values(":now", AttributeValue::S(now.to_rfc3339()))
+      .expression_attribute_values(
+        ":cooldown_start",
+        AttributeValue::S(cooldown_start.to_rfc3339()),
+      )
+      .expression_attribute_values(
+        ":cooldown_end",
+        key_refresh_due_at(now + KEY_REFRESH_REQUEST_COOLDOWN),
+      )
+      .send()
+      .await;
+
+    match result.map_err(DynamoDBError::from) {
+      Ok(_) => Ok(true),
+      Err(DynamoDBError::ConditionalCheckFailedException(_)) => Ok(false),
+      Err(e) => {
+        error!(
+          errorType = error_types::OTK_DB_LOG,
+          "Failed to record key refresh request: {:?}", e
+        );
+        Err(Error::AwsSdk(e))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key_health(
+    content_otk_count: usize,
+    notif_otk_count: usize,
+    prekeys_updated_at: Option<DateTime<Utc>>,
+  ) -> DeviceKeyHealth {
+    DeviceKeyHealth {
+      user_id: "user".to_string(),
+      device_id: "device".to_string(),
+      content_otk_count,
+      notif_otk_count,
+      prekeys_updated_at,
+      last_refresh_request: None,
+      content_otk_exhaustion_count: 0,
+      notif_otk_exhaustion_count: 0,
+      last_otk_exhaustion: None,
+    }
+  }
+
+  #[test]
+  fn test_healthy_device() {
+    let now = Utc::now();
+    let health = key_health(50, 50, Some(now));
+    assert!(!health.needs_refresh(now));
+    assert!(!health.should_request_refresh(now));
+  }
+
+  #[test]
+  fn test_few_one_time_keys() {
+    let now = Utc::now();
+    assert!(key_health(0, 50, Some(now)).needs_refresh(now));
+    assert!(key_health(50, 1, Some(now)).needs_refresh(now));
+  }
+
+  #[test]
+  fn test_stale_prekeys() {
+    let now = Utc::now();
+    let stale = now - PREKEY_MAX_AGE - chrono::Duration::seconds(1);
+    assert!(key_health(50, 50, Some(stale)).has_stale_prekeys(now));
+    // upload time wasn't tracked before
+    assert!(!key_health(50, 50, None).has_stale_prekeys(now));
+  }
+
+  #[test]
+  fn test_next_refresh_due() {
+    let now = Utc::now();
+    let health = key_health(50, 50, Some(now));
+    assert_eq!(health.next_refresh_due(now), Some(now + PREKEY_MAX_AGE));
+    assert_eq!(key_health(50, 50, None).next_refresh_due(now), None);
+
+    let mut health = key_health(0, 50, Some(now));
+    assert_eq!(health.next_refresh_due(now), Some(now));
+    health.last_refresh_request = Some(now);
+    assert_eq!(
+      health.next_refresh_due(now),
+      Some(now + KEY_REFRESH_REQUEST_COOLDOWN)
+    );
+  }
+
+  #[test]
+  fn test_refresh_request_cooldown() {
+    let now = Utc::now();
+    let mut health = key_health(0, 0, Some(now));
+    health.last_refresh_request = Some(now - chrono::Duration::hours(1));
+    assert!(!health.should_request_refresh(now));
+
+    health.last_refresh_request =
+      Some(now - KEY_REFRESH_REQUEST_COOLDOWN - chrono::Duration::seconds(1));
+    assert!(health.should_request_refresh(now));
+  }
+}
@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use comm_lib::{
+  aws::{
+    ddb::{
+      operation::update_item::builders::UpdateItemFluentBuilder,
+      types::AttributeValue,
+    },
+    DynamoDBError,
+  },
+  database::{DBItemAttributeError, DBItemError, TryFromAttribute, Value},
+};
+use tracing::error;
+
+use super::DatabaseClient;
+use crate::{
+  constants::{error_types, login_attempts_table::*, LOGIN_ATTEMPTS_TTL},
+  error::Error,
+  login_attempts::{AttemptSubject, LoginAttempts},
+};
+
+impl DatabaseClient {
+  #[tracing::instrument(skip_all)]
+  pub async fn get_login_attempts(
+    &self,
+    subject: &AttemptSubject,
+  ) -> Result<Option<LoginAttempts>, Error> {
+    let response = self
+      .client
+      .get_item()
+      .table_name(NAME)
+      .key(PARTITION_KEY, AttributeValue::S(subject.key()))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::LOGIN_ATTEMPTS_DB_LOG,
+          "DDB client failed to get login attempts: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    let Some(mut item) = response.item else {
+      return Ok(None);
+    };
+
+    // DynamoDB doesn't delete expired items immediately
+    let expiration_time = item
+      .remove(ATTR_EXPIRATION_TIME_UNIX)
+      .and_then(|attr| attr.as_n().ok().cloned())
+      .and_then(|val| val.parse::<i64>().ok());
+    if matches!(expiration_time, Some(t) if t <= Utc::now().timestamp()) {
+      return Ok(None);
+    }
+
+    let raw_attempts = item.remove(ATTR_ATTEMPTS);
+    let attempts = raw_attempts
+      .as_ref()
+      .and_then(|attr| attr.as_n().ok())
+      .and_then(|val| val.parse::<u32>().ok())
+      .ok_or_else(|| {
+        DBItemError::new(
+          ATTR_ATTEMPTS.to_string(),
+          Value::AttributeValue(raw_attempts.clone()),
+          DBItemAttributeError::InvalidValue,
+        )
+      })?;
+    let last_attempt = DateTime::<Utc>::try_from_attr(
+      ATTR_LAST_ATTEMPT,
+      item.remove(ATTR_LAST_ATTEMPT),
+    )?;
+
+    Ok(Some(LoginAttempts {
+      subject: subject.clone(),
+      attempts,
+      last_attempt,
+    }))
+  }
+
+  /// Counts an attempt and extends the counter's lifetime. A counter that
+  /// has expired, but wasn't deleted by DynamoDB yet, is started over.
+  #[tracing::instrument(skip_all)]
+  pub async fn increment_login_attempts(
+    &self,
+    subject: &AttemptSubject,
+    attempt_time: DateTime<Utc>,
+  ) -> Result<(), Error> {
+    let result = self
+      .update_login_attempts(
+        subject,
+        attempt_time,
+        "ADD #attempts :one \
+        SET #last_attempt = :last_attempt, #expiration_time = :expiration_time",
+      )
+      .condition_expression(
+        "attribute_not_exists(#expiration_time) OR #expiration_time > :now",
+      )
+      .expression_attribute_values(
+        ":now",
+        AttributeValue::N(attempt_time.timestamp().to_string()),
+      )
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()));
+
+    let result = match result {
+      Err(Error::AwsSdk(DynamoDBError::ConditionalCheckFailedException(_))) => {
+        self
+          .update_login_attempts(
+            subject,
+            attempt_time,
+            "SET #attempts = :one, #last_attempt = :last_attempt, \
+            #expiration_time = :expiration_time",
+          )
+          .send()
+          .await
+          .map_err(|e| Error::AwsSdk(e.into()))
+      }
+      result => result,
+    };
+
+    if let Err(err) = result {
+      error!(
+        errorType = error_types::LOGIN_ATTEMPTS_DB_LOG,
+        "DDB client failed to increment login attempts: {:?}", err
+      );
+      return Err(err);
+    }
+
+    Ok(())
+  }
+
+  /// Update of the attempts counter with given expression,
+  /// which also has to store the attempt time and expiration time
+  fn update_login_attempts(
+    &self,
+    subject: &AttemptSubject,
+    attempt_time: DateTime<Utc>,
+    update_expression: &str,
+  ) -> UpdateItemFluentBuilder {
+    let expiration_time = attempt_time + LOGIN_ATTEMPTS_TTL;
+    self
+      .client
+      .update_item()
+      .table_name(NAME)
+      .key(PARTITION_KEY, AttributeValue::S(subject.key()))
+      .update_expression(update_expression)
+      .expression_attribute_names("#attempts", ATTR_ATTEMPTS)
+      .expression_attribute_names("#last_attempt", ATTR_LAST_ATTEMPT)
+      .expression_attribute_names("#expiration_time", ATTR_EXPIRATION_TIME_UNIX)
+      .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+      .expression_attribute_values(
+        ":last_attempt",
+        AttributeValue::S(attempt_time.to_rfc3339()),
+      )
+      .expression_attribute_values(
+        ":expiration_time",
+        AttributeValue::N(expiration_time.timestamp().to_string()),
+      )
+  }
+
+  /// Called after successful authentication
+  #[tracing::instrument(skip_all)]
+  pub async fn reset_login_attempts(
+    &self,
+    subject: &AttemptSubject,
+  ) -> Result<(), Error> {
+    self
+      .client
+      .delete_item()
+      .table_name(NAME)
+      .key(PARTITION_KEY, AttributeValue::S(subject.key()))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::LOGIN_ATTEMPTS_DB_LOG,
+          "DDB client failed to reset login attempts: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use comm_lib::database::localstack::{localstack_config, random_user_id};
+
+  #[tokio::test]
+  #[ignore = "requires Localstack"]
+  async fn test_expired_login_attempts_start_over() {
+    let db_client = DatabaseClient::new(&localstack_config().await);
+    let subject = AttemptSubject::UserID(random_user_id());
+
+    let expired_attempt_time =
+      Utc::now() - LOGIN_ATTEMPTS_TTL - chrono::Duration::minutes(1);
+    for _ in 0..2 {
+      db_client
+        .increment_login_attempts(&subject, expired_attempt_time)
+        .await
+        .expect("Failed to increment login attempts");
+    }
+    let attempts = db_client
+      .get_login_attempts(&subject)
+      .await
+      .expect("Failed to get login attempts");
+    assert!(attempts.is_none(), "Expired attempts should be ignored");
+
+    let attempt_time = Utc::now();
+    db_client
+      .increment_login_attempts(&subject, attempt_time)
+      .await
+      .expect("Failed to increment login attempts");
+    db_client
+      .increment_login_attempts(&subject, attempt_time)
+      .await
+      .expect("Failed to increment login attempts");
+    let attempts = db_client
+      .get_login_attempts(&subject)
+      .await
+      .expect("Failed to get login attempts")
+      .expect("Missing login attempts");
+    assert_eq!(
+      attempts.attempts, 2,
+      "Expired attempts shouldn't be counted"
+    );
+  }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 
+use chrono::Utc;
 use comm_lib::{
   aws::{
     ddb::types::{
@@ -32,7 +33,10 @@ use crate::{
   olm::is_valid_olm_key,
 };
 
-use super::DatabaseClient;
+use super::{
+  key_health::{key_health_shard, key_refresh_due_at},
+  DatabaseClient,
+};
 
 impl DatabaseClient {
   /// Gets the next one-time key for the account and then, in a transaction,
@@ -74,7 +78,6 @@ impl DatabaseClient {
       ..Default::default()
     };
 
-    // TODO: Introduce nanny task that handles calling `spawn_refresh_keys_task`
     let mut requested_more_keys = false;
 
     let mut exponential_backoff = retry_config.new_counter();
@@ -86,6 +89,9 @@ impl DatabaseClient {
         requested_more_keys = true;
       }
       if otk_count < 1 {
+        self
+          .record_otk_exhaustion(user_id, device_id, account_type)
+          .await;
         return Ok((None, requested_more_keys));
       }
 
@@ -94,12 +100,15 @@ impl DatabaseClient {
         .await?
         .pop()
       else {
+        self
+          .record_otk_exhaustion(user_id, device_id, account_type)
+          .await;
         return Ok((None, requested_more_keys));
       };
 
       let delete_otk_operation = otk_row.as_delete_request();
 
-      let update_otk_count = Update::builder()
+      let mut update_otk_count = Update::builder()
         .table_name(devices_table::NAME)
         .key(
           devices_table::ATTR_USER_ID,
@@ -118,11 +127,29 @@ impl DatabaseClient {
         .expression_attribute_values(
           ":old_val",
           AttributeValue::N(otk_count.to_string()),
-        )
-        .build()
-        .expect(
-          "table_name, key or update_expression not set in Update builder",
         );
+      // the key health sweeper retries if the device doesn't respond
+      // to the refresh request
+      if otk_count <= ONE_TIME_KEY_MINIMUM_THRESHOLD {
+        update_otk_count = update_otk_count
+          .update_expression(format!(
+            "ADD {attr_otk_count} :decrement_val SET #due_at = :now, \
+            #shard = if_not_exists(#shard, :shard)"
+          ))
+          .expression_attribute_names(
+            "#due_at",
+            devices_table::ATTR_KEY_REFRESH_DUE_AT,
+          )
+          .expression_attribute_names(
+            "#shard",
+            devices_table::ATTR_KEY_HEALTH_SHARD,
+          )
+          .expression_attribute_values(":now", key_refresh_due_at(Utc::now()))
+          .expression_attribute_values(":shard", key_health_shard(device_id));
+      }
+      let update_otk_count = update_otk_count.build().expect(
+        "table_name, key or update_expression not set in Update builder",
+      );
 
       let update_otk_count_operation = TransactWriteItem::builder()
         .update(update_otk_count)
@@ -164,6 +191,66 @@ impl DatabaseClient {
     }
   }
 
+  /// Counts requests for the device's keys that had to fall back to the
+  /// prekey. Failures are only logged, as they shouldn't fail key retrieval.
+  #[tracing::instrument(skip_all)]
+  async fn record_otk_exhaustion(
+    &self,
+    user_id: &str,
+    device_id: &str,
+    account_type: OlmAccountType,
+  ) {
+    use crate::constants::devices_table;
+
+    let attr_exhaustion_count = match account_type {
+      OlmAccountType::Content => {
+        devices_table::ATTR_CONTENT_OTK_EXHAUSTION_COUNT
+      }
+      OlmAccountType::Notification => {
+        devices_table::ATTR_NOTIF_OTK_EXHAUSTION_COUNT
+      }
+    };
+
+    let result = self
+      .client
+      .update_item()
+      .table_name(devices_table::NAME)
+      .key(
+        devices_table::ATTR_USER_ID,
+        AttributeValue::S(user_id.to_string()),
+      )
+      .key(
+        devices_table::ATTR_ITEM_ID,
+        DeviceIDAttribute(device_id.into()).into(),
+      )
+      .update_expression(
+        "ADD #exhaustion_count :one SET #last_exhaustion = :now",
+      )
+      // don't recreate rows of removed devices
+      .condition_expression("attribute_exists(#user_id)")
+      .expression_attribute_names("#user_id", devices_table::ATTR_USER_ID)
+      .expression_attribute_names("#exhaustion_count", attr_exhaustion_count)
+      .expression_attribute_names(
+        "#last_exhaustion",
+        devices_table::ATTR_LAST_OTK_EXHAUSTION,
+      )
+      .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+      .expression_attribute_values(
+        ":now",
+        AttributeValue::S(Utc::now().to_rfc3339()),
+      )
+      .send()
+      .await;
+
+    if let Err(e) = result {
+      warn!(
+        "Failed to record one-time key exhaustion for device {}: {:?}",
+        redact_sensitive_data(device_id),
+        DynamoDBError::from(e)
+      );
+    }
+  }
+
   #[tracing::instrument(skip_all)]
   async fn get_one_time_keys(
     &self,
@@ -2,22 +2,26 @@ use std::collections::{HashMap, HashSet};
 
 use chrono::{DateTime, Utc};
 use comm_lib::{
-  aws::ddb::{
-    operation::{get_item::GetItemOutput, put_item::PutItemOutput},
-    types::{AttributeValue, DeleteRequest, WriteRequest},
+  aws::{
+    ddb::{
+      operation::{get_item::GetItemOutput, put_item::PutItemOutput},
+      types::{AttributeValue, DeleteRequest, ReturnValue, WriteRequest},
+    },
+    DynamoDBError,
   },
   database::{
     batch_operations::{batch_write, ExponentialBackoffConfig},
     DBItemAttributeError, DBItemError, TryFromAttribute,
   },
 };
-use constant_time_eq::constant_time_eq;
-use tracing::{error, info};
+use rand::rngs::OsRng;
+use tracing::{error, info, warn};
 
 use crate::{
-  constants::error_types,
+  config::CONFIG,
+  constants::{error_types, ACCESS_TOKEN_ROTATION_OVERLAP},
   error::Error,
-  token::{AccessTokenData, AuthType},
+  token::{AccessTokenData, AuthType, PreviousAccessToken},
 };
 
 use super::{create_composite_primary_key, DatabaseClient};
@@ -45,24 +49,11 @@ impl DatabaseClient {
       .await;
     match get_item_result {
       Ok(GetItemOutput {
-        item: Some(mut item),
-        ..
+        item: Some(item), ..
       }) => {
-        let created = DateTime::<Utc>::try_from_attr(
-          ATTR_CREATED,
-          item.remove(ATTR_CREATED),
-        )?;
-        let auth_type = parse_auth_type_attribute(item.remove(ATTR_AUTH_TYPE))?;
-        let valid = parse_valid_attribute(item.remove(ATTR_VALID))?;
-        let access_token = parse_token_attribute(item.remove(ATTR_TOKEN))?;
-        Ok(Some(AccessTokenData {
-          user_id,
-          signing_public_key,
-          access_token,
-          created,
-          auth_type,
-          valid,
-        }))
+        let access_token_data =
+          parse_access_token_item(user_id, signing_public_key, item)?;
+        Ok(Some(access_token_data))
       }
       Ok(_) => {
         info!(
@@ -81,6 +72,52 @@ impl DatabaseClient {
     }
   }
 
+  /// Returns access token data of all user's devices
+  #[tracing::instrument(skip_all)]
+  pub async fn get_access_tokens_for_user(
+    &self,
+    user_id: &str,
+  ) -> Result<Vec<AccessTokenData>, Error> {
+    use crate::constants::token_table::*;
+
+    let items = self
+      .client
+      .query()
+      .table_name(NAME)
+      .key_condition_expression("#pk = :pk")
+      .expression_attribute_names("#pk", PARTITION_KEY)
+      .expression_attribute_values(
+        ":pk",
+        AttributeValue::S(user_id.to_string()),
+      )
+      .consistent_read(true)
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::TOKEN_DB_LOG,
+          "Failed to list user's items in tokens table: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?
+      .items
+      .unwrap_or_default();
+
+    let mut tokens = Vec::with_capacity(items.len());
+    for mut item in items {
+      let signing_public_key = item
+        .remove(SORT_KEY)
+        .and_then(|attr| attr.as_s().ok().cloned())
+        .ok_or(Error::MalformedItem)?;
+      tokens.push(parse_access_token_item(
+        user_id.to_string(),
+        signing_public_key,
+        item,
+      )?);
+    }
+    Ok(tokens)
+  }
+
   pub async fn verify_access_token(
     &self,
     user_id: String,
@@ -94,18 +131,155 @@ impl DatabaseClient {
       return Ok(false);
     }
 
-    let is_valid = self
+    let Some(access_token_data) = self
       .get_access_token_data(user_id, signing_public_key)
       .await?
-      .map(|access_token_data| {
-        constant_time_eq(
-          access_token_data.access_token.as_bytes(),
-          access_token_to_verify.as_bytes(),
-        ) && access_token_data.is_valid()
-      })
-      .unwrap_or(false);
+    else {
+      return Ok(false);
+    };
+
+    let lifetimes = &CONFIG.access_token_lifetimes;
+    let now = Utc::now();
+    if !access_token_data.verify(&access_token_to_verify, lifetimes, now) {
+      return Ok(false);
+    }
+
+    if access_token_data.needs_last_used_update(lifetimes, now) {
+      // failing to record usage shouldn't fail the request
+      if let Err(err) = self
+        .update_access_token_last_used(&access_token_data, now)
+        .await
+      {
+        warn!("Failed to update access token last use time: {:?}", err);
+      }
+    }
 
-    Ok(is_valid)
+    Ok(true)
+  }
+
+  async fn update_access_token_last_used(
+    &self,
+    access_token_data: &AccessTokenData,
+    now: DateTime<Utc>,
+  ) -> Result<(), Error> {
+    use crate::constants::token_table::*;
+
+    self
+      .client
+      .update_item()
+      .table_name(NAME)
+      .key(
+        PARTITION_KEY,
+        AttributeValue::S(access_token_data.user_id.clone()),
+      )
+      .key(
+        SORT_KEY,
+        AttributeValue::S(access_token_data.signing_public_key.clone()),
+      )
+      .update_expression("SET #last_used = :last_used")
+      .condition_expression("attribute_exists(#token)")
+      .expression_attribute_names("#last_used", ATTR_LAST_USED)
+      .expression_attribute_names("#token", ATTR_TOKEN)
+      .expression_attribute_values(
+        ":last_used",
+        AttributeValue::S(now.to_rfc3339()),
+      )
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()))?;
+
+    Ok(())
+  }
+
+  /// Atomically replaces the access token of a device. The current token
+  /// stays valid for [`ACCESS_TOKEN_ROTATION_OVERLAP`]. Returns `None`
+  /// if `current_access_token` isn't the current token of the device,
+  /// e.g. when it has already been rotated.
+  #[tracing::instrument(skip_all)]
+  pub async fn rotate_access_token(
+    &self,
+    user_id: String,
+    signing_public_key: String,
+    current_access_token: String,
+  ) -> Result<Option<AccessTokenData>, Error> {
+    use crate::constants::token_table::*;
+
+    let now = Utc::now();
+    let overlap =
+      chrono::Duration::seconds(ACCESS_TOKEN_ROTATION_OVERLAP.as_secs() as i64);
+    // auth type is overwritten with the stored one below
+    let new_token = AccessTokenData::with_created_time(
+      user_id.clone(),
+      signing_public_key.clone(),
+      now,
+      AuthType::Password,
+      &mut OsRng,
+    );
+
+    let result = self
+      .client
+      .update_item()
+      .table_name(NAME)
+      .key(PARTITION_KEY, AttributeValue::S(user_id.clone()))
+      .key(SORT_KEY, AttributeValue::S(signing_public_key.clone()))
+      .update_expression(
+        "SET #token = :new_token, #created = :now, #last_used = :now, \
+        #previous_token = :current_token, \
+        #previous_token_valid_until = :previous_token_valid_until",
+      )
+      .condition_expression("#token = :current_token AND #valid = :true")
+      .expression_attribute_names("#token", ATTR_TOKEN)
+      .expression_attribute_names("#created", ATTR_CREATED)
+      .expression_attribute_names("#last_used", ATTR_LAST_USED)
+      .expression_attribute_names("#previous_token", ATTR_PREVIOUS_TOKEN)
+      .expression_attribute_names(
+        "#previous_token_valid_until",
+        ATTR_PREVIOUS_TOKEN_VALID_UNTIL,
+      )
+      .expression_attribute_names("#valid", ATTR_VALID)
+      .expression_attribute_values(
+        ":new_token",
+        AttributeValue::S(new_token.access_token.clone()),
+      )
+      .expression_attribute_values(":now", AttributeValue::S(now.to_rfc3339()))
+      .expression_attribute_values(
+        ":current_token",
+        AttributeValue::S(current_access_token.clone()),
+      )
+      .expression_attribute_values(
+        ":previous_token_valid_until",
+        AttributeValue::S((now + overlap).to_rfc3339()),
+      )
+      .expression_attribute_values(":true", AttributeValue::Bool(true))
+      .return_values(ReturnValue::AllNew)
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()));
+
+    let mut item = match result {
+      Ok(output) => output.attributes.unwrap_or_default(),
+      Err(Error::AwsSdk(DynamoDBError::ConditionalCheckFailedException(_))) => {
+        return Ok(None);
+      }
+      Err(err) => {
+        error!(
+          errorType = error_types::TOKEN_DB_LOG,
+          "DynamoDB client failed to rotate access token: {:?}", err
+        );
+        return Err(err);
+      }
+    };
+
+    let auth_type = parse_auth_type_attribute(item.remove(ATTR_AUTH_TYPE))?;
+    Ok(Some(AccessTokenData {
+      auth_type,
+      last_used: Some(now),
+      previous_token: Some(PreviousAccessToken {
+        access_token: current_access_token,
+        valid_until: now + overlap,
+      }),
+      ..new_token
+    }))
   }
 
   pub async fn put_access_token_data(
@@ -114,7 +288,7 @@ impl DatabaseClient {
   ) -> Result<PutItemOutput, Error> {
     use crate::constants::token_table::*;
 
-    let item = HashMap::from([
+    let mut item = HashMap::from([
       (
         PARTITION_KEY.to_string(),
         AttributeValue::S(access_token_data.user_id),
@@ -143,6 +317,22 @@ impl DatabaseClient {
         AttributeValue::Bool(access_token_data.valid),
       ),
     ]);
+    if let Some(last_used) = access_token_data.last_used {
+      item.insert(
+        ATTR_LAST_USED.to_string(),
+        AttributeValue::S(last_used.to_rfc3339()),
+      );
+    }
+    if let Some(previous_token) = access_token_data.previous_token {
+      item.insert(
+        ATTR_PREVIOUS_TOKEN.to_string(),
+        AttributeValue::S(previous_token.access_token),
+      );
+      item.insert(
+        ATTR_PREVIOUS_TOKEN_VALID_UNTIL.to_string(),
+        AttributeValue::S(previous_token.valid_until.to_rfc3339()),
+      );
+    }
     self
       .client
       .put_item()
@@ -311,3 +501,64 @@ fn parse_token_attribute(
     )),
   }
 }
+
+fn parse_access_token_item(
+  user_id: String,
+  signing_public_key: String,
+  mut item: HashMap<String, AttributeValue>,
+) -> Result<AccessTokenData, DBItemError> {
+  use crate::constants::token_table::*;
+
+  let created =
+    DateTime::<Utc>::try_from_attr(ATTR_CREATED, item.remove(ATTR_CREATED))?;
+  let auth_type = parse_auth_type_attribute(item.remove(ATTR_AUTH_TYPE))?;
+  let valid = parse_valid_attribute(item.remove(ATTR_VALID))?;
+  let access_token = parse_token_attribute(item.remove(ATTR_TOKEN))?;
+  let last_used =
+    parse_optional_timestamp(ATTR_LAST_USED, item.remove(ATTR_LAST_USED))?;
+  let previous_token = parse_previous_token(
+    item.remove(ATTR_PREVIOUS_TOKEN),
+    item.remove(ATTR_PREVIOUS_TOKEN_VALID_UNTIL),
+  )?;
+
+  Ok(AccessTokenData {
+    user_id,
+    signing_public_key,
+    access_token,
+    created,
+    auth_type,
+    valid,
+    last_used,
+    previous_token,
+  })
+}
+
+fn parse_optional_timestamp(
+  attribute_name: &str,
+  attribute: Option<AttributeValue>,
+) -> Result<Option<DateTime<Utc>>, DBItemError> {
+  attribute
+    .map(|value| DateTime::<Utc>::try_from_attr(attribute_name, Some(value)))
+    .transpose()
+}
+
+fn parse_previous_token(
+  token_attribute: Option<AttributeValue>,
+  valid_until_attribute: Option<AttributeValue>,
+) -> Result<Option<PreviousAccessToken>, DBItemError> {
+  use crate::constants::token_table::ATTR_PREVIOUS_TOKEN_VALID_UNTIL;
+
+  let Some(token_attribute) = token_attribute else {
+    return Ok(None);
+  };
+  let access_token = parse_token_attribute(Some(token_attribute))?;
+  let valid_until = DateTime::<Utc>::try_from_attr(
+    ATTR_PREVIOUS_TOKEN_VALID_UNTIL,
+    valid_until_attribute,
+  )?;
+
+  Ok(Some(PreviousAccessToken {
+    access_token,
+    valid_until,
+  }))
+}
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use comm_lib::{
+  aws::{
+    ddb::{primitives::Blob, types::AttributeValue},
+    DynamoDBError,
+  },
+  database::AttributeExtractor,
+};
+use tracing::error;
+
+use super::DatabaseClient;
+use crate::{
+  config::CONFIG,
+  constants::{
+    error_types, USERS_TABLE, USERS_TABLE_PARTITION_KEY,
+    USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME,
+    USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+    USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME,
+  },
+  error::Error,
+  totp,
+};
+
+/// Two-factor authentication settings stored on the user item
+pub struct TotpSettings {
+  /// Base32-encoded secret, decrypted when loaded
+  pub secret: String,
+  pub recovery_code_hashes: HashSet<String>,
+  pub last_used_step: Option<u64>,
+}
+
+impl DatabaseClient {
+  /// Returns `None` if the user doesn't have two-factor authentication enabled
+  #[tracing::instrument(skip_all)]
+  pub async fn get_totp_settings(
+    &self,
+    user_id: &str,
+  ) -> Result<Option<TotpSettings>, Error> {
+    let response = self
+      .client
+      .get_item()
+      .table_name(USERS_TABLE)
+      .key(
+        USERS_TABLE_PARTITION_KEY,
+        AttributeValue::S(user_id.to_string()),
+      )
+      .projection_expression("#secret, #recovery_codes, #last_used_step")
+      .expression_attribute_names(
+        "#secret",
+        USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#recovery_codes",
+        USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#last_used_step",
+        USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME,
+      )
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to get TOTP settings: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    let mut item = response.item.unwrap_or_default();
+    let Some(encrypted_secret) = item
+      .take_attr::<Option<Vec<u8>>>(USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME)?
+    else {
+      return Ok(None);
+    };
+    let secret =
+      totp::decrypt_secret(&encrypted_secret, &CONFIG.totp_encryption_key)
+        .ok_or_else(|| {
+          error!(
+            errorType = error_types::GENERIC_DB_LOG,
+            "Failed to decrypt TOTP secret"
+          );
+          Error::MalformedItem
+        })?;
+
+    // DynamoDB removes the set when the last code is used
+    let recovery_code_hashes = item
+      .take_attr::<Option<HashSet<String>>>(
+        USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+      )?
+      .unwrap_or_default();
+    let last_used_step = item
+      .remove(USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME)
+      .and_then(|attr| attr.as_n().ok().cloned())
+      .and_then(|val| val.parse::<u64>().ok());
+
+    Ok(Some(TotpSettings {
+      secret,
+      recovery_code_hashes,
+      last_used_step,
+    }))
+  }
+
+  /// Fails with [`Error::CannotOverwrite`] if two-factor authentication
+  /// is already enabled. The code used to confirm enrollment can't be
+  /// used again, so its step is saved as `last_used_step`.
+  #[tracing::instrument(skip_all)]
+  pub async fn enable_totp(
+    &self,
+    user_id: String,
+    secret: String,
+    recovery_code_hashes: Vec<String>,
+    last_used_step: u64,
+  ) -> Result<(), Error> {
+    let encrypted_secret =
+      totp::encrypt_secret(&secret, &CONFIG.totp_encryption_key).map_err(
+        |err| {
+          error!(
+            errorType = error_types::GENERIC_DB_LOG,
+            "Failed to encrypt TOTP secret: {:?}", err
+          );
+          Error::IllegalState
+        },
+      )?;
+
+    let result = self
+      .client
+      .update_item()
+      .table_name(USERS_TABLE)
+      .key(USERS_TABLE_PARTITION_KEY, AttributeValue::S(user_id))
+      .update_expression(
+        "SET #secret = :secret, #recovery_codes = :recovery_codes, \
+        #last_used_step = :last_used_step",
+      )
+      .condition_expression(
+        "attribute_exists(#user_id) AND attribute_not_exists(#secret)",
+      )
+      .expression_attribute_names("#user_id", USERS_TABLE_PARTITION_KEY)
+      .expression_attribute_names(
+        "#secret",
+        USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#recovery_codes",
+        USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#last_used_step",
+        USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_values(
+        ":secret",
+        AttributeValue::B(Blob::new(encrypted_secret)),
+      )
+      .expression_attribute_values(
+        ":recovery_codes",
+        AttributeValue::Ss(recovery_code_hashes),
+      )
+      .expression_attribute_values(
+        ":last_used_step",
+        AttributeValue::N(last_used_step.to_string()),
+      )
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()));
+
+    match result {
+      Ok(_) => Ok(()),
+      Err(Error::AwsSdk(DynamoDBError::ConditionalCheckFailedException(_))) => {
+        Err(Error::CannotOverwrite)
+      }
+      Err(err) => {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to enable TOTP: {:?}", err
+        );
+        Err(err)
+      }
+    }
+  }
+
+  #[tracing::instrument(skip_all)]
+  pub async fn disable_totp(&self, user_id: String) -> Result<(), Error> {
+    self
+      .client
+      .update_item()
+      .table_name(USERS_TABLE)
+      .key(USERS_TABLE_PARTITION_KEY, AttributeValue::S(user_id))
+      .update_expression("REMOVE #secret, #recovery_codes, #last_used_step")
+      .expression_attribute_names(
+        "#secret",
+        USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#recovery_codes",
+        USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#last_used_step",
+        USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME,
+      )
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to disable TOTP: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    Ok(())
+  }
+
+  /// Checks a TOTP code or one of the recovery codes.
+  /// Each code is accepted only once.
+  #[tracing::instrument(skip_all)]
+  pub async fn verify_second_factor(
+    &self,
+    user_id: &str,
+    settings: &TotpSettings,
+    code: &str,
+  ) -> Result<bool, Error> {
+    let code = code.trim();
+    if let Some(step) = totp::verify_code(
+      &settings.secret,
+      code,
+      Utc::now(),
+      settings.last_used_step,
+    ) {
+      return self.mark_totp_step_used(user_id, step).await;
+    }
+
+    let code_hash = totp::hash_recovery_code(code);
+    if settings.recovery_code_hashes.contains(&code_hash) {
+      return self.consume_recovery_code(user_id, code_hash).await;
+    }
+    Ok(false)
+  }
+
+  /// Returns `false` if the step or a later one was already used
+  async fn mark_totp_step_used(
+    &self,
+    user_id: &str,
+    step: u64,
+  ) -> Result<bool, Error> {
+    let result = self
+      .client
+      .update_item()
+      .table_name(USERS_TABLE)
+      .key(
+        USERS_TABLE_PARTITION_KEY,
+        AttributeValue::S(user_id.to_string()),
+      )
+      .update_expression("SET #last_used_step = :step")
+      .condition_expression(
+        "attribute_exists(#secret) AND \
+        (attribute_not_exists(#last_used_step) OR #last_used_step < :step)",
+      )
+      .expression_attribute_names(
+        "#secret",
+        USERS_TABLE_TOTP_SECRET_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#last_used_step",
+        USERS_TABLE_TOTP_LAST_USED_STEP_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_values(":step", AttributeValue::N(step.to_string()))
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()));
+
+    match result {
+      Ok(_) => Ok(true),
+      Err(Error::AwsSdk(DynamoDBError::ConditionalCheckFailedException(_))) => {
+        Ok(false)
+      }
+      Err(err) => {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to update last used TOTP step: {:?}", err
+        );
+        Err(err)
+      }
+    }
+  }
+
+  /// Returns `false` if the code was already used
+  async fn consume_recovery_code(
+    &self,
+    user_id: &str,
+    code_hash: String,
+  ) -> Result<bool, Error> {
+    let result = self
+      .client
+      .update_item()
+      .table_name(USERS_TABLE)
+      .key(
+        USERS_TABLE_PARTITION_KEY,
+        AttributeValue::S(user_id.to_string()),
+      )
+      .update_expression("DELETE #recovery_codes :code_set")
+      .condition_expression("contains(#recovery_codes, :code_hash)")
+      .expression_attribute_names(
+        "#recovery_codes",
+        USERS_TABLE_TOTP_RECOVERY_CODES_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_values(
+        ":code_set",
+        AttributeValue::Ss(vec![code_hash.clone()]),
+      )
+      .expression_attribute_values(":code_hash", AttributeValue::S(code_hash))
+      .send()
+      .await
+      .map_err(|e| Error::AwsSdk(e.into()));
+
+    match result {
+      Ok(_) => Ok(true),
+      Err(Error::AwsSdk(DynamoDBError::ConditionalCheckFailedException(_))) => {
+        Ok(false)
+      }
+      Err(err) => {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to consume recovery code: {:?}", err
+        );
+        Err(err)
+      }
+    }
+  }
+}
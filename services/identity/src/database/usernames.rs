@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use comm_lib::{
+  aws::{
+    ddb::{
+      primitives::Blob,
+      types::{AttributeValue, Delete, Put, TransactWriteItem, Update},
+    },
+    DynamoDBError,
+  },
+  database::{cancellation_reasons, error_codes, AttributeExtractor},
+};
+use tracing::{error, warn};
+
+use super::DatabaseClient;
+use crate::{
+  constants::{
+    error_types, released_usernames_table, RESERVED_USERNAMES_TABLE,
+    RESERVED_USERNAMES_TABLE_PARTITION_KEY,
+    RESERVED_USERNAMES_TABLE_USERNAME_LOWER_ATTRIBUTE,
+    RESERVED_USERNAMES_TABLE_USERNAME_LOWER_INDEX,
+    RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE,
+    RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_INDEX,
+    RESERVED_USERNAMES_TABLE_USER_ID_ATTRIBUTE, USERNAME_CHANGE_COOLDOWN,
+    USERS_TABLE, USERS_TABLE_PARTITION_KEY, USERS_TABLE_REGISTRATION_ATTRIBUTE,
+    USERS_TABLE_USERNAME_ATTRIBUTE, USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME,
+    USERS_TABLE_USERNAME_LOWER_INDEX,
+    USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME,
+    USERS_TABLE_USERNAME_SKELETON_INDEX,
+  },
+  error::Error,
+  log::redact_sensitive_data,
+  skeleton::username_skeleton,
+};
+
+/// Number of items updated by [`DatabaseClient::backfill_username_skeletons`]
+#[derive(Debug)]
+pub struct SkeletonBackfillSummary {
+  pub users_updated: usize,
+  pub reserved_usernames_updated: usize,
+}
+
+impl DatabaseClient {
+  /// Returns IDs of users whose usernames are confusable with the given one.
+  /// Users without a stored skeleton are matched by lowercase username.
+  #[tracing::instrument(skip_all)]
+  pub async fn get_user_ids_with_similar_username(
+    &self,
+    username: &str,
+  ) -> Result<Vec<String>, Error> {
+    let (by_skeleton, by_lowercase) = tokio::try_join!(
+      self.query_user_ids_by_index(
+        USERS_TABLE_USERNAME_SKELETON_INDEX,
+        USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME,
+        username_skeleton(username),
+      ),
+      self.query_user_ids_by_index(
+        USERS_TABLE_USERNAME_LOWER_INDEX,
+        USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME,
+        username.to_lowercase(),
+      ),
+    )?;
+
+    let user_ids: HashSet<String> =
+      by_skeleton.into_iter().chain(by_lowercase).collect();
+    Ok(user_ids.into_iter().collect())
+  }
+
+  /// Like [`DatabaseClient::get_user_id_from_reserved_usernames_table`], but
+  /// also matches reserved usernames confusable with the given one
+  #[tracing::instrument(skip_all)]
+  pub async fn get_user_id_of_similar_reserved_username(
+    &self,
+    username: &str,
+  ) -> Result<Option<String>, Error> {
+    let by_skeleton = self
+      .query_reserved_usernames_table_index(
+        username_skeleton(username),
+        (
+          RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_INDEX,
+          RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE,
+        ),
+        RESERVED_USERNAMES_TABLE_USER_ID_ATTRIBUTE,
+      )
+      .await?;
+    if by_skeleton.is_some() {
+      return Ok(by_skeleton);
+    }
+
+    self
+      .query_reserved_usernames_table_index(
+        username.to_lowercase(),
+        (
+          RESERVED_USERNAMES_TABLE_USERNAME_LOWER_INDEX,
+          RESERVED_USERNAMES_TABLE_USERNAME_LOWER_ATTRIBUTE,
+        ),
+        RESERVED_USERNAMES_TABLE_USER_ID_ATTRIBUTE,
+      )
+      .await
+  }
+
+  async fn query_user_ids_by_index(
+    &self,
+    index: &str,
+    key_attr: &str,
+    key_value: String,
+  ) -> Result<Vec<String>, Error> {
+    let response = self
+      .client
+      .query()
+      .table_name(USERS_TABLE)
+      .index_name(index)
+      .key_condition_expression("#key_name = :key_value")
+      .expression_attribute_names("#key_name", key_attr)
+      .expression_attribute_values(":key_value", AttributeValue::S(key_value))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "Failed to query usernames by {}: {:?}", index, e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    response
+      .items
+      .unwrap_or_default()
+      .into_iter()
+      .map(|mut item| item.take_attr(USERS_TABLE_PARTITION_KEY))
+      .collect::<Result<_, _>>()
+      .map_err(Error::from)
+  }
+
+  /// Returns IDs of users that changed their username from the given one
+  /// (or a confusable one), if it's still in the cooldown period.
+  /// Usernames released before skeletons were introduced are matched
+  /// by lowercase username.
+  #[tracing::instrument(skip_all)]
+  pub async fn get_released_username_owners(
+    &self,
+    username: &str,
+  ) -> Result<Vec<String>, Error> {
+    use released_usernames_table::*;
+
+    let (by_skeleton, by_lowercase) = tokio::try_join!(
+      self.query_released_usernames_by_skeleton(username_skeleton(username)),
+      self.get_released_username(username.to_lowercase()),
+    )?;
+
+    let now = Utc::now().timestamp();
+    let mut owners = HashSet::new();
+    for mut item in by_skeleton.into_iter().chain(by_lowercase) {
+      // DynamoDB doesn't delete expired items immediately
+      let expiration_time = item
+        .remove(ATTR_EXPIRATION_TIME_UNIX)
+        .and_then(|attr| attr.as_n().ok().cloned())
+        .and_then(|val| val.parse::<i64>().ok());
+      if matches!(expiration_time, Some(t) if t <= now) {
+        continue;
+      }
+      owners.insert(item.take_attr::<String>(ATTR_USER_ID)?);
+    }
+    Ok(owners.into_iter().collect())
+  }
+
+  async fn query_released_usernames_by_skeleton(
+    &self,
+    skeleton: String,
+  ) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    use released_usernames_table::*;
+
+    let response = self
+      .client
+      .query()
+      .table_name(NAME)
+      .index_name(USERNAME_SKELETON_INDEX)
+      .key_condition_expression("#skeleton = :skeleton")
+      .expression_attribute_names("#skeleton", ATTR_USERNAME_SKELETON)
+      .expression_attribute_values(":skeleton", AttributeValue::S(skeleton))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to query released usernames: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    Ok(response.items.unwrap_or_default())
+  }
+
+  async fn get_released_username(
+    &self,
+    username_lower: String,
+  ) -> Result<Option<HashMap<String, AttributeValue>>, Error> {
+    use released_usernames_table::*;
+
+    let response = self
+      .client
+      .get_item()
+      .table_name(NAME)
+      .key(PARTITION_KEY, AttributeValue::S(username_lower))
+      .send()
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::GENERIC_DB_LOG,
+          "DDB client failed to get released username: {:?}", e
+        );
+        Error::AwsSdk(e.into())
+      })?;
+
+    Ok(response.item)
+  }
+
+  /// OPAQUE password files are bound to the username, so the password file
+  /// has to be replaced together with it. The old username is reserved for
+  /// the user until the cooldown ends.
+  ///
+  /// Fails with [`Error::CannotOverwrite`] if the username was changed
+  /// concurrently or the new one was released by another user meanwhile.
+  /// Releases of look-alikes are found through an index, which can't be
+  /// checked in a transaction, so callers have to check them beforehand.
+  #[tracing::instrument(skip_all)]
+  pub async fn change_username(
+    &self,
+    user_id: String,
+    old_username: String,
+    new_username: String,
+    password_file: Vec<u8>,
+    change_time: DateTime<Utc>,
+  ) -> Result<(), Error> {
+    use released_usernames_table as released;
+
+    let old_skeleton = username_skeleton(&old_username);
+    let new_skeleton = username_skeleton(&new_username);
+
+    let update_user = Update::builder()
+      .table_name(USERS_TABLE)
+      .key(
+        USERS_TABLE_PARTITION_KEY,
+        AttributeValue::S(user_id.clone()),
+      )
+      .update_expression(
+        "SET #username = :new_username, #username_lower = :username_lower, \
+        #username_skeleton = :username_skeleton, \
+        #registration = :password_file",
+      )
+      .condition_expression("#username = :old_username")
+      .expression_attribute_names("#username", USERS_TABLE_USERNAME_ATTRIBUTE)
+      .expression_attribute_names(
+        "#username_lower",
+        USERS_TABLE_USERNAME_LOWER_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#username_skeleton",
+        USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME,
+      )
+      .expression_attribute_names(
+        "#registration",
+        USERS_TABLE_REGISTRATION_ATTRIBUTE,
+      )
+      .expression_attribute_values(
+        ":new_username",
+        AttributeValue::S(new_username.clone()),
+      )
+      .expression_attribute_values(
+        ":username_lower",
+        AttributeValue::S(new_username.to_lowercase()),
+      )
+      .expression_attribute_values(
+        ":username_skeleton",
+        AttributeValue::S(new_skeleton.clone()),
+      )
+      .expression_attribute_values(
+        ":password_file",
+        AttributeValue::B(Blob::new(password_file)),
+      )
+      .expression_attribute_values(
+        ":old_username",
+        AttributeValue::S(old_username.clone()),
+      )
+      .build()
+      .expect("key, update_expression or table_name not set in Update builder");
+
+    let mut transact_items =
+      vec![TransactWriteItem::builder().update(update_user).build()];
+
+    // Changing to a look-alike (e.g. only the letter case) doesn't release
+    // the username
+    if old_skeleton != new_skeleton {
+      let expiration_time = change_time + USERNAME_CHANGE_COOLDOWN;
+      let release_old_username = Put::builder()
+        .table_name(released::NAME)
+        .item(
+          released::PARTITION_KEY,
+          AttributeValue::S(old_username.to_lowercase()),
+        )
+        .item(
+          released::ATTR_USERNAME_SKELETON,
+          AttributeValue::S(old_skeleton),
+        )
+        .item(released::ATTR_USERNAME, AttributeValue::S(old_username))
+        .item(released::ATTR_USER_ID, AttributeValue::S(user_id.clone()))
+        .item(
+          released::ATTR_RELEASED_AT,
+          AttributeValue::S(change_time.to_rfc3339()),
+        )
+        .item(
+          released::ATTR_EXPIRATION_TIME_UNIX,
+          AttributeValue::N(expiration_time.timestamp().to_string()),
+        )
+        .build()
+        .expect("table_name or item not set in Put builder");
+
+      // The user can take back their own released username
+      let claim_new_username = Delete::builder()
+        .table_name(released::NAME)
+        .key(
+          released::PARTITION_KEY,
+          AttributeValue::S(new_username.to_lowercase()),
+        )
+        .condition_expression(
+          "attribute_not_exists(#username_lower) OR #user_id = :user_id \
+          OR #expiration_time <= :now",
+        )
+        .expression_attribute_names("#username_lower", released::PARTITION_KEY)
+        .expression_attribute_names("#user_id", released::ATTR_USER_ID)
+        .expression_attribute_names(
+          "#expiration_time",
+          released::ATTR_EXPIRATION_TIME_UNIX,
+        )
+        .expression_attribute_values(":user_id", AttributeValue::S(user_id))
+        .expression_attribute_values(
+          ":now",
+          AttributeValue::N(change_time.timestamp().to_string()),
+        )
+        .build()
+        .expect("key or table_name not set in Delete builder");
+
+      transact_items.extend([
+        TransactWriteItem::builder()
+          .put(release_old_username)
+          .build(),
+        TransactWriteItem::builder()
+          .delete(claim_new_username)
+          .build(),
+      ]);
+    }
+
+    self
+      .client
+      .transact_write_items()
+      .set_transact_items(Some(transact_items))
+      .send()
+      .await
+      .map_err(|e| match DynamoDBError::from(e) {
+        e if cancellation_reasons(&e)
+          .contains(&error_codes::CONDITIONAL_CHECK_FAILED) =>
+        {
+          warn!(
+            username = redact_sensitive_data(&new_username),
+            "Username change conflicted with another update"
+          );
+          Error::CannotOverwrite
+        }
+        other => {
+          error!(
+            errorType = error_types::GENERIC_DB_LOG,
+            "Username change transaction failed: {:?}", other
+          );
+          Error::AwsSdk(other)
+        }
+      })?;
+
+    Ok(())
+  }
+
+  /// Stores skeletons for usernames that don't have one yet or whose skeleton
+  /// was computed differently. Safe to run while the service is running.
+  #[tracing::instrument(skip_all)]
+  pub async fn backfill_username_skeletons(
+    &self,
+  ) -> Result<SkeletonBackfillSummary, Error> {
+    let users_updated = self
+      .backfill_table_skeletons(
+        USERS_TABLE,
+        USERS_TABLE_PARTITION_KEY,
+        USERS_TABLE_USERNAME_ATTRIBUTE,
+        USERS_TABLE_USERNAME_SKELETON_ATTRIBUTE_NAME,
+      )
+      .await?;
+    let reserved_usernames_updated = self
+      .backfill_table_skeletons(
+        RESERVED_USERNAMES_TABLE,
+        RESERVED_USERNAMES_TABLE_PARTITION_KEY,
+        RESERVED_USERNAMES_TABLE_PARTITION_KEY,
+        RESERVED_USERNAMES_TABLE_USERNAME_SKELETON_ATTRIBUTE,
+      )
+      .await?;
+
+    Ok(SkeletonBackfillSummary {
+      users_updated,
+      reserved_usernames_updated,
+    })
+  }
+
+  async fn backfill_table_skeletons(
+    &self,
+    table_name: &str,
+    partition_key: &str,
+    username_attr: &str,
+    skeleton_attr: &str,
+  ) -> Result<usize, Error> {
+    let mut updated = 0;
+    let mut exclusive_start_key: Option<HashMap<String, AttributeValue>> = None;
+
+    loop {
+      let scan_output = self
+        .client
+        .scan()
+        .table_name(table_name)
+        .projection_expression("#pk, #username, #skeleton")
+        .expression_attribute_names("#pk", partition_key)
+        .expression_attribute_names("#username", username_attr)
+        .expression_attribute_names("#skeleton", skeleton_attr)
+        .set_exclusive_start_key(exclusive_start_key)
+        .send()
+        .await
+        .map_err(|e| {
+          error!(
+            errorType = error_types::GENERIC_DB_LOG,
+            "Failed to scan {} for username skeletons: {:?}", table_name, e
+          );
+          Error::AwsSdk(e.into())
+        })?;
+
+      for mut item in scan_output.items.unwrap_or_default() {
+        // Wallet users don't have usernames
+        let Ok(username) = item.take_attr::<String>(username_attr) else {
+          continue;
+        };
+        let skeleton = username_skeleton(&username);
+        let stored_skeleton: Option<String> = item.take_attr(skeleton_attr)?;
+        if stored_skeleton.as_ref() == Some(&skeleton) {
+          continue;
+        }
+        let Some(key) = item.remove(partition_key) else {
+          continue;
+        };
+
+        let result = self
+          .client
+          .update_item()
+          .table_name(table_name)
+          .key(partition_key, key)
+          .update_expression("SET #skeleton = :skeleton")
+          // the username could have been changed since the scan
+          .condition_expression("#username = :username")
+          .expression_attribute_names("#skeleton", skeleton_attr)
+          .expression_attribute_names("#username", username_attr)
+          .expression_attribute_values(":skeleton", AttributeValue::S(skeleton))
+          .expression_attribute_values(":username", AttributeValue::S(username))
+          .send()
+          .await;
+
+        match result.map_err(DynamoDBError::from) {
+          Ok(_) => updated += 1,
+          Err(DynamoDBError::ConditionalCheckFailedException(_)) => {
+            warn!("Username changed during skeleton backfill. Skipping");
+          }
+          Err(e) => {
+            error!(
+              errorType = error_types::GENERIC_DB_LOG,
+              "Failed to store username skeleton: {:?}", e
+            );
+            return Err(Error::AwsSdk(e));
+          }
+        }
+      }
+
+      exclusive_start_key = scan_output.last_evaluated_key;
+      if exclusive_start_key.is_none() {
+        break;
+      }
+    }
+
+    Ok(updated)
+  }
+}
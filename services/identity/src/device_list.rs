@@ -1,3 +1,4 @@
+use base64::{engine::general_purpose, Engine as _};
 use chrono::{DateTime, Duration, Utc};
 use std::{collections::HashSet, str::FromStr};
 use tracing::{debug, error, warn};
@@ -8,7 +9,9 @@ use crate::{
   },
   database::{DeviceListRow, DeviceListUpdate},
   error::DeviceListError,
-  grpc_services::protos::auth::UpdateDeviceListRequest,
+  grpc_services::protos::auth::{
+    RotatePrimaryDeviceRequest, UpdateDeviceListRequest,
+  },
 };
 
 // serde helper for serializing/deserializing
@@ -99,6 +102,15 @@ impl TryFrom<UpdateDeviceListRequest> for SignedDeviceList {
   }
 }
 
+impl TryFrom<RotatePrimaryDeviceRequest> for SignedDeviceList {
+  type Error = tonic::Status;
+  fn try_from(
+    request: RotatePrimaryDeviceRequest,
+  ) -> Result<Self, Self::Error> {
+    request.new_device_list.parse()
+  }
+}
+
 impl FromStr for SignedDeviceList {
   type Err = tonic::Status;
   fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -317,6 +329,44 @@ pub fn verify_singleton_device_list(
   Ok(())
 }
 
+/// Returns devices added to and removed from the device list, in the order
+/// they appear on the new and previous list respectively
+pub fn device_list_diff(
+  previous_device_list: &[String],
+  new_device_list: &[String],
+) -> (Vec<String>, Vec<String>) {
+  let previous_set: HashSet<&String> = previous_device_list.iter().collect();
+  let new_set: HashSet<&String> = new_device_list.iter().collect();
+
+  let added = new_device_list
+    .iter()
+    .filter(|device_id| !previous_set.contains(device_id))
+    .cloned()
+    .collect();
+  let removed = previous_device_list
+    .iter()
+    .filter(|device_id| !new_set.contains(device_id))
+    .cloned()
+    .collect();
+  (added, removed)
+}
+
+/// Encodes the position in user's device list history, after which
+/// the next history page starts, as an opaque cursor
+pub fn encode_history_cursor(start_after: DateTime<Utc>) -> String {
+  general_purpose::URL_SAFE_NO_PAD.encode(start_after.to_rfc3339())
+}
+
+/// Decodes a cursor returned by [`encode_history_cursor`]. Returns `None`
+/// if the cursor is malformed.
+pub fn decode_history_cursor(cursor: &str) -> Option<DateTime<Utc>> {
+  let bytes = general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+  let timestamp = std::str::from_utf8(&bytes).ok()?;
+  DateTime::parse_from_rfc3339(timestamp)
+    .ok()
+    .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
 pub mod validation {
   /// utility alias to help infer validator type if exact function is not provided.
   pub type DeviceListValidator = fn(&[&str], &[&str]) -> bool;
@@ -379,14 +429,43 @@ pub mod validation {
     devices_set.len() != device_list.len()
   }
 
-  // This is going to be used when doing primary devicd keys rotation
-  #[allow(unused)]
+  /// The `RotatePrimaryDevice` RPC hands primacy over to one of the existing
+  /// secondary devices. The previous primary device can either become
+  /// a secondary device or be removed. No other changes are permitted.
   pub fn primary_device_rotation_validator(
     previous_device_list: &[&str],
     new_device_list: &[&str],
   ) -> bool {
-    primary_device_changed(previous_device_list, new_device_list)
-      && !is_device_replaced(&previous_device_list[1..], &new_device_list[1..])
+    let (Some(previous_primary), Some(new_primary)) =
+      (previous_device_list.first(), new_device_list.first())
+    else {
+      return false;
+    };
+
+    if !primary_device_changed(previous_device_list, new_device_list) {
+      return false;
+    }
+
+    if has_duplicates(new_device_list) {
+      return false;
+    }
+
+    // new primary must be an existing secondary device
+    if !previous_device_list[1..].contains(new_primary) {
+      return false;
+    }
+
+    let previous_set: HashSet<&str> =
+      previous_device_list.iter().copied().collect();
+    let new_set: HashSet<&str> = new_device_list.iter().copied().collect();
+    if new_set == previous_set {
+      return true;
+    }
+
+    // previous primary removed
+    new_set.len() + 1 == previous_set.len()
+      && !new_set.contains(previous_primary)
+      && new_set.is_subset(&previous_set)
   }
 
   /// The `UpdateDeviceList` RPC should be able to either add or remove
@@ -491,6 +570,68 @@ pub mod validation {
       assert!(has_duplicates(&list1).not(), "No duplicates");
       assert!(has_duplicates(&list2), "With duplicates");
     }
+
+    #[test]
+    fn test_primary_device_rotation() {
+      use std::ops::Not;
+
+      let previous = vec!["primary", "secondary1", "secondary2"];
+
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["secondary1", "primary", "secondary2"]
+        ),
+        "Previous primary becomes secondary"
+      );
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["secondary2", "secondary1"]
+        ),
+        "Previous primary removed"
+      );
+      assert!(
+        primary_device_rotation_validator(&previous, &previous).not(),
+        "Primary unchanged"
+      );
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["new", "primary", "secondary1", "secondary2"]
+        )
+        .not(),
+        "New primary not on previous list"
+      );
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["secondary1", "primary"]
+        )
+        .not(),
+        "Secondary device removed"
+      );
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["secondary1", "primary", "secondary2", "secondary3"]
+        )
+        .not(),
+        "Device added"
+      );
+      assert!(
+        primary_device_rotation_validator(
+          &previous,
+          &["secondary1", "secondary1", "secondary2"]
+        )
+        .not(),
+        "With duplicates"
+      );
+      assert!(
+        primary_device_rotation_validator(&previous, &[]).not(),
+        "Empty list"
+      );
+    }
   }
 }
 
@@ -639,6 +780,44 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_device_list_diff() {
+    let to_strings =
+      |ids: &[&str]| ids.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let previous = to_strings(&["device1", "device2", "device3"]);
+    let new = to_strings(&["device2", "device4", "device1", "device5"]);
+
+    let (added, removed) = device_list_diff(&previous, &new);
+    assert_eq!(added, to_strings(&["device4", "device5"]));
+    assert_eq!(removed, to_strings(&["device3"]));
+
+    let (added, removed) = device_list_diff(&previous, &previous);
+    assert!(added.is_empty() && removed.is_empty(), "Unchanged list");
+
+    let (added, removed) = device_list_diff(&[], &previous);
+    assert_eq!(added, previous, "All devices added");
+    assert!(removed.is_empty());
+  }
+
+  #[test]
+  fn test_history_cursor() {
+    let timestamp = DateTime::from_timestamp(1_700_000_000, 123_456_789)
+      .expect("Invalid timestamp");
+    let cursor = encode_history_cursor(timestamp);
+    assert_eq!(decode_history_cursor(&cursor), Some(timestamp));
+
+    assert!(decode_history_cursor("").is_none(), "Empty cursor");
+    assert!(
+      decode_history_cursor("not a cursor!").is_none(),
+      "Not base64"
+    );
+    let not_timestamp = general_purpose::URL_SAFE_NO_PAD.encode("device-list-");
+    assert!(
+      decode_history_cursor(&not_timestamp).is_none(),
+      "Not timestamp"
+    );
+  }
+
   /// helper for mocking DB rows from raw device list payloads
   fn create_device_list_row(raw_list: RawDeviceList) -> DeviceListRow {
     DeviceListRow {
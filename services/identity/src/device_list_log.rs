@@ -0,0 +1,424 @@
+//! Append-only Merkle log of device list updates (RFC 9162), which lets
+//! clients detect Identity rewriting device list history or presenting
+//! different histories to different peers.
+//!
+//! Each user has a separate log. Leaves are device list updates in the order
+//! they were accepted, serialized the same way as they're returned to clients.
+
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, SignatureError, Signer};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+  config::CONFIG,
+  constants::{error_types, tonic_status_messages},
+  grpc_services::protos::auth::{
+    DeviceListLogProof, InclusionProof, SignedTreeHead,
+  },
+};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+  let mut hasher = Sha256::new();
+  hasher.update([LEAF_PREFIX]);
+  hasher.update(data);
+  hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+  let mut hasher = Sha256::new();
+  hasher.update([NODE_PREFIX]);
+  hasher.update(left);
+  hasher.update(right);
+  hasher.finalize().into()
+}
+
+/// Largest power of two smaller than `n`. Requires `n > 1`.
+fn split_point(n: usize) -> usize {
+  debug_assert!(n > 1);
+  1 << (usize::BITS - (n - 1).leading_zeros() - 1)
+}
+
+/// Merkle Tree Hash of the given leaf hashes
+fn subtree_hash(leaves: &[Hash]) -> Hash {
+  match leaves {
+    [] => Sha256::digest([]).into(),
+    [leaf] => *leaf,
+    _ => {
+      let k = split_point(leaves.len());
+      node_hash(&subtree_hash(&leaves[..k]), &subtree_hash(&leaves[k..]))
+    }
+  }
+}
+
+fn inclusion_path(index: usize, leaves: &[Hash]) -> Vec<Hash> {
+  if leaves.len() <= 1 {
+    return Vec::new();
+  }
+  let k = split_point(leaves.len());
+  let (mut path, sibling) = if index < k {
+    (
+      inclusion_path(index, &leaves[..k]),
+      subtree_hash(&leaves[k..]),
+    )
+  } else {
+    (
+      inclusion_path(index - k, &leaves[k..]),
+      subtree_hash(&leaves[..k]),
+    )
+  };
+  path.push(sibling);
+  path
+}
+
+fn consistency_subproof(
+  old_size: usize,
+  leaves: &[Hash],
+  is_complete_subtree: bool,
+) -> Vec<Hash> {
+  if old_size == leaves.len() {
+    return if is_complete_subtree {
+      Vec::new()
+    } else {
+      vec![subtree_hash(leaves)]
+    };
+  }
+  let k = split_point(leaves.len());
+  let (mut proof, sibling) = if old_size <= k {
+    (
+      consistency_subproof(old_size, &leaves[..k], is_complete_subtree),
+      subtree_hash(&leaves[k..]),
+    )
+  } else {
+    (
+      consistency_subproof(old_size - k, &leaves[k..], false),
+      subtree_hash(&leaves[..k]),
+    )
+  };
+  proof.push(sibling);
+  proof
+}
+
+pub struct MerkleTree {
+  leaves: Vec<Hash>,
+}
+
+impl MerkleTree {
+  pub fn from_entries<T: AsRef<[u8]>>(entries: &[T]) -> Self {
+    let leaves = entries
+      .iter()
+      .map(|entry| leaf_hash(entry.as_ref()))
+      .collect();
+    Self { leaves }
+  }
+
+  pub fn size(&self) -> usize {
+    self.leaves.len()
+  }
+
+  pub fn root_hash(&self) -> Hash {
+    subtree_hash(&self.leaves)
+  }
+
+  /// Audit path of the leaf at `index`. Returns `None` if the index is out
+  /// of bounds.
+  pub fn inclusion_proof(&self, index: usize) -> Option<Vec<Hash>> {
+    if index >= self.size() {
+      return None;
+    }
+    Some(inclusion_path(index, &self.leaves))
+  }
+
+  /// Proof that the first `old_size` leaves of this tree form the tree with
+  /// the given size. Returns `None` if the tree is smaller than `old_size`.
+  pub fn consistency_proof(&self, old_size: usize) -> Option<Vec<Hash>> {
+    if old_size > self.size() {
+      return None;
+    }
+    if old_size == 0 || old_size == self.size() {
+      return Some(Vec::new());
+    }
+    Some(consistency_subproof(old_size, &self.leaves, true))
+  }
+}
+
+// serde helper for the signed tree head payload
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TreeHead<'a> {
+  #[serde(rename = "userID")]
+  user_id: &'a str,
+  tree_size: u64,
+  root_hash: String,
+}
+
+/// Creates a signed tree head of the user's log
+pub fn sign_tree_head(
+  user_id: &str,
+  tree: &MerkleTree,
+  keypair: &Keypair,
+) -> Result<SignedTreeHead, serde_json::Error> {
+  let tree_head = serde_json::to_string(&TreeHead {
+    user_id,
+    tree_size: tree.size() as u64,
+    root_hash: general_purpose::STANDARD_NO_PAD.encode(tree.root_hash()),
+  })?;
+
+  let signature = keypair.sign(tree_head.as_bytes());
+  let signature = general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes());
+
+  Ok(SignedTreeHead {
+    tree_head,
+    signature,
+  })
+}
+
+#[derive(Debug)]
+pub enum LogProofError {
+  /// Log size known by the client is larger than the log
+  InvalidLogSize,
+  TreeHeadSerialization(serde_json::Error),
+}
+
+impl From<LogProofError> for tonic::Status {
+  fn from(value: LogProofError) -> Self {
+    match value {
+      LogProofError::InvalidLogSize => {
+        tonic::Status::invalid_argument(tonic_status_messages::INVALID_LOG_SIZE)
+      }
+      LogProofError::TreeHeadSerialization(err) => {
+        error!(
+          errorType = error_types::GRPC_SERVICES_LOG,
+          "Failed to serialize device list tree head: {err}"
+        );
+        tonic::Status::internal(tonic_status_messages::UNEXPECTED_ERROR)
+      }
+    }
+  }
+}
+
+/// Builds log proofs for the user's device list history.
+/// `updates` are all stringified device list updates, oldest first.
+/// Inclusion proofs are generated for updates starting at `returned_from`.
+pub fn device_list_log_proof(
+  user_id: &str,
+  updates: &[String],
+  returned_from: usize,
+  known_log_size: Option<u64>,
+) -> Result<DeviceListLogProof, LogProofError> {
+  let tree = MerkleTree::from_entries(updates);
+
+  let consistency_proof = match known_log_size {
+    Some(old_size) => tree
+      .consistency_proof(old_size as usize)
+      .ok_or(LogProofError::InvalidLogSize)?,
+    None => Vec::new(),
+  };
+
+  let inclusion_proofs = (returned_from..tree.size())
+    .filter_map(|index| {
+      let audit_path = tree.inclusion_proof(index)?;
+      Some(InclusionProof {
+        leaf_index: index as u64,
+        audit_path: audit_path.into_iter().map(Vec::from).collect(),
+      })
+    })
+    .collect();
+
+  let keypair = &CONFIG.device_list_log_keypair;
+  let tree_head = sign_tree_head(user_id, &tree, keypair)
+    .map_err(LogProofError::TreeHeadSerialization)?;
+
+  Ok(DeviceListLogProof {
+    tree_head: Some(tree_head),
+    inclusion_proofs,
+    consistency_proof: consistency_proof.into_iter().map(Vec::from).collect(),
+  })
+}
+
+pub fn keypair_from_secret(
+  secret_bytes: &[u8],
+) -> Result<Keypair, SignatureError> {
+  let secret = SecretKey::from_bytes(secret_bytes)?;
+  let public = PublicKey::from(&secret);
+  Ok(Keypair { secret, public })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // RFC 9162, section 2.1.3.2
+  fn verify_inclusion(
+    index: usize,
+    tree_size: usize,
+    leaf: &Hash,
+    proof: &[Hash],
+    root: &Hash,
+  ) -> bool {
+    if index >= tree_size {
+      return false;
+    }
+    let (mut fnode, mut snode) = (index, tree_size - 1);
+    let mut hash = *leaf;
+    for sibling in proof {
+      if snode == 0 {
+        return false;
+      }
+      if fnode & 1 == 1 || fnode == snode {
+        hash = node_hash(sibling, &hash);
+        while fnode & 1 == 0 && fnode != 0 {
+          fnode >>= 1;
+          snode >>= 1;
+        }
+      } else {
+        hash = node_hash(&hash, sibling);
+      }
+      fnode >>= 1;
+      snode >>= 1;
+    }
+    snode == 0 && hash == *root
+  }
+
+  // RFC 9162, section 2.1.4.2
+  fn verify_consistency(
+    old_size: usize,
+    new_size: usize,
+    old_root: &Hash,
+    new_root: &Hash,
+    proof: &[Hash],
+  ) -> bool {
+    if old_size == new_size {
+      return proof.is_empty() && old_root == new_root;
+    }
+    let mut proof = proof.to_vec();
+    if old_size.is_power_of_two() {
+      proof.insert(0, *old_root);
+    }
+    let Some((first, rest)) = proof.split_first() else {
+      return false;
+    };
+    let (mut fnode, mut snode) = (old_size - 1, new_size - 1);
+    while fnode & 1 == 1 {
+      fnode >>= 1;
+      snode >>= 1;
+    }
+    let (mut old_hash, mut new_hash) = (*first, *first);
+    for hash in rest {
+      if snode == 0 {
+        return false;
+      }
+      if fnode & 1 == 1 || fnode == snode {
+        old_hash = node_hash(hash, &old_hash);
+        new_hash = node_hash(hash, &new_hash);
+        while fnode & 1 == 0 && fnode != 0 {
+          fnode >>= 1;
+          snode >>= 1;
+        }
+      } else {
+        new_hash = node_hash(&new_hash, hash);
+      }
+      fnode >>= 1;
+      snode >>= 1;
+    }
+    snode == 0 && old_hash == *old_root && new_hash == *new_root
+  }
+
+  fn entries(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("device list {i}")).collect()
+  }
+
+  #[test]
+  fn test_empty_tree_root() {
+    let tree = MerkleTree::from_entries::<String>(&[]);
+    assert_eq!(
+      hex::encode(tree.root_hash()),
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+  }
+
+  #[test]
+  fn test_inclusion_proofs() {
+    for size in 1..=17 {
+      let entries = entries(size);
+      let tree = MerkleTree::from_entries(&entries);
+      let root = tree.root_hash();
+      for (index, entry) in entries.iter().enumerate() {
+        let proof = tree.inclusion_proof(index).expect("index in bounds");
+        let leaf = leaf_hash(entry.as_bytes());
+        assert!(verify_inclusion(index, size, &leaf, &proof, &root));
+      }
+      assert!(tree.inclusion_proof(size).is_none());
+    }
+  }
+
+  #[test]
+  fn test_consistency_proofs() {
+    for new_size in 1..=17 {
+      let entries = entries(new_size);
+      let new_tree = MerkleTree::from_entries(&entries);
+      for old_size in 1..=new_size {
+        let old_tree = MerkleTree::from_entries(&entries[..old_size]);
+        let proof = new_tree
+          .consistency_proof(old_size)
+          .expect("old size not larger than tree");
+        assert!(verify_consistency(
+          old_size,
+          new_size,
+          &old_tree.root_hash(),
+          &new_tree.root_hash(),
+          &proof
+        ));
+      }
+      assert!(new_tree.consistency_proof(new_size + 1).is_none());
+    }
+  }
+
+  #[test]
+  fn test_rewritten_history_is_inconsistent() {
+    let mut entries = entries(8);
+    let old_tree = MerkleTree::from_entries(&entries[..5]);
+    entries[2] = "rewritten device list".to_string();
+    let new_tree = MerkleTree::from_entries(&entries);
+    let proof = new_tree.consistency_proof(5).expect("valid old size");
+    assert!(!verify_consistency(
+      5,
+      8,
+      &old_tree.root_hash(),
+      &new_tree.root_hash(),
+      &proof
+    ));
+  }
+
+  #[test]
+  fn test_signed_tree_head() {
+    use ed25519_dalek::{Signature, Verifier};
+
+    let keypair =
+      keypair_from_secret(&[7u8; 32]).expect("32 bytes is a valid secret");
+    let tree = MerkleTree::from_entries(&entries(3));
+    let signed = sign_tree_head("user", &tree, &keypair)
+      .expect("tree head should serialize");
+
+    let payload: serde_json::Value =
+      serde_json::from_str(&signed.tree_head).expect("valid JSON");
+    assert_eq!(payload["userID"], "user");
+    assert_eq!(payload["treeSize"], 3);
+
+    let signature_bytes = general_purpose::STANDARD_NO_PAD
+      .decode(signed.signature)
+      .expect("valid base64");
+    let signature =
+      Signature::from_bytes(&signature_bytes).expect("valid signature");
+    assert!(keypair
+      .public
+      .verify(signed.tree_head.as_bytes(), &signature)
+      .is_ok());
+  }
+}
@@ -37,6 +37,8 @@ pub enum Error {
   IllegalState,
   #[display(...)]
   InvalidFormat,
+  #[display(...)]
+  IndexingFailed,
 }
 
 #[derive(Debug, derive_more::Display, derive_more::Error)]
@@ -0,0 +1,179 @@
+//! Minimal Ethereum JSON-RPC client, used to verify smart contract
+//! wallet signatures and resolve ENS names.
+
+use derive_more::{Display, Error, From};
+use serde_json::json;
+use sha3::{Digest, Keccak256};
+use tracing::error;
+
+use crate::constants::{error_types, ETHEREUM_RPC_REQUEST_TIMEOUT};
+
+#[derive(Debug, Display, Error, From)]
+pub enum Error {
+  Reqwest(reqwest::Error),
+  /// Error returned by the node, e.g. when the call reverted
+  #[display(fmt = "JSON-RPC error: {_0}")]
+  #[from(ignore)]
+  Rpc(#[error(ignore)] String),
+  #[display(fmt = "Invalid JSON-RPC response: {_0}")]
+  #[from(ignore)]
+  InvalidResponse(#[error(ignore)] String),
+}
+
+const ENS_REGISTRY_ADDRESS: &str = "0x00000000000C2E074eC69A0dFb2997BA6C7d2e1e";
+/// `resolver(bytes32)` function selector
+const ENS_RESOLVER_SELECTOR: [u8; 4] = [0x01, 0x78, 0xb8, 0xbf];
+/// `addr(bytes32)` function selector
+const ENS_ADDR_SELECTOR: [u8; 4] = [0x3b, 0x3b, 0x57, 0xde];
+
+#[derive(Clone)]
+pub struct EthRpcClient {
+  url: reqwest::Url,
+  http_client: reqwest::Client,
+}
+
+impl EthRpcClient {
+  pub fn new(url: reqwest::Url) -> Result<Self, Error> {
+    let http_client = reqwest::Client::builder()
+      .timeout(ETHEREUM_RPC_REQUEST_TIMEOUT)
+      .build()?;
+    Ok(Self { url, http_client })
+  }
+
+  /// Executes a read-only contract call against the latest block
+  pub async fn call(&self, to: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let params = json!([
+      { "to": to, "data": format!("0x{}", hex::encode(data)) },
+      "latest"
+    ]);
+    let result = self.request("eth_call", params).await?;
+    decode_hex_result(result)
+  }
+
+  /// Returns an empty vector if there's no contract deployed at the address
+  pub async fn get_code(&self, address: &str) -> Result<Vec<u8>, Error> {
+    let result = self
+      .request("eth_getCode", json!([address, "latest"]))
+      .await?;
+    decode_hex_result(result)
+  }
+
+  /// Resolves ENS name to an address. Returns `None` if the name has
+  /// no resolver or the resolver has no address for it.
+  pub async fn resolve_ens_name(
+    &self,
+    name: &str,
+  ) -> Result<Option<String>, Error> {
+    let node = ens_namehash(&name.to_lowercase());
+
+    let resolver_call = [&ENS_RESOLVER_SELECTOR[..], &node].concat();
+    let resolver = self.call(ENS_REGISTRY_ADDRESS, &resolver_call).await?;
+    let Some(resolver_address) = decode_address(&resolver) else {
+      return Ok(None);
+    };
+
+    let addr_call = [&ENS_ADDR_SELECTOR[..], &node].concat();
+    let address = self.call(&resolver_address, &addr_call).await?;
+    Ok(decode_address(&address))
+  }
+
+  async fn request(
+    &self,
+    method: &str,
+    params: serde_json::Value,
+  ) -> Result<serde_json::Value, Error> {
+    let body = json!({
+      "jsonrpc": "2.0",
+      "id": 1,
+      "method": method,
+      "params": params,
+    });
+    let mut response: serde_json::Value = self
+      .http_client
+      .post(self.url.clone())
+      .json(&body)
+      .send()
+      .await
+      .and_then(reqwest::Response::error_for_status)
+      .map_err(|err| {
+        error!(
+          errorType = error_types::ETHEREUM_RPC_LOG,
+          "{} request failed: {}", method, err
+        );
+        err
+      })?
+      .json()
+      .await?;
+
+    if let Some(rpc_error) = response.get("error") {
+      return Err(Error::Rpc(rpc_error.to_string()));
+    }
+    match response.get_mut("result") {
+      Some(result) => Ok(result.take()),
+      None => Err(Error::InvalidResponse(response.to_string())),
+    }
+  }
+}
+
+fn decode_hex_result(result: serde_json::Value) -> Result<Vec<u8>, Error> {
+  let Some(hex_string) = result.as_str() else {
+    return Err(Error::InvalidResponse(result.to_string()));
+  };
+  hex::decode(hex_string.trim_start_matches("0x"))
+    .map_err(|_| Error::InvalidResponse(hex_string.to_string()))
+}
+
+/// ENS name hash, see EIP-137
+fn ens_namehash(name: &str) -> [u8; 32] {
+  let mut node = [0u8; 32];
+  if name.is_empty() {
+    return node;
+  }
+  for label in name.rsplit('.') {
+    let label_hash = Keccak256::digest(label.as_bytes());
+    let mut hasher = Keccak256::new();
+    hasher.update(node);
+    hasher.update(label_hash);
+    node = hasher.finalize().into();
+  }
+  node
+}
+
+/// Decodes ABI-encoded address. Returns `None` for the zero address.
+fn decode_address(word: &[u8]) -> Option<String> {
+  if word.len() != 32 || word[12..].iter().all(|byte| *byte == 0) {
+    return None;
+  }
+  Some(format!("0x{}", hex::encode(&word[12..])))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ens_namehash() {
+    assert_eq!(ens_namehash(""), [0u8; 32]);
+    assert_eq!(
+      hex::encode(ens_namehash("eth")),
+      "93cdeb708b7545dc668eb9280176169d1c33cfd8ed6f04690a0bcc88a93fc4ae"
+    );
+    assert_eq!(
+      hex::encode(ens_namehash("foo.eth")),
+      "de9b09fd7c5f901e23a3f19fecc54828e9c848539801e86591bd9801b019f84f"
+    );
+  }
+
+  #[test]
+  fn test_decode_address() {
+    let mut word = [0u8; 32];
+    assert_eq!(decode_address(&word), None);
+
+    word[12..].copy_from_slice(&[0xab; 20]);
+    assert_eq!(
+      decode_address(&word),
+      Some(format!("0x{}", "ab".repeat(20)))
+    );
+    assert_eq!(decode_address(&word[1..]), None);
+  }
+}
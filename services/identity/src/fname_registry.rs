@@ -0,0 +1,97 @@
+//! Minimal client of the Farcaster fname registry, used to find Farcaster
+//! usernames (fnames) of linked FIDs, so that users can be searched by them.
+
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+  constants::{
+    error_types, FNAME_REGISTRY_REQUEST_TIMEOUT, FNAME_REGISTRY_URL,
+  },
+  database::DatabaseClient,
+  error::consume_error,
+};
+
+#[derive(Deserialize)]
+struct CurrentTransferResponse {
+  transfer: Transfer,
+}
+
+#[derive(Deserialize)]
+struct Transfer {
+  username: String,
+  /// FID the fname was transferred to. Zero if the fname was released.
+  to: u64,
+}
+
+/// Returns the current fname of the FID, or `None` if it has none
+pub async fn get_fname_for_fid(
+  fid: &str,
+) -> Result<Option<String>, reqwest::Error> {
+  let http_client = reqwest::Client::builder()
+    .timeout(FNAME_REGISTRY_REQUEST_TIMEOUT)
+    .build()?;
+  let response = http_client
+    .get(format!("{FNAME_REGISTRY_URL}/transfers/current"))
+    .query(&[("fid", fid)])
+    .send()
+    .await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+
+  let CurrentTransferResponse { transfer } =
+    response.error_for_status()?.json().await?;
+  Ok(current_fname(fid, transfer))
+}
+
+/// The latest transfer of an FID can also be the one that took its
+/// fname away
+fn current_fname(fid: &str, transfer: Transfer) -> Option<String> {
+  (transfer.to.to_string() == fid).then_some(transfer.username)
+}
+
+/// Stores the fname of the user's FID for identity search. Failures are
+/// only logged, because the fname isn't used for anything else, so this
+/// is meant to be spawned after the FID is linked.
+#[tracing::instrument(skip_all)]
+pub async fn update_farcaster_username(
+  db_client: DatabaseClient,
+  user_id: String,
+  fid: String,
+) {
+  let fname = match get_fname_for_fid(&fid).await {
+    Ok(fname) => fname,
+    Err(err) => {
+      error!(
+        errorType = error_types::FNAME_REGISTRY_LOG,
+        "Failed to get fname of FID: {}", err
+      );
+      return;
+    }
+  };
+
+  consume_error(
+    db_client
+      .update_farcaster_username(&user_id, &fid, fname)
+      .await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_current_fname() {
+    let transfer_to = |to| Transfer {
+      username: "alice".to_string(),
+      to,
+    };
+    assert_eq!(
+      current_fname("123", transfer_to(123)),
+      Some("alice".to_string())
+    );
+    assert_eq!(current_fname("123", transfer_to(0)), None, "Released fname");
+  }
+}
@@ -2,43 +2,70 @@ use std::collections::{HashMap, HashSet};
 
 use crate::comm_service::{backup, blob, tunnelbroker};
 use crate::config::CONFIG;
-use crate::database::{DeviceListRow, DeviceListUpdate, PlatformDetails};
+use crate::database::{
+  AuditLogEntry, DeviceListRow, DeviceListUpdate, PlatformDetails,
+};
 use crate::device_list::validation::DeviceListValidator;
 use crate::device_list::SignedDeviceList;
+use crate::device_list_log;
 use crate::error::consume_error;
+use crate::fname_registry;
 use crate::log::redact_sensitive_data;
-use crate::token::AuthType;
+use crate::login_attempts::{register_login_attempt, AttemptSubject};
+use crate::regex::is_valid_username;
+use crate::skeleton::is_reserved_username;
+use crate::sync_identity_search::update_indexed_username;
+use crate::token::{AccessTokenData, AuthType};
+use crate::totp;
 use crate::{
   client_service::{handle_db_error, WorkflowInProgress},
-  constants::{error_types, request_metadata, staff, tonic_status_messages},
+  constants::{
+    error_types, request_metadata, staff, tonic_status_messages,
+    DEVICE_LIST_DIFFS_CONCURRENCY, DEVICE_LIST_DIFFS_MAX_USERS,
+  },
   database::DatabaseClient,
-  grpc_services::shared::{get_platform_metadata, get_value},
+  ddb_utils::Identifier,
+  error::Error as DBError,
+  grpc_services::shared::{
+    get_client_address, get_platform_metadata, get_value,
+  },
 };
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use comm_lib::auth::{AuthService, ServicesAuthToken};
 use comm_lib::blob::client::BlobServiceClient;
+use comm_lib::crypto::siwe::is_valid_ethereum_address;
 use comm_opaque2::grpc::protocol_error_to_grpc_status;
+use futures::{StreamExt, TryStreamExt};
+use rand::rngs::OsRng;
 use tonic::{Request, Response, Status};
 use tracing::{debug, error, info, trace, warn};
 
 use super::protos::auth::{
-  identity_client_service_server::IdentityClientService,
-  DeletePasswordUserFinishRequest, DeletePasswordUserStartRequest,
-  DeletePasswordUserStartResponse, GetDeviceListRequest, GetDeviceListResponse,
+  identity_client_service_server::IdentityClientService, AuditEventType,
+  ChangeUsernameFinishRequest, ChangeUsernameStartRequest,
+  ChangeUsernameStartResponse, DeletePasswordUserFinishRequest,
+  DeletePasswordUserStartRequest, DeletePasswordUserStartResponse,
+  DeviceListDiff, DeviceListDiffsRequest, DeviceListDiffsResponse,
+  DeviceListHistoryPageRequest, DeviceListHistoryPageResponse,
+  DisableTotpRequest, FinishTotpEnrollmentRequest,
+  FinishTotpEnrollmentResponse, GetAuditLogRequest, GetAuditLogResponse,
+  GetDeviceListRequest, GetDeviceListResponse, GetKeyHealthRequest,
   InboundKeyInfo, InboundKeysForUserRequest, InboundKeysForUserResponse,
-  KeyserverKeysResponse, LinkFarcasterAccountRequest,
-  LinkFarcasterDCsAccountRequest, OutboundKeyInfo, OutboundKeysForUserRequest,
-  OutboundKeysForUserResponse, PeersDeviceListsRequest,
-  PeersDeviceListsResponse, PrimaryDeviceLogoutRequest,
-  PrivilegedDeleteUsersRequest, PrivilegedResetUserPasswordFinishRequest,
+  KeyHealthResponse, KeyserverKeysResponse, LinkFarcasterAccountRequest,
+  LinkFarcasterDCsAccountRequest, ListSessionsResponse, OutboundKeyInfo,
+  OutboundKeysForUserRequest, OutboundKeysForUserResponse,
+  PeersDeviceListsRequest, PeersDeviceListsResponse,
+  PrimaryDeviceLogoutRequest, PrivilegedDeleteUsersRequest,
+  PrivilegedGetAuditLogRequest, PrivilegedResetUserPasswordFinishRequest,
   PrivilegedResetUserPasswordStartRequest,
-  PrivilegedResetUserPasswordStartResponse, RefreshUserPrekeysRequest,
-  UpdateDeviceListRequest, UpdateUserPasswordFinishRequest,
-  UpdateUserPasswordStartRequest, UpdateUserPasswordStartResponse,
-  UploadOneTimeKeysRequest, UserDevicesPlatformDetails, UserIdentitiesRequest,
-  UserIdentitiesResponse,
+  PrivilegedResetUserPasswordStartResponse, RefreshAccessTokenResponse,
+  RefreshUserPrekeysRequest, RevokeSessionRequest, RotatePrimaryDeviceRequest,
+  SessionInfo, StartTotpEnrollmentResponse, UpdateDeviceListRequest,
+  UpdateUserPasswordFinishRequest, UpdateUserPasswordStartRequest,
+  UpdateUserPasswordStartResponse, UploadOneTimeKeysRequest,
+  UserDevicesPlatformDetails, UserIdentitiesRequest, UserIdentitiesResponse,
 };
-use super::protos::unauth::Empty;
+use super::protos::unauth::{DeviceType, Empty};
 
 #[derive(derive_more::Constructor)]
 pub struct AuthenticatedService {
@@ -301,6 +328,57 @@ impl IdentityClientService for AuthenticatedService {
     return Ok(response);
   }
 
+  #[tracing::instrument(skip_all)]
+  async fn get_key_health(
+    &self,
+    request: Request<GetKeyHealthRequest>,
+  ) -> Result<Response<KeyHealthResponse>, Status> {
+    let (user_id, calling_device_id) = get_user_and_device_id(&request)?;
+    let device_id = request.into_inner().device_id.unwrap_or(calling_device_id);
+
+    // Device rows are keyed by user ID, so other users' devices aren't found
+    let (device_info, key_health) = tokio::try_join!(
+      self.db_client.get_device_data(&user_id, &device_id),
+      self.db_client.get_device_key_health(&user_id, &device_id),
+    )?;
+    let (Some(device_info), Some(key_health)) = (device_info, key_health)
+    else {
+      return Err(Status::not_found(tonic_status_messages::DEVICE_NOT_FOUND));
+    };
+
+    let content_prekey_signature_valid =
+      device_info.content_prekey.verify(&device_id).is_ok();
+    let notif_prekey_signature_valid = device_info
+      .device_key_info
+      .key_payload()
+      .is_ok_and(|payload| {
+        device_info
+          .notif_prekey
+          .verify(&payload.notification_identity_public_keys.ed25519)
+          .is_ok()
+      });
+
+    let response = KeyHealthResponse {
+      content_one_time_key_count: key_health.content_otk_count as u32,
+      notif_one_time_key_count: key_health.notif_otk_count as u32,
+      prekeys_updated_at: key_health
+        .prekeys_updated_at
+        .map(|time| time.timestamp_millis()),
+      content_prekey_signature_valid,
+      notif_prekey_signature_valid,
+      needs_refresh: key_health.needs_refresh(Utc::now()),
+      content_one_time_key_exhaustion_count: key_health
+        .content_otk_exhaustion_count
+        as u64,
+      notif_one_time_key_exhaustion_count: key_health.notif_otk_exhaustion_count
+        as u64,
+      last_one_time_key_exhaustion: key_health
+        .last_otk_exhaustion
+        .map(|time| time.timestamp_millis()),
+    };
+    Ok(Response::new(response))
+  }
+
   #[tracing::instrument(skip_all)]
   async fn upload_one_time_keys(
     &self,
@@ -381,7 +459,7 @@ impl IdentityClientService for AuthenticatedService {
     &self,
     request: tonic::Request<UpdateUserPasswordFinishRequest>,
   ) -> Result<tonic::Response<Empty>, tonic::Status> {
-    let (user_id, _) = get_user_and_device_id(&request)?;
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
 
     let message = request.into_inner();
 
@@ -405,13 +483,315 @@ impl IdentityClientService for AuthenticatedService {
 
     self
       .db_client
-      .update_user_password(user_id, password_file)
+      .update_user_password(user_id.clone(), password_file)
       .await?;
 
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::PasswordChange)
+          .with_device_id(device_id),
+      )
+      .await;
+
     let response = Empty {};
     Ok(Response::new(response))
   }
 
+  #[tracing::instrument(skip_all)]
+  async fn change_username_start(
+    &self,
+    request: tonic::Request<ChangeUsernameStartRequest>,
+  ) -> Result<tonic::Response<ChangeUsernameStartResponse>, tonic::Status> {
+    let (user_id, _) = get_user_and_device_id(&request)?;
+    let client_address = get_client_address(&request);
+    let message = request.into_inner();
+
+    let Some((username, password_file)) = self
+      .db_client
+      .get_username_and_password_file(&user_id)
+      .await?
+    else {
+      return Err(tonic::Status::permission_denied(
+        tonic_status_messages::WALLET_USER,
+      ));
+    };
+
+    let new_username = message.new_username;
+    if new_username == username
+      || !is_valid_username(&new_username)
+      || is_valid_ethereum_address(&new_username)
+    {
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::INVALID_USERNAME,
+      ));
+    }
+    self
+      .check_username_available(&user_id, &new_username)
+      .await?;
+
+    let mut attempt_subjects = vec![AttemptSubject::UserID(user_id.clone())];
+    attempt_subjects.extend(client_address.map(AttemptSubject::Client));
+    register_login_attempt(&self.db_client, &attempt_subjects).await?;
+
+    let mut server_login = comm_opaque2::server::Login::new();
+    let login_response = server_login
+      .start(
+        &CONFIG.server_setup,
+        &password_file,
+        &message.opaque_login_request,
+        username.as_bytes(),
+      )
+      .map_err(protocol_error_to_grpc_status)?;
+
+    let server_registration = comm_opaque2::server::Registration::new();
+    let registration_response = server_registration
+      .start(
+        &CONFIG.server_setup,
+        &message.opaque_registration_request,
+        new_username.to_lowercase().as_bytes(),
+      )
+      .map_err(protocol_error_to_grpc_status)?;
+
+    let change_state =
+      UsernameChangeInfo::new(user_id, username, new_username, server_login);
+    let session_id = self
+      .db_client
+      .insert_workflow(WorkflowInProgress::UsernameChange(Box::new(
+        change_state,
+      )))
+      .await?;
+
+    let response = ChangeUsernameStartResponse {
+      session_id,
+      opaque_registration_response: registration_response,
+      opaque_login_response: login_response,
+    };
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn change_username_finish(
+    &self,
+    request: tonic::Request<ChangeUsernameFinishRequest>,
+  ) -> Result<tonic::Response<Empty>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    let message = request.into_inner();
+
+    let Some(WorkflowInProgress::UsernameChange(state)) =
+      self.db_client.get_workflow(message.session_id).await?
+    else {
+      return Err(tonic::Status::not_found(
+        tonic_status_messages::SESSION_NOT_FOUND,
+      ));
+    };
+    if state.user_id != user_id {
+      return Err(tonic::Status::not_found(
+        tonic_status_messages::SESSION_NOT_FOUND,
+      ));
+    }
+
+    let mut server_login = state.opaque_server_login;
+    server_login
+      .finish(&message.opaque_login_upload)
+      .map_err(protocol_error_to_grpc_status)?;
+
+    let server_registration = comm_opaque2::server::Registration::new();
+    let password_file = server_registration
+      .finish(&message.opaque_registration_upload)
+      .map_err(protocol_error_to_grpc_status)?;
+
+    // the username could have been taken since the start request
+    self
+      .check_username_available(&user_id, &state.new_username)
+      .await?;
+
+    let result = self
+      .db_client
+      .change_username(
+        user_id.clone(),
+        state.old_username.clone(),
+        state.new_username.clone(),
+        password_file,
+        Utc::now(),
+      )
+      .await;
+    match result {
+      Ok(()) => (),
+      Err(DBError::CannotOverwrite) => {
+        return Err(tonic::Status::already_exists(
+          tonic_status_messages::USERNAME_ALREADY_EXISTS,
+        ));
+      }
+      Err(err) => return Err(handle_db_error(err)),
+    }
+    info!(
+      "User {} changed username from {} to {}",
+      redact_sensitive_data(&user_id),
+      redact_sensitive_data(&state.old_username),
+      redact_sensitive_data(&state.new_username)
+    );
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(&user_id, AuditEventType::UsernameChange)
+          .with_device_id(device_id)
+          .with_details(format!(
+            "{} -> {}",
+            state.old_username, state.new_username
+          )),
+      )
+      .await;
+
+    let device_list = self.get_current_device_list(&user_id).await?;
+    let new_username = state.new_username;
+    tokio::spawn(async move {
+      let reqwest_client = reqwest::Client::new();
+      let index_result =
+        update_indexed_username(&reqwest_client, &user_id, &new_username).await;
+      consume_error(index_result);
+
+      debug!("Sending username update to {:?}", device_list.device_ids);
+      let device_ids: Vec<&str> =
+        device_list.device_ids.iter().map(AsRef::as_ref).collect();
+      let result =
+        tunnelbroker::send_username_updated(&device_ids, &new_username).await;
+      consume_error(result);
+    });
+
+    let response = Empty {};
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn refresh_access_token(
+    &self,
+    request: tonic::Request<Empty>,
+  ) -> Result<tonic::Response<RefreshAccessTokenResponse>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    let access_token = get_value(&request, request_metadata::ACCESS_TOKEN)
+      .ok_or_else(|| {
+        Status::unauthenticated(tonic_status_messages::MISSING_CREDENTIALS)
+      })?;
+
+    debug!("Refreshing access token for device: {}", device_id);
+    let Some(token) = self
+      .db_client
+      .rotate_access_token(user_id, device_id, access_token)
+      .await?
+    else {
+      // The interceptor also accepts the previous token during
+      // the overlap window, but only the current one can be rotated
+      return Err(Status::failed_precondition(
+        tonic_status_messages::ACCESS_TOKEN_ROTATED,
+      ));
+    };
+
+    let expires_at = token
+      .expires_at(&CONFIG.access_token_lifetimes)
+      .map(|expires_at| expires_at.timestamp_millis());
+    let response = RefreshAccessTokenResponse {
+      access_token: token.access_token,
+      expires_at,
+    };
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn list_sessions(
+    &self,
+    request: tonic::Request<Empty>,
+  ) -> Result<tonic::Response<ListSessionsResponse>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+
+    let (devices, tokens) = tokio::try_join!(
+      self.db_client.get_current_devices(&user_id),
+      self.db_client.get_access_tokens_for_user(&user_id),
+    )?;
+
+    let now = Utc::now();
+    let lifetimes = &CONFIG.access_token_lifetimes;
+    let mut tokens: HashMap<String, AccessTokenData> = tokens
+      .into_iter()
+      .filter(|token| token.is_valid() && !token.is_expired(lifetimes, now))
+      .map(|token| (token.signing_public_key.clone(), token))
+      .collect();
+
+    let sessions = devices
+      .into_iter()
+      .filter_map(|device| {
+        let token = tokens.remove(&device.device_id)?;
+        Some(SessionInfo {
+          is_current_device: device.device_id == device_id,
+          platform_details: Some(device.platform_details.into()),
+          last_login_time: device.login_time.timestamp_millis(),
+          token_creation_time: token.created.timestamp_millis(),
+          token_last_used_time: token
+            .last_used
+            .map(|last_used| last_used.timestamp_millis()),
+          device_id: device.device_id,
+        })
+      })
+      .collect();
+
+    Ok(Response::new(ListSessionsResponse { sessions }))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn revoke_session(
+    &self,
+    request: tonic::Request<RevokeSessionRequest>,
+  ) -> Result<tonic::Response<Empty>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    let target_device_id = request.into_inner().device_id;
+
+    info!(
+      "Revoke session request for user_id={}, device_id={}",
+      redact_sensitive_data(&user_id),
+      redact_sensitive_data(&target_device_id)
+    );
+    if target_device_id != device_id {
+      self
+        .verify_device_on_device_list(
+          &user_id,
+          &device_id,
+          DeviceListItemKind::Primary,
+        )
+        .await?;
+    }
+
+    // only sessions of the caller's own account can be revoked
+    if self
+      .db_client
+      .get_access_token_data(user_id.clone(), target_device_id.clone())
+      .await?
+      .is_none()
+    {
+      return Err(Status::not_found(tonic_status_messages::DEVICE_NOT_FOUND));
+    }
+
+    self
+      .db_client
+      .delete_access_token_data(&user_id, &target_device_id)
+      .await?;
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::SessionRevoked)
+          .with_device_id(device_id)
+          .with_details(&target_device_id),
+      )
+      .await;
+
+    let result =
+      tunnelbroker::terminate_device_sessions(&[target_device_id]).await;
+    consume_error(result);
+
+    Ok(Response::new(Empty {}))
+  }
+
   #[tracing::instrument(skip_all)]
   async fn log_out_user(
     &self,
@@ -461,6 +841,7 @@ impl IdentityClientService for AuthenticatedService {
         crate::comm_service::backup::delete_backup_user_data(
           &user_id,
           &self.comm_auth_service,
+          false,
         )
         .await?;
       }
@@ -670,7 +1051,7 @@ impl IdentityClientService for AuthenticatedService {
       ));
     }
 
-    self.delete_services_data_for_user(&user_id).await?;
+    self.delete_services_data_for_user(&user_id, true).await?;
 
     self.db_client.delete_user(user_id.clone()).await?;
 
@@ -685,9 +1066,14 @@ impl IdentityClientService for AuthenticatedService {
   ) -> Result<tonic::Response<DeletePasswordUserStartResponse>, tonic::Status>
   {
     let (user_id, _) = get_user_and_device_id(&request)?;
+    let client_address = get_client_address(&request);
     let message = request.into_inner();
 
     debug!("Attempting to start deleting password user: {}", user_id);
+    let mut attempt_subjects = vec![AttemptSubject::UserID(user_id.clone())];
+    attempt_subjects.extend(client_address.map(AttemptSubject::Client));
+    register_login_attempt(&self.db_client, &attempt_subjects).await?;
+
     let maybe_username_and_password_file = self
       .db_client
       .get_username_and_password_file(&user_id)
@@ -749,7 +1135,7 @@ impl IdentityClientService for AuthenticatedService {
       .finish(&message.opaque_login_upload)
       .map_err(protocol_error_to_grpc_status)?;
 
-    self.delete_services_data_for_user(&user_id).await?;
+    self.delete_services_data_for_user(&user_id, true).await?;
 
     self.db_client.delete_user(user_id.clone()).await?;
 
@@ -771,7 +1157,7 @@ impl IdentityClientService for AuthenticatedService {
 
     for user_id_to_delete in request.into_inner().user_ids {
       self
-        .delete_services_data_for_user(&user_id_to_delete)
+        .delete_services_data_for_user(&user_id_to_delete, true)
         .await?;
       self.db_client.delete_user(user_id_to_delete).await?;
     }
@@ -884,9 +1270,19 @@ impl IdentityClientService for AuthenticatedService {
 
     // Delete backups, blob holders and tunnelbroker device tokens.
     // This has to be done before resetting device list.
-    self.delete_services_data_for_user(&state.user_id).await?;
+    self
+      .delete_services_data_for_user(&state.user_id, false)
+      .await?;
     self.db_client.reset_device_list(&state.user_id).await?;
 
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(state.user_id, AuditEventType::PasswordReset)
+          .with_staff_user_id(staff_user_id),
+      )
+      .await;
+
     let response = Empty {};
     Ok(Response::new(response))
   }
@@ -899,6 +1295,8 @@ impl IdentityClientService for AuthenticatedService {
     let GetDeviceListRequest {
       user_id,
       since_timestamp,
+      known_log_size,
+      include_log_proof,
     } = request.into_inner();
 
     let since = since_timestamp
@@ -911,26 +1309,51 @@ impl IdentityClientService for AuthenticatedService {
       })
       .transpose()?;
 
+    // The log covers the whole history, so it's needed even if
+    // only recent updates are requested. Otherwise only the requested
+    // updates are fetched.
+    let include_log_proof = include_log_proof || known_log_size.is_some();
+    let history_since = if include_log_proof { None } else { since };
     let mut db_result = self
       .db_client
-      .get_device_list_history(user_id, since)
+      .get_device_list_history(&user_id, history_since)
       .await?;
 
     // these should be sorted already, but just in case
     db_result.sort_by_key(|list| list.timestamp);
 
+    // updates before `since` are only fetched for the proof
+    let first_returned_index = match (include_log_proof, since) {
+      (true, Some(since)) => {
+        db_result.partition_point(|list| list.timestamp <= since)
+      }
+      _ => 0,
+    };
+
     let device_list_updates: Vec<SignedDeviceList> = db_result
       .into_iter()
       .map(SignedDeviceList::try_from)
       .collect::<Result<Vec<_>, _>>()?;
 
-    let stringified_updates = device_list_updates
+    let mut stringified_updates = device_list_updates
       .iter()
       .map(SignedDeviceList::as_json_string)
       .collect::<Result<Vec<_>, _>>()?;
 
+    let log_proof = include_log_proof
+      .then(|| {
+        device_list_log::device_list_log_proof(
+          &user_id,
+          &stringified_updates,
+          first_returned_index,
+          known_log_size,
+        )
+      })
+      .transpose()?;
+
     Ok(Response::new(GetDeviceListResponse {
-      device_list_updates: stringified_updates,
+      device_list_updates: stringified_updates.split_off(first_returned_index),
+      log_proof,
     }))
   }
 
@@ -998,6 +1421,145 @@ impl IdentityClientService for AuthenticatedService {
     Ok(Response::new(response))
   }
 
+  #[tracing::instrument(skip_all)]
+  async fn get_device_list_history_page(
+    &self,
+    request: tonic::Request<DeviceListHistoryPageRequest>,
+  ) -> Result<tonic::Response<DeviceListHistoryPageResponse>, tonic::Status> {
+    let DeviceListHistoryPageRequest {
+      user_id,
+      cursor,
+      since_timestamp,
+    } = request.into_inner();
+
+    let since = since_timestamp
+      .map(|timestamp| {
+        DateTime::from_timestamp_millis(timestamp).ok_or_else(|| {
+          tonic::Status::invalid_argument(
+            tonic_status_messages::INVALID_TIMESTAMP,
+          )
+        })
+      })
+      .transpose()?;
+    let start_after = match cursor {
+      Some(cursor) => Some(
+        crate::device_list::decode_history_cursor(&cursor).ok_or_else(
+          || {
+            tonic::Status::invalid_argument(
+              tonic_status_messages::INVALID_CURSOR,
+            )
+          },
+        )?,
+      ),
+      None => since,
+    };
+
+    let (rows, next_start_after) = self
+      .db_client
+      .get_device_list_history_page(&user_id, start_after)
+      .await?;
+    let next_cursor =
+      next_start_after.map(crate::device_list::encode_history_cursor);
+
+    let device_list_updates = rows
+      .into_iter()
+      .map(|row| SignedDeviceList::try_from(row)?.as_json_string())
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Response::new(DeviceListHistoryPageResponse {
+      device_list_updates,
+      next_cursor,
+    }))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn get_device_list_diffs(
+    &self,
+    request: tonic::Request<DeviceListDiffsRequest>,
+  ) -> Result<tonic::Response<DeviceListDiffsResponse>, tonic::Status> {
+    let DeviceListDiffsRequest {
+      user_ids,
+      from_timestamp,
+      to_timestamp,
+    } = request.into_inner();
+
+    let parse_timestamp = |timestamp| {
+      DateTime::from_timestamp_millis(timestamp).ok_or_else(|| {
+        tonic::Status::invalid_argument(
+          tonic_status_messages::INVALID_TIMESTAMP,
+        )
+      })
+    };
+    let from = parse_timestamp(from_timestamp)?;
+    let to = to_timestamp.map(parse_timestamp).transpose()?;
+    if to.is_some_and(|to| to < from) {
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::INVALID_TIMESTAMP,
+      ));
+    }
+
+    let user_ids: HashSet<String> = user_ids.into_iter().collect();
+    if user_ids.len() > DEVICE_LIST_DIFFS_MAX_USERS {
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::TOO_MANY_USERS,
+      ));
+    }
+    debug!("Requesting device list diffs for {} users", user_ids.len());
+
+    let device_lists: Vec<_> = futures::stream::iter(user_ids)
+      .map(|user_id| async move {
+        let to_list = async {
+          match to {
+            Some(to) => self.db_client.get_device_list_at(&user_id, to).await,
+            None => self.db_client.get_current_device_list(&user_id).await,
+          }
+        };
+        let (from_list, to_list) = tokio::try_join!(
+          self.db_client.get_device_list_at(&user_id, from),
+          to_list
+        )?;
+        Ok::<_, tonic::Status>((user_id, from_list, to_list))
+      })
+      .buffer_unordered(DEVICE_LIST_DIFFS_CONCURRENCY)
+      .try_collect()
+      .await?;
+
+    let users_device_list_diffs = device_lists
+      .into_iter()
+      .filter_map(|(user_id, from_list, to_list)| {
+        let previous_devices =
+          from_list.map(|list| list.device_ids).unwrap_or_default();
+        let (new_devices, timestamp) = to_list
+          .map(|list| {
+            (list.device_ids, Some(list.timestamp.timestamp_millis()))
+          })
+          .unwrap_or_default();
+
+        let (added_device_ids, removed_device_ids) =
+          crate::device_list::device_list_diff(&previous_devices, &new_devices);
+        let primary_device_id = new_devices.first().cloned();
+        if added_device_ids.is_empty()
+          && removed_device_ids.is_empty()
+          && primary_device_id.as_ref() == previous_devices.first()
+        {
+          return None;
+        }
+
+        let diff = DeviceListDiff {
+          added_device_ids,
+          removed_device_ids,
+          primary_device_id,
+          timestamp,
+        };
+        Some((user_id, diff))
+      })
+      .collect();
+
+    Ok(Response::new(DeviceListDiffsResponse {
+      users_device_list_diffs,
+    }))
+  }
+
   #[tracing::instrument(skip_all)]
   async fn update_device_list(
     &self,
@@ -1068,6 +1630,112 @@ impl IdentityClientService for AuthenticatedService {
       .apply_devicelist_update(&user_id, update, validator, true)
       .await?;
 
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::DeviceListUpdate)
+          .with_device_id(device_id),
+      )
+      .await;
+
+    Ok(Response::new(Empty {}))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn rotate_primary_device(
+    &self,
+    request: tonic::Request<RotatePrimaryDeviceRequest>,
+  ) -> Result<Response<Empty>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    info!(
+      "Primary device rotation request for user {}.",
+      redact_sensitive_data(&user_id),
+    );
+
+    if self
+      .db_client
+      .get_user_login_flow(&user_id)
+      .await?
+      .is_v1_flow()
+    {
+      return Err(tonic::Status::failed_precondition(
+        tonic_status_messages::USE_V1_FLOW,
+      ));
+    }
+
+    self
+      .verify_device_on_device_list(
+        &user_id,
+        &device_id,
+        DeviceListItemKind::Primary,
+      )
+      .await?;
+
+    let new_list = SignedDeviceList::try_from(request.into_inner())?;
+    let update = DeviceListUpdate::try_from(new_list)?;
+
+    // Both the previous and the new primary device have to sign the list
+    if update.current_primary_signature.is_none()
+      || update.last_primary_signature.is_none()
+    {
+      debug!("Missing primary device signature for primary device rotation");
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::INVALID_DEVICE_LIST_SIGNATURE,
+      ));
+    }
+
+    // Only mobile devices can be primary
+    let Some(new_primary_device_id) = update.devices.first() else {
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::INVALID_DEVICE_LIST_UPDATE,
+      ));
+    };
+    let new_primary_device = self
+      .db_client
+      .get_device_data(&user_id, new_primary_device_id)
+      .await?;
+    let is_mobile_device = new_primary_device.is_some_and(|device| {
+      matches!(device.device_type(), DeviceType::Ios | DeviceType::Android)
+    });
+    if !is_mobile_device {
+      debug!("New primary device is not a mobile device");
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::INVALID_DEVICE_LIST_UPDATE,
+      ));
+    }
+
+    let new_device_list = self
+      .db_client
+      .apply_devicelist_update(
+        &user_id,
+        update,
+        Some(crate::device_list::validation::primary_device_rotation_validator),
+        true,
+      )
+      .await?;
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(&user_id, AuditEventType::PrimaryDeviceRotation)
+          .with_device_id(device_id),
+      )
+      .await;
+
+    tokio::spawn(async move {
+      debug!(
+        "Sending device list updates to {:?}",
+        new_device_list.device_ids
+      );
+      let device_ids: Vec<&str> = new_device_list
+        .device_ids
+        .iter()
+        .map(AsRef::as_ref)
+        .collect();
+      let result = tunnelbroker::send_device_list_update(&device_ids).await;
+      consume_error(result);
+    });
+
     Ok(Response::new(Empty {}))
   }
 
@@ -1111,8 +1779,21 @@ impl IdentityClientService for AuthenticatedService {
 
     self
       .db_client
-      .add_farcaster_id(user_id, message.farcaster_id)
+      .add_farcaster_id(user_id.clone(), message.farcaster_id.clone())
       .await?;
+    tokio::spawn(fname_registry::update_farcaster_username(
+      self.db_client.clone(),
+      user_id.clone(),
+      message.farcaster_id.clone(),
+    ));
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::FarcasterLink)
+          .with_details(message.farcaster_id),
+      )
+      .await;
 
     let response = Empty {};
     Ok(Response::new(response))
@@ -1158,6 +1839,200 @@ impl IdentityClientService for AuthenticatedService {
     Ok(Response::new(response))
   }
 
+  #[tracing::instrument(skip_all)]
+  async fn start_totp_enrollment(
+    &self,
+    request: tonic::Request<Empty>,
+  ) -> Result<Response<StartTotpEnrollmentResponse>, tonic::Status> {
+    let (user_id, _) = get_user_and_device_id(&request)?;
+
+    let identifier = self
+      .db_client
+      .get_user_identity(&user_id)
+      .await?
+      .ok_or_else(|| Status::not_found(tonic_status_messages::USER_NOT_FOUND))?
+      .identifier;
+    let Identifier::Username(username) = identifier else {
+      return Err(Status::failed_precondition(
+        tonic_status_messages::WALLET_USER,
+      ));
+    };
+
+    if self.db_client.get_totp_settings(&user_id).await?.is_some() {
+      return Err(Status::already_exists(
+        tonic_status_messages::SECOND_FACTOR_ALREADY_ENABLED,
+      ));
+    }
+
+    let secret = totp::generate_secret(&mut OsRng);
+    let provisioning_uri = totp::provisioning_uri(&secret, &username);
+    let session_id = self
+      .db_client
+      .insert_workflow(WorkflowInProgress::TotpEnrollment(Box::new(
+        TotpEnrollmentInfo::new(user_id, secret.clone()),
+      )))
+      .await?;
+
+    let response = StartTotpEnrollmentResponse {
+      session_id,
+      secret,
+      provisioning_uri,
+    };
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn finish_totp_enrollment(
+    &self,
+    request: tonic::Request<FinishTotpEnrollmentRequest>,
+  ) -> Result<Response<FinishTotpEnrollmentResponse>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    let message = request.into_inner();
+
+    let Some(WorkflowInProgress::TotpEnrollment(state)) =
+      self.db_client.get_workflow(message.session_id).await?
+    else {
+      return Err(Status::not_found(tonic_status_messages::SESSION_NOT_FOUND));
+    };
+    if state.user_id != user_id {
+      return Err(Status::not_found(tonic_status_messages::SESSION_NOT_FOUND));
+    }
+
+    let Some(step) =
+      totp::verify_code(&state.secret, message.code.trim(), Utc::now(), None)
+    else {
+      return Err(Status::invalid_argument(
+        tonic_status_messages::INVALID_SECOND_FACTOR_CODE,
+      ));
+    };
+
+    let recovery_codes = totp::generate_recovery_codes(&mut OsRng);
+    let recovery_code_hashes = recovery_codes
+      .iter()
+      .map(|code| totp::hash_recovery_code(code))
+      .collect();
+    self
+      .db_client
+      .enable_totp(user_id.clone(), state.secret, recovery_code_hashes, step)
+      .await
+      .map_err(|err| match err {
+        DBError::CannotOverwrite => Status::already_exists(
+          tonic_status_messages::SECOND_FACTOR_ALREADY_ENABLED,
+        ),
+        err => handle_db_error(err),
+      })?;
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::SecondFactorEnabled)
+          .with_device_id(device_id),
+      )
+      .await;
+
+    let response = FinishTotpEnrollmentResponse { recovery_codes };
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn disable_totp(
+    &self,
+    request: tonic::Request<DisableTotpRequest>,
+  ) -> Result<Response<Empty>, tonic::Status> {
+    let (user_id, device_id) = get_user_and_device_id(&request)?;
+    let message = request.into_inner();
+
+    let Some(totp_settings) =
+      self.db_client.get_totp_settings(&user_id).await?
+    else {
+      return Err(Status::failed_precondition(
+        tonic_status_messages::SECOND_FACTOR_NOT_ENABLED,
+      ));
+    };
+
+    // a stolen session shouldn't allow guessing codes
+    let attempt_subject = AttemptSubject::UserID(user_id.clone());
+    register_login_attempt(
+      &self.db_client,
+      std::slice::from_ref(&attempt_subject),
+    )
+    .await?;
+
+    let is_valid = self
+      .db_client
+      .verify_second_factor(&user_id, &totp_settings, &message.code)
+      .await?;
+    if !is_valid {
+      return Err(Status::unauthenticated(
+        tonic_status_messages::INVALID_SECOND_FACTOR_CODE,
+      ));
+    }
+
+    self.db_client.disable_totp(user_id.clone()).await?;
+    consume_error(self.db_client.reset_login_attempts(&attempt_subject).await);
+
+    self
+      .db_client
+      .record_audit_event(
+        AuditLogEntry::new(user_id, AuditEventType::SecondFactorDisabled)
+          .with_device_id(device_id),
+      )
+      .await;
+
+    Ok(Response::new(Empty {}))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn get_audit_log(
+    &self,
+    request: tonic::Request<GetAuditLogRequest>,
+  ) -> Result<Response<GetAuditLogResponse>, tonic::Status> {
+    let (user_id, _) = get_user_and_device_id(&request)?;
+    let message = request.into_inner();
+
+    let (entries, next_cursor) = self
+      .db_client
+      .get_audit_log(&user_id, message.cursor)
+      .await?;
+
+    let response = GetAuditLogResponse {
+      entries: entries.into_iter().map(Into::into).collect(),
+      next_cursor,
+    };
+    Ok(Response::new(response))
+  }
+
+  #[tracing::instrument(skip_all)]
+  async fn privileged_get_audit_log(
+    &self,
+    request: tonic::Request<PrivilegedGetAuditLogRequest>,
+  ) -> Result<Response<GetAuditLogResponse>, tonic::Status> {
+    let (staff_user_id, _) = get_user_and_device_id(&request)?;
+    if !staff::STAFF_USER_IDS.contains(&staff_user_id.as_str()) {
+      return Err(Status::permission_denied(
+        tonic_status_messages::USER_IS_NOT_STAFF,
+      ));
+    }
+
+    let message = request.into_inner();
+    info!(
+      user_id = redact_sensitive_data(&message.user_id),
+      staff_user_id = redact_sensitive_data(&staff_user_id),
+      "Staff member requested audit log."
+    );
+
+    let (entries, next_cursor) = self
+      .db_client
+      .get_audit_log(&message.user_id, message.cursor)
+      .await?;
+
+    let response = GetAuditLogResponse {
+      entries: entries.into_iter().map(Into::into).collect(),
+      next_cursor,
+    };
+    Ok(Response::new(response))
+  }
+
   #[tracing::instrument(skip_all)]
   async fn unlink_farcaster_account(
     &self,
@@ -1170,7 +2045,15 @@ impl IdentityClientService for AuthenticatedService {
       "Attempting to unlink Farcaster account."
     );
 
-    self.db_client.unlink_farcaster(user_id).await?;
+    self.db_client.unlink_farcaster(user_id.clone()).await?;
+
+    self
+      .db_client
+      .record_audit_event(AuditLogEntry::new(
+        user_id,
+        AuditEventType::FarcasterUnlink,
+      ))
+      .await;
 
     let response = Empty {};
     Ok(Response::new(response))
@@ -1270,14 +2153,21 @@ impl AuthenticatedService {
     Ok(())
   }
 
+  /// Removes data of the user from other services. Backups are restorable
+  /// only when `is_account_deletion` is set.
   async fn delete_services_data_for_user(
     &self,
     user_id: &str,
+    is_account_deletion: bool,
   ) -> Result<(), Status> {
     debug!("Attempting to delete Backup data for user: {}", &user_id);
     let (device_list_result, delete_backup_result) = tokio::join!(
       self.db_client.get_current_device_list(user_id),
-      backup::delete_backup_user_data(user_id, &self.comm_auth_service)
+      backup::delete_backup_user_data(
+        user_id,
+        &self.comm_auth_service,
+        is_account_deletion,
+      )
     );
 
     let device_ids = device_list_result?
@@ -1323,6 +2213,45 @@ impl AuthenticatedService {
     Ok(blob_client)
   }
 
+  /// Checks if the username can be taken by the user. Changing own username
+  /// to a look-alike and taking back own released usernames is allowed.
+  async fn check_username_available(
+    &self,
+    user_id: &str,
+    username: &str,
+  ) -> Result<(), tonic::Status> {
+    if is_reserved_username(username) {
+      return Err(tonic::Status::invalid_argument(
+        tonic_status_messages::USERNAME_RESERVED,
+      ));
+    }
+
+    let current_owners = self
+      .db_client
+      .get_user_ids_with_similar_username(username)
+      .await?;
+    let reserved_for = self
+      .db_client
+      .get_user_id_of_similar_reserved_username(username)
+      .await?;
+    let released_by = self
+      .db_client
+      .get_released_username_owners(username)
+      .await?;
+
+    let taken_by_other_user = current_owners
+      .into_iter()
+      .chain(reserved_for)
+      .chain(released_by)
+      .any(|owner| owner != user_id);
+    if taken_by_other_user {
+      return Err(tonic::Status::already_exists(
+        tonic_status_messages::USERNAME_ALREADY_EXISTS,
+      ));
+    }
+    Ok(())
+  }
+
   async fn get_current_device_list(
     &self,
     user_id: &str,
@@ -1370,6 +2299,25 @@ pub struct UpdatePasswordInfo {
   pub opaque_server_login: comm_opaque2::server::Login,
 }
 
+#[derive(
+  Clone, serde::Serialize, serde::Deserialize, derive_more::Constructor,
+)]
+pub struct UsernameChangeInfo {
+  pub user_id: String,
+  pub old_username: String,
+  pub new_username: String,
+  pub opaque_server_login: comm_opaque2::server::Login,
+}
+
+#[derive(
+  Clone, serde::Serialize, serde::Deserialize, derive_more::Constructor,
+)]
+pub struct TotpEnrollmentInfo {
+  pub user_id: String,
+  /// Base32-encoded secret, saved after the first code is verified
+  pub secret: String,
+}
+
 #[derive(
   Clone, serde::Serialize, serde::Deserialize, derive_more::Constructor,
 )]
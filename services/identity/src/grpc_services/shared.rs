@@ -2,6 +2,7 @@ use grpc_clients::error::unsupported_version;
 use tonic::{Request, Status};
 use tracing::trace;
 
+use crate::config::CONFIG;
 use crate::constants::{
   request_metadata, tonic_status_messages, MIN_SUPPORTED_NATIVE_VERSION,
   MIN_SUPPORTED_WEB_VERSION,
@@ -70,3 +71,62 @@ pub fn get_value<T>(req: &Request<T>, key: &str) -> Option<String> {
   let raw_value = req.metadata().get(key)?;
   raw_value.to_str().ok().map(|s| s.to_string())
 }
+
+/// Returns IP address of the client. Requests coming through the load
+/// balancer have it in the `X-Forwarded-For` header.
+pub fn get_client_address<T>(req: &Request<T>) -> Option<String> {
+  if let Some(forwarded_for) = get_value(req, request_metadata::FORWARDED_FOR) {
+    let client_address =
+      forwarded_client_address(&forwarded_for, CONFIG.trusted_proxy_count);
+    if let Some(client_address) = client_address {
+      return Some(client_address.to_string());
+    }
+  }
+  req.remote_addr().map(|addr| addr.ip().to_string())
+}
+
+/// Each proxy appends the address it received the request from, so only
+/// the last `trusted_proxy_count` entries can be trusted. The leftmost
+/// of them is the client as seen by the outermost trusted proxy.
+fn forwarded_client_address(
+  forwarded_for: &str,
+  trusted_proxy_count: usize,
+) -> Option<&str> {
+  let addresses: Vec<&str> = forwarded_for.split(',').map(str::trim).collect();
+  let trusted_count = trusted_proxy_count.min(addresses.len());
+  if trusted_count == 0 {
+    return None;
+  }
+
+  let client_address = addresses[addresses.len() - trusted_count];
+  (!client_address.is_empty()).then_some(client_address)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_forwarded_client_address() {
+    assert_eq!(forwarded_client_address("1.1.1.1", 1), Some("1.1.1.1"));
+    assert_eq!(
+      forwarded_client_address("1.1.1.1, 2.2.2.2", 2),
+      Some("1.1.1.1")
+    );
+    assert_eq!(forwarded_client_address("1.1.1.1", 2), Some("1.1.1.1"));
+    assert_eq!(forwarded_client_address("1.1.1.1", 0), None);
+    assert_eq!(forwarded_client_address("", 1), None);
+  }
+
+  #[test]
+  fn test_spoofed_forwarded_for_entry_is_ignored() {
+    // client sent `X-Forwarded-For: 6.6.6.6`, the load balancer
+    // appended the address the request actually came from
+    let forwarded_for = "6.6.6.6, 1.1.1.1";
+    assert_eq!(forwarded_client_address(forwarded_for, 1), Some("1.1.1.1"));
+
+    // CDN in front of the load balancer
+    let forwarded_for = "6.6.6.6, 1.1.1.1, 3.3.3.3";
+    assert_eq!(forwarded_client_address(forwarded_for, 2), Some("1.1.1.1"));
+  }
+}
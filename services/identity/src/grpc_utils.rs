@@ -129,6 +129,8 @@ impl From<DeviceKeysInfo> for OutboundKeyInfo {
       identity_info: Some(device_info.device_key_info.into()),
       content_prekey: Some(device_info.content_prekey.into()),
       notif_prekey: Some(device_info.notif_prekey.into()),
+      content_prekey_only: content_one_time_key.is_none(),
+      notif_prekey_only: notif_one_time_key.is_none(),
       one_time_content_prekey: content_one_time_key,
       one_time_notif_prekey: notif_one_time_key,
     }
@@ -0,0 +1,62 @@
+//! Asks devices that are running out of one-time keys or have stale
+//! prekeys to upload new ones, so that peers don't fail to start
+//! sessions with them. Runs as a scheduled task, so that only one
+//! sweep runs at a time.
+
+use chrono::Utc;
+use tracing::{error, info};
+
+use crate::{
+  comm_service::tunnelbroker, constants::error_types, database::DatabaseClient,
+  error::Error, log::redact_sensitive_data,
+};
+
+/// Checks devices whose key refresh is due. Devices that don't need
+/// a refresh are rescheduled for when their prekeys become stale.
+#[tracing::instrument(skip_all)]
+pub async fn sweep_devices(db_client: &DatabaseClient) -> Result<(), Error> {
+  let now = Utc::now();
+  let devices = db_client.get_devices_due_for_key_refresh(now).await?;
+
+  let mut num_requested = 0;
+  for device in &devices {
+    if !device.should_request_refresh(now) {
+      db_client
+        .reschedule_key_refresh(
+          &device.user_id,
+          &device.device_id,
+          device.next_refresh_due(now),
+        )
+        .await?;
+      continue;
+    }
+
+    // The device could have been checked by a sweep that is still running
+    let marked = db_client
+      .try_mark_key_refresh_requested(&device.user_id, &device.device_id, now)
+      .await?;
+    if !marked {
+      continue;
+    }
+
+    let result =
+      tunnelbroker::send_refresh_keys_request(&device.device_id).await;
+    if let Err(err) = result {
+      error!(
+        errorType = error_types::KEY_HEALTH_LOG,
+        device_id = redact_sensitive_data(&device.device_id),
+        "Failed to request keys refresh: {:?}",
+        err
+      );
+      continue;
+    }
+    num_requested += 1;
+  }
+
+  info!(
+    "Key health sweep finished. Requested keys refresh from {} of {} devices",
+    num_requested,
+    devices.len()
+  );
+  Ok(())
+}
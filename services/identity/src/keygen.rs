@@ -1,5 +1,10 @@
-use crate::constants::SECRETS_SETUP_FILE;
+use crate::constants::{
+  SECRETS_DEVICE_LIST_LOG_KEY_FILE, SECRETS_SETUP_FILE,
+  SECRETS_TOTP_ENCRYPTION_KEY_FILE,
+};
+use crate::device_list_log::keypair_from_secret;
 use base64::{engine::general_purpose, Engine as _};
+use rand::{rngs::OsRng, RngCore};
 use std::{fs, io, path};
 
 pub fn generate_and_persist_keypair(dir: &str) -> Result<(), io::Error> {
@@ -23,5 +28,36 @@ pub fn generate_and_persist_keypair(dir: &str) -> Result<(), io::Error> {
     fs::write(&path, encoded_server_setup)?;
   }
 
+  // Device list transparency log signing key
+  let mut path = secrets_dir.clone();
+  path.push(SECRETS_DEVICE_LIST_LOG_KEY_FILE);
+  if path.exists() {
+    eprintln!("{:?} already exists, skipping", path);
+  } else {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let keypair = keypair_from_secret(&secret)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    println!("Writing device list log signing key to {:?}", path);
+    fs::write(&path, general_purpose::STANDARD_NO_PAD.encode(secret))?;
+    println!(
+      "Device list log public key (distribute to clients): {}",
+      general_purpose::STANDARD_NO_PAD.encode(keypair.public.as_bytes())
+    );
+  }
+
+  // TOTP secrets encryption key
+  let mut path = secrets_dir.clone();
+  path.push(SECRETS_TOTP_ENCRYPTION_KEY_FILE);
+  if path.exists() {
+    eprintln!("{:?} already exists, skipping", path);
+  } else {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    println!("Writing TOTP encryption key to {:?}", path);
+    fs::write(&path, general_purpose::STANDARD_NO_PAD.encode(key))?;
+  }
+
   Ok(())
 }
@@ -0,0 +1,236 @@
+//! Brute-force protection for password authentication.
+//!
+//! Every OPAQUE login start is counted as an attempt, because the client
+//! can verify a password guess locally without calling the finish RPC.
+//! After a number of attempts each next one has to wait progressively
+//! longer, and past a threshold the subject is locked out for a while.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tonic::{
+  metadata::{MetadataMap, MetadataValue},
+  Code, Status,
+};
+use tracing::warn;
+
+use crate::{
+  constants::{
+    request_metadata, tonic_status_messages, ACCOUNT_FREE_LOGIN_ATTEMPTS,
+    ACCOUNT_LOGIN_LOCKOUT_THRESHOLD, CLIENT_FREE_LOGIN_ATTEMPTS,
+    CLIENT_LOGIN_LOCKOUT_THRESHOLD, LOGIN_ATTEMPTS_BASE_DELAY,
+    LOGIN_ATTEMPTS_MAX_DELAY, LOGIN_LOCKOUT_DURATION,
+  },
+  database::DatabaseClient,
+  log::redact_sensitive_data,
+};
+
+/// Who the attempts are counted for
+#[derive(Clone, Debug)]
+pub enum AttemptSubject {
+  Username(String),
+  UserID(String),
+  /// Client IP address
+  Client(String),
+}
+
+impl AttemptSubject {
+  pub fn key(&self) -> String {
+    match self {
+      Self::Username(username) => {
+        format!("username#{}", username.to_lowercase())
+      }
+      Self::UserID(user_id) => format!("user#{user_id}"),
+      Self::Client(address) => format!("client#{address}"),
+    }
+  }
+
+  /// Returns number of attempts allowed without delay
+  /// and number of attempts that trigger a lockout
+  fn limits(&self) -> (u32, u32) {
+    match self {
+      Self::Username(_) | Self::UserID(_) => {
+        (ACCOUNT_FREE_LOGIN_ATTEMPTS, ACCOUNT_LOGIN_LOCKOUT_THRESHOLD)
+      }
+      Self::Client(_) => {
+        (CLIENT_FREE_LOGIN_ATTEMPTS, CLIENT_LOGIN_LOCKOUT_THRESHOLD)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct LoginAttempts {
+  pub subject: AttemptSubject,
+  /// Number of attempts since the last successful login
+  pub attempts: u32,
+  pub last_attempt: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoginThrottled {
+  /// `true` if the lockout threshold was reached,
+  /// `false` if the attempt was just too soon
+  pub locked: bool,
+  pub retry_after: Duration,
+}
+
+impl LoginAttempts {
+  /// Checks if another attempt is allowed at the given time
+  pub fn check(&self, now: DateTime<Utc>) -> Result<(), LoginThrottled> {
+    let (free_attempts, lockout_threshold) = self.subject.limits();
+
+    let (locked, delay) = if self.attempts >= lockout_threshold {
+      (true, LOGIN_LOCKOUT_DURATION)
+    } else if self.attempts >= free_attempts {
+      (false, attempt_delay(self.attempts - free_attempts))
+    } else {
+      return Ok(());
+    };
+
+    let retry_at = self.last_attempt + delay;
+    match (retry_at - now).to_std() {
+      Ok(retry_after) if !retry_after.is_zero() => Err(LoginThrottled {
+        locked,
+        retry_after,
+      }),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Delay required after given number of attempts over the free limit
+fn attempt_delay(excess_attempts: u32) -> Duration {
+  let multiplier = 2u32.saturating_pow(excess_attempts);
+  LOGIN_ATTEMPTS_BASE_DELAY
+    .saturating_mul(multiplier)
+    .min(LOGIN_ATTEMPTS_MAX_DELAY)
+}
+
+impl From<LoginThrottled> for Status {
+  fn from(value: LoginThrottled) -> Self {
+    let message = if value.locked {
+      tonic_status_messages::LOGIN_TEMPORARILY_LOCKED
+    } else {
+      tonic_status_messages::TOO_MANY_LOGIN_ATTEMPTS
+    };
+
+    // round up, so that clients don't retry too early
+    let retry_after_secs = value.retry_after.as_secs()
+      + u64::from(value.retry_after.subsec_nanos() > 0);
+    let mut metadata = MetadataMap::new();
+    metadata.insert(
+      request_metadata::RETRY_AFTER_SECS,
+      MetadataValue::from(retry_after_secs),
+    );
+    Status::with_metadata(Code::ResourceExhausted, message, metadata)
+  }
+}
+
+/// Fails if any of the subjects is throttled. Otherwise counts
+/// the attempt for all of them.
+pub async fn register_login_attempt(
+  db_client: &DatabaseClient,
+  subjects: &[AttemptSubject],
+) -> Result<(), Status> {
+  let now = Utc::now();
+  for subject in subjects {
+    let Some(attempts) = db_client.get_login_attempts(subject).await? else {
+      continue;
+    };
+    if let Err(throttled) = attempts.check(now) {
+      warn!(
+        subject = redact_sensitive_data(&subject.key()),
+        attempts = attempts.attempts,
+        locked = throttled.locked,
+        "Login attempt throttled."
+      );
+      return Err(throttled.into());
+    }
+  }
+
+  for subject in subjects {
+    db_client.increment_login_attempts(subject, now).await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn attempts(
+    subject: AttemptSubject,
+    attempts: u32,
+    last_attempt: DateTime<Utc>,
+  ) -> LoginAttempts {
+    LoginAttempts {
+      subject,
+      attempts,
+      last_attempt,
+    }
+  }
+
+  #[test]
+  fn test_progressive_delay() {
+    let now = Utc::now();
+    let username = AttemptSubject::Username("Alice".to_string());
+    assert_eq!(username.key(), "username#alice");
+
+    let free = ACCOUNT_FREE_LOGIN_ATTEMPTS;
+    assert!(attempts(username.clone(), free - 1, now).check(now).is_ok());
+
+    let throttled = attempts(username.clone(), free, now)
+      .check(now)
+      .expect_err("attempt should be delayed");
+    assert!(!throttled.locked);
+    assert_eq!(throttled.retry_after, LOGIN_ATTEMPTS_BASE_DELAY);
+
+    let throttled = attempts(username.clone(), free + 2, now)
+      .check(now)
+      .expect_err("attempt should be delayed");
+    assert_eq!(throttled.retry_after, LOGIN_ATTEMPTS_BASE_DELAY * 4);
+
+    let last_attempt = now - LOGIN_ATTEMPTS_BASE_DELAY * 4;
+    assert!(attempts(username, free + 2, last_attempt)
+      .check(now)
+      .is_ok());
+  }
+
+  #[test]
+  fn test_lockout() {
+    let now = Utc::now();
+    let user = AttemptSubject::UserID("user".to_string());
+    let threshold = ACCOUNT_LOGIN_LOCKOUT_THRESHOLD;
+
+    let throttled = attempts(user.clone(), threshold, now)
+      .check(now)
+      .expect_err("subject should be locked");
+    assert!(throttled.locked);
+    assert_eq!(throttled.retry_after, LOGIN_LOCKOUT_DURATION);
+
+    let last_attempt = now - LOGIN_LOCKOUT_DURATION;
+    assert!(attempts(user, threshold, last_attempt).check(now).is_ok());
+
+    // clients have higher limits
+    let client = AttemptSubject::Client("127.0.0.1".to_string());
+    assert!(attempts(client, threshold, now).check(now).is_ok());
+  }
+
+  #[test]
+  fn test_throttled_status() {
+    let status = Status::from(LoginThrottled {
+      locked: true,
+      retry_after: Duration::from_millis(1500),
+    });
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(
+      status.message(),
+      tonic_status_messages::LOGIN_TEMPORARILY_LOCKED
+    );
+    assert_eq!(
+      status.metadata().get(request_metadata::RETRY_AFTER_SECS),
+      Some(&MetadataValue::from(2u64))
+    );
+  }
+}
@@ -15,20 +15,27 @@ mod cors;
 mod database;
 pub mod ddb_utils;
 mod device_list;
+mod device_list_log;
 pub mod error;
+mod eth_rpc;
+mod fname_registry;
 mod grpc_services;
 mod grpc_utils;
 mod http;
 mod id;
+mod key_health;
 mod keygen;
 mod log;
+mod login_attempts;
 mod nonce;
 mod olm;
 mod regex;
 mod reserved_users;
 mod siwe;
+mod skeleton;
 mod sync_identity_search;
 mod token;
+mod totp;
 mod websockets;
 
 mod comm_service {
@@ -162,6 +169,51 @@ async fn main() -> Result<(), BoxedError> {
 
       error::consume_error(sync_result);
     }
+    Command::SweepKeyHealth => {
+      let aws_config = aws::config::defaults(BehaviorVersion::v2024_03_28())
+        .region("us-east-2")
+        .load()
+        .await;
+      let database_client = DatabaseClient::new(&aws_config);
+      let sweep_result = key_health::sweep_devices(&database_client).await;
+
+      error::consume_error(sweep_result);
+    }
+    Command::BackfillUsernameSkeletons => {
+      let aws_config = aws::config::defaults(BehaviorVersion::v2024_03_28())
+        .region("us-east-2")
+        .load()
+        .await;
+      let database_client = DatabaseClient::new(&aws_config);
+      let backfill_result = database_client.backfill_username_skeletons().await;
+      if let Ok(summary) = &backfill_result {
+        info!(
+          "Username skeleton backfill finished. Updated {} users and {} \
+          reserved usernames",
+          summary.users_updated, summary.reserved_usernames_updated
+        );
+      }
+
+      error::consume_error(backfill_result);
+    }
+    Command::BackfillKeyRefreshSchedule => {
+      let aws_config = aws::config::defaults(BehaviorVersion::v2024_03_28())
+        .region("us-east-2")
+        .load()
+        .await;
+      let database_client = DatabaseClient::new(&aws_config);
+      let backfill_result = database_client
+        .backfill_key_refresh_schedule(chrono::Utc::now())
+        .await;
+      if let Ok(devices_updated) = &backfill_result {
+        info!(
+          "Key refresh schedule backfill finished. Updated {} devices",
+          devices_updated
+        );
+      }
+
+      error::consume_error(backfill_result);
+    }
   }
 
   Ok(())
@@ -30,3 +30,9 @@ impl NonceData {
     Utc::now() > self.expiration_time
   }
 }
+
+/// Checks if the nonce has the format of nonces generated by this service
+pub fn is_valid_nonce_format(nonce: &str) -> bool {
+  nonce.len() == NONCE_LENGTH
+    && nonce.chars().all(|c| c.is_ascii_alphanumeric())
+}
@@ -1,6 +1,8 @@
 use regex::Regex;
 
-use crate::constants::VALID_USERNAME_REGEX_STRING;
+use crate::constants::{
+  VALID_FARCASTER_USERNAME_REGEX_STRING, VALID_USERNAME_REGEX_STRING,
+};
 
 pub fn is_valid_username(candidate: &str) -> bool {
   let valid_username_regex = Regex::new(VALID_USERNAME_REGEX_STRING)
@@ -8,6 +10,13 @@ pub fn is_valid_username(candidate: &str) -> bool {
   valid_username_regex.is_match(candidate)
 }
 
+/// Checks if the candidate is a valid Farcaster username (fname)
+pub fn is_valid_farcaster_username(candidate: &str) -> bool {
+  let valid_fname_regex = Regex::new(VALID_FARCASTER_USERNAME_REGEX_STRING)
+    .expect("regex pattern should be valid");
+  valid_fname_regex.is_match(candidate)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -55,4 +64,13 @@ mod tests {
   fn test_invalid_username_invalid_symbol() {
     assert_eq!(is_valid_username("asdf$"), false);
   }
+
+  #[test]
+  fn test_farcaster_username() {
+    assert!(is_valid_farcaster_username("alice-1"));
+    assert!(!is_valid_farcaster_username("Alice"), "Uppercase");
+    assert!(!is_valid_farcaster_username("-alice"), "Leading dash");
+    assert!(!is_valid_farcaster_username("alice.eth"), "ENS name");
+    assert!(!is_valid_farcaster_username(&"a".repeat(17)), "Too long");
+  }
 }
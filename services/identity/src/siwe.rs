@@ -4,16 +4,40 @@ use comm_lib::{
   aws::ddb::types::AttributeValue,
   database::{AttributeExtractor, AttributeMap, TryFromAttribute},
 };
-use siwe::{Message, VerificationOpts};
+use once_cell::sync::Lazy;
+use sha3::{Digest, Keccak256};
+use siwe::Message;
 use time::OffsetDateTime;
 use tonic::Status;
-use tracing::error;
+use tracing::{debug, error};
 
-use crate::constants::{
-  error_types, tonic_status_messages, SOCIAL_PROOF_MESSAGE_ATTRIBUTE,
-  SOCIAL_PROOF_SIGNATURE_ATTRIBUTE,
+use crate::{
+  config::{ServerConfig, CONFIG},
+  constants::{
+    error_types, tonic_status_messages, SOCIAL_PROOF_MESSAGE_ATTRIBUTE,
+    SOCIAL_PROOF_SIGNATURE_ATTRIBUTE,
+  },
+  eth_rpc::{self, EthRpcClient},
+  nonce::is_valid_nonce_format,
 };
 
+/// Value returned by EIP-1271 `isValidSignature` for valid signatures.
+/// It's also the function selector.
+const EIP1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+/// Suffix of EIP-6492 signatures of wallets that may not be deployed yet
+const EIP6492_MAGIC_SUFFIX: [u8; 32] = [
+  0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64,
+  0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92, 0x64, 0x92,
+  0x64, 0x92, 0x64, 0x92, 0x64, 0x92,
+];
+
+static UNIVERSAL_VALIDATOR_SELECTOR: Lazy<[u8; 4]> =
+  Lazy::new(|| function_selector("isValidSig(address,bytes32,bytes)"));
+
+static SIGNATURE_VERIFIER: Lazy<SignatureVerifier> =
+  Lazy::new(|| SignatureVerifier::from_config(&CONFIG));
+
 pub async fn parse_and_verify_siwe_message(
   siwe_message: &str,
   siwe_signature: &str,
@@ -35,23 +59,296 @@ pub async fn parse_and_verify_siwe_message(
       Status::invalid_argument(tonic_status_messages::SIGNATURE_INVALID)
     })?;
 
-  let options = VerificationOpts {
-    domain: None,
-    nonce: None,
-    timestamp: Some(OffsetDateTime::now_utc()),
-  };
-  siwe_message
-    .verify(&decoded_signature, &options)
-    .await
-    .map_err(|e| {
+  verify_message_fields(
+    &siwe_message,
+    &CONFIG.siwe_allowed_domains,
+    OffsetDateTime::now_utc(),
+  )?;
+  SIGNATURE_VERIFIER
+    .verify(&siwe_message, &decoded_signature)
+    .await?;
+
+  Ok(siwe_message)
+}
+
+/// Checks the domain, nonce format and validity period of the message.
+/// Callers still have to check that the nonce was issued by this service.
+fn verify_message_fields(
+  message: &Message,
+  allowed_domains: &[String],
+  now: OffsetDateTime,
+) -> Result<(), Status> {
+  let domain = message.domain.as_str();
+  if !allowed_domains.iter().any(|allowed| allowed == domain) {
+    error!(
+      errorType = error_types::SIWE_LOG,
+      "SIWE message domain not allowed: {}", domain
+    );
+    return Err(Status::invalid_argument(
+      tonic_status_messages::INVALID_SIWE_DOMAIN,
+    ));
+  }
+
+  if !is_valid_nonce_format(&message.nonce) {
+    error!(
+      errorType = error_types::SIWE_LOG,
+      "SIWE message nonce has invalid format"
+    );
+    return Err(Status::invalid_argument(
+      tonic_status_messages::INVALID_NONCE,
+    ));
+  }
+
+  if !message.valid_at(&now) {
+    error!(
+      errorType = error_types::SIWE_LOG,
+      "SIWE message is expired or not yet valid"
+    );
+    return Err(Status::unauthenticated(
+      tonic_status_messages::MESSAGE_NOT_AUTHENTICATED,
+    ));
+  }
+
+  Ok(())
+}
+
+/// Verifies SIWE signatures of externally owned accounts locally.
+/// Smart contract wallet signatures (EIP-1271 and EIP-6492) are verified
+/// by calling the wallet through the Ethereum JSON-RPC endpoint.
+pub struct SignatureVerifier {
+  rpc_client: Option<EthRpcClient>,
+  universal_validator_address: Option<String>,
+}
+
+impl SignatureVerifier {
+  pub fn new(
+    rpc_client: Option<EthRpcClient>,
+    universal_validator_address: Option<String>,
+  ) -> Self {
+    Self {
+      rpc_client,
+      universal_validator_address,
+    }
+  }
+
+  fn from_config(config: &ServerConfig) -> Self {
+    let rpc_client = config.ethereum_rpc_url.clone().map(|url| {
+      EthRpcClient::new(url).expect("Failed to create Ethereum RPC client")
+    });
+    Self::new(rpc_client, config.siwe_universal_validator_address.clone())
+  }
+
+  pub async fn verify(
+    &self,
+    message: &Message,
+    signature: &[u8],
+  ) -> Result<(), Status> {
+    if let Ok(eoa_signature) = <&[u8; 65]>::try_from(signature) {
+      if message.verify_eip191(eoa_signature).is_ok() {
+        return Ok(());
+      }
+    }
+
+    let Some(rpc_client) = &self.rpc_client else {
+      error!(
+        errorType = error_types::SIWE_LOG,
+        "Signature verification failed: invalid EOA signature"
+      );
+      return Err(Status::unauthenticated(
+        tonic_status_messages::MESSAGE_NOT_AUTHENTICATED,
+      ));
+    };
+
+    let message_hash = message.eip191_hash().map_err(|e| {
       error!(
         errorType = error_types::SIWE_LOG,
-        "Signature verification failed: {}", e
+        "Failed to hash SIWE message: {}", e
       );
-      Status::unauthenticated(tonic_status_messages::MESSAGE_NOT_AUTHENTICATED)
+      Status::invalid_argument(tonic_status_messages::INVALID_MESSAGE)
     })?;
 
-  Ok(siwe_message)
+    let is_valid = self
+      .verify_contract_signature(
+        rpc_client,
+        message.address,
+        message_hash,
+        signature,
+      )
+      .await
+      .map_err(|e| {
+        error!(
+          errorType = error_types::ETHEREUM_RPC_LOG,
+          "Failed to verify contract wallet signature: {}", e
+        );
+        Status::internal(tonic_status_messages::UNEXPECTED_ERROR)
+      })?;
+
+    if !is_valid {
+      error!(
+        errorType = error_types::SIWE_LOG,
+        "Signature verification failed: invalid contract wallet signature"
+      );
+      return Err(Status::unauthenticated(
+        tonic_status_messages::MESSAGE_NOT_AUTHENTICATED,
+      ));
+    }
+    Ok(())
+  }
+
+  async fn verify_contract_signature(
+    &self,
+    rpc_client: &EthRpcClient,
+    signer: [u8; 20],
+    message_hash: [u8; 32],
+    signature: &[u8],
+  ) -> Result<bool, eth_rpc::Error> {
+    let signer_address = format!("0x{}", hex::encode(signer));
+    let Some(wrapped_signature) =
+      signature.strip_suffix(&EIP6492_MAGIC_SUFFIX[..])
+    else {
+      return is_valid_eip1271_signature(
+        rpc_client,
+        &signer_address,
+        message_hash,
+        signature,
+      )
+      .await;
+    };
+
+    // The validator simulates the wallet deployment if needed
+    if let Some(validator_address) = &self.universal_validator_address {
+      let call_data = encode_is_valid_sig_call(signer, message_hash, signature);
+      return match rpc_client.call(validator_address, &call_data).await {
+        Ok(result) => Ok(decode_abi_bool(&result)),
+        Err(eth_rpc::Error::Rpc(e)) => {
+          debug!("isValidSig call reverted: {}", e);
+          Ok(false)
+        }
+        Err(e) => Err(e),
+      };
+    }
+
+    // Without the validator only deployed wallets can be verified
+    if rpc_client.get_code(&signer_address).await?.is_empty() {
+      debug!("EIP-6492 signature of a wallet that isn't deployed yet");
+      return Ok(false);
+    }
+    let Some(inner_signature) = decode_eip6492_signature(wrapped_signature)
+    else {
+      debug!("Malformed EIP-6492 signature");
+      return Ok(false);
+    };
+    is_valid_eip1271_signature(
+      rpc_client,
+      &signer_address,
+      message_hash,
+      &inner_signature,
+    )
+    .await
+  }
+}
+
+async fn is_valid_eip1271_signature(
+  rpc_client: &EthRpcClient,
+  signer_address: &str,
+  message_hash: [u8; 32],
+  signature: &[u8],
+) -> Result<bool, eth_rpc::Error> {
+  let call_data = encode_is_valid_signature_call(message_hash, signature);
+  match rpc_client.call(signer_address, &call_data).await {
+    // Calls to accounts without code return empty data
+    Ok(result) => Ok(is_eip1271_magic_value(&result)),
+    Err(eth_rpc::Error::Rpc(e)) => {
+      debug!("isValidSignature call reverted: {}", e);
+      Ok(false)
+    }
+    Err(e) => Err(e),
+  }
+}
+
+fn function_selector(signature: &str) -> [u8; 4] {
+  let hash = Keccak256::digest(signature.as_bytes());
+  [hash[0], hash[1], hash[2], hash[3]]
+}
+
+fn abi_word(value: usize) -> [u8; 32] {
+  let mut word = [0u8; 32];
+  word[24..].copy_from_slice(&(value as u64).to_be_bytes());
+  word
+}
+
+/// Length-prefixed and padded to a multiple of 32 bytes
+fn abi_encode_bytes(bytes: &[u8]) -> Vec<u8> {
+  let mut encoded = abi_word(bytes.len()).to_vec();
+  encoded.extend_from_slice(bytes);
+  let padding = (32 - bytes.len() % 32) % 32;
+  encoded.resize(encoded.len() + padding, 0);
+  encoded
+}
+
+/// `isValidSignature(bytes32 hash, bytes signature)`
+fn encode_is_valid_signature_call(
+  message_hash: [u8; 32],
+  signature: &[u8],
+) -> Vec<u8> {
+  let mut call_data = EIP1271_MAGIC_VALUE.to_vec();
+  call_data.extend_from_slice(&message_hash);
+  call_data.extend_from_slice(&abi_word(64));
+  call_data.extend(abi_encode_bytes(signature));
+  call_data
+}
+
+/// `isValidSig(address signer, bytes32 hash, bytes signature)`
+fn encode_is_valid_sig_call(
+  signer: [u8; 20],
+  message_hash: [u8; 32],
+  signature: &[u8],
+) -> Vec<u8> {
+  let mut signer_word = [0u8; 32];
+  signer_word[12..].copy_from_slice(&signer);
+
+  let mut call_data = UNIVERSAL_VALIDATOR_SELECTOR.to_vec();
+  call_data.extend_from_slice(&signer_word);
+  call_data.extend_from_slice(&message_hash);
+  call_data.extend_from_slice(&abi_word(96));
+  call_data.extend(abi_encode_bytes(signature));
+  call_data
+}
+
+/// The magic value is returned as `bytes4`, padded with zeros
+/// to a full ABI word
+fn is_eip1271_magic_value(data: &[u8]) -> bool {
+  data.len() == 32
+    && data.starts_with(&EIP1271_MAGIC_VALUE)
+    && data[4..].iter().all(|b| *b == 0)
+}
+
+fn decode_abi_bool(data: &[u8]) -> bool {
+  data.len() == 32 && data[31] == 1 && data[..31].iter().all(|b| *b == 0)
+}
+
+fn decode_abi_usize(data: &[u8], offset: usize) -> Option<usize> {
+  let word = data.get(offset..offset.checked_add(32)?)?;
+  if word[..24].iter().any(|b| *b != 0) {
+    return None;
+  }
+  let value = u64::from_be_bytes(word[24..].try_into().ok()?);
+  usize::try_from(value).ok()
+}
+
+fn decode_abi_bytes(data: &[u8], offset: usize) -> Option<Vec<u8>> {
+  let length = decode_abi_usize(data, offset)?;
+  let start = offset.checked_add(32)?;
+  data
+    .get(start..start.checked_add(length)?)
+    .map(<[u8]>::to_vec)
+}
+
+/// Returns the signature from EIP-6492 data with the magic suffix removed:
+/// `abi.encode(address factory, bytes factoryCalldata, bytes signature)`
+fn decode_eip6492_signature(wrapped_signature: &[u8]) -> Option<Vec<u8>> {
+  let signature_offset = decode_abi_usize(wrapped_signature, 64)?;
+  decode_abi_bytes(wrapped_signature, signature_offset)
 }
 
 #[derive(derive_more::Constructor, Clone)]
@@ -120,4 +417,190 @@ mod tests {
     assert_eq!(social_proof_from_attr.message, message);
     assert_eq!(social_proof_from_attr.signature, signature);
   }
+
+  // 2024-01-01T00:00:00Z
+  const ISSUED_AT_UNIX: i64 = 1704067200;
+
+  fn test_message(domain: &str, nonce: &str) -> Message {
+    format!(
+      "{domain} wants you to sign in with your Ethereum account:\n\
+      0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed\n\
+      \n\
+      Test statement\n\
+      \n\
+      URI: https://{domain}\n\
+      Version: 1\n\
+      Chain ID: 1\n\
+      Nonce: {nonce}\n\
+      Issued At: 2024-01-01T00:00:00Z\n\
+      Expiration Time: 2024-01-02T00:00:00Z"
+    )
+    .parse()
+    .expect("failed to parse SIWE message")
+  }
+
+  /// Mock JSON-RPC endpoint. `eth_call` succeeds only if the call data
+  /// contains `valid_signature`, other calls revert.
+  fn spawn_mock_rpc(valid_signature: Vec<u8>) -> reqwest::Url {
+    use hyper::{
+      service::{make_service_fn, service_fn},
+      Body, Request, Response, Server,
+    };
+    use serde_json::json;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let make_service = make_service_fn(move |_| {
+      let valid_signature = hex::encode(&valid_signature);
+      async move {
+        Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+          let valid_signature = valid_signature.clone();
+          async move {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            let request: serde_json::Value =
+              serde_json::from_slice(&body).unwrap();
+            let call_data =
+              request["params"][0]["data"].as_str().unwrap_or_default();
+
+            let response = if request["method"] == "eth_getCode" {
+              json!({ "jsonrpc": "2.0", "id": 1, "result": "0x6080" })
+            } else if call_data.contains(&valid_signature) {
+              let mut result = EIP1271_MAGIC_VALUE.to_vec();
+              result.resize(32, 0);
+              json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": format!("0x{}", hex::encode(result)),
+              })
+            } else {
+              json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": 3, "message": "execution reverted" },
+              })
+            };
+            Ok::<_, hyper::Error>(Response::new(Body::from(
+              response.to_string(),
+            )))
+          }
+        }))
+      }
+    });
+    let server = Server::from_tcp(listener).unwrap().serve(make_service);
+    tokio::spawn(server);
+
+    format!("http://{address}").parse().unwrap()
+  }
+
+  #[test]
+  fn test_message_fields() {
+    let nonce = "abcdefghijklmnopq";
+    let valid_time =
+      OffsetDateTime::from_unix_timestamp(ISSUED_AT_UNIX + 3600).unwrap();
+    let allowed_domains = vec!["comm.app".to_string()];
+
+    let message = test_message("comm.app", nonce);
+    assert!(
+      verify_message_fields(&message, &allowed_domains, valid_time).is_ok()
+    );
+    let status = verify_message_fields(&message, &[], valid_time)
+      .expect_err("domain should be rejected if none are allowed");
+    assert_eq!(status.message(), tonic_status_messages::INVALID_SIWE_DOMAIN);
+
+    let expired_time =
+      OffsetDateTime::from_unix_timestamp(ISSUED_AT_UNIX + 2 * 86400).unwrap();
+    let status =
+      verify_message_fields(&message, &allowed_domains, expired_time)
+        .expect_err("expired message should be rejected");
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    let message = test_message("evil.example", nonce);
+    let status = verify_message_fields(&message, &allowed_domains, valid_time)
+      .expect_err("domain should be rejected");
+    assert_eq!(status.message(), tonic_status_messages::INVALID_SIWE_DOMAIN);
+
+    let message = test_message("comm.app", "shortnonce");
+    let status = verify_message_fields(&message, &allowed_domains, valid_time)
+      .expect_err("nonce should be rejected");
+    assert_eq!(status.message(), tonic_status_messages::INVALID_NONCE);
+  }
+
+  #[test]
+  fn test_abi_encoding() {
+    assert_eq!(
+      function_selector("isValidSignature(bytes32,bytes)"),
+      EIP1271_MAGIC_VALUE
+    );
+
+    let signature = vec![0xab; 40];
+    let call_data = encode_is_valid_signature_call([1; 32], &signature);
+    // selector, hash, offset, length and two words of signature
+    assert_eq!(call_data.len(), 4 + 5 * 32);
+    assert_eq!(decode_abi_bytes(&call_data[4..], 64), Some(signature));
+
+    let mut bool_result = [0u8; 32];
+    bool_result[31] = 1;
+    assert!(decode_abi_bool(&bool_result));
+    assert!(!decode_abi_bool(&[]));
+
+    let mut magic_value = EIP1271_MAGIC_VALUE.to_vec();
+    assert!(!is_eip1271_magic_value(&magic_value));
+    magic_value.resize(32, 0);
+    assert!(is_eip1271_magic_value(&magic_value));
+    magic_value[31] = 1;
+    assert!(!is_eip1271_magic_value(&magic_value));
+    magic_value.truncate(4);
+    magic_value.resize(64, 0);
+    assert!(!is_eip1271_magic_value(&magic_value));
+  }
+
+  #[test]
+  fn test_eip6492_signature_decoding() {
+    let factory_calldata = vec![0xcd; 70];
+    let signature = vec![0xab; 65];
+    let encoded_calldata = abi_encode_bytes(&factory_calldata);
+
+    let mut wrapped_signature = [0u8; 32].to_vec();
+    wrapped_signature.extend_from_slice(&abi_word(96));
+    wrapped_signature.extend_from_slice(&abi_word(96 + encoded_calldata.len()));
+    wrapped_signature.extend(encoded_calldata);
+    wrapped_signature.extend(abi_encode_bytes(&signature));
+
+    assert_eq!(
+      decode_eip6492_signature(&wrapped_signature),
+      Some(signature)
+    );
+    assert_eq!(decode_eip6492_signature(&wrapped_signature[..64]), None);
+  }
+
+  #[tokio::test]
+  async fn test_contract_signature_verification() {
+    let message = test_message("comm.app", "abcdefghijklmnopq");
+    let contract_signature = vec![0xab; 100];
+
+    let verifier = SignatureVerifier::new(None, None);
+    let status = verifier
+      .verify(&message, &contract_signature)
+      .await
+      .expect_err("contract signatures require RPC endpoint");
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    let rpc_url = spawn_mock_rpc(contract_signature.clone());
+    let rpc_client = EthRpcClient::new(rpc_url).unwrap();
+    let verifier = SignatureVerifier::new(Some(rpc_client), None);
+
+    assert!(verifier.verify(&message, &contract_signature).await.is_ok());
+    assert!(verifier.verify(&message, &[0xcd; 100]).await.is_err());
+
+    // EIP-6492 signature of a deployed wallet
+    let mut wrapped_signature = [0u8; 32].to_vec();
+    wrapped_signature.extend_from_slice(&abi_word(96));
+    wrapped_signature.extend_from_slice(&abi_word(128));
+    wrapped_signature.extend(abi_encode_bytes(&[]));
+    wrapped_signature.extend(abi_encode_bytes(&contract_signature));
+    wrapped_signature.extend_from_slice(&EIP6492_MAGIC_SUFFIX);
+    assert!(verifier.verify(&message, &wrapped_signature).await.is_ok());
+  }
 }
@@ -0,0 +1,81 @@
+//! Canonical forms of usernames used to detect look-alikes, e.g. `ashoat` and
+//! `аshoat` (with Cyrillic `а`).
+
+use std::collections::HashSet;
+
+use caseless::default_case_fold_str;
+use comm_lib::shared::reserved_users::RESERVED_USERNAME_SET;
+use once_cell::sync::Lazy;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton;
+
+static RESERVED_USERNAME_SKELETONS: Lazy<HashSet<String>> = Lazy::new(|| {
+  RESERVED_USERNAME_SET
+    .iter()
+    .map(|username| username_skeleton(username))
+    .collect()
+});
+
+/// Returns the UTS #39 skeleton of NFKC-normalized and case-folded username.
+/// Two usernames are visually confusable if their skeletons are equal.
+///
+/// The result is stored alongside usernames, so changing this function
+/// requires recomputing all stored skeletons.
+pub fn username_skeleton(username: &str) -> String {
+  let normalized: String = username.nfkc().collect();
+  let folded = default_case_fold_str(&normalized);
+  let skeleton: String = skeleton(&folded).collect();
+  // Confusable prototypes aren't always lowercase, e.g. `0` maps to `O`
+  default_case_fold_str(&skeleton).nfc().collect()
+}
+
+/// Checks if the username is reserved or confusable with a reserved one
+pub fn is_reserved_username(username: &str) -> bool {
+  RESERVED_USERNAME_SKELETONS.contains(&username_skeleton(username))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_skeleton_ignores_case() {
+    assert_eq!(username_skeleton("Ashoat"), username_skeleton("ashoat"));
+    assert_eq!(username_skeleton("ASHOAT"), username_skeleton("ashoat"));
+  }
+
+  #[test]
+  fn test_skeleton_of_homoglyphs() {
+    // Cyrillic `а`
+    assert_eq!(
+      username_skeleton("\u{430}shoat"),
+      username_skeleton("ashoat")
+    );
+    assert_eq!(username_skeleton("c0mm"), username_skeleton("comm"));
+    assert_eq!(username_skeleton("rn"), username_skeleton("m"));
+  }
+
+  #[test]
+  fn test_skeleton_applies_compatibility_normalization() {
+    // Fullwidth `Ａ` and `ﬁ` ligature
+    assert_eq!(username_skeleton("\u{ff21}bc"), username_skeleton("abc"));
+    assert_eq!(username_skeleton("\u{fb01}sh"), username_skeleton("fish"));
+  }
+
+  #[test]
+  fn test_skeleton_of_different_usernames() {
+    assert_ne!(username_skeleton("ashoat"), username_skeleton("ashoal"));
+    assert_ne!(username_skeleton("user1"), username_skeleton("user2"));
+  }
+
+  #[test]
+  fn test_reserved_username_look_alikes() {
+    let reserved = RESERVED_USERNAME_SET
+      .iter()
+      .next()
+      .expect("reserved usernames set should not be empty");
+    assert!(is_reserved_username(reserved));
+    assert!(is_reserved_username(&reserved.to_uppercase()));
+    assert!(!is_reserved_username("not-a-reserved-username-1234"));
+  }
+}
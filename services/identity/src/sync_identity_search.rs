@@ -1,105 +1,538 @@
+//! Rebuilds the identity search index from DynamoDB.
+//!
+//! Users are loaded in batches into a new index, then the
+//! [`IDENTITY_SEARCH_INDEX`] alias is atomically switched to it and the
+//! previous index is deleted, so search keeps working during the sync.
+//! Progress is stored in [`IDENTITY_SEARCH_SYNC_STATE_INDEX`] after each
+//! step, and an interrupted sync is resumed when the command is run again.
+//!
+//! The new index is created with the [`IDENTITY_SEARCH_SYNC_ALIAS`] alias,
+//! and the search index lambda writes changes made during the sync to it
+//! too. Users are loaded with `create`, so that documents the lambda has
+//! already written aren't overwritten with data read before the change.
+//! A user removed between being read and being loaded stays in the new
+//! index until the next sync.
+
+use std::collections::HashMap;
+
 use crate::config::CONFIG;
-use crate::constants::{error_types, IDENTITY_SEARCH_INDEX};
-use crate::database::DatabaseClient;
+use crate::constants::{
+  error_types, IDENTITY_SEARCH_INDEX, IDENTITY_SEARCH_SYNC_ALIAS,
+  IDENTITY_SEARCH_SYNC_STATE_INDEX,
+};
+use crate::database::{DatabaseClient, IndexedUser};
 use crate::error;
-use identity_search_messages::IdentitySearchUser;
+use crate::skeleton::username_skeleton;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::error;
+use tracing::{error, info};
+
+const CHECKPOINT_DOCUMENT_ID: &str = "checkpoint";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum SyncStage {
+  Users,
+  /// Loaded after users, so that users with the same ID are kept
+  ReservedUsernames,
+  SwapAlias,
+  DeletePreviousIndices,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SyncCheckpoint {
+  /// Index being loaded
+  index: String,
+  stage: SyncStage,
+  /// Key to continue scanning the table of the current stage from
+  exclusive_start_key: Option<String>,
+  /// Indices the alias pointed to before the swap
+  #[serde(default)]
+  previous_indices: Vec<String>,
+}
 
 pub async fn sync_index(
   database_client: &DatabaseClient,
 ) -> Result<(), error::Error> {
-  let identity_reserved_users =
-    database_client.get_all_reserved_user_details().await?;
-  let identity_users = database_client.get_all_user_details().await?;
-
-  let mut identity_search_users: Vec<IdentitySearchUser> =
-    identity_reserved_users
-      .into_iter()
-      .map(|user| IdentitySearchUser {
-        username: user.username,
-        user_id: user.user_id,
-      })
-      .collect();
-
-  for user in identity_users {
-    identity_search_users.push(IdentitySearchUser {
-      username: user.username,
-      user_id: user.user_id,
-    });
-  }
-
   let client = reqwest::Client::new();
 
-  clear_index(&client).await?;
-  restore_index(&client, &identity_search_users).await?;
+  // Only an index that received lambda writes the whole time is complete
+  let sync_indices = get_alias_indices(&client, IDENTITY_SEARCH_SYNC_ALIAS)
+    .await?
+    .unwrap_or_default();
+  let mut checkpoint = match load_checkpoint(&client).await? {
+    Some(checkpoint) if sync_indices.contains(&checkpoint.index) => {
+      info!(
+        "Resuming identity search sync of {} at {:?}",
+        checkpoint.index, checkpoint.stage
+      );
+      checkpoint
+    }
+    _ => {
+      discard_sync_indices(&client, &sync_indices).await?;
 
-  Ok(())
+      let index = format!(
+        "{}-{}",
+        IDENTITY_SEARCH_INDEX,
+        Utc::now().timestamp_millis()
+      );
+      create_index(&client, &index).await?;
+      info!("Started identity search sync into {}", index);
+
+      let checkpoint = SyncCheckpoint {
+        index,
+        stage: SyncStage::Users,
+        exclusive_start_key: None,
+        previous_indices: Vec::new(),
+      };
+      save_checkpoint(&client, &checkpoint).await?;
+      checkpoint
+    }
+  };
+
+  loop {
+    match checkpoint.stage {
+      SyncStage::Users => {
+        let (users, next_key) = database_client
+          .get_user_details_page(checkpoint.exclusive_start_key.clone())
+          .await?;
+        bulk_index(&client, &checkpoint.index, &users).await?;
+
+        if next_key.is_none() {
+          checkpoint.stage = SyncStage::ReservedUsernames;
+        }
+        checkpoint.exclusive_start_key = next_key;
+      }
+      SyncStage::ReservedUsernames => {
+        let (reserved_users, next_key) = database_client
+          .get_reserved_user_details_page(
+            checkpoint.exclusive_start_key.clone(),
+          )
+          .await?;
+        let users: Vec<IndexedUser> = reserved_users
+          .into_iter()
+          .map(|user| IndexedUser {
+            username: user.username,
+            user_id: user.user_id,
+            wallet_address: None,
+            farcaster_id: None,
+            farcaster_username: None,
+          })
+          .collect();
+        bulk_index(&client, &checkpoint.index, &users).await?;
+
+        if next_key.is_none() {
+          checkpoint.stage = SyncStage::SwapAlias;
+        }
+        checkpoint.exclusive_start_key = next_key;
+      }
+      SyncStage::SwapAlias => {
+        checkpoint.previous_indices =
+          swap_alias(&client, &checkpoint.index).await?;
+        checkpoint.stage = SyncStage::DeletePreviousIndices;
+      }
+      SyncStage::DeletePreviousIndices => {
+        for index in &checkpoint.previous_indices {
+          delete_index(&client, index).await?;
+        }
+        remove_alias(&client, &checkpoint.index, IDENTITY_SEARCH_SYNC_ALIAS)
+          .await?;
+        delete_checkpoint(&client).await?;
+        info!("Finished identity search sync into {}", checkpoint.index);
+        return Ok(());
+      }
+    }
+
+    save_checkpoint(&client, &checkpoint).await?;
+  }
 }
 
-pub async fn clear_index(
+fn search_document(user: &IndexedUser) -> serde_json::Value {
+  let mut document = json!({
+    "userID": user.user_id,
+    "username": user.username,
+    "usernameSkeleton": username_skeleton(&user.username),
+  });
+  if let Some(wallet_address) = &user.wallet_address {
+    document["walletAddress"] = json!(wallet_address);
+  }
+  if let Some(farcaster_id) = &user.farcaster_id {
+    document["farcasterID"] = json!(farcaster_id);
+  }
+  if let Some(farcaster_username) = &user.farcaster_username {
+    document["farcasterUsername"] = json!(farcaster_username);
+  }
+  document
+}
+
+async fn bulk_index(
   reqwest_client: &reqwest::Client,
+  index: &str,
+  users: &[IndexedUser],
 ) -> Result<(), error::Error> {
-  let url = format!(
-    "https://{}/{}/_delete_by_query",
-    &CONFIG.opensearch_endpoint, IDENTITY_SEARCH_INDEX
-  );
+  if users.is_empty() {
+    return Ok(());
+  }
 
-  let query = serde_json::json!({
-    "query": {
-        "match_all": {}
-    }
-  });
+  let mut bulk_data = String::new();
+  for user in users {
+    let action = json!({ "create": { "_index": index, "_id": user.user_id } });
+    bulk_data.push_str(&action.to_string());
+    bulk_data.push('\n');
+    bulk_data.push_str(&search_document(user).to_string());
+    bulk_data.push('\n');
+  }
+
+  let url = format!("https://{}/{}/_bulk/", &CONFIG.opensearch_endpoint, index);
 
   let response = reqwest_client
     .post(&url)
-    .header(reqwest::header::CONTENT_TYPE, "application/json")
-    .json(&query)
+    .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+    .body(bulk_data)
     .send()
-    .await
-    .expect("Failed to send clear index request");
+    .await?
+    .error_for_status()?
+    .json::<serde_json::Value>()
+    .await?;
 
-  if !response.status().is_success() {
-    error!(errorType = error_types::SYNC_LOG, "Failed to clear index");
+  if has_bulk_failures(&response) {
+    error!(
+      errorType = error_types::SYNC_LOG,
+      "Failed to index users in {}: {}", index, response
+    );
+    return Err(error::Error::IndexingFailed);
   }
 
   Ok(())
 }
 
-pub async fn restore_index(
+/// Bulk requests succeed even if some of the operations fail. Conflicts
+/// mean that the lambda has already indexed a newer version of the user.
+fn has_bulk_failures(response: &serde_json::Value) -> bool {
+  if response["errors"].as_bool() == Some(false) {
+    return false;
+  }
+  let Some(items) = response["items"].as_array() else {
+    return true;
+  };
+  items.iter().any(|item| {
+    let status = item["create"]["status"].as_u64();
+    !matches!(status, Some(200 | 201 | 409))
+  })
+}
+
+async fn index_exists(
+  reqwest_client: &reqwest::Client,
+  index: &str,
+) -> Result<bool, error::Error> {
+  let url = format!("https://{}/{}", &CONFIG.opensearch_endpoint, index);
+
+  let response = reqwest_client.head(&url).send().await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(false);
+  }
+  response.error_for_status()?;
+  Ok(true)
+}
+
+/// Creates the index with the sync alias, so that it receives changes
+/// from the lambda from the start
+async fn create_index(
   reqwest_client: &reqwest::Client,
-  identity_search_users: &Vec<IdentitySearchUser>,
+  index: &str,
 ) -> Result<(), error::Error> {
-  let mut bulk_data = String::new();
+  let url = format!("https://{}/{}", &CONFIG.opensearch_endpoint, index);
 
-  for user in identity_search_users {
-    let action = json!({ "index": { "_index": IDENTITY_SEARCH_INDEX, "_id": user.user_id } });
-    bulk_data.push_str(&action.to_string());
-    bulk_data.push('\n');
+  let mut definition = index_definition();
+  definition["aliases"] = json!({ IDENTITY_SEARCH_SYNC_ALIAS: {} });
+  reqwest_client
+    .put(&url)
+    .json(&definition)
+    .send()
+    .await?
+    .error_for_status()?;
+  Ok(())
+}
+
+/// Settings and mappings of identity search indices. Text fields have
+/// a `keyword` subfield for exact matches and sorting.
+fn index_definition() -> serde_json::Value {
+  let text_with_keyword = json!({
+    "type": "text",
+    "fields": { "keyword": { "type": "keyword", "ignore_above": 256 } }
+  });
+  let properties: serde_json::Map<_, _> = [
+    "userID",
+    "username",
+    "usernameSkeleton",
+    "walletAddress",
+    "farcasterID",
+    "farcasterUsername",
+  ]
+  .into_iter()
+  .map(|field| (field.to_string(), text_with_keyword.clone()))
+  .collect();
+
+  json!({
+    "settings": {
+      // The index is small and the domain has a single data node
+      "number_of_shards": 1,
+      "number_of_replicas": 0
+    },
+    "mappings": {
+      // Fields not listed here aren't searchable
+      "dynamic": false,
+      "properties": properties
+    }
+  })
+}
+
+/// Deletes indices of syncs that were abandoned, e.g. because they were
+/// started by a version that didn't create the sync alias
+async fn discard_sync_indices(
+  reqwest_client: &reqwest::Client,
+  sync_indices: &[String],
+) -> Result<(), error::Error> {
+  let live_indices = get_alias_indices(reqwest_client, IDENTITY_SEARCH_INDEX)
+    .await?
+    .unwrap_or_default();
+  for index in sync_indices {
+    if live_indices.contains(index) {
+      remove_alias(reqwest_client, index, IDENTITY_SEARCH_SYNC_ALIAS).await?;
+    } else {
+      info!("Deleting index of an abandoned sync: {}", index);
+      delete_index(reqwest_client, index).await?;
+    }
+  }
+  Ok(())
+}
+
+async fn remove_alias(
+  reqwest_client: &reqwest::Client,
+  index: &str,
+  alias: &str,
+) -> Result<(), error::Error> {
+  let url = format!(
+    "https://{}/{}/_alias/{}",
+    &CONFIG.opensearch_endpoint, index, alias
+  );
+
+  let response = reqwest_client.delete(&url).send().await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(());
+  }
+  response.error_for_status()?;
+  Ok(())
+}
+
+/// Returns indices the alias points to, or `None` if it doesn't exist
+async fn get_alias_indices(
+  reqwest_client: &reqwest::Client,
+  alias: &str,
+) -> Result<Option<Vec<String>>, error::Error> {
+  let url = format!("https://{}/_alias/{}", &CONFIG.opensearch_endpoint, alias);
 
-    bulk_data.push_str(
-      &serde_json::to_string(&user)
-        .expect("Failed to serialize identity search index user"),
+  let response = reqwest_client.get(&url).send().await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  let indices = response
+    .error_for_status()?
+    .json::<HashMap<String, serde_json::Value>>()
+    .await?
+    .into_keys()
+    .collect();
+  Ok(Some(indices))
+}
+
+async fn delete_index(
+  reqwest_client: &reqwest::Client,
+  index: &str,
+) -> Result<(), error::Error> {
+  let url = format!("https://{}/{}", &CONFIG.opensearch_endpoint, index);
+
+  let response = reqwest_client.delete(&url).send().await?;
+  // Already deleted before the sync was interrupted
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(());
+  }
+  response.error_for_status()?;
+  Ok(())
+}
+
+/// Points the alias to the new index in a single request, so searches
+/// never see an empty index. Returns indices the alias pointed to before.
+async fn swap_alias(
+  reqwest_client: &reqwest::Client,
+  new_index: &str,
+) -> Result<Vec<String>, error::Error> {
+  let alias_indices =
+    get_alias_indices(reqwest_client, IDENTITY_SEARCH_INDEX).await?;
+  let alias_exists = alias_indices.is_some();
+  let previous_indices: Vec<String> = alias_indices
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|index| index != new_index)
+    .collect();
+
+  let mut actions = vec![
+    json!({ "add": { "index": new_index, "alias": IDENTITY_SEARCH_INDEX } }),
+  ];
+  for index in &previous_indices {
+    actions.push(
+      json!({ "remove": { "index": index, "alias": IDENTITY_SEARCH_INDEX } }),
     );
-    bulk_data.push('\n');
   }
+  // Before the first sync, the alias name is used by a regular index
+  if !alias_exists
+    && index_exists(reqwest_client, IDENTITY_SEARCH_INDEX).await?
+  {
+    info!("Replacing {} index with an alias", IDENTITY_SEARCH_INDEX);
+    actions.push(json!({ "remove_index": { "index": IDENTITY_SEARCH_INDEX } }));
+  }
+
+  let url = format!("https://{}/_aliases", &CONFIG.opensearch_endpoint);
+  reqwest_client
+    .post(&url)
+    .json(&json!({ "actions": actions }))
+    .send()
+    .await?
+    .error_for_status()?;
 
+  info!("Identity search alias now points to {}", new_index);
+  Ok(previous_indices)
+}
+
+fn checkpoint_url() -> String {
+  format!(
+    "https://{}/{}/_doc/{}",
+    &CONFIG.opensearch_endpoint,
+    IDENTITY_SEARCH_SYNC_STATE_INDEX,
+    CHECKPOINT_DOCUMENT_ID
+  )
+}
+
+async fn load_checkpoint(
+  reqwest_client: &reqwest::Client,
+) -> Result<Option<SyncCheckpoint>, error::Error> {
+  let response = reqwest_client.get(checkpoint_url()).send().await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+
+  let mut document = response
+    .error_for_status()?
+    .json::<serde_json::Value>()
+    .await?;
+  let checkpoint = serde_json::from_value(document["_source"].take())?;
+  Ok(Some(checkpoint))
+}
+
+async fn save_checkpoint(
+  reqwest_client: &reqwest::Client,
+  checkpoint: &SyncCheckpoint,
+) -> Result<(), error::Error> {
+  reqwest_client
+    .put(checkpoint_url())
+    .json(checkpoint)
+    .send()
+    .await?
+    .error_for_status()?;
+  Ok(())
+}
+
+async fn delete_checkpoint(
+  reqwest_client: &reqwest::Client,
+) -> Result<(), error::Error> {
+  let response = reqwest_client.delete(checkpoint_url()).send().await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(());
+  }
+  response.error_for_status()?;
+  Ok(())
+}
+
+/// Updates username of a single user. The index is also updated from the
+/// users table stream, this makes the change visible without waiting for it.
+pub async fn update_indexed_username(
+  reqwest_client: &reqwest::Client,
+  user_id: &str,
+  username: &str,
+) -> Result<(), error::Error> {
   let url = format!(
-    "https://{}/{}/_bulk/",
-    &CONFIG.opensearch_endpoint, IDENTITY_SEARCH_INDEX
+    "https://{}/{}/_update/{}",
+    &CONFIG.opensearch_endpoint, IDENTITY_SEARCH_INDEX, user_id
   );
 
+  let update = json!({
+    "doc": {
+      "username": username,
+      "usernameSkeleton": username_skeleton(username),
+    }
+  });
+
   let response = reqwest_client
     .post(&url)
-    .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
-    .body(bulk_data)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .json(&update)
     .send()
-    .await
-    .expect("Failed to send restore index request");
+    .await?;
 
   if !response.status().is_success() {
-    error!(errorType = error_types::SYNC_LOG, "Failed to restore index");
+    error!(
+      errorType = error_types::SEARCH_LOG,
+      "Failed to update username in index: {}",
+      response.status()
+    );
   }
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bulk_failures() {
+    assert!(!has_bulk_failures(&json!({ "errors": false, "items": [] })));
+
+    let conflict = json!({
+      "errors": true,
+      "items": [
+        { "create": { "_id": "user1", "status": 201 } },
+        { "create": { "_id": "user2", "status": 409 } }
+      ]
+    });
+    assert!(!has_bulk_failures(&conflict), "Conflicts are expected");
+
+    let failure = json!({
+      "errors": true,
+      "items": [
+        { "create": { "_id": "user1", "status": 201 } },
+        { "create": { "_id": "user2", "status": 400 } }
+      ]
+    });
+    assert!(has_bulk_failures(&failure));
+    assert!(
+      has_bulk_failures(&json!({ "errors": true })),
+      "Missing items"
+    );
+  }
+
+  #[test]
+  fn test_index_definition() {
+    let definition = index_definition();
+    let properties = &definition["mappings"]["properties"];
+    // fields queried by the search handlers and the lambda
+    for field in ["userID", "username", "walletAddress", "farcasterUsername"] {
+      assert_eq!(
+        properties[field]["fields"]["keyword"]["type"], "keyword",
+        "Missing keyword subfield of {field}"
+      );
+    }
+    assert_eq!(properties["usernameSkeleton"]["type"], "text");
+  }
+}
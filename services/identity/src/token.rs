@@ -1,11 +1,15 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use comm_lib::crypto::siwe::is_valid_ethereum_address;
+use constant_time_eq::constant_time_eq;
 use rand::{
   distributions::{Alphanumeric, DistString},
   CryptoRng, Rng,
 };
 
-use crate::{constants::ACCESS_TOKEN_LENGTH, ddb_utils::Identifier};
+use crate::{
+  constants::{ACCESS_TOKEN_LAST_USED_UPDATE_INTERVAL, ACCESS_TOKEN_LENGTH},
+  ddb_utils::Identifier,
+};
 
 #[derive(Clone, Eq, PartialEq)]
 pub enum AuthType {
@@ -32,6 +36,24 @@ impl From<Identifier> for AuthType {
   }
 }
 
+/// Configured lifetimes of access tokens. Tokens don't expire
+/// if a lifetime isn't set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccessTokenLifetimes {
+  /// Max time since the token was issued or rotated
+  pub max_age: Option<Duration>,
+  /// Max time since the token was last used
+  pub idle_timeout: Option<Duration>,
+}
+
+/// Token replaced during rotation. It's still accepted for a short time
+/// so that requests sent before the rotation don't fail.
+#[derive(Clone)]
+pub struct PreviousAccessToken {
+  pub access_token: String,
+  pub valid_until: DateTime<Utc>,
+}
+
 #[derive(Clone)]
 pub struct AccessTokenData {
   pub user_id: String,
@@ -40,6 +62,9 @@ pub struct AccessTokenData {
   pub created: DateTime<Utc>,
   pub auth_type: AuthType,
   pub valid: bool,
+  /// `None` for tokens that haven't been used since they were issued
+  pub last_used: Option<DateTime<Utc>>,
+  pub previous_token: Option<PreviousAccessToken>,
 }
 
 impl AccessTokenData {
@@ -57,10 +82,140 @@ impl AccessTokenData {
       created: creation_time,
       auth_type,
       valid: true,
+      last_used: None,
+      previous_token: None,
     }
   }
 
   pub fn is_valid(&self) -> bool {
     self.valid
   }
+
+  pub fn expires_at(
+    &self,
+    lifetimes: &AccessTokenLifetimes,
+  ) -> Option<DateTime<Utc>> {
+    lifetimes.max_age.map(|max_age| self.created + max_age)
+  }
+
+  pub fn is_expired(
+    &self,
+    lifetimes: &AccessTokenLifetimes,
+    now: DateTime<Utc>,
+  ) -> bool {
+    let max_age_exceeded = self
+      .expires_at(lifetimes)
+      .is_some_and(|expires_at| expires_at <= now);
+    let idle_timeout_exceeded = lifetimes
+      .idle_timeout
+      .is_some_and(|idle_timeout| self.last_active() + idle_timeout <= now);
+
+    max_age_exceeded || idle_timeout_exceeded
+  }
+
+  /// Checks if the given token is the current one or the previous one
+  /// that's still in its rotation overlap window.
+  pub fn verify(
+    &self,
+    access_token: &str,
+    lifetimes: &AccessTokenLifetimes,
+    now: DateTime<Utc>,
+  ) -> bool {
+    if !self.is_valid() || self.is_expired(lifetimes, now) {
+      return false;
+    }
+
+    if constant_time_eq(self.access_token.as_bytes(), access_token.as_bytes()) {
+      return true;
+    }
+
+    self.previous_token.as_ref().is_some_and(|previous| {
+      previous.valid_until > now
+        && constant_time_eq(
+          previous.access_token.as_bytes(),
+          access_token.as_bytes(),
+        )
+    })
+  }
+
+  /// Last use is only tracked when idle timeout is configured,
+  /// and is updated at most once per
+  /// [`ACCESS_TOKEN_LAST_USED_UPDATE_INTERVAL`] to limit DB writes.
+  pub fn needs_last_used_update(
+    &self,
+    lifetimes: &AccessTokenLifetimes,
+    now: DateTime<Utc>,
+  ) -> bool {
+    let update_interval = Duration::seconds(
+      ACCESS_TOKEN_LAST_USED_UPDATE_INTERVAL.as_secs() as i64,
+    );
+    lifetimes.idle_timeout.is_some()
+      && self.last_active() + update_interval <= now
+  }
+
+  fn last_active(&self) -> DateTime<Utc> {
+    self.last_used.unwrap_or(self.created)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::rngs::OsRng;
+
+  fn token_created_at(created: DateTime<Utc>) -> AccessTokenData {
+    AccessTokenData::with_created_time(
+      "user".to_string(),
+      "device".to_string(),
+      created,
+      AuthType::Password,
+      &mut OsRng,
+    )
+  }
+
+  #[test]
+  fn test_tokens_without_lifetimes_dont_expire() {
+    let now = Utc::now();
+    let token = token_created_at(now - Duration::days(3650));
+    let access_token = token.access_token.clone();
+
+    assert!(token.verify(&access_token, &AccessTokenLifetimes::default(), now));
+    assert!(!token.verify("other", &AccessTokenLifetimes::default(), now));
+  }
+
+  #[test]
+  fn test_token_expiration() {
+    let now = Utc::now();
+    let lifetimes = AccessTokenLifetimes {
+      max_age: Some(Duration::days(30)),
+      idle_timeout: Some(Duration::days(7)),
+    };
+
+    let mut token = token_created_at(now - Duration::days(10));
+    assert!(token.is_expired(&lifetimes, now));
+
+    token.last_used = Some(now - Duration::days(1));
+    assert!(!token.is_expired(&lifetimes, now));
+    assert!(token.needs_last_used_update(&lifetimes, now));
+
+    token.last_used = Some(now - Duration::minutes(1));
+    assert!(!token.needs_last_used_update(&lifetimes, now));
+
+    token.created = now - Duration::days(31);
+    assert!(token.is_expired(&lifetimes, now));
+  }
+
+  #[test]
+  fn test_previous_token_overlap() {
+    let now = Utc::now();
+    let lifetimes = AccessTokenLifetimes::default();
+    let mut token = token_created_at(now);
+    token.previous_token = Some(PreviousAccessToken {
+      access_token: "previous".to_string(),
+      valid_until: now + Duration::seconds(30),
+    });
+
+    assert!(token.verify("previous", &lifetimes, now));
+    assert!(!token.verify("previous", &lifetimes, now + Duration::seconds(31)));
+  }
 }
@@ -0,0 +1,204 @@
+//! Time-based one-time passwords (RFC 6238), used as an optional
+//! second factor for password users.
+
+use chrono::{DateTime, Utc};
+use comm_lib::crypto::aes256::{self, AES256Error, EncryptionKey};
+use constant_time_eq::constant_time_eq;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::constants::{
+  TOTP_ALLOWED_DRIFT_STEPS, TOTP_DIGITS, TOTP_ISSUER, TOTP_PERIOD_SECS,
+  TOTP_RECOVERY_CODE_COUNT, TOTP_RECOVERY_CODE_LENGTH, TOTP_SECRET_LENGTH,
+};
+
+/// Lowercase letters and digits without easily confused characters
+const RECOVERY_CODE_CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Returns a new base32-encoded secret
+pub fn generate_secret(rng: &mut (impl Rng + CryptoRng)) -> String {
+  let mut secret = [0u8; TOTP_SECRET_LENGTH];
+  rng.fill_bytes(&mut secret);
+  BASE32_NOPAD.encode(&secret)
+}
+
+/// Secrets are stored encrypted, so that database access alone
+/// doesn't allow generating codes
+pub fn encrypt_secret(
+  secret: &str,
+  key: &EncryptionKey,
+) -> Result<Vec<u8>, AES256Error> {
+  aes256::encrypt(secret.as_bytes(), key)
+}
+
+/// Returns `None` if the secret can't be decrypted with the key
+pub fn decrypt_secret(
+  encrypted_secret: &[u8],
+  key: &EncryptionKey,
+) -> Option<String> {
+  let secret = aes256::decrypt(encrypted_secret, key).ok()?;
+  String::from_utf8(secret).ok()
+}
+
+/// URI for authenticator apps, usually shown as a QR code
+pub fn provisioning_uri(secret: &str, username: &str) -> String {
+  let label: String = url::form_urlencoded::byte_serialize(
+    format!("{TOTP_ISSUER}:{username}").as_bytes(),
+  )
+  .collect();
+  format!(
+    "otpauth://totp/{label}?secret={secret}&issuer={TOTP_ISSUER}\
+    &algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_PERIOD_SECS}"
+  )
+}
+
+fn code_for_step(secret: &[u8], step: u64) -> String {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret)
+    .expect("HMAC should accept keys of any length");
+  mac.update(&step.to_be_bytes());
+  let hash = mac.finalize().into_bytes();
+
+  // dynamic truncation, RFC 4226 section 5.3
+  let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+  let truncated = u32::from_be_bytes([
+    hash[offset] & 0x7f,
+    hash[offset + 1],
+    hash[offset + 2],
+    hash[offset + 3],
+  ]);
+  let code = truncated % 10u32.pow(TOTP_DIGITS);
+  format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+/// Returns the time step matching the code. Steps up to `last_used_step`
+/// are rejected, so that a code can't be used twice.
+pub fn verify_code(
+  secret: &str,
+  code: &str,
+  now: DateTime<Utc>,
+  last_used_step: Option<u64>,
+) -> Option<u64> {
+  if code.len() != TOTP_DIGITS as usize
+    || !code.chars().all(|c| c.is_ascii_digit())
+  {
+    return None;
+  }
+  let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+
+  let current_step = now.timestamp().max(0) as u64 / TOTP_PERIOD_SECS;
+  let first_step = current_step.saturating_sub(TOTP_ALLOWED_DRIFT_STEPS);
+  let last_step = current_step + TOTP_ALLOWED_DRIFT_STEPS;
+  (first_step..=last_step)
+    .filter(|step| last_used_step.is_none_or(|last_used| *step > last_used))
+    .find(|step| {
+      constant_time_eq(
+        code_for_step(&secret, *step).as_bytes(),
+        code.as_bytes(),
+      )
+    })
+}
+
+/// Single-use codes allowing to log in without the authenticator app
+pub fn generate_recovery_codes(
+  rng: &mut (impl Rng + CryptoRng),
+) -> Vec<String> {
+  (0..TOTP_RECOVERY_CODE_COUNT)
+    .map(|_| {
+      let code: String = (0..TOTP_RECOVERY_CODE_LENGTH)
+        .map(|_| {
+          let index = rng.gen_range(0..RECOVERY_CODE_CHARSET.len());
+          RECOVERY_CODE_CHARSET[index] as char
+        })
+        .collect();
+      let (first_half, second_half) =
+        code.split_at(TOTP_RECOVERY_CODE_LENGTH / 2);
+      format!("{first_half}-{second_half}")
+    })
+    .collect()
+}
+
+/// Recovery codes are stored hashed. Case and separators are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+  let normalized: String = code
+    .chars()
+    .filter(char::is_ascii_alphanumeric)
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+  hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  // secret from RFC 6238 test vectors
+  const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+  #[test]
+  fn test_rfc_6238_vectors() {
+    // last 6 digits of the SHA1 test vectors
+    let vectors = [
+      (59, "287082"),
+      (1111111109, "081804"),
+      (1111111111, "050471"),
+      (1234567890, "005924"),
+      (2000000000, "279037"),
+    ];
+    for (time, expected_code) in vectors {
+      let step = time / TOTP_PERIOD_SECS;
+      assert_eq!(code_for_step(RFC_SECRET, step), expected_code);
+    }
+  }
+
+  #[test]
+  fn test_verify_code() {
+    let secret = BASE32_NOPAD.encode(RFC_SECRET);
+    let now = Utc.timestamp_opt(1234567890, 0).unwrap();
+    let step = 1234567890 / TOTP_PERIOD_SECS;
+
+    assert_eq!(verify_code(&secret, "005924", now, None), Some(step));
+    assert_eq!(verify_code(&secret, "005924", now, Some(step)), None);
+    assert_eq!(verify_code(&secret, "000000", now, None), None);
+    assert_eq!(verify_code(&secret, "5924", now, None), None);
+
+    // previous code is still accepted because of clock drift
+    let next_period = now + chrono::Duration::seconds(30);
+    assert_eq!(
+      verify_code(&secret, "005924", next_period, None),
+      Some(step)
+    );
+  }
+
+  #[test]
+  fn test_secret_encryption() {
+    let secret = generate_secret(&mut rand::thread_rng());
+    let key = EncryptionKey::new();
+
+    let encrypted_secret = encrypt_secret(&secret, &key).unwrap();
+    assert_ne!(encrypted_secret, secret.as_bytes());
+    assert_eq!(decrypt_secret(&encrypted_secret, &key), Some(secret));
+    assert_eq!(
+      decrypt_secret(&encrypted_secret, &EncryptionKey::new()),
+      None
+    );
+  }
+
+  #[test]
+  fn test_recovery_codes() {
+    let codes = generate_recovery_codes(&mut rand::thread_rng());
+    assert_eq!(codes.len(), TOTP_RECOVERY_CODE_COUNT);
+    assert!(codes
+      .iter()
+      .all(|code| code.len() == TOTP_RECOVERY_CODE_LENGTH + 1));
+
+    let code = &codes[0];
+    assert_eq!(
+      hash_recovery_code(code),
+      hash_recovery_code(&code.to_uppercase().replace('-', " "))
+    );
+  }
+}
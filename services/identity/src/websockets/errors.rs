@@ -11,6 +11,7 @@ pub enum WebsocketError {
   SearchError,
   AuthError,
   SerializationError,
+  InvalidCursor,
 }
 
 impl From<serde_json::Error> for WebsocketError {
@@ -34,3 +35,14 @@ impl From<reqwest::Error> for WebsocketError {
     WebsocketError::SearchError
   }
 }
+
+impl From<crate::eth_rpc::Error> for WebsocketError {
+  fn from(err: crate::eth_rpc::Error) -> Self {
+    tracing::error!(
+      errorType = error_types::SEARCH_LOG,
+      "Error resolving ENS name: {}",
+      err
+    );
+    WebsocketError::SearchError
+  }
+}
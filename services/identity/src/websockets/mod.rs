@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use comm_lib::crypto::siwe::is_valid_ethereum_address;
 use futures::lock::Mutex;
 use futures_util::{SinkExt, StreamExt};
 use hyper::{Body, Request, Response};
@@ -13,7 +15,8 @@ use identity_search_messages::{
   IdentitySearchFailure, IdentitySearchMethod, IdentitySearchResponse,
   IdentitySearchResult, IdentitySearchUser, MessagesToServer,
 };
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
+use serde_json::json;
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tracing::{debug, error, info, trace, warn};
@@ -28,25 +31,97 @@ use crate::constants::{
   IDENTITY_SERVICE_WEBSOCKET_ADDR, SOCKET_HEARTBEAT_TIMEOUT,
 };
 use crate::cors::cors_layer;
-use crate::regex::is_valid_username;
-use opensearch::OpenSearchResponse;
+use crate::eth_rpc::EthRpcClient;
+use crate::regex::{is_valid_farcaster_username, is_valid_username};
+use crate::skeleton::username_skeleton;
+use opensearch::{Hit, OpenSearchResponse};
 use send::{send_message, WebsocketSink};
 pub mod errors;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct Query {
   size: u32,
-  query: Prefix,
+  query: BoolQuery,
+  sort: serde_json::Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  search_after: Option<Vec<serde_json::Value>>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Prefix {
-  prefix: Username,
+#[derive(Serialize)]
+struct BoolQuery {
+  bool: Should,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Username {
-  username: String,
+#[derive(Serialize)]
+struct Should {
+  should: Vec<QueryClause>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum QueryClause {
+  Prefix(HashMap<SearchField, ClauseParams>),
+  Term(HashMap<SearchField, ClauseParams>),
+  Fuzzy(HashMap<SearchField, ClauseParams>),
+}
+
+#[derive(Serialize, PartialEq, Eq, Hash)]
+enum SearchField {
+  #[serde(rename = "username")]
+  Username,
+  #[serde(rename = "username.keyword")]
+  UsernameExact,
+  /// Matches look-alikes of the username, see [`username_skeleton`]
+  #[serde(rename = "usernameSkeleton")]
+  UsernameSkeleton,
+  #[serde(rename = "walletAddress.keyword")]
+  WalletAddress,
+  #[serde(rename = "farcasterID.keyword")]
+  FarcasterID,
+  #[serde(rename = "farcasterUsername.keyword")]
+  FarcasterUsername,
+}
+
+#[derive(Serialize)]
+struct ClauseParams {
+  value: String,
+  boost: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  case_insensitive: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  fuzziness: Option<&'static str>,
+}
+
+impl QueryClause {
+  fn prefix(field: SearchField, value: String, boost: f32) -> Self {
+    let params = ClauseParams {
+      value,
+      boost,
+      case_insensitive: None,
+      fuzziness: None,
+    };
+    QueryClause::Prefix(HashMap::from([(field, params)]))
+  }
+
+  fn term(field: SearchField, value: String, boost: f32) -> Self {
+    let params = ClauseParams {
+      value,
+      boost,
+      case_insensitive: Some(true),
+      fuzziness: None,
+    };
+    QueryClause::Term(HashMap::from([(field, params)]))
+  }
+
+  fn fuzzy(field: SearchField, value: String, boost: f32) -> Self {
+    let params = ClauseParams {
+      value,
+      boost,
+      case_insensitive: None,
+      fuzziness: Some("AUTO"),
+    };
+    QueryClause::Fuzzy(HashMap::from([(field, params)]))
+  }
 }
 
 struct WebsocketService {
@@ -154,26 +229,74 @@ async fn close_connection(outgoing: WebsocketSink) {
   }
 }
 
+fn empty_search_result(request_id: &str) -> IdentitySearchResult {
+  IdentitySearchResult {
+    id: request_id.to_string(),
+    hits: Vec::new(),
+    next_cursor: None,
+  }
+}
+
+/// Hits are sorted by score, and then by user ID, so that hits with equal
+/// scores aren't skipped or repeated between pages
+fn search_sort() -> serde_json::Value {
+  json!([{ "_score": "desc" }, { "userID.keyword": "asc" }])
+}
+
+/// Parses the cursor, i.e. the sort values of the last hit of
+/// the previous page, see [`search_sort`]
+fn decode_search_cursor(
+  cursor: &str,
+) -> Result<Vec<serde_json::Value>, errors::WebsocketError> {
+  let search_after: Vec<serde_json::Value> = serde_json::from_str(cursor)
+    .map_err(|_| errors::WebsocketError::InvalidCursor)?;
+  match search_after.as_slice() {
+    [score, user_id] if score.is_number() && user_id.is_string() => {
+      Ok(search_after)
+    }
+    _ => Err(errors::WebsocketError::InvalidCursor),
+  }
+}
+
+/// Returns a page of users and the cursor of the next page. One hit more
+/// than the page size is requested, to know whether there's a next page.
+fn page_search_hits(
+  mut hits: Vec<Hit<IdentitySearchUser>>,
+) -> Result<(Vec<IdentitySearchUser>, Option<String>), serde_json::Error> {
+  let next_cursor = if hits.len() > IDENTITY_SEARCH_RESULT_SIZE as usize {
+    hits.truncate(IDENTITY_SEARCH_RESULT_SIZE as usize);
+    hits
+      .last()
+      .and_then(|hit| hit.sort.as_ref())
+      .map(serde_json::to_string)
+      .transpose()?
+  } else {
+    None
+  };
+
+  let users = hits.into_iter().filter_map(|hit| hit.source).collect();
+  Ok((users, next_cursor))
+}
+
+/// Runs the query and returns a single page of hits. Exact matches have
+/// the highest boost, so they are ranked first.
 #[tracing::instrument(skip_all)]
-async fn handle_prefix_search(
+async fn search_users(
   request_id: &str,
-  prefix_request: identity_search_messages::IdentitySearchPrefix,
+  clauses: Vec<QueryClause>,
+  cursor: Option<String>,
 ) -> Result<IdentitySearchResult, errors::WebsocketError> {
-  let username_prefix = prefix_request.prefix.trim().to_string();
-  if !is_valid_username(&username_prefix) {
-    return Ok(IdentitySearchResult {
-      id: request_id.to_string(),
-      hits: Vec::new(),
-    });
-  }
-
-  let prefix_query = Query {
-    size: IDENTITY_SEARCH_RESULT_SIZE,
-    query: Prefix {
-      prefix: Username {
-        username: username_prefix,
-      },
+  let search_after = cursor
+    .map(|cursor| decode_search_cursor(&cursor))
+    .transpose()?;
+
+  let query = Query {
+    size: IDENTITY_SEARCH_RESULT_SIZE + 1,
+    query: BoolQuery {
+      bool: Should { should: clauses },
     },
+    sort: search_sort(),
+    search_after,
   };
 
   let opensearch_url = format!(
@@ -181,24 +304,143 @@ async fn handle_prefix_search(
     &CONFIG.opensearch_endpoint, IDENTITY_SEARCH_INDEX
   );
 
-  let search_response = send_search_request(&opensearch_url, prefix_query)
+  let search_response = send_search_request(&opensearch_url, query)
     .await?
     .json::<OpenSearchResponse<IdentitySearchUser>>()
     .await?;
 
-  let usernames: Vec<IdentitySearchUser> = search_response
-    .hits
-    .inner
-    .into_iter()
-    .filter_map(|hit| hit.source)
-    .collect();
+  let (users, next_cursor) = page_search_hits(search_response.hits.inner)?;
 
-  let search_result = IdentitySearchResult {
+  Ok(IdentitySearchResult {
     id: request_id.to_string(),
-    hits: usernames,
+    hits: users,
+    next_cursor,
+  })
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_prefix_search(
+  request_id: &str,
+  prefix_request: identity_search_messages::IdentitySearchPrefix,
+  cursor: Option<String>,
+) -> Result<IdentitySearchResult, errors::WebsocketError> {
+  let username_prefix = prefix_request.prefix.trim().to_string();
+  if !is_valid_username(&username_prefix) {
+    return Ok(empty_search_result(request_id));
+  }
+
+  let skeleton_prefix = username_skeleton(&username_prefix);
+  let clauses = vec![
+    QueryClause::term(
+      SearchField::UsernameExact,
+      username_prefix.clone(),
+      10.0,
+    ),
+    QueryClause::prefix(SearchField::Username, username_prefix, 2.0),
+    QueryClause::prefix(SearchField::UsernameSkeleton, skeleton_prefix, 1.0),
+  ];
+
+  search_users(request_id, clauses, cursor).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_farcaster_search(
+  request_id: &str,
+  farcaster_request: identity_search_messages::IdentitySearchFarcaster,
+  cursor: Option<String>,
+) -> Result<IdentitySearchResult, errors::WebsocketError> {
+  let farcaster_id = farcaster_request.farcaster_id.trim().to_string();
+  if farcaster_id.is_empty()
+    || !farcaster_id.chars().all(|c| c.is_ascii_digit())
+  {
+    return Ok(empty_search_result(request_id));
+  }
+
+  let clauses = vec![QueryClause::term(
+    SearchField::FarcasterID,
+    farcaster_id,
+    10.0,
+  )];
+
+  search_users(request_id, clauses, cursor).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_farcaster_username_search(
+  request_id: &str,
+  username_request: identity_search_messages::IdentitySearchFarcasterUsername,
+  cursor: Option<String>,
+) -> Result<IdentitySearchResult, errors::WebsocketError> {
+  let username = username_request
+    .username
+    .trim()
+    .trim_start_matches('@')
+    .to_lowercase();
+  if !is_valid_farcaster_username(&username) {
+    return Ok(empty_search_result(request_id));
+  }
+
+  let clauses = vec![
+    QueryClause::term(SearchField::FarcasterUsername, username.clone(), 10.0),
+    QueryClause::prefix(SearchField::FarcasterUsername, username, 2.0),
+  ];
+
+  search_users(request_id, clauses, cursor).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_wallet_search(
+  request_id: &str,
+  wallet_request: identity_search_messages::IdentitySearchWallet,
+  cursor: Option<String>,
+) -> Result<IdentitySearchResult, errors::WebsocketError> {
+  let wallet = wallet_request.wallet.trim();
+
+  let wallet_address = if is_valid_ethereum_address(wallet) {
+    wallet.to_string()
+  } else if wallet.contains('.') {
+    let Some(rpc_url) = CONFIG.ethereum_rpc_url.clone() else {
+      warn!("Ethereum RPC URL not configured, skipping ENS name lookup");
+      return Ok(empty_search_result(request_id));
+    };
+    let rpc_client = EthRpcClient::new(rpc_url)?;
+    match rpc_client.resolve_ens_name(wallet).await? {
+      Some(wallet_address) => wallet_address,
+      None => return Ok(empty_search_result(request_id)),
+    }
+  } else {
+    return Ok(empty_search_result(request_id));
   };
 
-  Ok(search_result)
+  // Users registered with wallet without a username have the address
+  // indexed as their username
+  let clauses = vec![
+    QueryClause::term(SearchField::WalletAddress, wallet_address.clone(), 10.0),
+    QueryClause::term(SearchField::UsernameExact, wallet_address, 10.0),
+  ];
+
+  search_users(request_id, clauses, cursor).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn handle_fuzzy_search(
+  request_id: &str,
+  fuzzy_request: identity_search_messages::IdentitySearchFuzzy,
+  cursor: Option<String>,
+) -> Result<IdentitySearchResult, errors::WebsocketError> {
+  let query = fuzzy_request.query.trim().to_string();
+  if !is_valid_username(&query) {
+    return Ok(empty_search_result(request_id));
+  }
+
+  // `username` is an analyzed field with lowercased terms
+  let clauses = vec![
+    QueryClause::term(SearchField::UsernameExact, query.clone(), 10.0),
+    QueryClause::prefix(SearchField::Username, query.to_lowercase(), 2.0),
+    QueryClause::fuzzy(SearchField::Username, query.to_lowercase(), 1.0),
+  ];
+
+  search_users(request_id, clauses, cursor).await
 }
 
 #[tracing::instrument(skip_all)]
@@ -217,9 +459,30 @@ async fn handle_websocket_frame(
       Ok(())
     }
     MessagesToServer::IdentitySearchQuery(search_query) => {
+      let cursor = search_query.cursor;
       let handler_result = match search_query.search_method {
         IdentitySearchMethod::IdentitySearchPrefix(prefix_query) => {
-          handle_prefix_search(&search_query.id, prefix_query).await
+          handle_prefix_search(&search_query.id, prefix_query, cursor).await
+        }
+        IdentitySearchMethod::IdentitySearchFarcaster(farcaster_query) => {
+          handle_farcaster_search(&search_query.id, farcaster_query, cursor)
+            .await
+        }
+        IdentitySearchMethod::IdentitySearchFarcasterUsername(
+          username_query,
+        ) => {
+          handle_farcaster_username_search(
+            &search_query.id,
+            username_query,
+            cursor,
+          )
+          .await
+        }
+        IdentitySearchMethod::IdentitySearchWallet(wallet_query) => {
+          handle_wallet_search(&search_query.id, wallet_query, cursor).await
+        }
+        IdentitySearchMethod::IdentitySearchFuzzy(fuzzy_query) => {
+          handle_fuzzy_search(&search_query.id, fuzzy_query, cursor).await
         }
       };
 
@@ -368,3 +631,136 @@ async fn accept_connection(
   trace!("unregistering connection to: {}", addr);
   close_connection(outgoing).await;
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_hit(user_id: &str, score: f64) -> Hit<IdentitySearchUser> {
+    Hit {
+      source: Some(IdentitySearchUser {
+        user_id: user_id.to_string(),
+        username: format!("{user_id}-username"),
+      }),
+      sort: Some(vec![json!(score), json!(user_id)]),
+    }
+  }
+
+  #[test]
+  fn test_query_serialization() {
+    let query = Query {
+      size: 21,
+      query: BoolQuery {
+        bool: Should {
+          should: vec![
+            QueryClause::term(SearchField::UsernameExact, "Alice".into(), 10.0),
+            QueryClause::prefix(SearchField::Username, "ali".into(), 2.0),
+            QueryClause::fuzzy(SearchField::Username, "alcie".into(), 1.0),
+          ],
+        },
+      },
+      sort: search_sort(),
+      search_after: None,
+    };
+
+    let expected = json!({
+      "size": 21,
+      "query": {
+        "bool": {
+          "should": [
+            {
+              "term": {
+                "username.keyword": {
+                  "value": "Alice",
+                  "boost": 10.0,
+                  "case_insensitive": true
+                }
+              }
+            },
+            { "prefix": { "username": { "value": "ali", "boost": 2.0 } } },
+            {
+              "fuzzy": {
+                "username": {
+                  "value": "alcie",
+                  "boost": 1.0,
+                  "fuzziness": "AUTO"
+                }
+              }
+            }
+          ]
+        }
+      },
+      "sort": [{ "_score": "desc" }, { "userID.keyword": "asc" }]
+    });
+    assert_eq!(serde_json::to_value(&query).unwrap(), expected);
+
+    let query = Query {
+      search_after: Some(vec![json!(1.5), json!("user")]),
+      ..query
+    };
+    let serialized = serde_json::to_value(&query).unwrap();
+    assert_eq!(serialized["search_after"], json!([1.5, "user"]));
+  }
+
+  #[test]
+  fn test_search_field_names() {
+    let field_name = |field| {
+      serde_json::to_value(QueryClause::term(field, "".into(), 1.0)).unwrap()
+        ["term"]
+        .as_object()
+        .and_then(|fields| fields.keys().next().cloned())
+        .unwrap()
+    };
+    assert_eq!(
+      field_name(SearchField::UsernameSkeleton),
+      "usernameSkeleton"
+    );
+    assert_eq!(
+      field_name(SearchField::WalletAddress),
+      "walletAddress.keyword"
+    );
+    assert_eq!(field_name(SearchField::FarcasterID), "farcasterID.keyword");
+    assert_eq!(
+      field_name(SearchField::FarcasterUsername),
+      "farcasterUsername.keyword"
+    );
+  }
+
+  #[test]
+  fn test_search_cursor() {
+    let cursor = r#"[2.5,"user"]"#;
+    assert_eq!(
+      decode_search_cursor(cursor).unwrap(),
+      vec![json!(2.5), json!("user")]
+    );
+
+    for invalid_cursor in ["", "not json", "[]", r#"["user",2.5]"#, "[1,2,3]"] {
+      assert!(
+        decode_search_cursor(invalid_cursor).is_err(),
+        "Cursor {invalid_cursor} should be invalid"
+      );
+    }
+  }
+
+  #[test]
+  fn test_page_search_hits() {
+    let page_size = IDENTITY_SEARCH_RESULT_SIZE as usize;
+    let hits = (0..=page_size)
+      .map(|i| test_hit(&format!("user{i:02}"), 1.0))
+      .collect();
+
+    let (users, next_cursor) = page_search_hits(hits).unwrap();
+    assert_eq!(users.len(), page_size);
+    let next_cursor = next_cursor.expect("Missing next page cursor");
+    let last_user_id = &users.last().unwrap().user_id;
+    assert_eq!(
+      decode_search_cursor(&next_cursor).unwrap(),
+      vec![json!(1.0), json!(last_user_id)]
+    );
+
+    let last_page = vec![test_hit("user00", 2.0), test_hit("user01", 1.0)];
+    let (users, next_cursor) = page_search_hits(last_page).unwrap();
+    assert_eq!(users.len(), 2);
+    assert!(next_cursor.is_none(), "Unexpected next page");
+  }
+}
@@ -15,4 +15,6 @@ pub struct HitsWrapper<T> {
 pub struct Hit<T> {
   #[serde(rename = "_source")]
   pub source: Option<T>,
+  /// Sort values of the hit, used as `search_after` for the next page
+  pub sort: Option<Vec<serde_json::Value>>,
 }
@@ -1,4 +1,11 @@
 pub const DYNAMODB_USER_ID_KEY: &str = "userID";
 pub const DYNAMODB_USERNAME_KEY: &str = "username";
+pub const DYNAMODB_USERNAME_SKELETON_KEY: &str = "usernameSkeleton";
+pub const DYNAMODB_WALLET_ADDRESS_KEY: &str = "walletAddress";
+pub const DYNAMODB_FARCASTER_ID_KEY: &str = "farcasterID";
+pub const DYNAMODB_FARCASTER_USERNAME_KEY: &str = "farcasterUsername";
+/// Alias of the index being rebuilt by the identity search sync, must match
+/// `IDENTITY_SEARCH_SYNC_ALIAS` in the identity service
+pub const OPENSEARCH_SYNC_INDEX_ALIAS: &str = "users-sync";
 pub const LOG_LEVEL_ENV_VAR: &str =
   tracing_subscriber::filter::EnvFilter::DEFAULT_ENV;
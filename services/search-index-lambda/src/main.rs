@@ -1,6 +1,7 @@
 use lambda_runtime::{service_fn, LambdaEvent};
 use reqwest::Response;
 use serde::Serialize;
+use std::collections::HashMap;
 use tracing::{self, Level};
 use tracing_subscriber::EnvFilter;
 
@@ -18,6 +19,20 @@ pub struct User {
   #[serde(rename = "userID")]
   pub user_id: String,
   pub username: String,
+  #[serde(
+    rename = "usernameSkeleton",
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub username_skeleton: Option<String>,
+  #[serde(rename = "walletAddress", skip_serializing_if = "Option::is_none")]
+  pub wallet_address: Option<String>,
+  #[serde(rename = "farcasterID", skip_serializing_if = "Option::is_none")]
+  pub farcaster_id: Option<String>,
+  #[serde(
+    rename = "farcasterUsername",
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub farcaster_username: Option<String>,
 }
 
 #[tokio::main]
@@ -53,6 +68,8 @@ async fn func(event: LambdaEvent<EventPayload>) -> Result<(), error::Error> {
   let (payload, _context) = event.into_parts();
   println!("records: {}", &payload.records.len());
 
+  let sync_index = get_sync_index(&endpoint).await?;
+
   for record in payload.records {
     let event_name = record
       .event_name
@@ -80,11 +97,119 @@ async fn func(event: LambdaEvent<EventPayload>) -> Result<(), error::Error> {
         return Err(Error::UpdateIndexError(res.status()));
       }
     }
+
+    if let Some(sync_index) = &sync_index {
+      update_sync_index(&dynamodb, &event_name, &endpoint, sync_index).await?;
+    }
   }
 
   Ok(())
 }
 
+/// Returns the index being rebuilt by the identity search sync, if a sync
+/// is running. Changes are written to it too, so that they aren't lost
+/// when it replaces the current index.
+async fn get_sync_index(
+  endpoint: &str,
+) -> Result<Option<String>, error::Error> {
+  let url = format!(
+    "https://{}/_alias/{}",
+    endpoint,
+    constants::OPENSEARCH_SYNC_INDEX_ALIAS
+  );
+
+  let res = reqwest::Client::new().get(url).send().await.map_err(|e| {
+    tracing::error!("Reqwest Error: {:?}", e);
+    Error::ReqwestError(e)
+  })?;
+  if res.status() == reqwest::StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  if !res.status().is_success() {
+    tracing::error!("failed to get sync index, status: {}", res.status());
+    return Err(Error::UpdateIndexError(res.status()));
+  }
+
+  let body = res.text().await.map_err(|e| {
+    tracing::error!("Reqwest Error: {:?}", e);
+    Error::ReqwestError(e)
+  })?;
+  let indices: HashMap<String, serde_json::Value> = serde_json::from_str(&body)
+    .map_err(|e| {
+      tracing::error!("Serialization Error: {:?}", e);
+      Error::SerializationError(e)
+    })?;
+  let sync_index = indices.into_keys().next();
+  tracing::info!("identity-search sync index: {:?}", sync_index);
+  Ok(sync_index)
+}
+
+/// Writes whole documents, because the sync loads users that don't exist
+/// in the index yet after the change
+async fn update_sync_index(
+  dynamodb: &StreamRecord,
+  event_name: &OperationType,
+  endpoint: &str,
+  index: &str,
+) -> Result<(), error::Error> {
+  let res = match event_name {
+    OperationType::Insert | OperationType::Modify => {
+      let new_image = dynamodb
+        .new_image
+        .as_ref()
+        .ok_or(Error::PayloadError(RecordError::MissingNewImage))?;
+      let user_body = user_document(new_image)?;
+
+      let json_body = serde_json::to_string(&user_body).map_err(|e| {
+        tracing::error!("Serialization Error: {:?}", e);
+        Error::SerializationError(e)
+      })?;
+
+      let url =
+        format!("https://{}/{}/_doc/{}", endpoint, index, user_body.user_id);
+      update_index(url, json_body).await?
+    }
+    OperationType::Remove => {
+      let user_id = match dynamodb
+        .old_image
+        .as_ref()
+        .ok_or(Error::PayloadError(RecordError::MissingOldImage))?
+        .get(constants::DYNAMODB_USER_ID_KEY)
+      {
+        Some(AttributeValue::S(user_id)) => user_id,
+        Some(_) => {
+          return Err(Error::PayloadError(RecordError::InvalidAttributeType))
+        }
+        None => return Err(Error::PayloadError(RecordError::MissingUserId)),
+      };
+
+      let url = format!("https://{}/{}/_doc/{}", endpoint, index, user_id);
+      reqwest::Client::new()
+        .delete(url)
+        .send()
+        .await
+        .map_err(|e| {
+          tracing::error!("Reqwest Error: {:?}", e);
+          Error::ReqwestError(e)
+        })?
+    }
+  };
+
+  match res.status() {
+    // Removed users may have not been loaded into the new index yet
+    reqwest::StatusCode::OK
+    | reqwest::StatusCode::CREATED
+    | reqwest::StatusCode::NOT_FOUND => Ok(()),
+    status => {
+      tracing::error!(
+        "failed to update identity-search sync index, status: {}",
+        status
+      );
+      Err(Error::UpdateIndexError(status))
+    }
+  }
+}
+
 async fn update_index(
   url: String,
   json_body: String,
@@ -113,12 +238,28 @@ async fn handle_insert(
     .as_ref()
     .ok_or(Error::PayloadError(RecordError::MissingNewImage))?;
 
+  let user_body = user_document(new_image)?;
+
+  let json_body = serde_json::to_string(&user_body).map_err(|e| {
+    tracing::error!("Serialization Error: {:?}", e);
+    Error::SerializationError(e)
+  })?;
+
+  let url = format!("https://{}/users/_doc/{}", endpoint, user_body.user_id);
+
+  update_index(url, json_body).await
+}
+
+fn user_document(
+  new_image: &HashMap<String, AttributeValue>,
+) -> Result<User, error::Error> {
   let user_id_attribute = new_image
     .get(constants::DYNAMODB_USER_ID_KEY)
     .ok_or(Error::PayloadError(RecordError::MissingUserId))?;
 
   let username_attribute = new_image
     .get(constants::DYNAMODB_USERNAME_KEY)
+    .or_else(|| new_image.get(constants::DYNAMODB_WALLET_ADDRESS_KEY))
     .ok_or(Error::PayloadError(RecordError::MissingUsername))?;
 
   let (user_id, username) = match (user_id_attribute, username_attribute) {
@@ -128,19 +269,23 @@ async fn handle_insert(
     _ => return Err(Error::PayloadError(RecordError::InvalidAttributeType)),
   };
 
-  let user_body = User {
+  Ok(User {
     user_id: user_id.clone(),
     username: username.clone(),
-  };
-
-  let json_body = serde_json::to_string(&user_body).map_err(|e| {
-    tracing::error!("Serialization Error: {:?}", e);
-    Error::SerializationError(e)
-  })?;
-
-  let url = format!("https://{}/users/_doc/{}", endpoint, user_body.user_id);
-
-  update_index(url, json_body).await
+    username_skeleton: get_username_skeleton(new_image),
+    wallet_address: get_string_attribute(
+      new_image,
+      constants::DYNAMODB_WALLET_ADDRESS_KEY,
+    ),
+    farcaster_id: get_string_attribute(
+      new_image,
+      constants::DYNAMODB_FARCASTER_ID_KEY,
+    ),
+    farcaster_username: get_string_attribute(
+      new_image,
+      constants::DYNAMODB_FARCASTER_USERNAME_KEY,
+    ),
+  })
 }
 
 async fn handle_modify(
@@ -153,31 +298,23 @@ async fn handle_modify(
     .as_ref()
     .ok_or(Error::PayloadError(RecordError::MissingNewImage))?;
 
-  let user_id_attribute = new_image
-    .get(constants::DYNAMODB_USER_ID_KEY)
-    .ok_or(Error::PayloadError(RecordError::MissingUserId))?;
-
-  let username_attribute = new_image
-    .get(constants::DYNAMODB_USERNAME_KEY)
-    .ok_or(Error::PayloadError(RecordError::MissingUsername))?;
-
-  let (user_id, username) = match (user_id_attribute, username_attribute) {
-    (AttributeValue::S(user_id), AttributeValue::S(username)) => {
-      (user_id, username)
-    }
-    _ => return Err(Error::PayloadError(RecordError::InvalidAttributeType)),
-  };
+  let user = user_document(new_image)?;
+  let params = serde_json::to_value(&user).map_err(|e| {
+    tracing::error!("Serialization Error: {:?}", e);
+    Error::SerializationError(e)
+  })?;
 
   let update_by_query = UpdateByQuery {
     query: Query {
       r#match: None,
       term: Some(Term {
-        user_id_keyword: user_id.clone(),
+        user_id_keyword: user.user_id,
       }),
     },
     script: Some(Script {
-      source: format!("ctx._source.username = \"{}\"", username),
+      source: UPDATE_USER_SCRIPT.to_string(),
       lang: "painless".to_string(),
+      params: Some(params),
     }),
   };
 
@@ -191,6 +328,38 @@ async fn handle_modify(
   update_index(url, json_body).await
 }
 
+fn get_string_attribute(
+  image: &HashMap<String, AttributeValue>,
+  key: &str,
+) -> Option<String> {
+  match image.get(key) {
+    Some(AttributeValue::S(value)) => Some(value.clone()),
+    _ => None,
+  }
+}
+
+fn get_username_skeleton(
+  image: &HashMap<String, AttributeValue>,
+) -> Option<String> {
+  get_string_attribute(image, constants::DYNAMODB_USERNAME_SKELETON_KEY)
+}
+
+/// Updates a user document with the `User` passed as params. Values are
+/// never spliced into the source, so they don't need escaping. Linked
+/// Farcaster ID can be removed, so stale values are cleared.
+const UPDATE_USER_SCRIPT: &str = "\
+  ctx._source.username = params.username; \
+  if (params.usernameSkeleton != null) { \
+    ctx._source.usernameSkeleton = params.usernameSkeleton; \
+  } \
+  for (String field : ['walletAddress', 'farcasterID', 'farcasterUsername']) { \
+    if (params[field] != null) { \
+      ctx._source[field] = params[field]; \
+    } else { \
+      ctx._source.remove(field); \
+    } \
+  }";
+
 async fn handle_remove(
   dynamodb: &StreamRecord,
   endpoint: &str,
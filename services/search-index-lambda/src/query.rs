@@ -12,6 +12,9 @@ pub struct UpdateByQuery {
 pub struct Script {
   pub source: String,
   pub lang: String,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub params: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
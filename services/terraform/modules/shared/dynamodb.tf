@@ -57,6 +57,89 @@ resource "aws_dynamodb_table" "backup-service-log" {
   }
 }
 
+resource "aws_dynamodb_table" "backup-service-device-log" {
+  name         = "backup-service-device-log"
+  hash_key     = "backupID"
+  range_key    = "logKey"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "backupID"
+    type = "S"
+  }
+
+  attribute {
+    name = "logKey"
+    type = "S"
+  }
+
+  attribute {
+    name = "uploadedAt"
+    type = "N"
+  }
+
+  local_secondary_index {
+    name            = "backupID-uploadedAt-index"
+    range_key       = "uploadedAt"
+    projection_type = "ALL"
+  }
+
+  point_in_time_recovery {
+    enabled = local.pitr_enabled
+  }
+}
+
+resource "aws_dynamodb_table" "backup-service-attachment-ref" {
+  name         = "backup-service-attachment-ref"
+  hash_key     = "userID"
+  range_key    = "blobHash"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "userID"
+    type = "S"
+  }
+
+  attribute {
+    name = "blobHash"
+    type = "S"
+  }
+
+  point_in_time_recovery {
+    enabled = local.pitr_enabled
+  }
+}
+
+resource "aws_dynamodb_table" "backup-service-user-tombstone" {
+  name         = "backup-service-user-tombstone"
+  hash_key     = "userID"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "userID"
+    type = "S"
+  }
+
+  point_in_time_recovery {
+    enabled = local.pitr_enabled
+  }
+}
+
+resource "aws_dynamodb_table" "backup-service-user-usage" {
+  name         = "backup-service-user-usage"
+  hash_key     = "userID"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "userID"
+    type = "S"
+  }
+
+  point_in_time_recovery {
+    enabled = local.pitr_enabled
+  }
+}
+
 resource "aws_dynamodb_table" "blob-service-blobs" {
   name         = "blob-service-blobs"
   hash_key     = "blob_hash"
@@ -190,6 +273,11 @@ resource "aws_dynamodb_table" "identity-users" {
     type = "S"
   }
 
+  attribute {
+    name = "usernameSkeleton"
+    type = "S"
+  }
+
   global_secondary_index {
     name            = "username-index"
     hash_key        = "username"
@@ -215,6 +303,12 @@ resource "aws_dynamodb_table" "identity-users" {
     projection_type = "KEYS_ONLY"
   }
 
+  global_secondary_index {
+    name            = "usernameSkeleton-index"
+    hash_key        = "usernameSkeleton"
+    projection_type = "KEYS_ONLY"
+  }
+
   point_in_time_recovery {
     enabled = local.pitr_enabled
   }
@@ -249,6 +343,17 @@ resource "aws_dynamodb_table" "identity-devices" {
     type = "S"
   }
 
+  # used for keyRefreshDue-index
+  attribute {
+    name = "keyHealthShard"
+    type = "N"
+  }
+
+  attribute {
+    name = "keyRefreshDueAt"
+    type = "N"
+  }
+
   # sparse index allowing to sort device list updates by timestamp
   local_secondary_index {
     name            = "deviceList-timestamp-index"
@@ -264,6 +369,23 @@ resource "aws_dynamodb_table" "identity-devices" {
     projection_type = "KEYS_ONLY"
   }
 
+  # sparse index of devices the key health sweeper should check
+  global_secondary_index {
+    name            = "keyRefreshDue-index"
+    hash_key        = "keyHealthShard"
+    range_key       = "keyRefreshDueAt"
+    projection_type = "INCLUDE"
+    non_key_attributes = [
+      "contentOTKCount",
+      "notifOTKCount",
+      "preKeysUpdatedAt",
+      "lastKeyRefreshRequest",
+      "contentOTKExhaustionCount",
+      "notifOTKExhaustionCount",
+      "lastOTKExhaustion",
+    ]
+  }
+
   point_in_time_recovery {
     enabled = local.pitr_enabled
   }
@@ -290,6 +412,71 @@ resource "aws_dynamodb_table" "identity-tokens" {
   }
 }
 
+resource "aws_dynamodb_table" "identity-audit-log" {
+  name         = "identity-audit-log"
+  hash_key     = "userID"
+  range_key    = "eventID"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "userID"
+    type = "S"
+  }
+
+  attribute {
+    name = "eventID"
+    type = "S"
+  }
+
+  point_in_time_recovery {
+    enabled = local.pitr_enabled
+  }
+}
+
+resource "aws_dynamodb_table" "identity-login-attempts" {
+  name         = "identity-login-attempts"
+  hash_key     = "attemptKey"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "attemptKey"
+    type = "S"
+  }
+
+  ttl {
+    attribute_name = "expirationTimeUnix"
+    enabled        = true
+  }
+}
+
+resource "aws_dynamodb_table" "identity-released-usernames" {
+  name         = "identity-released-usernames"
+  hash_key     = "usernameLower"
+  billing_mode = "PAY_PER_REQUEST"
+
+  attribute {
+    name = "usernameLower"
+    type = "S"
+  }
+
+  attribute {
+    name = "usernameSkeleton"
+    type = "S"
+  }
+
+  global_secondary_index {
+    name               = "usernameSkeleton-index"
+    hash_key           = "usernameSkeleton"
+    projection_type    = "INCLUDE"
+    non_key_attributes = ["userID", "expirationTimeUnix"]
+  }
+
+  ttl {
+    attribute_name = "expirationTimeUnix"
+    enabled        = true
+  }
+}
+
 resource "aws_dynamodb_table" "identity-nonces" {
   name         = "identity-nonces"
   hash_key     = "nonce"
@@ -344,6 +531,11 @@ resource "aws_dynamodb_table" "identity-reserved-usernames" {
     type = "S"
   }
 
+  attribute {
+    name = "usernameSkeleton"
+    type = "S"
+  }
+
   global_secondary_index {
     name               = "usernameLower-index"
     hash_key           = "usernameLower"
@@ -351,6 +543,13 @@ resource "aws_dynamodb_table" "identity-reserved-usernames" {
     non_key_attributes = ["userID"]
   }
 
+  global_secondary_index {
+    name               = "usernameSkeleton-index"
+    hash_key           = "usernameSkeleton"
+    projection_type    = "INCLUDE"
+    non_key_attributes = ["userID"]
+  }
+
   global_secondary_index {
     name            = "userID-index"
     hash_key        = "userID"
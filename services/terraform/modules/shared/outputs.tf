@@ -3,6 +3,10 @@ locals {
     aws_dynamodb_table.feature-flags,
     aws_dynamodb_table.backup-service-backup,
     aws_dynamodb_table.backup-service-log,
+    aws_dynamodb_table.backup-service-device-log,
+    aws_dynamodb_table.backup-service-attachment-ref,
+    aws_dynamodb_table.backup-service-user-tombstone,
+    aws_dynamodb_table.backup-service-user-usage,
     aws_dynamodb_table.reports-service-reports,
     aws_dynamodb_table.tunnelbroker-undelivered-messages,
     aws_dynamodb_table.identity-users,
@@ -185,6 +185,11 @@ data "aws_iam_policy_document" "manage_backup_ddb" {
       module.shared.dynamodb_tables["backup-service-backup"].arn,
       "${module.shared.dynamodb_tables["backup-service-backup"].arn}/index/*",
       module.shared.dynamodb_tables["backup-service-log"].arn,
+      module.shared.dynamodb_tables["backup-service-device-log"].arn,
+      "${module.shared.dynamodb_tables["backup-service-device-log"].arn}/index/*",
+      module.shared.dynamodb_tables["backup-service-attachment-ref"].arn,
+      module.shared.dynamodb_tables["backup-service-user-tombstone"].arn,
+      module.shared.dynamodb_tables["backup-service-user-usage"].arn,
     ]
   }
 }
@@ -24,6 +24,8 @@ locals {
   identity_service_domain_name      = "identity.${local.root_domain}"
 
   opaque_server_setup_secret_name = "identity/ServerSetup"
+  totp_encryption_key_secret_name = "identity/TotpEncryptionKey"
+  device_list_log_key_secret_name = "identity/DeviceListLogSigningKey"
   staging_allow_origin_list       = <<EOT
     http://localhost:3000,
     http://localhost:3001,
@@ -56,6 +58,14 @@ data "aws_secretsmanager_secret" "identity_server_setup" {
   name = local.opaque_server_setup_secret_name
 }
 
+data "aws_secretsmanager_secret" "identity_totp_encryption_key" {
+  name = local.totp_encryption_key_secret_name
+}
+
+data "aws_secretsmanager_secret" "identity_device_list_log_key" {
+  name = local.device_list_log_key_secret_name
+}
+
 
 
 # Security group to configure access to the service
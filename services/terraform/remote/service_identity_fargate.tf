@@ -65,12 +65,24 @@ resource "aws_ecs_task_definition" "identity_service_fargate" {
         {
           name  = "REDACT_SENSITIVE_DATA",
           value = local.is_staging ? "false" : "true"
+        },
+        {
+          name  = "SIWE_ALLOWED_DOMAINS",
+          value = "comm.app,web.comm.app"
         }
       ]
       secrets = [
         {
           name      = "OPAQUE_SERVER_SETUP"
           valueFrom = data.aws_secretsmanager_secret.identity_server_setup.arn
+        },
+        {
+          name      = "TOTP_ENCRYPTION_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_totp_encryption_key.arn
+        },
+        {
+          name      = "DEVICE_LIST_LOG_SIGNING_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_device_list_log_key.arn
         }
       ]
       logConfiguration = {
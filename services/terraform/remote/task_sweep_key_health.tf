@@ -0,0 +1,148 @@
+locals {
+  # Run every hour
+  sweep_key_health_enabled  = true
+  sweep_key_health_schedule = "cron(0 * * * ? *)"
+}
+
+resource "aws_cloudwatch_log_group" "sweep_key_health" {
+  count = local.service_enabled.identity ? 1 : 0
+
+  name              = "/ecs/sweep-key-health"
+  retention_in_days = 7
+}
+
+resource "aws_ecs_task_definition" "sweep_key_health" {
+  count = local.service_enabled.identity ? 1 : 0
+
+  family = "sweep-key-health-task-def"
+  container_definitions = jsonencode([
+    {
+      essential = true
+      name      = local.identity_service_container_name
+      image     = local.identity_service_server_image
+      command   = ["identity", "sweep-key-health"]
+      environment = [
+        {
+          name  = "RUST_LOG"
+          value = local.is_staging ? "info,identity=trace,comm_lib=debug" : "info"
+        },
+        {
+          name  = "TUNNELBROKER_GRPC_ENDPOINT"
+          value = local.tunnelbroker_fargate_grpc_url
+        }
+      ]
+      secrets = [
+        {
+          # This is exposed as an environment variable in the container
+          name      = "OPAQUE_SERVER_SETUP"
+          valueFrom = data.aws_secretsmanager_secret.identity_server_setup.arn
+        },
+        {
+          name      = "TOTP_ENCRYPTION_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_totp_encryption_key.arn
+        },
+        {
+          name      = "DEVICE_LIST_LOG_SIGNING_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_device_list_log_key.arn
+        }
+      ]
+      logConfiguration = {
+        "logDriver" = "awslogs"
+        "options" = {
+          "awslogs-group"         = aws_cloudwatch_log_group.sweep_key_health[0].name
+          "awslogs-region"        = "us-east-2"
+          "awslogs-stream-prefix" = "ecs"
+        }
+      }
+    }
+  ])
+  task_role_arn            = aws_iam_role.services_ddb_full_access.arn
+  execution_role_arn       = aws_iam_role.ecs_task_execution.arn
+  network_mode             = "awsvpc"
+  cpu                      = "256"
+  memory                   = "512"
+  requires_compatibilities = ["FARGATE"]
+  skip_destroy             = false
+}
+
+resource "aws_scheduler_schedule" "sweep_key_health" {
+  count = local.service_enabled.identity ? 1 : 0
+
+  name       = "sweep-key-health-schedule"
+  group_name = "default"
+
+  schedule_expression = local.sweep_key_health_schedule
+  state               = local.sweep_key_health_enabled ? "ENABLED" : "DISABLED"
+
+  # Task can run within 15 minutes window of the scheduled time
+  flexible_time_window {
+    mode                      = "FLEXIBLE"
+    maximum_window_in_minutes = 15
+  }
+
+  target {
+    arn      = aws_ecs_cluster.comm_services.arn
+    role_arn = aws_iam_role.task_scheduler.arn
+
+    ecs_parameters {
+      task_definition_arn = aws_ecs_task_definition.sweep_key_health[0].arn_without_revision
+      launch_type         = "FARGATE"
+
+      network_configuration {
+        assign_public_ip = true
+        security_groups = [
+          aws_security_group.identity_service[0].id,
+          aws_security_group.comm_services_internal.id,
+        ]
+        subnets = [
+          aws_subnet.public_a.id,
+          aws_subnet.public_b.id,
+          aws_subnet.public_c.id,
+        ]
+      }
+    }
+
+    retry_policy {
+      maximum_event_age_in_seconds = 300
+      maximum_retry_attempts       = 5
+    }
+  }
+}
+
+resource "aws_iam_role_policy_attachment" "sweep_key_health_scheduler" {
+  count = local.service_enabled.identity ? 1 : 0
+
+  policy_arn = aws_iam_policy.sweep_key_health_scheduler[0].arn
+  role       = aws_iam_role.task_scheduler.name
+}
+
+resource "aws_iam_policy" "sweep_key_health_scheduler" {
+  count = local.service_enabled.identity ? 1 : 0
+
+  name = "cron-sweep-key-health-scheduler-policy"
+  policy = jsonencode({
+    Version = "2012-10-17"
+    Statement = [
+      # Allow scheduler to execute the task
+      {
+
+        Effect = "Allow",
+        Action = [
+          "ecs:RunTask"
+        ]
+        Resource = aws_ecs_task_definition.sweep_key_health[0].arn_without_revision
+      },
+      # Allow scheduler to set the IAM roles of the ECS task
+      {
+        Effect = "Allow",
+        Action = [
+          "iam:PassRole"
+        ]
+        Resource = [
+          aws_ecs_task_definition.sweep_key_health[0].execution_role_arn,
+          aws_ecs_task_definition.sweep_key_health[0].task_role_arn
+        ]
+      },
+    ]
+  })
+}
@@ -36,6 +36,14 @@ resource "aws_ecs_task_definition" "sync_identity_search" {
           # This is exposed as an environment variable in the container
           name      = "OPAQUE_SERVER_SETUP"
           valueFrom = data.aws_secretsmanager_secret.identity_server_setup.arn
+        },
+        {
+          name      = "TOTP_ENCRYPTION_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_totp_encryption_key.arn
+        },
+        {
+          name      = "DEVICE_LIST_LOG_SIGNING_KEY"
+          valueFrom = data.aws_secretsmanager_secret.identity_device_list_log_key.arn
         }
       ]
       logConfiguration = {
@@ -4,9 +4,11 @@ mod web;
 use async_stream::{stream, try_stream};
 pub use comm_lib::auth::UserIdentity;
 pub use comm_lib::backup::{
-  BackupVersionInfo, DownloadLogsRequest, LatestBackupInfoResponse,
-  LogWSRequest, LogWSResponse, UploadLogRequest,
+  BackupVersionInfo, DeviceLogCursor, DownloadDeviceLogsRequest,
+  DownloadLogsRequest, LatestBackupInfoResponse, LogWSHandshake, LogWSRequest,
+  LogWSRequestError, LogWSResponse, UploadLogRequest,
 };
+use futures_util::future::{self, select, Either};
 pub use futures_util::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
 use hex::ToHex;
 use reqwest::{
@@ -16,13 +18,19 @@ use reqwest::{
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio_tungstenite_wasm::{
   connect, Error as TungsteniteError, Message::Binary,
 };
+pub use tokio_util::sync::CancellationToken;
 
 const LOG_DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
 const LOG_DOWNLOAD_MAX_RETRY: usize = 3;
+#[cfg(not(target_arch = "wasm32"))]
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::wasm_bindgen;
@@ -48,6 +56,30 @@ impl BackupClient {
     user_identity: &UserIdentity,
     backup_data: BackupData,
   ) -> Result<(), Error> {
+    self
+      .upload_backup_with_options(
+        user_identity,
+        backup_data,
+        UploadOptions::default(),
+      )
+      .await
+  }
+
+  /// Uploads a backup, reporting progress as the request body is sent.
+  /// If the cancellation token is cancelled, the request is aborted
+  /// and [`Error::Cancelled`] is returned.
+  pub async fn upload_backup_with_options(
+    &self,
+    user_identity: &UserIdentity,
+    backup_data: BackupData,
+    options: UploadOptions,
+  ) -> Result<(), Error> {
+    let UploadOptions {
+      on_progress,
+      cancellation_token,
+    } = options;
+    let cancellation_token = cancellation_token.unwrap_or_default();
+
     let BackupData {
       backup_id,
       user_keys,
@@ -64,25 +96,37 @@ impl BackupClient {
       (None, Some(_)) => "backups/user_keys",
     };
 
+    let total_bytes = user_keys.as_ref().map_or(0, Vec::len)
+      + user_data.as_ref().map_or(0, Vec::len);
+    let progress =
+      Arc::new(UploadProgressTracker::new(total_bytes as u64, on_progress));
+    progress.report();
+
     let client = reqwest::Client::new();
     let mut form = Form::new().text("backup_id", backup_id);
 
-    if let Some(user_keys_value) = user_keys.clone() {
+    if let Some(user_keys_value) = user_keys {
       form = form
         .text(
           "user_keys_hash",
           Sha256::digest(&user_keys_value).encode_hex::<String>(),
         )
-        .part("user_keys", Part::stream(Body::from(user_keys_value)));
+        .part(
+          "user_keys",
+          upload_part(user_keys_value, &progress, &cancellation_token),
+        );
     }
 
-    if let Some(user_data_value) = user_data.clone() {
+    if let Some(user_data_value) = user_data {
       form = form
         .text(
           "user_data_hash",
           Sha256::digest(&user_data_value).encode_hex::<String>(),
         )
-        .part("user_data", Part::stream(Body::from(user_data_value)))
+        .part(
+          "user_data",
+          upload_part(user_data_value, &progress, &cancellation_token),
+        )
         .text("attachments", attachments.join("\n"));
     }
 
@@ -93,12 +137,22 @@ impl BackupClient {
     let version_info_payload = serde_json::to_string(&version_info)?;
     form = form.text("version_info", version_info_payload);
 
-    let response = client
+    let request = client
       .post(self.url.join(endpoint)?)
       .bearer_auth(user_identity.as_authorization_token()?)
       .multipart(form)
-      .send()
-      .await?;
+      .send();
+
+    // Dropping the request future aborts the upload
+    let response =
+      match select(pin!(request), pin!(cancellation_token.cancelled())).await {
+        Either::Left((response, _)) => response,
+        Either::Right(_) => return Err(Error::Cancelled),
+      };
+    if cancellation_token.is_cancelled() {
+      return Err(Error::Cancelled);
+    }
+    let response = response?;
 
     if matches!(
       response.status(),
@@ -108,6 +162,7 @@ impl BackupClient {
     }
 
     response.error_for_status()?;
+    progress.complete();
 
     Ok(())
   }
@@ -154,29 +209,88 @@ impl BackupClient {
 
 /// Log functions
 impl BackupClient {
+  /// Uploads logs to the primary log stream of a backup
   pub async fn upload_logs(
     &self,
     user_identity: &UserIdentity,
   ) -> Result<
     (
-      impl Sink<UploadLogRequest, Error = Error>,
-      impl Stream<Item = Result<LogUploadConfirmation, Error>>,
+      impl Sink<UploadLogRequest, Error = Error> + 'static,
+      impl Stream<Item = Result<LogUploadConfirmation, Error>> + 'static,
     ),
     Error,
   > {
-    let (tx, rx) = self.create_log_ws_connection(user_identity).await?;
+    self
+      .upload_log_stream(user_identity, LogWSRequest::UploadLog)
+      .await
+  }
+
+  /// Uploads logs to the log stream of the authenticated device.
+  /// Log IDs only have to be sequential within this device's stream.
+  pub async fn upload_device_logs(
+    &self,
+    user_identity: &UserIdentity,
+  ) -> Result<
+    (
+      impl Sink<UploadLogRequest, Error = Error> + 'static,
+      impl Stream<Item = Result<LogUploadConfirmation, Error>> + 'static,
+    ),
+    Error,
+  > {
+    self
+      .upload_log_stream(user_identity, LogWSRequest::UploadDeviceLog)
+      .await
+  }
+
+  async fn upload_log_stream(
+    &self,
+    user_identity: &UserIdentity,
+    into_request: fn(UploadLogRequest) -> LogWSRequest,
+  ) -> Result<
+    (
+      impl Sink<UploadLogRequest, Error = Error> + 'static,
+      impl Stream<Item = Result<LogUploadConfirmation, Error>> + 'static,
+    ),
+    Error,
+  > {
+    let (tx, rx) = self
+      .create_log_ws_connection::<LogWSRequest>(user_identity)
+      .await?;
+    // `Ready` keeps the sink `Unpin`, so callers can use `SinkExt::send`
+    let tx = tx.with(move |request: UploadLogRequest| {
+      future::ready(Ok::<_, Error>(into_request(request)))
+    });
 
     let rx = rx.map(|response| match response? {
       LogWSResponse::LogUploaded { backup_id, log_id } => {
         Ok(LogUploadConfirmation { backup_id, log_id })
       }
       LogWSResponse::ServerError => Err(Error::ServerError),
+      LogWSResponse::RequestError(err) => Err(Error::RequestError(err)),
       msg => Err(Error::InvalidBackupMessage(msg)),
     });
 
     Ok((tx, rx))
   }
 
+  /// Uploads logs to the primary log stream of a backup, keeping at most
+  /// `max_logs_in_flight` of them waiting for a confirmation.
+  /// See [`LogUploader`].
+  pub async fn log_uploader(
+    &self,
+    user_identity: &UserIdentity,
+    max_logs_in_flight: usize,
+  ) -> Result<
+    LogUploader<
+      impl Sink<UploadLogRequest, Error = Error> + 'static,
+      impl Stream<Item = Result<LogUploadConfirmation, Error>> + 'static,
+    >,
+    Error,
+  > {
+    let (tx, rx) = self.upload_logs(user_identity).await?;
+    Ok(LogUploader::new(tx, rx, max_logs_in_flight))
+  }
+
   /// Handles complete log download.
   /// It will try and retry download a few times, but if the issues persist
   /// the next item returned will be the last received error and the stream
@@ -268,6 +382,108 @@ impl BackupClient {
           LogWSResponse::LogDownloadFinished { .. } => {
             Err(Error::LogMissing)?;
           }
+          LogWSResponse::RequestError(err) => Err(Error::RequestError(err))?,
+          msg => Err(Error::InvalidBackupMessage(msg))?,
+        }
+      }
+
+      Err(Error::WSClosed)?;
+    }
+  }
+
+  /// Downloads logs of the primary log stream and all device log streams
+  /// of a backup, merged in upload order by the backup service. Retries
+  /// like [`Self::download_logs`].
+  pub async fn download_device_logs<'this>(
+    &'this self,
+    user_identity: &'this UserIdentity,
+    backup_id: &'this str,
+  ) -> impl Stream<Item = Result<DownloadedDeviceLog, Error>> + 'this {
+    stream! {
+      let mut cursor = None;
+      let mut fail_count = 0;
+
+      'retry: loop {
+        let stream = self.device_log_download_stream(user_identity, backup_id, &mut cursor).await;
+        let mut stream = Box::pin(stream);
+
+        while let Some(item) = stream.next().await {
+          match item {
+            Ok(log) => yield Ok(log),
+            Err(err) => {
+              println!("Error when downloading device logs: {err:?}");
+
+              fail_count += 1;
+              if fail_count >= LOG_DOWNLOAD_MAX_RETRY {
+                yield Err(err);
+                break 'retry;
+              }
+
+              #[cfg(target_arch = "wasm32")]
+              let _ = web::sleep(LOG_DOWNLOAD_RETRY_DELAY).await;
+              #[cfg(not(target_arch = "wasm32"))]
+              tokio::time::sleep(LOG_DOWNLOAD_RETRY_DELAY).await;
+              continue 'retry;
+            }
+          }
+        }
+
+        // Everything downloaded
+        return;
+      }
+
+      println!("Device log download failed!");
+    }
+  }
+
+  /// Handles a single device log download connection. Logs of a page are
+  /// yielded only after the whole page is received, so that a retry
+  /// starting from `cursor` doesn't yield them twice.
+  async fn device_log_download_stream<'stream>(
+    &'stream self,
+    user_identity: &'stream UserIdentity,
+    backup_id: &'stream str,
+    cursor: &'stream mut Option<DeviceLogCursor>,
+  ) -> impl Stream<Item = Result<DownloadedDeviceLog, Error>> + 'stream {
+    try_stream! {
+      let (mut tx, mut rx) = self.create_log_ws_connection(user_identity).await?;
+
+      tx.send(DownloadDeviceLogsRequest {
+        backup_id: backup_id.to_string(),
+        from: cursor.clone(),
+      })
+      .await?;
+
+      let mut page = Vec::new();
+      while let Some(response) = rx.try_next().await? {
+        match response {
+          LogWSResponse::DeviceLogDownload {
+            device_id,
+            log_id,
+            content,
+            attachments,
+          } => page.push(DownloadedDeviceLog {
+            device_id,
+            log_id,
+            content,
+            attachments,
+          }),
+          LogWSResponse::DeviceLogDownloadFinished { last_cursor } => {
+            for log in page.drain(..) {
+              yield log;
+            }
+
+            let Some(last_cursor) = last_cursor else {
+              return;
+            };
+            *cursor = Some(last_cursor.clone());
+            tx.send(DownloadDeviceLogsRequest {
+              backup_id: backup_id.to_string(),
+              from: Some(last_cursor),
+            })
+            .await?
+          }
+          LogWSResponse::RequestError(err) => Err(Error::RequestError(err))?,
           msg => Err(Error::InvalidBackupMessage(msg))?,
         }
       }
@@ -276,13 +492,13 @@ impl BackupClient {
     }
   }
 
-  async fn create_log_ws_connection<Request: Into<LogWSRequest>>(
+  async fn create_log_ws_connection<Request: Into<LogWSRequest> + 'static>(
     &self,
     user_identity: &UserIdentity,
   ) -> Result<
     (
-      impl Sink<Request, Error = Error>,
-      impl Stream<Item = Result<LogWSResponse, Error>>,
+      impl Sink<Request, Error = Error> + 'static,
+      impl Stream<Item = Result<LogWSResponse, Error>> + 'static,
     ),
     Error,
   > {
@@ -291,6 +507,10 @@ impl BackupClient {
 
     let (mut tx, rx) = stream.split();
 
+    tx.send(Binary(bincode::serialize(&LogWSRequest::Handshake(
+      LogWSHandshake::current(),
+    ))?))
+    .await?;
     tx.send(Binary(bincode::serialize(&LogWSRequest::Authenticate(
       user_identity.clone(),
     ))?))
@@ -318,10 +538,22 @@ impl BackupClient {
     let tx = Box::pin(tx);
     let mut rx = Box::pin(rx);
 
-    if let Some(response) = rx.try_next().await? {
+    // Handshake and auth responses can arrive in any order
+    let mut authenticated = false;
+    let mut handshake_finished = false;
+    while !(authenticated && handshake_finished) {
+      let Some(response) = rx.try_next().await? else {
+        return Err(Error::WSClosed);
+      };
       match response {
-        LogWSResponse::AuthSuccess => {}
+        LogWSResponse::AuthSuccess => authenticated = true,
         LogWSResponse::Unauthenticated => Err(Error::Unauthenticated)?,
+        LogWSResponse::HandshakeAccepted(_) => handshake_finished = true,
+        // Services that predate the handshake can't deserialize it
+        LogWSResponse::ServerError if !handshake_finished => {
+          handshake_finished = true
+        }
+        LogWSResponse::RequestError(err) => Err(Error::RequestError(err))?,
         msg => Err(Error::InvalidBackupMessage(msg))?,
       }
     }
@@ -353,6 +585,108 @@ pub struct BackupData {
   pub version_info: BackupVersionInfo,
 }
 
+/// Progress of a backup upload. Only the user keys and user data parts
+/// are counted in `total_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadProgress {
+  pub uploaded_bytes: u64,
+  pub total_bytes: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type UploadProgressCallback = Box<dyn Fn(UploadProgress) + Send + Sync>;
+#[cfg(target_arch = "wasm32")]
+pub type UploadProgressCallback = Box<dyn Fn(UploadProgress)>;
+
+#[derive(Default)]
+pub struct UploadOptions {
+  pub on_progress: Option<UploadProgressCallback>,
+  pub cancellation_token: Option<CancellationToken>,
+}
+
+struct UploadProgressTracker {
+  uploaded_bytes: AtomicU64,
+  total_bytes: u64,
+  on_progress: Option<UploadProgressCallback>,
+}
+
+impl UploadProgressTracker {
+  fn new(
+    total_bytes: u64,
+    on_progress: Option<UploadProgressCallback>,
+  ) -> Self {
+    UploadProgressTracker {
+      uploaded_bytes: AtomicU64::new(0),
+      total_bytes,
+      on_progress,
+    }
+  }
+
+  fn report(&self) {
+    let Some(on_progress) = &self.on_progress else {
+      return;
+    };
+    on_progress(UploadProgress {
+      uploaded_bytes: self.uploaded_bytes.load(Ordering::Relaxed),
+      total_bytes: self.total_bytes,
+    });
+  }
+
+  #[cfg(not(target_arch = "wasm32"))]
+  fn advance(&self, bytes: u64) {
+    self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    self.report();
+  }
+
+  fn complete(&self) {
+    let previous = self
+      .uploaded_bytes
+      .swap(self.total_bytes, Ordering::Relaxed);
+    if previous != self.total_bytes {
+      self.report();
+    }
+  }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn upload_part(
+  data: Vec<u8>,
+  progress: &Arc<UploadProgressTracker>,
+  cancellation_token: &CancellationToken,
+) -> Part {
+  let length = data.len();
+  let progress = progress.clone();
+  let cancellation_token = cancellation_token.clone();
+
+  let chunks = (0..length).step_by(UPLOAD_CHUNK_SIZE).map(move |start| {
+    if cancellation_token.is_cancelled() {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::Interrupted,
+        "upload cancelled",
+      ));
+    }
+    let end = usize::min(start + UPLOAD_CHUNK_SIZE, length);
+    progress.advance((end - start) as u64);
+    Ok(data[start..end].to_vec())
+  });
+
+  Part::stream_with_length(
+    Body::wrap_stream(futures_util::stream::iter(chunks)),
+    length as u64,
+  )
+}
+
+/// Browsers can't stream request bodies, so on web the progress is only
+/// reported when the upload starts and completes.
+#[cfg(target_arch = "wasm32")]
+fn upload_part(
+  data: Vec<u8>,
+  _progress: &Arc<UploadProgressTracker>,
+  _cancellation_token: &CancellationToken,
+) -> Part {
+  Part::stream(Body::from(data))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BackupDescriptor {
@@ -381,12 +715,89 @@ pub struct LogUploadConfirmation {
   pub log_id: usize,
 }
 
+/// Log upload with backpressure. Logs can be passed one by one as they
+/// are produced: [`LogUploader::upload`] waits for confirmations when
+/// too many logs are unconfirmed, so neither the caller nor the websocket
+/// has to buffer all of them.
+pub struct LogUploader<Tx, Rx> {
+  tx: Pin<Box<Tx>>,
+  rx: Pin<Box<Rx>>,
+  max_logs_in_flight: usize,
+  logs_in_flight: usize,
+  confirmed_logs: usize,
+}
+
+impl<Tx, Rx> LogUploader<Tx, Rx>
+where
+  Tx: Sink<UploadLogRequest, Error = Error>,
+  Rx: Stream<Item = Result<LogUploadConfirmation, Error>>,
+{
+  pub fn new(tx: Tx, rx: Rx, max_logs_in_flight: usize) -> Self {
+    LogUploader {
+      tx: Box::pin(tx),
+      rx: Box::pin(rx),
+      max_logs_in_flight: max_logs_in_flight.max(1),
+      logs_in_flight: 0,
+      confirmed_logs: 0,
+    }
+  }
+
+  /// Sends a log once the number of unconfirmed logs is below the limit
+  pub async fn upload(&mut self, log: UploadLogRequest) -> Result<(), Error> {
+    while self.logs_in_flight >= self.max_logs_in_flight {
+      self.wait_for_confirmation().await?;
+    }
+    self.tx.send(log).await?;
+    self.logs_in_flight += 1;
+    Ok(())
+  }
+
+  /// Waits for the next confirmation. Returns `None` if all sent logs
+  /// are already confirmed.
+  pub async fn wait_for_confirmation(
+    &mut self,
+  ) -> Result<Option<LogUploadConfirmation>, Error> {
+    if self.logs_in_flight == 0 {
+      return Ok(None);
+    }
+    let confirmation = self.rx.next().await.ok_or(Error::WSClosed)??;
+    self.logs_in_flight -= 1;
+    self.confirmed_logs += 1;
+    Ok(Some(confirmation))
+  }
+
+  /// Waits until all sent logs are confirmed and closes the connection.
+  /// Returns the number of confirmed logs.
+  pub async fn finish(mut self) -> Result<usize, Error> {
+    while self.wait_for_confirmation().await?.is_some() {}
+    self.tx.close().await?;
+    Ok(self.confirmed_logs)
+  }
+
+  pub fn confirmed_logs(&self) -> usize {
+    self.confirmed_logs
+  }
+
+  pub fn logs_in_flight(&self) -> usize {
+    self.logs_in_flight
+  }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DownloadedLog {
   pub content: Vec<u8>,
   pub attachments: Option<Vec<String>>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DownloadedDeviceLog {
+  /// `None` for logs of the primary stream
+  pub device_id: Option<String>,
+  pub log_id: usize,
+  pub content: Vec<u8>,
+  pub attachments: Option<Vec<String>>,
+}
+
 #[derive(Debug, derive_more::Display, derive_more::From)]
 pub enum Error {
   InvalidAuthorizationHeader,
@@ -401,12 +812,15 @@ pub enum Error {
   #[display(fmt = "Error::InvalidBackupMessage({:?})", _0)]
   InvalidBackupMessage(LogWSResponse),
   ServerError,
+  #[display(fmt = "Error::RequestError({:?})", _0)]
+  RequestError(LogWSRequestError),
   LogMissing,
   WSClosed,
   Unauthenticated,
   InvalidRequest,
   #[display(fmt = "no_backup_data")]
   NoBackupData,
+  Cancelled,
 }
 
 impl Error {
@@ -417,6 +831,54 @@ impl Error {
   pub fn is_backup_not_found(&self) -> bool {
     matches!(self, Self::ReqwestError(err) if err.status() == Some(StatusCode::NOT_FOUND))
   }
+
+  pub fn is_quota_exceeded(&self) -> bool {
+    match self {
+      Self::ReqwestError(err) => {
+        err.status() == Some(StatusCode::PAYLOAD_TOO_LARGE)
+      }
+      Self::RequestError(err) => matches!(
+        err,
+        LogWSRequestError::LogSizeQuotaExceeded { .. }
+          | LogWSRequestError::LogCountQuotaExceeded { .. }
+          | LogWSRequestError::BackupSizeQuotaExceeded { .. }
+      ),
+      _ => false,
+    }
+  }
+
+  /// User data was deleted and uploads are rejected until it's restored
+  pub fn is_user_data_deleted(&self) -> bool {
+    match self {
+      Self::ReqwestError(err) => err.status() == Some(StatusCode::GONE),
+      Self::RequestError(err) => {
+        matches!(err, LogWSRequestError::UserDataDeleted)
+      }
+      _ => false,
+    }
+  }
+
+  /// Whether the same request might succeed when retried later.
+  /// Client errors other than timeouts and rate limiting are permanent.
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      Self::ReqwestError(err) => match err.status() {
+        Some(StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) => {
+          true
+        }
+        Some(status) => !status.is_client_error(),
+        None => true,
+      },
+      Self::RequestError(_)
+      | Self::Unauthenticated
+      | Self::InvalidRequest
+      | Self::UrlSchemaError
+      | Self::UrlError(_)
+      | Self::InvalidAuthorizationHeader
+      | Self::Cancelled => false,
+      _ => true,
+    }
+  }
 }
 
 impl std::error::Error for Error {}
@@ -1,8 +1,15 @@
-use crate::{BackupClient, RequestedData};
-use futures_util::TryStreamExt;
+use crate::{
+  BackupClient, BackupData, BackupVersionInfo, CancellationToken, Error,
+  LogUploadConfirmation, LogUploader, RequestedData, UploadLogRequest,
+  UploadOptions, UploadProgress, UploadProgressCallback,
+};
+use futures_util::{lock::Mutex, Sink, Stream, TryStreamExt};
+use serde::Deserialize;
+use std::pin::Pin;
 use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::js_sys::{self, Function as JSFunction, Uint8Array};
+use web_sys::AbortSignal;
 
 #[wasm_bindgen]
 impl BackupClient {
@@ -33,7 +40,8 @@ impl BackupClient {
     f: &JSFunction,
   ) -> Result<(), JsError> {
     let user_identity = serde_wasm_bindgen::from_value(user_identity)?;
-    let stream = self.download_logs(&user_identity, &backup_id).await;
+    // logs of secondary devices are merged with primary logs
+    let stream = self.download_device_logs(&user_identity, &backup_id).await;
     let mut stream = Box::pin(stream);
 
     let this = JsValue::null();
@@ -50,6 +58,247 @@ impl BackupClient {
 
     Ok(())
   }
+
+  /// Uploads a new backup. `user_keys` and `user_data` are already
+  /// encrypted by the caller. At least one of them has to be provided.
+  ///
+  /// `on_progress` is called with `(uploadedBytes, totalBytes)`.
+  /// Aborting `abort_signal` cancels the upload.
+  #[wasm_bindgen(js_name = "uploadBackup")]
+  pub async fn wasm_upload_backup(
+    &self,
+    user_identity: JsValue,
+    backup_metadata: JsValue,
+    user_keys: Option<Uint8Array>,
+    user_data: Option<Uint8Array>,
+    on_progress: Option<JSFunction>,
+    abort_signal: Option<AbortSignal>,
+  ) -> Result<(), JsError> {
+    let user_identity = serde_wasm_bindgen::from_value(user_identity)?;
+    let WasmBackupMetadata {
+      backup_id,
+      attachments,
+      siwe_backup_msg,
+      version_info,
+    } = serde_wasm_bindgen::from_value(backup_metadata)?;
+
+    let backup_data = BackupData {
+      backup_id,
+      user_keys: user_keys.map(|data| data.to_vec()),
+      user_data: user_data.map(|data| data.to_vec()),
+      attachments,
+      siwe_backup_msg,
+      version_info,
+    };
+
+    let on_progress = on_progress.map(|callback| {
+      Box::new(move |progress: UploadProgress| {
+        let _ = call_progress_callback(
+          Some(&callback),
+          progress.uploaded_bytes as usize,
+          progress.total_bytes as usize,
+        );
+      }) as UploadProgressCallback
+    });
+
+    let cancellation_token = CancellationToken::new();
+    let on_abort = {
+      let cancellation_token = cancellation_token.clone();
+      Closure::once(move || cancellation_token.cancel())
+    };
+    if let Some(signal) = &abort_signal {
+      if signal.aborted() {
+        return Err(Error::Cancelled.into());
+      }
+      signal
+        .add_event_listener_with_callback(
+          "abort",
+          on_abort.as_ref().unchecked_ref(),
+        )
+        .map_err(|_| JsError::new("Failed to listen for abort signal"))?;
+    }
+
+    let options = UploadOptions {
+      on_progress,
+      cancellation_token: Some(cancellation_token),
+    };
+    let result = self
+      .upload_backup_with_options(&user_identity, backup_data, options)
+      .await;
+
+    if let Some(signal) = &abort_signal {
+      let _ = signal.remove_event_listener_with_callback(
+        "abort",
+        on_abort.as_ref().unchecked_ref(),
+      );
+    }
+
+    Ok(result?)
+  }
+
+  /// Uploads logs over a single websocket connection and waits until
+  /// the service confirms all of them. Use `createLogUploader` to upload
+  /// logs as they are produced.
+  ///
+  /// `on_progress` is called with `(confirmedLogs, totalLogs)`.
+  #[wasm_bindgen(js_name = "uploadLogs")]
+  pub async fn wasm_upload_logs(
+    &self,
+    user_identity: JsValue,
+    logs: JsValue,
+    on_progress: Option<JSFunction>,
+  ) -> Result<(), JsError> {
+    let user_identity = serde_wasm_bindgen::from_value(user_identity)?;
+    let logs: Vec<WasmUploadLogRequest> = serde_wasm_bindgen::from_value(logs)?;
+    let total_logs = logs.len();
+    if total_logs == 0 {
+      return Ok(());
+    }
+
+    let mut uploader = self
+      .log_uploader(&user_identity, WASM_MAX_LOGS_IN_FLIGHT)
+      .await?;
+    for log in logs {
+      let confirmed_logs = uploader.confirmed_logs();
+      uploader.upload(UploadLogRequest::from(log)).await?;
+      if uploader.confirmed_logs() != confirmed_logs {
+        call_progress_callback(
+          on_progress.as_ref(),
+          uploader.confirmed_logs(),
+          total_logs,
+        )?;
+      }
+    }
+    while uploader.wait_for_confirmation().await?.is_some() {
+      call_progress_callback(
+        on_progress.as_ref(),
+        uploader.confirmed_logs(),
+        total_logs,
+      )?;
+    }
+
+    uploader.finish().await?;
+    Ok(())
+  }
+
+  /// Opens a log upload connection. Logs are passed to
+  /// `WasmLogUploader.upload()` one by one, which waits while
+  /// `max_logs_in_flight` logs are unconfirmed.
+  #[wasm_bindgen(js_name = "createLogUploader")]
+  pub async fn wasm_create_log_uploader(
+    &self,
+    user_identity: JsValue,
+    max_logs_in_flight: Option<usize>,
+  ) -> Result<WasmLogUploader, JsError> {
+    let user_identity = serde_wasm_bindgen::from_value(user_identity)?;
+    let (tx, rx) = self.upload_logs(&user_identity).await?;
+    let uploader = LogUploader::new(
+      Box::pin(tx) as BoxedLogSink,
+      Box::pin(rx) as BoxedConfirmationStream,
+      max_logs_in_flight.unwrap_or(WASM_MAX_LOGS_IN_FLIGHT),
+    );
+
+    Ok(WasmLogUploader {
+      uploader: Mutex::new(Some(uploader)),
+    })
+  }
+}
+
+const WASM_MAX_LOGS_IN_FLIGHT: usize = 8;
+
+type BoxedLogSink = Pin<Box<dyn Sink<UploadLogRequest, Error = Error>>>;
+type BoxedConfirmationStream =
+  Pin<Box<dyn Stream<Item = Result<LogUploadConfirmation, Error>>>>;
+
+/// JS handle of a [`LogUploader`]. Calls are serialized, so `upload()`
+/// doesn't have to be awaited before the next one, but awaiting it
+/// is what applies the backpressure.
+#[wasm_bindgen]
+pub struct WasmLogUploader {
+  uploader: Mutex<Option<LogUploader<BoxedLogSink, BoxedConfirmationStream>>>,
+}
+
+#[wasm_bindgen]
+impl WasmLogUploader {
+  pub async fn upload(&self, log: JsValue) -> Result<(), JsError> {
+    let log: WasmUploadLogRequest = serde_wasm_bindgen::from_value(log)?;
+    let mut uploader = self.uploader.lock().await;
+    let Some(uploader) = uploader.as_mut() else {
+      return Err(Error::WSClosed.into());
+    };
+    uploader.upload(UploadLogRequest::from(log)).await?;
+    Ok(())
+  }
+
+  #[wasm_bindgen(getter, js_name = "confirmedLogs")]
+  pub fn confirmed_logs(&self) -> usize {
+    self
+      .uploader
+      .try_lock()
+      .and_then(|uploader| uploader.as_ref().map(LogUploader::confirmed_logs))
+      .unwrap_or_default()
+  }
+
+  /// Waits for all uploaded logs to be confirmed and closes
+  /// the connection. Returns the number of confirmed logs.
+  pub async fn finish(&self) -> Result<usize, JsError> {
+    let Some(uploader) = self.uploader.lock().await.take() else {
+      return Err(Error::WSClosed.into());
+    };
+    Ok(uploader.finish().await?)
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WasmBackupMetadata {
+  #[serde(rename = "backupID")]
+  backup_id: String,
+  #[serde(default)]
+  attachments: Vec<String>,
+  siwe_backup_msg: Option<String>,
+  version_info: BackupVersionInfo,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WasmUploadLogRequest {
+  #[serde(rename = "backupID")]
+  backup_id: String,
+  #[serde(rename = "logID")]
+  log_id: usize,
+  content: Vec<u8>,
+  attachments: Option<Vec<String>>,
+}
+
+impl From<WasmUploadLogRequest> for UploadLogRequest {
+  fn from(value: WasmUploadLogRequest) -> Self {
+    UploadLogRequest {
+      backup_id: value.backup_id,
+      log_id: value.log_id,
+      content: value.content,
+      attachments: value.attachments,
+    }
+  }
+}
+
+fn call_progress_callback(
+  callback: Option<&JSFunction>,
+  done: usize,
+  total: usize,
+) -> Result<(), JsError> {
+  let Some(callback) = callback else {
+    return Ok(());
+  };
+
+  callback
+    .call2(
+      &JsValue::null(),
+      &JsValue::from(done as f64),
+      &JsValue::from(total as f64),
+    )
+    .map_err(|_| JsError::new("Progress callback failed"))?;
+  Ok(())
 }
 
 pub async fn sleep(duration: Duration) -> Result<JsValue, JsValue> {
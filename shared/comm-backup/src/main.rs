@@ -0,0 +1,271 @@
+//! Command line tool for inspecting backups stored by the backup service.
+//! It works against any backup service instance, including a locally
+//! running one.
+
+use anyhow::{bail, Context, Result};
+use backup_client::{
+  BackupClient, BackupData, BackupDescriptor, BackupVersionInfo,
+  CancellationToken, DownloadedLog, Error as BackupError,
+  LatestBackupInfoResponse, RequestedData, TryStreamExt, UploadOptions,
+  UploadProgress, UserIdentity,
+};
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "comm-backup", version, about, long_about = None)]
+struct Cli {
+  /// Backup service URL
+  #[arg(long, env = "BACKUP_SERVICE_URL")]
+  #[arg(default_value = "http://localhost:50052")]
+  url: String,
+  #[command(subcommand)]
+  command: Command,
+}
+
+/// Credentials of an authenticated device
+#[derive(Args)]
+struct IdentityArgs {
+  #[arg(long, env = "COMM_USER_ID")]
+  user_id: String,
+  #[arg(long, env = "COMM_ACCESS_TOKEN")]
+  access_token: String,
+  #[arg(long, env = "COMM_DEVICE_ID")]
+  device_id: String,
+}
+
+impl From<IdentityArgs> for UserIdentity {
+  fn from(value: IdentityArgs) -> Self {
+    UserIdentity {
+      user_id: value.user_id,
+      access_token: value.access_token,
+      device_id: value.device_id,
+    }
+  }
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Shows info about the latest backup of a user
+  LatestInfo {
+    /// Username or wallet address
+    user_identifier: String,
+  },
+  /// Downloads user keys of a backup to a file
+  DownloadUserKeys(DownloadArgs),
+  /// Downloads user data of a backup to a file
+  DownloadUserData(DownloadArgs),
+  /// Downloads all logs of a backup to a directory
+  DownloadLogs {
+    #[command(flatten)]
+    identity: IdentityArgs,
+    #[arg(long)]
+    backup_id: String,
+    /// Each log is saved as `log_<n>`, its attachment hashes
+    /// as `log_<n>_attachments`
+    #[arg(short, long)]
+    output_dir: PathBuf,
+  },
+  /// Uploads a compaction from files
+  Upload(UploadArgs),
+}
+
+#[derive(Args)]
+struct DownloadArgs {
+  #[command(flatten)]
+  identity: IdentityArgs,
+  #[arg(long)]
+  backup_id: String,
+  #[arg(short, long)]
+  output: PathBuf,
+}
+
+#[derive(Args)]
+struct UploadArgs {
+  #[command(flatten)]
+  identity: IdentityArgs,
+  #[arg(long)]
+  backup_id: String,
+  /// Encrypted user keys file
+  #[arg(long)]
+  user_keys: Option<PathBuf>,
+  /// Encrypted user data file
+  #[arg(long)]
+  user_data: Option<PathBuf>,
+  /// File with attachment blob hashes, one per line
+  #[arg(long)]
+  attachments: Option<PathBuf>,
+  /// SIWE backup message file
+  #[arg(long)]
+  siwe_backup_msg: Option<PathBuf>,
+  #[arg(long, default_value_t = 0)]
+  code_version: u16,
+  #[arg(long, default_value_t = 0)]
+  state_version: u16,
+  #[arg(long, default_value_t = 0)]
+  db_version: u16,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+  let cli = Cli::parse();
+  let client = BackupClient::new(cli.url.as_str())
+    .with_context(|| format!("Invalid backup service URL: {}", cli.url))?;
+
+  match cli.command {
+    Command::LatestInfo { user_identifier } => {
+      latest_info(&client, user_identifier).await
+    }
+    Command::DownloadUserKeys(args) => {
+      download(&client, args, RequestedData::UserKeys).await
+    }
+    Command::DownloadUserData(args) => {
+      download(&client, args, RequestedData::UserData).await
+    }
+    Command::DownloadLogs {
+      identity,
+      backup_id,
+      output_dir,
+    } => download_logs(&client, identity.into(), backup_id, output_dir).await,
+    Command::Upload(args) => upload(&client, args).await,
+  }
+}
+
+async fn latest_info(
+  client: &BackupClient,
+  user_identifier: String,
+) -> Result<()> {
+  let descriptor = BackupDescriptor::Latest { user_identifier };
+  let response = client
+    .download_backup_data(&descriptor, RequestedData::BackupInfo)
+    .await?;
+  let info: LatestBackupInfoResponse = serde_json::from_slice(&response)?;
+
+  println!("{}", serde_json::to_string_pretty(&info)?);
+  Ok(())
+}
+
+async fn download(
+  client: &BackupClient,
+  args: DownloadArgs,
+  requested_data: RequestedData,
+) -> Result<()> {
+  let descriptor = BackupDescriptor::BackupID {
+    backup_id: args.backup_id,
+    user_identity: args.identity.into(),
+  };
+  let data = client
+    .download_backup_data(&descriptor, requested_data)
+    .await?;
+
+  tokio::fs::write(&args.output, &data)
+    .await
+    .with_context(|| format!("Failed to write {}", args.output.display()))?;
+  println!("Saved {} bytes to {}", data.len(), args.output.display());
+  Ok(())
+}
+
+async fn download_logs(
+  client: &BackupClient,
+  user_identity: UserIdentity,
+  backup_id: String,
+  output_dir: PathBuf,
+) -> Result<()> {
+  tokio::fs::create_dir_all(&output_dir).await?;
+
+  let stream = client.download_logs(&user_identity, &backup_id).await;
+  let mut stream = Box::pin(stream);
+
+  let mut log_count = 0;
+  while let Some(DownloadedLog {
+    content,
+    attachments,
+  }) = stream.try_next().await?
+  {
+    log_count += 1;
+    let log_path = output_dir.join(format!("log_{log_count}"));
+    tokio::fs::write(&log_path, content).await?;
+
+    if let Some(attachments) = attachments {
+      let attachments_path =
+        output_dir.join(format!("log_{log_count}_attachments"));
+      tokio::fs::write(&attachments_path, attachments.join("\n")).await?;
+    }
+  }
+
+  println!("Saved {log_count} logs to {}", output_dir.display());
+  Ok(())
+}
+
+async fn upload(client: &BackupClient, args: UploadArgs) -> Result<()> {
+  if args.user_keys.is_none() && args.user_data.is_none() {
+    bail!("At least one of --user-keys and --user-data is required");
+  }
+
+  let attachments = read_optional_file(args.attachments.as_deref())
+    .await?
+    .map(|data| String::from_utf8(data).context("Invalid attachments file"))
+    .transpose()?
+    .map(|attachments| attachments.lines().map(String::from).collect())
+    .unwrap_or_default();
+  let siwe_backup_msg = read_optional_file(args.siwe_backup_msg.as_deref())
+    .await?
+    .map(|data| String::from_utf8(data).context("Invalid SIWE message file"))
+    .transpose()?;
+
+  let backup_data = BackupData {
+    backup_id: args.backup_id,
+    user_keys: read_optional_file(args.user_keys.as_deref()).await?,
+    user_data: read_optional_file(args.user_data.as_deref()).await?,
+    attachments,
+    siwe_backup_msg,
+    version_info: BackupVersionInfo {
+      code_version: args.code_version,
+      state_version: args.state_version,
+      db_version: args.db_version,
+    },
+  };
+
+  // Ctrl+C aborts the upload
+  let cancellation_token = CancellationToken::new();
+  let ctrl_c_token = cancellation_token.clone();
+  tokio::spawn(async move {
+    if tokio::signal::ctrl_c().await.is_ok() {
+      ctrl_c_token.cancel();
+    }
+  });
+
+  let options = UploadOptions {
+    on_progress: Some(Box::new(
+      |UploadProgress {
+         uploaded_bytes,
+         total_bytes,
+       }| {
+        eprint!("\rUploaded {uploaded_bytes}/{total_bytes} bytes");
+      },
+    )),
+    cancellation_token: Some(cancellation_token),
+  };
+  let result = client
+    .upload_backup_with_options(&args.identity.into(), backup_data, options)
+    .await;
+  eprintln!();
+
+  match result {
+    Ok(()) => println!("Backup uploaded"),
+    Err(BackupError::Cancelled) => bail!("Upload cancelled"),
+    Err(err) => return Err(err.into()),
+  }
+  Ok(())
+}
+
+async fn read_optional_file(path: Option<&Path>) -> Result<Option<Vec<u8>>> {
+  let Some(path) = path else {
+    return Ok(None);
+  };
+
+  let data = tokio::fs::read(path)
+    .await
+    .with_context(|| format!("Failed to read {}", path.display()))?;
+  Ok(Some(data))
+}
@@ -0,0 +1,504 @@
+//! Self-contained backup archive format, used to export a backup
+//! from the Backup service and import it back (possibly into
+//! another deployment).
+//!
+//! An archive consists of a header followed by raw parts:
+//!
+//! ```text
+//! archive  := MAGIC manifest_length manifest part*
+//! MAGIC    := "COMMBKP" 0x01                       (8 bytes)
+//! manifest_length := u32, big-endian               (4 bytes)
+//! manifest := JSON-serialized BackupArchiveManifest
+//! part     := data digest
+//! data     := raw bytes, length given by the manifest
+//! digest   := SHA-256 of data                      (32 bytes)
+//! ```
+//!
+//! Parts are concatenated in the order of [`BackupArchiveManifest::parts`]:
+//! User Keys, User Data, attachments and then logs: first the primary
+//! log stream ordered by log ID, then device logs in upload order.
+//! Every part is stored exactly as it is kept by the Backup service:
+//! User Keys, User Data and logs are already encrypted by the client,
+//! and so are attachments. The archive itself doesn't add another
+//! encryption layer: the Backup service doesn't have the user's backup
+//! keys, so it could only encrypt the archive with a key it holds itself,
+//! which wouldn't protect the data any further. Instead, each part is
+//! followed by its digest, so that a corrupted or truncated archive
+//! is rejected before the part is stored. Attachments referenced
+//! by several logs are stored only once.
+
+use super::BackupVersionInfo;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"COMMBKP\x01";
+pub const ARCHIVE_FORMAT_VERSION: u16 = 1;
+/// Manifests larger than that are considered malformed
+pub const MAX_MANIFEST_SIZE: u32 = 16 * 1024 * 1024;
+
+pub const PART_DIGEST_SIZE: usize = 32;
+
+const MANIFEST_LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+const HEADER_SIZE: usize = ARCHIVE_MAGIC.len() + MANIFEST_LENGTH_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupArchiveManifest {
+  pub format_version: u16,
+  #[serde(rename = "backupID")]
+  pub backup_id: String,
+  // ISO 8601 / RFC 3339 DateTime string
+  pub creation_timestamp: String,
+  pub siwe_backup_msg: Option<String>,
+  pub version_info: BackupVersionInfo,
+  /// Blob hashes of backup (User Data) attachments. Their content is stored
+  /// in [`BackupArchivePart::Attachment`] parts.
+  pub attachments: Vec<String>,
+  pub parts: Vec<BackupArchivePart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BackupArchivePart {
+  #[serde(rename_all = "camelCase")]
+  UserKeys { blob_hash: String, size: u64 },
+  #[serde(rename_all = "camelCase")]
+  UserData { blob_hash: String, size: u64 },
+  #[serde(rename_all = "camelCase")]
+  Attachment { blob_hash: String, size: u64 },
+  #[serde(rename_all = "camelCase")]
+  Log {
+    #[serde(rename = "logID")]
+    log_id: usize,
+    size: u64,
+    /// Blob hashes of log attachments. Their content is stored
+    /// in separate [`BackupArchivePart::Attachment`] parts.
+    attachments: Vec<String>,
+    /// Device log stream. `None` for the primary log stream.
+    #[serde(
+      default,
+      rename = "deviceID",
+      skip_serializing_if = "Option::is_none"
+    )]
+    device_id: Option<String>,
+    // ISO 8601 / RFC 3339 DateTime string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uploaded_at: Option<String>,
+  },
+}
+
+impl BackupArchivePart {
+  pub fn size(&self) -> u64 {
+    match self {
+      Self::UserKeys { size, .. }
+      | Self::UserData { size, .. }
+      | Self::Attachment { size, .. }
+      | Self::Log { size, .. } => *size,
+    }
+  }
+}
+
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+pub enum ArchiveError {
+  #[display(fmt = "Invalid archive magic bytes")]
+  InvalidMagic,
+  #[display(fmt = "Unsupported archive format version: {_0}")]
+  UnsupportedVersion(#[error(not(source))] u16),
+  #[display(fmt = "Archive manifest is too large")]
+  ManifestTooLarge,
+  #[display(fmt = "Invalid archive manifest: {_0}")]
+  InvalidManifest(serde_json::Error),
+  #[display(fmt = "Archive part {_0} doesn't match its digest")]
+  DigestMismatch(#[error(not(source))] usize),
+  #[display(fmt = "Archive ended unexpectedly")]
+  UnexpectedEof,
+  #[display(fmt = "Archive contains unexpected trailing data")]
+  TrailingData,
+}
+
+/// Computes the digest written after each part's data
+#[derive(Default)]
+pub struct PartDigest(Sha256);
+
+impl PartDigest {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    self.0.update(data);
+  }
+
+  pub fn finalize(self) -> [u8; PART_DIGEST_SIZE] {
+    self.0.finalize().into()
+  }
+}
+
+/// Serializes archive header: magic bytes and the manifest.
+/// Parts should be written directly after the header, each one
+/// followed by its [`PartDigest`].
+pub fn encode_header(
+  manifest: &BackupArchiveManifest,
+) -> Result<Vec<u8>, ArchiveError> {
+  let manifest_bytes =
+    serde_json::to_vec(manifest).map_err(ArchiveError::InvalidManifest)?;
+  let manifest_length = u32::try_from(manifest_bytes.len())
+    .ok()
+    .filter(|length| *length <= MAX_MANIFEST_SIZE)
+    .ok_or(ArchiveError::ManifestTooLarge)?;
+
+  let mut header = Vec::with_capacity(HEADER_SIZE + manifest_bytes.len());
+  header.extend_from_slice(ARCHIVE_MAGIC);
+  header.extend_from_slice(&manifest_length.to_be_bytes());
+  header.extend_from_slice(&manifest_bytes);
+  Ok(header)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveEvent {
+  Manifest(BackupArchiveManifest),
+  /// A chunk of data of the part at given index of
+  /// [`BackupArchiveManifest::parts`]
+  PartData {
+    index: usize,
+    data: Vec<u8>,
+  },
+  /// All part data has been emitted and matches its digest
+  PartEnd {
+    index: usize,
+  },
+}
+
+enum DecoderState {
+  Header,
+  Manifest {
+    length: usize,
+  },
+  Parts {
+    sizes: Vec<u64>,
+    index: usize,
+    remaining: u64,
+    digest: PartDigest,
+  },
+  PartDigest {
+    sizes: Vec<u64>,
+    index: usize,
+    expected: [u8; PART_DIGEST_SIZE],
+  },
+}
+
+/// Incremental archive decoder. Archive data can be fed in chunks
+/// of arbitrary size, e.g. directly from a network stream.
+pub struct BackupArchiveDecoder {
+  state: DecoderState,
+  buffer: Vec<u8>,
+}
+
+impl Default for BackupArchiveDecoder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl BackupArchiveDecoder {
+  pub fn new() -> Self {
+    Self {
+      state: DecoderState::Header,
+      buffer: Vec::new(),
+    }
+  }
+
+  /// Consumes next chunk of archive data. Returns events decoded so far.
+  pub fn feed(
+    &mut self,
+    mut data: &[u8],
+  ) -> Result<Vec<ArchiveEvent>, ArchiveError> {
+    let mut events = Vec::new();
+
+    loop {
+      match &mut self.state {
+        DecoderState::Header => {
+          if !Self::fill_buffer(&mut self.buffer, &mut data, HEADER_SIZE) {
+            return Ok(events);
+          }
+          let header = std::mem::take(&mut self.buffer);
+          let (magic, length) = header.split_at(ARCHIVE_MAGIC.len());
+          if magic != ARCHIVE_MAGIC {
+            return Err(ArchiveError::InvalidMagic);
+          }
+          let length = u32::from_be_bytes(
+            length
+              .try_into()
+              .expect("manifest length must have 4 bytes"),
+          );
+          if length > MAX_MANIFEST_SIZE {
+            return Err(ArchiveError::ManifestTooLarge);
+          }
+          self.state = DecoderState::Manifest {
+            length: length as usize,
+          };
+        }
+        DecoderState::Manifest { length } => {
+          if !Self::fill_buffer(&mut self.buffer, &mut data, *length) {
+            return Ok(events);
+          }
+          let manifest: BackupArchiveManifest =
+            serde_json::from_slice(&std::mem::take(&mut self.buffer))
+              .map_err(ArchiveError::InvalidManifest)?;
+          if manifest.format_version != ARCHIVE_FORMAT_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(
+              manifest.format_version,
+            ));
+          }
+
+          let sizes: Vec<u64> =
+            manifest.parts.iter().map(BackupArchivePart::size).collect();
+          let remaining = sizes.first().copied().unwrap_or_default();
+          events.push(ArchiveEvent::Manifest(manifest));
+          self.state = DecoderState::Parts {
+            sizes,
+            index: 0,
+            remaining,
+            digest: PartDigest::new(),
+          };
+        }
+        DecoderState::Parts {
+          sizes,
+          index,
+          remaining,
+          digest,
+        } => {
+          if *index >= sizes.len() {
+            if data.is_empty() {
+              return Ok(events);
+            }
+            return Err(ArchiveError::TrailingData);
+          }
+          if *remaining == 0 {
+            // empty parts are followed by a digest as well
+            self.state = DecoderState::PartDigest {
+              sizes: std::mem::take(sizes),
+              index: *index,
+              expected: std::mem::take(digest).finalize(),
+            };
+            continue;
+          }
+          if data.is_empty() {
+            return Ok(events);
+          }
+
+          let remaining_size =
+            usize::try_from(*remaining).unwrap_or(usize::MAX);
+          let chunk_size = data.len().min(remaining_size);
+          let (chunk, rest) = data.split_at(chunk_size);
+          data = rest;
+          *remaining -= chunk_size as u64;
+          digest.update(chunk);
+          events.push(ArchiveEvent::PartData {
+            index: *index,
+            data: chunk.to_vec(),
+          });
+        }
+        DecoderState::PartDigest {
+          sizes,
+          index,
+          expected,
+        } => {
+          if !Self::fill_buffer(&mut self.buffer, &mut data, PART_DIGEST_SIZE) {
+            return Ok(events);
+          }
+          if std::mem::take(&mut self.buffer) != *expected {
+            return Err(ArchiveError::DigestMismatch(*index));
+          }
+          events.push(ArchiveEvent::PartEnd { index: *index });
+
+          let index = *index + 1;
+          self.state = DecoderState::Parts {
+            remaining: sizes.get(index).copied().unwrap_or_default(),
+            sizes: std::mem::take(sizes),
+            index,
+            digest: PartDigest::new(),
+          };
+        }
+      }
+    }
+  }
+
+  /// Should be called after all archive data has been fed.
+  /// Fails if the archive is incomplete.
+  pub fn finish(self) -> Result<(), ArchiveError> {
+    match self.state {
+      DecoderState::Parts { sizes, index, .. } if index >= sizes.len() => {
+        Ok(())
+      }
+      _ => Err(ArchiveError::UnexpectedEof),
+    }
+  }
+
+  /// Moves data to the buffer until it has `target_size` bytes.
+  /// Returns `true` if the buffer is full.
+  fn fill_buffer(
+    buffer: &mut Vec<u8>,
+    data: &mut &[u8],
+    target_size: usize,
+  ) -> bool {
+    let missing = target_size.saturating_sub(buffer.len());
+    let (chunk, rest) = data.split_at(missing.min(data.len()));
+    buffer.extend_from_slice(chunk);
+    *data = rest;
+    buffer.len() >= target_size
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_manifest() -> BackupArchiveManifest {
+    BackupArchiveManifest {
+      format_version: ARCHIVE_FORMAT_VERSION,
+      backup_id: "backup_id".to_string(),
+      creation_timestamp: "2025-05-13T11:20:33.799712136Z".to_string(),
+      siwe_backup_msg: None,
+      version_info: BackupVersionInfo::default(),
+      attachments: Vec::new(),
+      parts: vec![
+        BackupArchivePart::UserKeys {
+          blob_hash: "keys".to_string(),
+          size: 3,
+        },
+        BackupArchivePart::Attachment {
+          blob_hash: "empty".to_string(),
+          size: 0,
+        },
+        BackupArchivePart::Log {
+          log_id: 1,
+          size: 5,
+          attachments: vec!["empty".to_string()],
+          device_id: None,
+          uploaded_at: None,
+        },
+        BackupArchivePart::Log {
+          log_id: 1,
+          size: 2,
+          attachments: Vec::new(),
+          device_id: Some("device".to_string()),
+          uploaded_at: Some("2025-05-13T11:21:00Z".to_string()),
+        },
+      ],
+    }
+  }
+
+  fn sample_archive() -> Vec<u8> {
+    let mut archive = encode_header(&sample_manifest()).unwrap();
+    for part in [&b"abc"[..], b"", b"hello", b"hi"] {
+      let mut digest = PartDigest::new();
+      digest.update(part);
+      archive.extend_from_slice(part);
+      archive.extend_from_slice(&digest.finalize());
+    }
+    archive
+  }
+
+  fn collect_parts(
+    events: Vec<ArchiveEvent>,
+  ) -> (Option<BackupArchiveManifest>, Vec<Vec<u8>>) {
+    let (mut manifest, mut parts, mut current) = (None, Vec::new(), Vec::new());
+    for event in events {
+      match event {
+        ArchiveEvent::Manifest(value) => manifest = Some(value),
+        ArchiveEvent::PartData { index, data } => {
+          assert_eq!(index, parts.len());
+          current.extend(data);
+        }
+        ArchiveEvent::PartEnd { index } => {
+          assert_eq!(index, parts.len());
+          parts.push(std::mem::take(&mut current));
+        }
+      }
+    }
+    (manifest, parts)
+  }
+
+  #[test]
+  fn test_archive_roundtrip_in_chunks() {
+    let archive = sample_archive();
+    let expected_parts = vec![
+      b"abc".to_vec(),
+      Vec::new(),
+      b"hello".to_vec(),
+      b"hi".to_vec(),
+    ];
+
+    for chunk_size in [1, 2, 7, archive.len()] {
+      let mut decoder = BackupArchiveDecoder::new();
+      let mut events = Vec::new();
+      for chunk in archive.chunks(chunk_size) {
+        events.extend(decoder.feed(chunk).unwrap());
+      }
+      decoder.finish().unwrap();
+
+      let (manifest, parts) = collect_parts(events);
+      assert_eq!(manifest, Some(sample_manifest()));
+      assert_eq!(parts, expected_parts, "chunk size {chunk_size}");
+    }
+  }
+
+  #[test]
+  fn test_log_part_without_device_stream() {
+    let part: BackupArchivePart = serde_json::from_str(
+      r#"{"type":"log","logID":1,"size":5,"attachments":[]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+      part,
+      BackupArchivePart::Log {
+        log_id: 1,
+        size: 5,
+        attachments: Vec::new(),
+        device_id: None,
+        uploaded_at: None,
+      }
+    );
+  }
+
+  #[test]
+  fn test_archive_decoder_errors() {
+    let mut archive = sample_archive();
+    archive[0] = b'X';
+    let result = BackupArchiveDecoder::new().feed(&archive);
+    assert!(matches!(result, Err(ArchiveError::InvalidMagic)));
+
+    let archive = sample_archive();
+    let mut decoder = BackupArchiveDecoder::new();
+    decoder.feed(&archive[..archive.len() - 1]).unwrap();
+    assert!(matches!(decoder.finish(), Err(ArchiveError::UnexpectedEof)));
+
+    let mut archive = sample_archive();
+    archive.push(0);
+    let result = BackupArchiveDecoder::new().feed(&archive);
+    assert!(matches!(result, Err(ArchiveError::TrailingData)));
+  }
+
+  #[test]
+  fn test_archive_part_digest_mismatch() {
+    let archive = sample_archive();
+    let header_size = encode_header(&sample_manifest()).unwrap().len();
+    // "hello" follows "abc" and the digests of both first parts
+    let hello_offset = header_size + 3 + 2 * PART_DIGEST_SIZE;
+    assert_eq!(&archive[hello_offset..hello_offset + 5], b"hello");
+
+    let mut corrupted = archive.clone();
+    corrupted[hello_offset] = b'j';
+    let mut decoder = BackupArchiveDecoder::new();
+    let mut events = Vec::new();
+    let result = corrupted
+      .chunks(4)
+      .try_for_each(|chunk| decoder.feed(chunk).map(|it| events.extend(it)));
+    assert!(matches!(result, Err(ArchiveError::DigestMismatch(2))));
+
+    // the corrupted part is never reported as complete
+    let (_, parts) = collect_parts(events);
+    assert_eq!(parts.len(), 2);
+  }
+}
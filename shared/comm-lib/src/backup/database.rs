@@ -29,6 +29,7 @@ pub mod backup_table {
     pub const ATTACHMENTS: &str = "attachments";
     pub const SIWE_BACKUP_MSG: &str = "siweBackupMsg";
     pub const VERSION_INFO: &str = "versionInfo";
+    pub const SIZE: &str = "size";
 
     pub mod version_info {
       pub const CODE_VERSION: &str = "codeVersion";
@@ -49,6 +50,11 @@ pub struct BackupItem {
   pub siwe_backup_msg: Option<String>,
   #[serde(default)]
   pub version_info: BackupVersionInfo,
+  /// Size of user keys and user data in bytes, counted towards the user
+  /// quota. Zero if the item wasn't counted. Attachments are counted
+  /// by their references.
+  #[serde(default)]
+  pub size: u64,
 }
 
 impl BackupItem {
@@ -70,6 +76,7 @@ impl BackupItem {
       attachments,
       siwe_backup_msg,
       version_info,
+      size: 0,
     }
   }
 
@@ -81,19 +88,15 @@ impl BackupItem {
     );
   }
 
+  /// Revokes User Data holder. Attachment holders are shared between
+  /// backups and reference-counted by the Backup service,
+  /// so they aren't revoked here.
   #[cfg(feature = "blob-client")]
   pub fn revoke_user_data_holders(&self, blob_client: &BlobServiceClient) {
     if let Some(user_data) = &self.user_data {
       blob_client
         .schedule_revoke_holder(&user_data.blob_hash, &user_data.holder);
     }
-
-    for attachment_info in &self.attachments {
-      blob_client.schedule_revoke_holder(
-        &attachment_info.blob_hash,
-        &attachment_info.holder,
-      );
-    }
   }
 
   #[cfg(feature = "aws")]
@@ -138,6 +141,10 @@ impl From<BackupItem> for AttributeMap {
         backup_table::attr::VERSION_INFO.to_string(),
         value.version_info.into(),
       ),
+      (
+        backup_table::attr::SIZE.to_string(),
+        AttributeValue::N(value.size.to_string()),
+      ),
     ]);
 
     if let Some(user_data) = value.user_data {
@@ -211,6 +218,13 @@ impl TryFrom<AttributeMap> for BackupItem {
       .take_attr::<Option<_>>(backup_table::attr::VERSION_INFO)?
       .unwrap_or_default();
 
+    // items stored before sizes were tracked aren't counted
+    let size = value
+      .remove(backup_table::attr::SIZE)
+      .map(|size| parse_int_attribute(backup_table::attr::SIZE, Some(size)))
+      .transpose()?
+      .unwrap_or_default();
+
     Ok(BackupItem {
       user_id,
       backup_id,
@@ -220,6 +234,7 @@ impl TryFrom<AttributeMap> for BackupItem {
       attachments,
       siwe_backup_msg,
       version_info,
+      size,
     })
   }
 }
@@ -2,10 +2,12 @@ use crate::auth::UserIdentity;
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
 
+/// offline backup archive format
+pub mod archive;
 /// shared database types and constants
 pub mod database;
 
-#[derive(Debug, Display, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Display, Default, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[display(
   fmt = "BackupVersion(code={}, state={}, db={})",
@@ -34,11 +36,49 @@ pub struct LatestBackupInfoResponse {
   pub keyserver_device_id: Option<String>,
   // ISO 8601 / RFC 3339 DateTime string
   pub creation_timestamp: String,
+  /// Size of all backups of the user, counted towards [`BackupQuota`]
   pub total_backup_size: u64,
   pub version_info: BackupVersionInfo,
+  /// Number of all logs of the user, counted towards [`BackupQuota`]
+  #[serde(default)]
+  pub log_count: u64,
+  #[serde(default)]
+  pub quota: BackupQuota,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Per-user backup limits enforced by the backup service.
+/// `None` means there is no limit.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupQuota {
+  /// Max total size of all backups of the user: user keys, user data,
+  /// attachments and logs
+  pub max_backup_size: Option<u64>,
+  /// Max number of logs of the user, including device logs
+  pub max_log_count: Option<u64>,
+  /// Max size of a single log content
+  pub max_log_size: Option<u64>,
+}
+
+/// Current version of the log websocket protocol. Clients that connect
+/// without sending [`LogWSRequest::Handshake`] are treated as version 0.
+pub const LOG_WS_PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version accepted in [`LogWSRequest::Handshake`]
+pub const LOG_WS_MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional log websocket protocol features negotiated during handshake
+pub mod log_ws_capabilities {
+  /// Invalid requests are answered with [`super::LogWSResponse::RequestError`]
+  /// instead of [`super::LogWSResponse::ServerError`]
+  pub const TYPED_ERRORS: &str = "typed_errors";
+  /// Server supports [`super::LogWSRequest::UploadDeviceLog`]
+  /// and [`super::LogWSRequest::DownloadDeviceLogs`]
+  pub const DEVICE_LOGS: &str = "device_logs";
+
+  pub const ALL: &[&str] = &[TYPED_ERRORS, DEVICE_LOGS];
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UploadLogRequest {
   pub backup_id: String,
   pub log_id: usize,
@@ -46,20 +86,123 @@ pub struct UploadLogRequest {
   pub attachments: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DownloadLogsRequest {
   pub backup_id: String,
   pub from_id: Option<usize>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, derive_more::From)]
+/// Position of a log in the device log streams, which are ordered
+/// by upload time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceLogPosition {
+  /// Unix timestamp in milliseconds
+  pub uploaded_at: i64,
+  pub device_id: String,
+  pub log_id: usize,
+}
+
+/// Position in the merged download of logs, see
+/// [`LogWSRequest::DownloadDeviceLogs`]. The primary stream is tracked
+/// separately, because its logs are downloaded in the order of their IDs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceLogCursor {
+  /// Last downloaded log of the primary stream
+  pub primary_log_id: Option<usize>,
+  /// Last downloaded log of the device streams
+  pub device_log: Option<DeviceLogPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DownloadDeviceLogsRequest {
+  pub backup_id: String,
+  /// Download starts after this log
+  pub from: Option<DeviceLogCursor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogWSHandshake {
+  pub protocol_version: u16,
+  pub capabilities: Vec<String>,
+}
+
+impl LogWSHandshake {
+  /// Handshake of the current protocol version with all capabilities
+  pub fn current() -> Self {
+    LogWSHandshake {
+      protocol_version: LOG_WS_PROTOCOL_VERSION,
+      capabilities: log_ws_capabilities::ALL
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect(),
+    }
+  }
+
+  pub fn has_capability(&self, capability: &str) -> bool {
+    self.capabilities.iter().any(|it| it == capability)
+  }
+}
+
+/// Log websocket messages are bincode-encoded, so enum variants are
+/// identified by their index. New variants must only be appended
+/// at the end to stay compatible with older clients.
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize, derive_more::From,
+)]
 pub enum LogWSRequest {
   Authenticate(UserIdentity),
+  /// Uploads a log to the primary log stream of the backup
   UploadLog(UploadLogRequest),
   DownloadLogs(DownloadLogsRequest),
+  Handshake(LogWSHandshake),
+  /// Uploads a log to the log stream of the authenticated device.
+  /// Each device has its own sequence of log IDs within a backup.
+  #[from(ignore)]
+  UploadDeviceLog(UploadLogRequest),
+  /// Downloads logs of the primary stream and all device streams
+  /// of a backup, merged in the order they were uploaded. Logs within
+  /// the primary stream keep the order of their IDs.
+  DownloadDeviceLogs(DownloadDeviceLogsRequest),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl LogWSRequest {
+  pub const VARIANT_COUNT: u32 = 6;
+
+  /// Reads the variant index from a bincode-encoded request. Useful when
+  /// the request can't be deserialized.
+  pub fn variant_index(bytes: &[u8]) -> Option<u32> {
+    let index_bytes = bytes.get(..4)?.try_into().ok()?;
+    Some(u32::from_le_bytes(index_bytes))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LogWSRequestError {
+  /// Request variant isn't known to the server
+  UnknownRequest {
+    variant_index: u32,
+  },
+  /// Request couldn't be deserialized
+  MalformedRequest,
+  /// Client protocol version is too old
+  UnsupportedProtocolVersion {
+    min_supported_version: u16,
+  },
+  LogSizeQuotaExceeded {
+    max_log_size: u64,
+  },
+  LogCountQuotaExceeded {
+    max_log_count: u64,
+  },
+  BackupSizeQuotaExceeded {
+    max_backup_size: u64,
+  },
+  /// User data was deleted, uploads are rejected until it's restored
+  UserDataDeleted,
+}
+
+/// See [`LogWSRequest`] for compatibility rules
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LogWSResponse {
   LogUploaded {
     backup_id: String,
@@ -76,4 +219,197 @@ pub enum LogWSResponse {
   ServerError,
   AuthSuccess,
   Unauthenticated,
+  /// Negotiated protocol version and capabilities
+  HandshakeAccepted(LogWSHandshake),
+  /// Sent only if [`log_ws_capabilities::TYPED_ERRORS`] was negotiated
+  RequestError(LogWSRequestError),
+  /// `device_id` is `None` for logs of the primary stream
+  DeviceLogDownload {
+    device_id: Option<String>,
+    log_id: usize,
+    content: Vec<u8>,
+    attachments: Option<Vec<String>>,
+  },
+  /// `last_cursor` is `None` when there are no more logs to download
+  DeviceLogDownloadFinished {
+    last_cursor: Option<DeviceLogCursor>,
+  },
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Serializes the value and checks that it matches the pinned
+  /// hex-encoded bincode bytes in both directions
+  fn assert_wire_format<T>(value: T, expected_hex: &str)
+  where
+    T: Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+  {
+    let bytes = bincode::serialize(&value).expect("serialization failed");
+    assert_eq!(hex::encode(&bytes), expected_hex, "{value:?}");
+
+    let expected_bytes = hex::decode(expected_hex).unwrap();
+    let deserialized: T =
+      bincode::deserialize(&expected_bytes).expect("deserialization failed");
+    assert_eq!(deserialized, value);
+  }
+
+  #[test]
+  fn test_log_ws_request_wire_format() {
+    assert_wire_format(
+      LogWSRequest::Authenticate(UserIdentity {
+        user_id: "user".to_string(),
+        access_token: "token".to_string(),
+        device_id: "device".to_string(),
+      }),
+      "000000000400000000000000757365720500000000000000746f6b656e06000000\
+       00000000646576696365",
+    );
+    assert_wire_format(
+      LogWSRequest::UploadLog(UploadLogRequest {
+        backup_id: "backup".to_string(),
+        log_id: 1,
+        content: vec![1, 2, 3],
+        attachments: Some(vec!["att".to_string()]),
+      }),
+      "0100000006000000000000006261636b75700100000000000000030000000000\
+       00000102030101000000000000000300000000000000617474",
+    );
+    assert_wire_format(
+      LogWSRequest::DownloadLogs(DownloadLogsRequest {
+        backup_id: "backup".to_string(),
+        from_id: Some(2),
+      }),
+      "0200000006000000000000006261636b7570010200000000000000",
+    );
+    assert_wire_format(
+      LogWSRequest::Handshake(LogWSHandshake::current()),
+      "03000000010002000000000000000c0000000000000074797065645f6572726f72\
+       730b000000000000006465766963655f6c6f6773",
+    );
+    assert_wire_format(
+      LogWSRequest::UploadDeviceLog(UploadLogRequest {
+        backup_id: "backup".to_string(),
+        log_id: 1,
+        content: vec![1, 2, 3],
+        attachments: None,
+      }),
+      "0400000006000000000000006261636b75700100000000000000030000000000\
+       000001020300",
+    );
+    assert_wire_format(
+      LogWSRequest::DownloadDeviceLogs(DownloadDeviceLogsRequest {
+        backup_id: "backup".to_string(),
+        from: Some(DeviceLogCursor {
+          primary_log_id: Some(3),
+          device_log: Some(DeviceLogPosition {
+            uploaded_at: 1000,
+            device_id: "device".to_string(),
+            log_id: 2,
+          }),
+        }),
+      }),
+      "0500000006000000000000006261636b75700101030000000000000001e80300\
+       000000000006000000000000006465766963650200000000000000",
+    );
+  }
+
+  #[test]
+  fn test_log_ws_response_wire_format() {
+    assert_wire_format(
+      LogWSResponse::LogUploaded {
+        backup_id: "backup".to_string(),
+        log_id: 1,
+      },
+      "0000000006000000000000006261636b75700100000000000000",
+    );
+    assert_wire_format(
+      LogWSResponse::LogDownload {
+        log_id: 1,
+        content: vec![1, 2, 3],
+        attachments: None,
+      },
+      "010000000100000000000000030000000000000001020300",
+    );
+    assert_wire_format(
+      LogWSResponse::LogDownloadFinished {
+        last_log_id: Some(1),
+      },
+      "02000000010100000000000000",
+    );
+    assert_wire_format(LogWSResponse::ServerError, "03000000");
+    assert_wire_format(LogWSResponse::AuthSuccess, "04000000");
+    assert_wire_format(LogWSResponse::Unauthenticated, "05000000");
+    assert_wire_format(
+      LogWSResponse::HandshakeAccepted(LogWSHandshake::current()),
+      "06000000010002000000000000000c0000000000000074797065645f6572726f72\
+       730b000000000000006465766963655f6c6f6773",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::UnknownRequest {
+        variant_index: 9,
+      }),
+      "070000000000000009000000",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::MalformedRequest),
+      "0700000001000000",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(
+        LogWSRequestError::UnsupportedProtocolVersion {
+          min_supported_version: 1,
+        },
+      ),
+      "07000000020000000100",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::LogSizeQuotaExceeded {
+        max_log_size: 1024,
+      }),
+      "07000000030000000004000000000000",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::LogCountQuotaExceeded {
+        max_log_count: 1024,
+      }),
+      "07000000040000000004000000000000",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::BackupSizeQuotaExceeded {
+        max_backup_size: 1024,
+      }),
+      "07000000050000000004000000000000",
+    );
+    assert_wire_format(
+      LogWSResponse::RequestError(LogWSRequestError::UserDataDeleted),
+      "0700000006000000",
+    );
+    assert_wire_format(
+      LogWSResponse::DeviceLogDownload {
+        device_id: Some("device".to_string()),
+        log_id: 1,
+        content: vec![1, 2, 3],
+        attachments: None,
+      },
+      "0800000001060000000000000064657669636501000000000000000300000000\
+       00000001020300",
+    );
+    assert_wire_format(
+      LogWSResponse::DeviceLogDownloadFinished { last_cursor: None },
+      "0900000000",
+    );
+  }
+
+  #[test]
+  fn test_unknown_log_ws_request() {
+    let bytes = LogWSRequest::VARIANT_COUNT.to_le_bytes();
+    assert!(bincode::deserialize::<LogWSRequest>(&bytes).is_err());
+    assert_eq!(
+      LogWSRequest::variant_index(&bytes),
+      Some(LogWSRequest::VARIANT_COUNT)
+    );
+    assert_eq!(LogWSRequest::variant_index(&[1, 0]), None);
+  }
 }
@@ -20,6 +20,14 @@ impl EncryptionKey {
   pub fn new() -> EncryptionKey {
     Aes256Gcm::generate_key(&mut OsRng).into()
   }
+
+  /// Creates a key from raw bytes. Returns `None` if they aren't 32 bytes.
+  pub fn from_bytes(bytes: &[u8]) -> Option<EncryptionKey> {
+    if bytes.len() != aes_gcm::Key::<Aes256Gcm>::default().len() {
+      return None;
+    }
+    Some(Self(*aes_gcm::Key::<Aes256Gcm>::from_slice(bytes)))
+  }
 }
 
 impl Default for EncryptionKey {
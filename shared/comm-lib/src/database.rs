@@ -9,6 +9,7 @@ use std::str::FromStr;
 
 #[cfg(feature = "blob-client")]
 pub mod blob;
+pub mod localstack;
 pub mod shared_tables;
 // # Useful type aliases
 
@@ -0,0 +1,33 @@
+//! Helpers for tests of DynamoDB operations. The tests need the tables
+//! created by the dev Terraform config in Localstack and are marked
+//! `#[ignore]`, so they're run with `cargo test -- --ignored`.
+
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::config::{Credentials, Region};
+
+const LOCALSTACK_ENDPOINT_ENV_VAR: &str = "LOCALSTACK_ENDPOINT";
+const DEFAULT_LOCALSTACK_ENDPOINT: &str = "http://localhost:4566";
+
+/// Loads AWS config pointing to Localstack. The endpoint can be
+/// overridden with the `LOCALSTACK_ENDPOINT` environment variable.
+pub async fn localstack_config() -> aws_config::SdkConfig {
+  let endpoint = std::env::var(LOCALSTACK_ENDPOINT_ENV_VAR)
+    .unwrap_or_else(|_| DEFAULT_LOCALSTACK_ENDPOINT.to_string());
+  aws_config::defaults(BehaviorVersion::latest())
+    .endpoint_url(endpoint)
+    .region(Region::new("us-east-2"))
+    .credentials_provider(Credentials::new(
+      "test",
+      "test",
+      None,
+      None,
+      "localstack",
+    ))
+    .load()
+    .await
+}
+
+/// Random user ID, so that test runs don't see each other's items
+pub fn random_user_id() -> String {
+  format!("test-user-{}", uuid::Uuid::new_v4())
+}
@@ -18,10 +18,39 @@ pub struct IdentitySearchPrefix {
   pub prefix: String,
 }
 
+/// Exact match of a linked Farcaster ID (FID)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentitySearchFarcaster {
+  #[serde(rename = "farcasterID")]
+  pub farcaster_id: String,
+}
+
+/// Match of the Farcaster username (fname) of a linked FID
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentitySearchFarcasterUsername {
+  pub username: String,
+}
+
+/// Exact match of a wallet address, or an ENS name resolving to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentitySearchWallet {
+  pub wallet: String,
+}
+
+/// Typo-tolerant username match
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentitySearchFuzzy {
+  pub query: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum IdentitySearchMethod {
   IdentitySearchPrefix(IdentitySearchPrefix),
+  IdentitySearchFarcaster(IdentitySearchFarcaster),
+  IdentitySearchFarcasterUsername(IdentitySearchFarcasterUsername),
+  IdentitySearchWallet(IdentitySearchWallet),
+  IdentitySearchFuzzy(IdentitySearchFuzzy),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,4 +58,7 @@ pub enum IdentitySearchMethod {
 pub struct IdentitySearchQuery {
   pub id: String,
   pub search_method: IdentitySearchMethod,
+  /// Cursor returned with the previous page of results
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cursor: Option<String>,
 }
@@ -31,6 +31,13 @@ pub struct IdentitySearchUser {
 pub struct IdentitySearchResult {
   pub id: String,
   pub hits: Vec<IdentitySearchUser>,
+  /// Not set if there are no more results
+  #[serde(
+    rename = "nextCursor",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,6 +12,7 @@ pub mod message_to_tunnelbroker;
 pub mod message_to_tunnelbroker_request;
 pub mod notif;
 pub mod session;
+pub mod username_updated;
 
 pub use device_list_updated::*;
 pub use keys::*;
@@ -22,6 +23,7 @@ pub use message_to_device_request_status::*;
 pub use message_to_tunnelbroker::*;
 pub use message_to_tunnelbroker_request::*;
 pub use session::*;
+pub use username_updated::*;
 pub use websocket_messages::{
   ConnectionInitializationResponse, ConnectionInitializationStatus, Heartbeat,
 };
@@ -75,6 +77,7 @@ pub enum TunnelbrokerToDeviceMessage {
 pub enum ServiceToDeviceMessages {
   RefreshKeysRequest(RefreshKeyRequest),
   IdentityDeviceListUpdated(IdentityDeviceListUpdated),
+  IdentityUsernameUpdated(IdentityUsernameUpdated),
   BadDeviceToken(BadDeviceToken),
 }
 
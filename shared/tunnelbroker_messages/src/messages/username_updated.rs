@@ -0,0 +1,10 @@
+//! Message sent from Identity to user's devices informing that the username
+//! was changed.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub struct IdentityUsernameUpdated {
+  pub username: String,
+}
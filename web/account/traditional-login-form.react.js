@@ -81,6 +81,11 @@ function TraditionalLoginForm(): React.Node {
         setUsername('');
         setPassword('');
         setErrorMessage('incorrect username or password');
+      } else if (
+        messageForException === 'too_many_login_attempts' ||
+        messageForException === 'login_temporarily_locked'
+      ) {
+        setErrorMessage('too many attempts, please try again later');
       } else if (
         messageForException === 'client_version_unsupported' ||
         messageForException === 'unsupported_version' ||